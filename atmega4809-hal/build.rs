@@ -0,0 +1,404 @@
+// build.rs
+//
+// Reads the ATDF device description bundled in `../atpack` and writes typed
+// register blocks, bitfields and enumerated values for every peripheral
+// instance into `$OUT_DIR/regs.rs`. See `src/regs.rs` for the types the
+// generated code is built on.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use roxmltree::{Document, Node};
+
+const ATDF: &str = "../atpack/atdf/ATmega4809.atdf";
+
+fn main() {
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let atdf_path = Path::new(&manifest_dir).join(ATDF);
+    let atdf = fs::read_to_string(&atdf_path)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", atdf_path.display(), e));
+    let doc = Document::parse(&atdf).expect("ATDF is not valid XML");
+
+    let out = generate(&doc);
+
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let dest_path = Path::new(&out_dir).join("regs.rs");
+    fs::write(dest_path, out).unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", ATDF);
+}
+
+/// One peripheral instance from `<device><peripherals>`, e.g. `TWI0` of module `TWI`.
+struct Instance {
+    name: String,
+    module: String,
+    offset: u16,
+}
+
+fn generate(doc: &Document) -> String {
+    let root = doc.root_element();
+    let device = child(root, "devices")
+        .and_then(|d| child(d, "device"))
+        .expect("ATDF has no <device>");
+    let peripherals = child(device, "peripherals").expect("ATDF has no <peripherals>");
+    let modules = child(root, "modules").expect("ATDF has no <modules>");
+
+    let mut instances = Vec::new();
+    for module in elements(peripherals, "module") {
+        for instance in elements(module, "instance") {
+            let group = match child(instance, "register-group") {
+                Some(g) if g.attribute("address-space") == Some("data") => g,
+                _ => continue,
+            };
+            instances.push(Instance {
+                name: attr(instance, "name").to_string(),
+                module: group
+                    .attribute("name-in-module")
+                    .unwrap_or_else(|| attr(module, "name"))
+                    .to_string(),
+                offset: parse_int(attr(group, "offset")) as u16,
+            });
+        }
+    }
+    instances.sort_by_key(|i| i.offset);
+
+    let mut out = String::new();
+    writeln!(out, "// @generated by build.rs from {}. Do not edit.", ATDF).unwrap();
+    writeln!(out).unwrap();
+
+    // Only emit modules that have at least one instance on this chip.
+    let mut emitted = BTreeSet::new();
+    for module in elements(modules, "module") {
+        let name = attr(module, "name");
+        if !instances.iter().any(|i| i.module == name) || !emitted.insert(name) {
+            continue;
+        }
+        gen_module(&mut out, module);
+    }
+
+    for i in &instances {
+        writeln!(out, "/// `{}` at `0x{:04X}`", i.name, i.offset).unwrap();
+        writeln!(
+            out,
+            "pub const {}: {}::RegisterBlock = {}::RegisterBlock::at(0x{:04X});",
+            i.name,
+            i.module.to_lowercase(),
+            i.module.to_lowercase(),
+            i.offset
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+fn gen_module(out: &mut String, module: Node) {
+    let name = attr(module, "name");
+    let group = elements(module, "register-group")
+        .find(|g| attr(*g, "name") == name)
+        .unwrap_or_else(|| panic!("module {} has no register-group", name));
+
+    let caption = module.attribute("caption").unwrap_or(name);
+    writeln!(out, "/// {}", caption).unwrap();
+    // `LOCKBIT.LOCKBIT` and friends
+    writeln!(out, "#[allow(clippy::module_inception)]").unwrap();
+    writeln!(out, "pub mod {} {{", ident(&name.to_lowercase())).unwrap();
+    writeln!(out, "    #[allow(unused_imports)]").unwrap();
+    writeln!(out, "    use crate::regs::{{Field, Reg16, Reg8}};").unwrap();
+    writeln!(out).unwrap();
+
+    let modes: Vec<&str> = elements(group, "mode").map(|m| attr(m, "name")).collect();
+    let registers: Vec<Node> = elements(group, "register").collect();
+
+    if modes.is_empty() {
+        gen_block(out, "    ", caption, &registers, None);
+    } else {
+        // Registers are overlaid per mode (e.g. TCA single/split), so each
+        // mode gets its own block and the top-level block only switches views.
+        writeln!(out, "    #[derive(Clone, Copy)]").unwrap();
+        writeln!(out, "    pub struct RegisterBlock {{").unwrap();
+        writeln!(out, "        base: u16,").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "    impl RegisterBlock {{").unwrap();
+        writeln!(out, "        pub(crate) const fn at(base: u16) -> Self {{").unwrap();
+        writeln!(out, "            Self {{ base }}").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "        pub const fn base(&self) -> u16 {{").unwrap();
+        writeln!(out, "            self.base").unwrap();
+        writeln!(out, "        }}").unwrap();
+        for mode in &modes {
+            let m = ident(&mode.to_lowercase());
+            writeln!(out).unwrap();
+            writeln!(out, "        /// Register view used in {} mode", mode).unwrap();
+            writeln!(
+                out,
+                "        pub const fn {}(&self) -> {}::RegisterBlock {{",
+                m, m
+            )
+            .unwrap();
+            writeln!(out, "            {}::RegisterBlock::at(self.base)", m).unwrap();
+            writeln!(out, "        }}").unwrap();
+        }
+        writeln!(out, "    }}").unwrap();
+
+        for mode in &modes {
+            let regs: Vec<Node> = registers
+                .iter()
+                .copied()
+                .filter(|r| match r.attribute("modes") {
+                    Some(m) => m.split_whitespace().any(|m| m == *mode),
+                    None => true,
+                })
+                .collect();
+            writeln!(out).unwrap();
+            writeln!(out, "    /// {} mode", mode).unwrap();
+            writeln!(out, "    pub mod {} {{", ident(&mode.to_lowercase())).unwrap();
+            writeln!(out, "        #[allow(unused_imports)]").unwrap();
+            writeln!(out, "        use crate::regs::{{Field, Reg16, Reg8}};").unwrap();
+            writeln!(out).unwrap();
+            gen_block(out, "        ", caption, &regs, Some("super::super"));
+            writeln!(out, "    }}").unwrap();
+        }
+    }
+
+    let prefix = format!("{}_", name);
+    for values in elements(module, "value-group") {
+        gen_values(out, values, &prefix);
+    }
+
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+}
+
+fn gen_block(
+    out: &mut String,
+    indent: &str,
+    caption: &str,
+    registers: &[Node],
+    values_path: Option<&str>,
+) {
+    let values_path = values_path.unwrap_or("super");
+
+    writeln!(out, "{}/// {}", indent, caption).unwrap();
+    writeln!(out, "{}#[derive(Clone, Copy)]", indent).unwrap();
+    writeln!(out, "{}pub struct RegisterBlock {{", indent).unwrap();
+    writeln!(out, "{}    base: u16,", indent).unwrap();
+    writeln!(out, "{}}}", indent).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{}impl RegisterBlock {{", indent).unwrap();
+    writeln!(
+        out,
+        "{}    pub(crate) const fn at(base: u16) -> Self {{",
+        indent
+    )
+    .unwrap();
+    writeln!(out, "{}        Self {{ base }}", indent).unwrap();
+    writeln!(out, "{}    }}", indent).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{}    pub const fn base(&self) -> u16 {{", indent).unwrap();
+    writeln!(out, "{}        self.base", indent).unwrap();
+    writeln!(out, "{}    }}", indent).unwrap();
+
+    for reg in registers {
+        let name = attr(*reg, "name");
+        let offset = parse_int(attr(*reg, "offset"));
+        let ty = match parse_int(reg.attribute("size").unwrap_or("1")) {
+            1 => "Reg8",
+            2 => "Reg16",
+            s => panic!("register {} has unsupported size {}", name, s),
+        };
+        writeln!(out).unwrap();
+        if let Some(c) = reg.attribute("caption") {
+            writeln!(out, "{}    /// {} (offset `0x{:02X}`)", indent, c, offset).unwrap();
+        }
+        writeln!(out, "{}    #[inline(always)]", indent).unwrap();
+        writeln!(
+            out,
+            "{}    pub const fn {}(&self) -> {} {{",
+            indent,
+            ident(&name.to_lowercase()),
+            ty
+        )
+        .unwrap();
+        if offset == 0 {
+            writeln!(out, "{}        {}::at(self.base)", indent, ty).unwrap();
+        } else {
+            writeln!(
+                out,
+                "{}        {}::at(self.base + 0x{:02X})",
+                indent, ty, offset
+            )
+            .unwrap();
+        }
+        writeln!(out, "{}    }}", indent).unwrap();
+    }
+    writeln!(out, "{}}}", indent).unwrap();
+
+    for reg in registers {
+        let fields: Vec<Node> = elements(*reg, "bitfield").collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let name = attr(*reg, "name");
+        writeln!(out).unwrap();
+        if let Some(c) = reg.attribute("caption") {
+            writeln!(out, "{}/// Fields of `{}`: {}", indent, name, c).unwrap();
+        }
+        writeln!(out, "{}pub mod {} {{", indent, ident(&name.to_lowercase())).unwrap();
+        writeln!(out, "{}    use crate::regs::Field;", indent).unwrap();
+        let mut seen = Vec::new();
+        for f in fields {
+            let fname = ident(&sanitize(attr(f, "name")));
+            if seen.contains(&fname) {
+                continue;
+            }
+            writeln!(out).unwrap();
+            let caption = f.attribute("caption").unwrap_or("");
+            match f.attribute("values") {
+                Some(v) => {
+                    let ty = value_group_name(v, reg);
+                    writeln!(
+                        out,
+                        "{}    /// {}, see [`{}::{}`]",
+                        indent, caption, values_path, ty
+                    )
+                    .unwrap();
+                }
+                None if caption.is_empty() => {}
+                None => writeln!(out, "{}    /// {}", indent, caption).unwrap(),
+            }
+            writeln!(
+                out,
+                "{}    pub const {}: Field = Field::new(0x{:02X});",
+                indent,
+                fname,
+                parse_int(attr(f, "mask"))
+            )
+            .unwrap();
+            seen.push(fname);
+        }
+        writeln!(out, "{}}}", indent).unwrap();
+    }
+}
+
+fn gen_values(out: &mut String, values: Node, prefix: &str) {
+    let name = attr(values, "name");
+    let ty = ident(&sanitize(name.strip_prefix(prefix).unwrap_or(name)));
+
+    // Discriminants must be unique, keep the first name for each value.
+    let mut variants: Vec<(String, u64, &str)> = Vec::new();
+    for v in elements(values, "value") {
+        let value = parse_int(attr(v, "value"));
+        let vname = sanitize(attr(v, "name"));
+        if variants.iter().any(|(n, x, _)| *x == value || *n == vname) {
+            continue;
+        }
+        variants.push((vname, value, v.attribute("caption").unwrap_or("")));
+    }
+
+    writeln!(out).unwrap();
+    if let Some(c) = values.attribute("caption") {
+        writeln!(out, "    /// {}", c).unwrap();
+    }
+    writeln!(out, "    #[allow(non_camel_case_types)]").unwrap();
+    writeln!(out, "    #[repr(u8)]").unwrap();
+    writeln!(out, "    #[derive(Clone, Copy, Debug, PartialEq, Eq)]").unwrap();
+    writeln!(out, "    pub enum {} {{", ty).unwrap();
+    for (vname, value, caption) in &variants {
+        if !caption.is_empty() {
+            writeln!(out, "        /// {}", caption).unwrap();
+        }
+        writeln!(out, "        {} = 0x{:02X},", vname, value).unwrap();
+    }
+    writeln!(out, "    }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "    impl {} {{", ty).unwrap();
+    writeln!(out, "        /// Decodes an already shifted field value").unwrap();
+    writeln!(
+        out,
+        "        pub const fn from_bits(bits: u8) -> Option<Self> {{"
+    )
+    .unwrap();
+    writeln!(out, "            match bits {{").unwrap();
+    for (vname, value, _) in &variants {
+        writeln!(
+            out,
+            "                0x{:02X} => Some(Self::{}),",
+            value, vname
+        )
+        .unwrap();
+    }
+    writeln!(out, "                _ => None,").unwrap();
+    writeln!(out, "            }}").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "        pub const fn bits(self) -> u8 {{").unwrap();
+    writeln!(out, "            self as u8").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+}
+
+/// Name of the enum generated for a bitfield's `values` attribute.
+fn value_group_name(values: &str, reg: &Node) -> String {
+    let module = reg
+        .ancestors()
+        .find(|n| n.has_tag_name("module"))
+        .map(|m| attr(m, "name"))
+        .unwrap_or("");
+    let prefix = format!("{}_", module);
+    ident(&sanitize(values.strip_prefix(&prefix).unwrap_or(values)))
+}
+
+/// ATDF names may start with a digit (`10mV`, `8BIT`), which Rust does not allow.
+fn sanitize(name: &str) -> String {
+    let mut s: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.insert(0, '_');
+    }
+    s
+}
+
+/// Escapes register names like `IN` that are Rust keywords once lowercased.
+fn ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn",
+        "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+        "return", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+        "while", "async", "await", "dyn", "abstract", "become", "box", "do", "final", "macro",
+        "override", "priv", "typeof", "unsized", "virtual", "yield", "try",
+    ];
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn parse_int(s: &str) -> u64 {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).unwrap(),
+        None => s.parse().unwrap(),
+    }
+}
+
+fn attr<'a>(n: Node<'a, '_>, name: &str) -> &'a str {
+    n.attribute(name)
+        .unwrap_or_else(|| panic!("<{}> is missing '{}'", n.tag_name().name(), name))
+}
+
+fn child<'a, 'i>(n: Node<'a, 'i>, tag: &str) -> Option<Node<'a, 'i>> {
+    n.children().find(|c| c.has_tag_name(tag))
+}
+
+fn elements<'a, 'i: 'a>(n: Node<'a, 'i>, tag: &'a str) -> impl Iterator<Item = Node<'a, 'i>> + 'a {
+    n.children().filter(move |c| c.has_tag_name(tag))
+}
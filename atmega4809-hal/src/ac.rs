@@ -0,0 +1,415 @@
+//! AC0, the analog comparator.
+//!
+//! [`AC::setup`] compares a [`Positive`] pin against a [`Negative`] input:
+//! another pin or [`DacRef`], a fraction of the AC0 reference from
+//! [`crate::vref`]. The output can drive PA7, fire the compare interrupt
+//! on the edges picked in [`ACConfig`] and is always an event generator,
+//! [`AC::event_output`] puts it on an EVSYS channel.
+//!
+//! ```ignore
+//! // igniter continuity: the sense line sits above 200 mV while intact
+//! let (_, ac_ref) = dp.VREF.split();
+//! let threshold = DacRef::new(ac_ref.set::<V1_1>(), 200);
+//! let config = ACConfig::new().hysteresis(Hysteresis::Mv25).edge(Edge::Falling);
+//! let mut ac = dp.AC0.setup(pins.pd2.into_disabled(), threshold, config);
+//! ac.listen(igniter_open);
+//! ```
+
+use crate::evsys;
+use crate::gpio::{Disabled, Output, Pin, PA7, PORTD};
+use crate::interrupt::Handler;
+use crate::regs::{self, ac, AC0};
+use crate::vref::{self, Voltage};
+
+/// AC0, handed out once by [`crate::Peripherals`] and comparing `P` with
+/// `N` once set up.
+pub struct AC<P = (), N = ()> {
+    positive: P,
+    negative: N,
+}
+
+/// The positive input, as its MUXPOS.
+pub trait Positive {
+    fn muxpos() -> u8;
+}
+
+/// The negative input, as its MUXNEG.
+pub trait Negative {
+    fn muxneg() -> u8;
+
+    /// DACREF.DATA, for [`DacRef`]
+    #[doc(hidden)]
+    fn dacref(&self) -> Option<u8> {
+        None
+    }
+}
+
+macro_rules! ac_pins {
+    ($($trait:ident $fn:ident: $port:ident $pin:literal $mux:expr,)*) => {
+        $(
+            impl $trait for Pin<$port, $pin, Disabled> {
+                fn $fn() -> u8 {
+                    $mux.bits()
+                }
+            }
+        )*
+    };
+}
+
+ac_pins! {
+    Positive muxpos: PORTD 2 ac::MUXPOS::PIN0,
+    Positive muxpos: PORTD 4 ac::MUXPOS::PIN1,
+    Positive muxpos: PORTD 6 ac::MUXPOS::PIN2,
+    Positive muxpos: PORTD 1 ac::MUXPOS::PIN3,
+    Negative muxneg: PORTD 3 ac::MUXNEG::PIN0,
+    Negative muxneg: PORTD 5 ac::MUXNEG::PIN1,
+    Negative muxneg: PORTD 7 ac::MUXNEG::PIN2,
+}
+
+/// `DATA / 256` of the AC0 reference at `V`.
+pub struct DacRef<V> {
+    reference: vref::Reference<vref::Ac, V>,
+    data: u8,
+}
+
+impl<V: Voltage> DacRef<V> {
+    /// The step closest to `millivolts`, clamped to the reference.
+    pub fn new(reference: vref::Reference<vref::Ac, V>, millivolts: u16) -> Self {
+        Self {
+            reference,
+            data: data::<V>(millivolts),
+        }
+    }
+
+    /// The reference itself, or as close as DACREF gets: 255/256 of it.
+    pub fn full(reference: vref::Reference<vref::Ac, V>) -> Self {
+        Self {
+            reference,
+            data: 0xFF,
+        }
+    }
+
+    pub fn millivolts(&self) -> u16 {
+        (self.data as u32 * V::MILLIVOLTS as u32 / 256) as u16
+    }
+
+    pub fn release(self) -> vref::Reference<vref::Ac, V> {
+        self.reference
+    }
+}
+
+impl<V: Voltage> Negative for DacRef<V> {
+    fn muxneg() -> u8 {
+        ac::MUXNEG::DACREF.bits()
+    }
+
+    fn dacref(&self) -> Option<u8> {
+        Some(self.data)
+    }
+}
+
+fn data<V: Voltage>(millivolts: u16) -> u8 {
+    let data = (millivolts as u32 * 256 + V::MILLIVOLTS as u32 / 2) / V::MILLIVOLTS as u32;
+    data.min(0xFF) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hysteresis {
+    Off,
+    Mv10,
+    Mv25,
+    Mv50,
+}
+
+/// Output edges that set the compare flag, after the inversion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edge {
+    fn intmode(&self) -> ac::INTMODE {
+        match self {
+            Edge::Rising => ac::INTMODE::POSEDGE,
+            Edge::Falling => ac::INTMODE::NEGEDGE,
+            Edge::Both => ac::INTMODE::BOTHEDGE,
+        }
+    }
+}
+
+/// How AC0 is set up, applied by [`AC::setup`].
+#[derive(Clone, Copy)]
+pub struct ACConfig {
+    hysteresis: Hysteresis,
+    invert: bool,
+    edge: Edge,
+    low_power: bool,
+    run_in_standby: bool,
+}
+
+impl ACConfig {
+    /// No hysteresis, high while positive is above negative, flags both
+    /// edges.
+    pub const fn new() -> Self {
+        Self {
+            hysteresis: Hysteresis::Off,
+            invert: false,
+            edge: Edge::Both,
+            low_power: false,
+            run_in_standby: false,
+        }
+    }
+
+    pub const fn hysteresis(mut self, hysteresis: Hysteresis) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// High while positive is below negative.
+    pub const fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    pub const fn edge(mut self, edge: Edge) -> Self {
+        self.edge = edge;
+        self
+    }
+
+    /// Less current for a slower response.
+    pub const fn low_power(mut self, low_power: bool) -> Self {
+        self.low_power = low_power;
+        self
+    }
+
+    /// Keeps comparing in standby sleep, to wake the chip up.
+    pub const fn run_in_standby(mut self, run: bool) -> Self {
+        self.run_in_standby = run;
+        self
+    }
+}
+
+impl Default for ACConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AC {
+    pub(crate) const fn new() -> Self {
+        Self {
+            positive: (),
+            negative: (),
+        }
+    }
+
+    /// A second handle on AC0.
+    ///
+    /// # Safety
+    ///
+    /// Inputs muxed in through it replace the owner's, its `state` and
+    /// handler then report on another comparison.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Configures and enables AC0 to compare `positive` with `negative`.
+    pub fn setup<P: Positive, N: Negative>(
+        self,
+        positive: P,
+        negative: N,
+        config: ACConfig,
+    ) -> AC<P, N> {
+        AC0.ctrla().write(0);
+        AC0.intctrl().write(0);
+        if let Some(data) = negative.dacref() {
+            AC0.dacref().write(data);
+        }
+        let invert = if config.invert {
+            ac::muxctrla::INVERT.mask
+        } else {
+            0
+        };
+        AC0.muxctrla().write(
+            invert
+                | ac::muxctrla::MUXPOS.bits(P::muxpos())
+                | ac::muxctrla::MUXNEG.bits(N::muxneg()),
+        );
+        let mut ctrla = ac::ctrla::HYSMODE.bits(config.hysteresis as u8)
+            | ac::ctrla::INTMODE.bits(config.edge.intmode().bits())
+            | ac::ctrla::ENABLE.mask;
+        if config.low_power {
+            ctrla |= ac::ctrla::LPMODE.mask;
+        }
+        if config.run_in_standby {
+            ctrla |= ac::ctrla::RUNSTDBY.mask;
+        }
+        AC0.ctrla().write(ctrla);
+        // switching the inputs can leave a flag behind
+        AC0.status().write(ac::status::CMP.mask);
+
+        AC { positive, negative }
+    }
+}
+
+impl<P: Positive, N: Negative> AC<P, N> {
+    /// The output, after the inversion.
+    pub fn state(&self) -> bool {
+        AC0.status().is_set(ac::status::STATE)
+    }
+
+    /// Whether the output took one of the configured edges since the last
+    /// call.
+    pub fn edge_seen(&mut self) -> bool {
+        let status = AC0.status();
+        let seen = status.is_set(ac::status::CMP);
+        if seen {
+            status.write(ac::status::CMP.mask);
+        }
+        seen
+    }
+
+    /// Calls `handler` with the new [`state`](Self::state) on every
+    /// configured edge, from the compare interrupt. `AC0_AC` has to be bound
+    /// with [`crate::vectors!`].
+    pub fn listen(&mut self, handler: fn(bool)) {
+        HANDLER.set(Some(handler));
+        AC0.status().write(ac::status::CMP.mask);
+        AC0.intctrl().set_bits(ac::intctrl::CMP);
+    }
+
+    pub fn unlisten(&mut self) {
+        AC0.intctrl().clear_bits(ac::intctrl::CMP);
+        HANDLER.set(None);
+    }
+
+    /// Drives PA7 with the output.
+    pub fn enable_output(&mut self, _pin: PA7<Output>) {
+        AC0.ctrla().set_bits(ac::ctrla::OUTEN);
+    }
+
+    pub fn disable_output(&mut self) {
+        AC0.ctrla().clear_bits(ac::ctrla::OUTEN);
+    }
+
+    /// Makes the output the generator of EVSYS `channel`, for e.g. a TCB
+    /// [`EventInput`](crate::tcb::EventInput) or a CCL LUT.
+    pub fn event_output(&mut self, channel: evsys::Channel) {
+        channel.generator().write_field(
+            regs::evsys::channel0::GENERATOR,
+            regs::evsys::GENERATOR::AC0_OUT.bits(),
+        );
+    }
+
+    /// Turns AC0 off and hands back the inputs.
+    pub fn release(self) -> (AC, P, N) {
+        AC0.ctrla().write(0);
+        AC0.intctrl().write(0);
+        (AC::new(), self.positive, self.negative)
+    }
+}
+
+impl<P: Positive, V: Voltage> AC<P, DacRef<V>> {
+    /// Moves the DACREF threshold, keeping the reference.
+    pub fn set_threshold(&mut self, millivolts: u16) {
+        self.negative.data = data::<V>(millivolts);
+        AC0.dacref().write(self.negative.data);
+    }
+
+    /// The DACREF threshold, rounded to its step.
+    pub fn threshold(&self) -> u16 {
+        self.negative.millivolts()
+    }
+}
+
+static HANDLER: Handler<fn(bool)> = Handler::new();
+
+/// Body of the AC0 compare interrupt, bound by [`crate::vectors!`].
+#[doc(hidden)]
+pub fn compare() {
+    let state = AC0.status().is_set(ac::status::STATE);
+    AC0.status().write(ac::status::CMP.mask);
+    if let Some(handler) = HANDLER.get() {
+        handler(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::regs::{EVSYS, VREF as VREF_REGS};
+    use crate::vref::{V1_1, VREF};
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    static STATE: AtomicU8 = AtomicU8::new(0);
+
+    fn state(high: bool) {
+        STATE.store(1 + high as u8, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn dacref_threshold_and_interrupt() {
+        host::reset();
+        let pins = Pins::new();
+        let (_, reference) = VREF::new().split();
+        let threshold = DacRef::new(reference.set::<V1_1>(), 200);
+        let config = ACConfig::new()
+            .hysteresis(Hysteresis::Mv25)
+            .edge(Edge::Falling);
+        let mut ac = AC::new().setup(pins.pd2.into_disabled(), threshold, config);
+        assert_eq!(host::peek(VREF_REGS.ctrla()), 0x01);
+        // 200 mV of 1.1 V in 256 steps
+        assert_eq!(host::peek(AC0.dacref()), 47);
+        assert_eq!(ac.threshold(), 201);
+        assert_eq!(host::peek(AC0.muxctrla()), 0x03);
+        assert_eq!(host::peek(AC0.ctrla()), 0x25);
+
+        assert_eq!(host::writes_to(AC0.status()), [0x01]);
+
+        ac.set_threshold(5000);
+        assert_eq!(host::peek(AC0.dacref()), 0xFF);
+
+        host::poke(AC0.status(), 0);
+        assert!(!ac.edge_seen());
+        host::poke(AC0.status(), ac::status::CMP.mask);
+        assert!(ac.edge_seen());
+
+        ac.listen(state);
+        assert_eq!(host::peek(AC0.intctrl()), 0x01);
+        host::poke(AC0.status(), ac::status::STATE.mask);
+        compare();
+        assert_eq!(STATE.load(Ordering::Relaxed), 2);
+        ac.unlisten();
+        assert_eq!(host::peek(AC0.intctrl()), 0x00);
+
+        let (_, _, threshold) = ac.release();
+        assert_eq!(host::peek(AC0.ctrla()), 0x00);
+        threshold.release();
+    }
+
+    #[test]
+    fn pins_output_and_event() {
+        host::reset();
+        let pins = Pins::new();
+        let config = ACConfig::new()
+            .invert(true)
+            .edge(Edge::Rising)
+            .run_in_standby(true);
+        let mut ac = AC::new().setup(pins.pd1.into_disabled(), pins.pd7.into_disabled(), config);
+        assert_eq!(host::peek(AC0.muxctrla()), 0x9A);
+        assert_eq!(host::peek(AC0.ctrla()), 0xB1);
+        assert_eq!(host::writes_to(AC0.dacref()), []);
+
+        ac.enable_output(pins.pa7.into_output());
+        assert_eq!(host::peek(AC0.ctrla()), 0xF1);
+        ac.disable_output();
+        assert_eq!(host::peek(AC0.ctrla()), 0xB1);
+
+        ac.event_output(evsys::Channel::Ch3);
+        assert_eq!(host::peek(EVSYS.channel3()), 0x20);
+    }
+}
@@ -0,0 +1,879 @@
+//! ADC0, the 10-bit analog to digital converter.
+//!
+//! [`ADC::setup`] applies an [`ADCConfig`] with an [`AdcReference`]: an
+//! internal one from [`crate::vref`], [`Vdd`] or [`External`]. Conversions are started on a
+//! [`Channel`] (a pin in [`Disabled`] mode or one of the internal signals),
+//! either one at a time through [`OneShot`] or back to back with
+//! [`ADC::start_free_running`]. With accumulation the result is the sum of
+//! the samples, [`ADC::millivolts`] takes that into account.
+//!
+//! ```ignore
+//! let (adc_ref, _) = dp.VREF.split();
+//! let mut adc = dp.ADC0.setup(adc_ref.set::<V2_5>(), ADCConfig::new().accumulate(Accumulate::X16), &clocks)?;
+//! let mut battery = pins.pd2.into_disabled();
+//! let raw = nb::block!(adc.read(&mut battery))?;
+//! let mv = adc.millivolts(raw) * 11; // 100k/10k divider
+//! ```
+
+use crate::clock::Clocks;
+use crate::gpio::{Disabled, Pin, PORTD, PORTE, PORTF};
+use crate::interrupt::{self, Handler};
+use crate::regs::{adc, ADC0, SIGROW};
+use crate::vref::{self, Voltage, V1_1};
+
+/// ADC0, handed out once by [`crate::Peripherals`] and measuring against
+/// `R` once set up.
+pub struct ADC<R = ()> {
+    reference: R,
+    resolution: Resolution,
+    accumulate: Accumulate,
+    /// MUXPOS of the conversion [`OneShot::read`] started
+    pending: Option<u8>,
+}
+
+/// Something ADC0 can convert, as its MUXPOS. The same as embedded-hal
+/// 0.2's `adc::Channel`, which the alpha this crate uses doesn't have.
+pub trait Channel<ADC> {
+    fn channel() -> u8;
+}
+
+/// A single conversion, the way embedded-hal 0.2 has it: the first call
+/// starts it, later ones return [`nb::Error::WouldBlock`] until it is done.
+pub trait OneShot<ADC, Word, Pin: Channel<ADC>> {
+    type Error;
+
+    fn read(&mut self, pin: &mut Pin) -> nb::Result<Word, Self::Error>;
+}
+
+macro_rules! adc_pins {
+    ($($port:ident $pin:literal: $ain:ident,)*) => {
+        $(
+            impl Channel<ADC> for Pin<$port, $pin, Disabled> {
+                fn channel() -> u8 {
+                    adc::MUXPOS::$ain.bits()
+                }
+            }
+        )*
+    };
+}
+
+adc_pins! {
+    PORTD 0: AIN0,
+    PORTD 1: AIN1,
+    PORTD 2: AIN2,
+    PORTD 3: AIN3,
+    PORTD 4: AIN4,
+    PORTD 5: AIN5,
+    PORTD 6: AIN6,
+    PORTD 7: AIN7,
+    PORTE 0: AIN8,
+    PORTE 1: AIN9,
+    PORTE 2: AIN10,
+    PORTE 3: AIN11,
+    PORTF 2: AIN12,
+    PORTF 3: AIN13,
+    PORTF 4: AIN14,
+    PORTF 5: AIN15,
+}
+
+/// The DAC reference of AC0.
+pub struct DacRef;
+
+/// The internal temperature sensor, needs the 1.1 V reference.
+pub struct TempSense;
+
+/// Ground, for offset measurements.
+pub struct Gnd;
+
+impl Channel<ADC> for DacRef {
+    fn channel() -> u8 {
+        adc::MUXPOS::DACREF.bits()
+    }
+}
+
+impl Channel<ADC> for TempSense {
+    fn channel() -> u8 {
+        adc::MUXPOS::TEMPSENSE.bits()
+    }
+}
+
+impl Channel<ADC> for Gnd {
+    fn channel() -> u8 {
+        adc::MUXPOS::GND.bits()
+    }
+}
+
+/// The voltage a full scale result stands for.
+pub trait AdcReference {
+    const MILLIVOLTS: u16;
+    #[doc(hidden)]
+    const REFSEL: adc::REFSEL;
+}
+
+impl<V: Voltage> AdcReference for vref::Reference<vref::Adc, V> {
+    const MILLIVOLTS: u16 = V::MILLIVOLTS;
+    const REFSEL: adc::REFSEL = adc::REFSEL::INTREF;
+}
+
+/// VDD at `MV` millivolts.
+pub struct Vdd<const MV: u16>;
+
+impl<const MV: u16> AdcReference for Vdd<MV> {
+    const MILLIVOLTS: u16 = MV;
+    const REFSEL: adc::REFSEL = adc::REFSEL::VDDREF;
+}
+
+/// `MV` millivolts on the VREFA pin.
+pub struct External<const MV: u16>;
+
+impl<const MV: u16> AdcReference for External<MV> {
+    const MILLIVOLTS: u16 = MV;
+    const REFSEL: adc::REFSEL = adc::REFSEL::VREFA;
+}
+
+/// Fastest ADC clock the datasheet allows with a reference of `mv`.
+fn max_clock(mv: u16) -> u32 {
+    if mv < 1000 {
+        260_000
+    } else {
+        1_500_000
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Bits10,
+    Bits8,
+}
+
+impl Resolution {
+    fn full_scale(&self) -> u32 {
+        match self {
+            Resolution::Bits10 => 1024,
+            Resolution::Bits8 => 256,
+        }
+    }
+}
+
+/// How many samples are added up into one result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accumulate {
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+    X32,
+    X64,
+}
+
+impl Accumulate {
+    pub fn samples(&self) -> u16 {
+        1 << *self as u16
+    }
+}
+
+/// CLK_PER divider of the ADC clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prescaler {
+    D2,
+    D4,
+    D8,
+    D16,
+    D32,
+    D64,
+    D128,
+    D256,
+}
+
+impl Prescaler {
+    pub fn divisor(&self) -> u32 {
+        2 << *self as u32
+    }
+}
+
+const PRESCALERS: [Prescaler; 8] = [
+    Prescaler::D2,
+    Prescaler::D4,
+    Prescaler::D8,
+    Prescaler::D16,
+    Prescaler::D32,
+    Prescaler::D64,
+    Prescaler::D128,
+    Prescaler::D256,
+];
+
+/// Which results [`ADC::set_window`] picks, thresholds in millivolts.
+/// The thresholds themselves are outside the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    Below(u16),
+    Above(u16),
+    /// Between the two
+    Inside(u16, u16),
+    /// Below the first or above the second
+    Outside(u16, u16),
+}
+
+/// Which way a result crossed the edge of the [`Window`], see
+/// [`ADC::listen_window`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crossing {
+    Entered,
+    Left,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ADCError {
+    /// The ADC clock would be faster than the reference allows.
+    ClockTooFast,
+    /// Not the 10 bits the temperature sensor is calibrated for.
+    NotForTemperature,
+    /// Results go to the [`ADC::listen`] handler, or free-running mode
+    /// replaces them.
+    Busy,
+    /// No result came in the longest a conversion can take.
+    Timeout,
+}
+
+/// How ADC0 is set up, applied by [`ADC::setup`].
+#[derive(Clone, Copy)]
+pub struct ADCConfig {
+    resolution: Resolution,
+    accumulate: Accumulate,
+    /// `None` for the fastest allowed
+    prescaler: Option<Prescaler>,
+    sample_delay: u8,
+    sample_length: u8,
+}
+
+impl ADCConfig {
+    /// 10 bits, one sample, the fastest clock allowed and no extra delay.
+    pub const fn new() -> Self {
+        Self {
+            resolution: Resolution::Bits10,
+            accumulate: Accumulate::X1,
+            prescaler: None,
+            sample_delay: 0,
+            sample_length: 0,
+        }
+    }
+
+    pub const fn resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    pub const fn accumulate(mut self, accumulate: Accumulate) -> Self {
+        self.accumulate = accumulate;
+        self
+    }
+
+    pub const fn prescaler(mut self, prescaler: Prescaler) -> Self {
+        self.prescaler = Some(prescaler);
+        self
+    }
+
+    /// ADC clocks between two samples (0..=15), spreads the samples of an
+    /// accumulation out.
+    pub const fn sample_delay(mut self, cycles: u8) -> Self {
+        self.sample_delay = cycles & 0x0F;
+        self
+    }
+
+    /// Extra ADC clocks of sampling (0..=31), for sources with a high
+    /// impedance.
+    pub const fn sample_length(mut self, cycles: u8) -> Self {
+        self.sample_length = cycles & 0x1F;
+        self
+    }
+
+    /// What [`ADC::temperature`] needs besides the 1.1 V reference: 10
+    /// bits and at least 32 µs of sampling, which takes an ADC clock of
+    /// 1 MHz at most.
+    pub fn temperature(clocks: &Clocks) -> Self {
+        let prescaler = PRESCALERS
+            .iter()
+            .find(|p| clocks.f_per() / p.divisor() <= 1_000_000)
+            .copied();
+        Self {
+            prescaler,
+            ..Self::new().sample_length(31)
+        }
+    }
+}
+
+impl Default for ADCConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Factory calibration of the temperature sensor, from SIGROW.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempCalibration {
+    /// TEMPSENSE0, in 1/256 K per LSB
+    pub gain: u8,
+    /// TEMPSENSE1
+    pub offset: i8,
+}
+
+impl TempCalibration {
+    pub fn read() -> Self {
+        Self {
+            gain: SIGROW.tempsense0().read(),
+            offset: SIGROW.tempsense1().read() as i8,
+        }
+    }
+
+    /// Kelvin of a 10-bit result against 1.1 V, the sum of `samples`.
+    pub fn kelvin(&self, raw: u16, samples: u16) -> u16 {
+        let samples = samples as i32;
+        let t = (raw as i32 - self.offset as i32 * samples) * self.gain as i32;
+        ((t + 0x80 * samples) / (0x100 * samples)) as u16
+    }
+
+    pub fn celsius(&self, raw: u16, samples: u16) -> i16 {
+        self.kelvin(raw, samples) as i16 - 273
+    }
+}
+
+/// The shortest INITDLY giving the reference 32 µs to settle at `f_adc`.
+// div_ceil isn't stable on the pinned nightly
+#[allow(clippy::manual_div_ceil)]
+fn initial_delay(f_adc: u32) -> adc::INITDLY {
+    let cycles = (f_adc as u64 * 32 + 999_999) / 1_000_000;
+    match cycles {
+        0 => adc::INITDLY::DLY0,
+        1..=16 => adc::INITDLY::DLY16,
+        17..=32 => adc::INITDLY::DLY32,
+        33..=64 => adc::INITDLY::DLY64,
+        _ => adc::INITDLY::DLY128,
+    }
+}
+
+impl ADC {
+    pub(crate) const fn new() -> Self {
+        Self {
+            reference: (),
+            resolution: Resolution::Bits10,
+            accumulate: Accumulate::X1,
+            pending: None,
+        }
+    }
+
+    /// A second handle on ADC0.
+    ///
+    /// # Safety
+    ///
+    /// There is one MUXPOS and one RES: a conversion started through it
+    /// while the owner waits on one (a [`OneShot::read`] in progress, say)
+    /// gives the owner the result of the other channel.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Configures and enables ADC0 to measure against `reference`. Nothing
+    /// is touched if the prescaler is too small for the reference.
+    pub fn setup<R: AdcReference>(
+        self,
+        reference: R,
+        config: ADCConfig,
+        clocks: &Clocks,
+    ) -> Result<ADC<R>, ADCError> {
+        let max = max_clock(R::MILLIVOLTS);
+        let prescaler = match config.prescaler {
+            Some(p) if clocks.f_per() / p.divisor() > max => return Err(ADCError::ClockTooFast),
+            Some(p) => p,
+            None => *PRESCALERS
+                .iter()
+                .find(|p| clocks.f_per() / p.divisor() <= max)
+                .ok_or(ADCError::ClockTooFast)?,
+        };
+        let f_adc = clocks.f_per() / prescaler.divisor();
+
+        ADC0.ctrla().write(0);
+        ADC0.ctrlb()
+            .write(adc::ctrlb::SAMPNUM.bits(config.accumulate as u8));
+        // the larger sample capacitor is for references of 1 V and up
+        let sampcap = if R::MILLIVOLTS >= 1000 {
+            adc::ctrlc::SAMPCAP.mask
+        } else {
+            0
+        };
+        ADC0.ctrlc().write(
+            sampcap
+                | adc::ctrlc::REFSEL.bits(R::REFSEL.bits())
+                | adc::ctrlc::PRESC.bits(prescaler as u8),
+        );
+        ADC0.ctrld().write(
+            adc::ctrld::INITDLY.bits(initial_delay(f_adc).bits())
+                | adc::ctrld::SAMPDLY.bits(config.sample_delay),
+        );
+        ADC0.sampctrl().write(config.sample_length);
+        let ressel = match config.resolution {
+            Resolution::Bits10 => adc::RESSEL::_10BIT,
+            Resolution::Bits8 => adc::RESSEL::_8BIT,
+        };
+        ADC0.ctrla()
+            .write(adc::ctrla::RESSEL.bits(ressel.bits()) | adc::ctrla::ENABLE.mask);
+
+        Ok(ADC {
+            reference,
+            resolution: config.resolution,
+            accumulate: config.accumulate,
+            pending: None,
+        })
+    }
+}
+
+impl<R: AdcReference> ADC<R> {
+    pub fn reference(&self) -> &R {
+        &self.reference
+    }
+
+    /// Millivolts of a result, accumulated samples are averaged.
+    pub fn millivolts(&self, raw: u16) -> u32 {
+        let full_scale = self.resolution.full_scale() * self.accumulate.samples() as u32;
+        raw as u32 * R::MILLIVOLTS as u32 / full_scale
+    }
+
+    /// Starts a single conversion of `C`.
+    pub fn start<C: Channel<ADC>>(&mut self, _channel: &C) {
+        self.pending = None;
+        ADC0.muxpos().write(C::channel());
+        ADC0.command().write(adc::command::STCONV.mask);
+    }
+
+    /// Converts `C` over and over, each result replaces the last.
+    pub fn start_free_running<C: Channel<ADC>>(&mut self, _channel: &C) {
+        self.pending = None;
+        ADC0.muxpos().write(C::channel());
+        ADC0.ctrla().set_bits(adc::ctrla::FREERUN);
+        ADC0.command().write(adc::command::STCONV.mask);
+    }
+
+    /// Stops after the conversion in progress.
+    pub fn stop_free_running(&mut self) {
+        ADC0.ctrla().clear_bits(adc::ctrla::FREERUN);
+    }
+
+    /// Whether a result is waiting.
+    pub fn is_ready(&self) -> bool {
+        ADC0.intflags().is_set(adc::intflags::RESRDY)
+    }
+
+    /// The latest result, if there is one since the last call. Reading it
+    /// clears the flag.
+    pub fn result(&mut self) -> Option<u16> {
+        if !self.is_ready() {
+            return None;
+        }
+        Some(ADC0.res().read())
+    }
+
+    /// Calls `handler` with every result, from the RESRDY interrupt.
+    /// [`result`](Self::result) won't see them anymore. `ADC0_RESRDY` has to
+    /// be bound with [`crate::vectors!`].
+    pub fn listen(&mut self, handler: fn(u16)) {
+        HANDLER.set(Some(handler));
+        ADC0.intctrl().set_bits(adc::intctrl::RESRDY);
+    }
+
+    pub fn unlisten(&mut self) {
+        ADC0.intctrl().clear_bits(adc::intctrl::RESRDY);
+        HANDLER.set(None);
+    }
+
+    /// The result `millivolts` would give, with the accumulation, e.g. for
+    /// thresholds. Clamped to the largest result.
+    pub fn raw(&self, millivolts: u16) -> u16 {
+        let full_scale = self.resolution.full_scale() * self.accumulate.samples() as u32;
+        let raw = millivolts as u32 * full_scale / R::MILLIVOLTS as u32;
+        raw.min(full_scale - self.accumulate.samples() as u32) as u16
+    }
+
+    /// Compares every result against `window`, see
+    /// [`listen_window`](Self::listen_window).
+    pub fn set_window(&mut self, window: Window) {
+        // one threshold in both, the way back is the other mode
+        let (mode, low, high) = match window {
+            Window::Below(mv) => (adc::WINCM::BELOW, mv, mv),
+            Window::Above(mv) => (adc::WINCM::ABOVE, mv, mv),
+            Window::Inside(low, high) => (adc::WINCM::INSIDE, low, high),
+            Window::Outside(low, high) => (adc::WINCM::OUTSIDE, low, high),
+        };
+        ADC0.winlt().write(self.raw(low));
+        ADC0.winht().write(self.raw(high));
+        interrupt::free(|| {
+            unsafe { WINDOW_MODE = mode };
+            ADC0.ctrle().write(mode.bits());
+        });
+    }
+
+    /// Stops comparing.
+    pub fn clear_window(&mut self) {
+        interrupt::free(|| {
+            unsafe { WINDOW_MODE = adc::WINCM::NONE };
+            ADC0.ctrle().write(adc::WINCM::NONE.bits());
+        });
+    }
+
+    /// Whether a result matched the window since the last call. Without
+    /// [`listen_window`](Self::listen_window) every result inside counts,
+    /// not only the first.
+    pub fn window_hit(&mut self) -> bool {
+        let intflags = ADC0.intflags();
+        let hit = intflags.is_set(adc::intflags::WCMP);
+        if hit {
+            intflags.write(adc::intflags::WCMP.mask);
+        }
+        hit
+    }
+
+    /// Calls `handler` from the window compare interrupt when the result
+    /// crosses the edge of the window, with the first result on the other
+    /// side. After each call the comparator is turned around to wait for the
+    /// way back (INSIDE for OUTSIDE, BELOW for ABOVE), so a result that stays
+    /// put calls it once, also in free-running mode. The first call can be
+    /// an [`Crossing::Entered`] for a result that was inside all along.
+    /// `ADC0_WCOMP` has to be bound with [`crate::vectors!`].
+    pub fn listen_window(&mut self, handler: fn(u16, Crossing)) {
+        WINDOW_HANDLER.set(Some(handler));
+        ADC0.intflags().write(adc::intflags::WCMP.mask);
+        ADC0.intctrl().set_bits(adc::intctrl::WCMP);
+    }
+
+    /// Stops the calls and puts the comparator back to the window.
+    pub fn unlisten_window(&mut self) {
+        ADC0.intctrl().clear_bits(adc::intctrl::WCMP);
+        WINDOW_HANDLER.set(None);
+        interrupt::free(|| ADC0.ctrle().write(unsafe { WINDOW_MODE }.bits()));
+    }
+
+    /// Turns ADC0 off and hands back the reference. The interrupts, their
+    /// handlers and the window go with it.
+    pub fn release(self) -> (ADC, R) {
+        ADC0.ctrla().write(0);
+        ADC0.intctrl().write(0);
+        HANDLER.set(None);
+        WINDOW_HANDLER.set(None);
+        interrupt::free(|| {
+            unsafe { WINDOW_MODE = adc::WINCM::NONE };
+            ADC0.ctrle().write(adc::WINCM::NONE.bits());
+        });
+        (ADC::new(), self.reference)
+    }
+}
+
+/// ADC clocks a conversion takes at most: the longest INITDLY, SAMPDLY and
+/// sample length, and 13 to convert.
+const MAX_CONVERSION: u32 = 256 + 15 + 33 + 13;
+
+impl ADC<vref::Reference<vref::Adc, V1_1>> {
+    /// Measures the chip's temperature in °C, waiting for the conversion.
+    /// Needs ADC0 set up with [`ADCConfig::temperature`], accumulation is
+    /// fine. Not while listening or free-running, the result wouldn't get
+    /// here.
+    pub fn temperature(&mut self) -> Result<i16, ADCError> {
+        if self.resolution != Resolution::Bits10 {
+            return Err(ADCError::NotForTemperature);
+        }
+        if ADC0.intctrl().is_set(adc::intctrl::RESRDY) || ADC0.ctrla().is_set(adc::ctrla::FREERUN) {
+            return Err(ADCError::Busy);
+        }
+        // an old result would be taken for this one
+        self.result();
+        self.start(&TempSense);
+        // a poll takes a CPU clock at least
+        let prescaler = 2 << ADC0.ctrlc().read_field(adc::ctrlc::PRESC) as u32;
+        let polls = MAX_CONVERSION * prescaler * self.accumulate.samples() as u32;
+        let raw = (0..polls)
+            .find_map(|_| self.result())
+            .ok_or(ADCError::Timeout)?;
+        Ok(TempCalibration::read().celsius(raw, self.accumulate.samples()))
+    }
+}
+
+impl<R: AdcReference, C: Channel<ADC>> OneShot<ADC, u16, C> for ADC<R> {
+    type Error = !;
+
+    fn read(&mut self, _pin: &mut C) -> nb::Result<u16, !> {
+        let channel = C::channel();
+        if self.pending != Some(channel) {
+            ADC0.muxpos().write(channel);
+            ADC0.command().write(adc::command::STCONV.mask);
+            self.pending = Some(channel);
+            return Err(nb::Error::WouldBlock);
+        }
+        match self.result() {
+            Some(v) => {
+                self.pending = None;
+                Ok(v)
+            }
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+static HANDLER: Handler<fn(u16)> = Handler::new();
+static WINDOW_HANDLER: Handler<fn(u16, Crossing)> = Handler::new();
+/// WINCM of the window, CTRLE has the other side while a result is in it
+static mut WINDOW_MODE: adc::WINCM = adc::WINCM::NONE;
+
+/// Body of the ADC0 RESRDY interrupt, bound by [`crate::vectors!`].
+/// Reading RES clears the flag.
+#[doc(hidden)]
+pub fn result_ready() {
+    let result = ADC0.res().read();
+    if let Some(handler) = HANDLER.get() {
+        handler(result);
+    }
+}
+
+/// Body of the ADC0 WCMP interrupt, bound by [`crate::vectors!`]. Turns
+/// the comparator around for the way back.
+#[doc(hidden)]
+pub fn window_compare() {
+    ADC0.intflags().write(adc::intflags::WCMP.mask);
+    let result = ADC0.res().read();
+    let ctrle = ADC0.ctrle();
+    let mode = adc::WINCM::from_bits(ctrle.read_field(adc::ctrle::WINCM));
+    let back = match mode {
+        Some(adc::WINCM::BELOW) => adc::WINCM::ABOVE,
+        Some(adc::WINCM::ABOVE) => adc::WINCM::BELOW,
+        Some(adc::WINCM::INSIDE) => adc::WINCM::OUTSIDE,
+        Some(adc::WINCM::OUTSIDE) => adc::WINCM::INSIDE,
+        _ => return,
+    };
+    ctrle.write(back.bits());
+    let crossing = if mode == Some(unsafe { WINDOW_MODE }) {
+        Crossing::Entered
+    } else {
+        Crossing::Left
+    };
+    if let Some(handler) = WINDOW_HANDLER.get() {
+        handler(result, crossing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::regs::VREF as VREF_REGS;
+    use crate::vref::{V0_55, V2_5, VREF};
+    use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+    use std::sync::Mutex;
+
+    const CLOCKS: Clocks = Clocks::from_hz(20_000_000);
+
+    /// Held by the tests that set or drop the handlers, those are shared by
+    /// the test threads.
+    static HANDLERS: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn setup_picks_prescaler_and_delays() {
+        let _handlers = HANDLERS.lock();
+        host::reset();
+        let (reference, _) = VREF::new().split();
+        let config = ADCConfig::new().accumulate(Accumulate::X16).sample_delay(3);
+        let adc = ADC::new()
+            .setup(reference.set::<V2_5>(), config, &CLOCKS)
+            .unwrap();
+        assert_eq!(host::peek(VREF_REGS.ctrla()), 0x20);
+        assert_eq!(host::peek(ADC0.ctrlb()), 0x04);
+        // 20 MHz / 16 = 1.25 MHz, 40 clocks for 32 µs
+        assert_eq!(host::peek(ADC0.ctrlc()), 0x43);
+        assert_eq!(host::peek(ADC0.ctrld()), 0x63);
+        assert_eq!(host::peek(ADC0.ctrla()), 0x01);
+        // 16 samples at full scale
+        assert_eq!(adc.millivolts(16 * 1023), 2497);
+
+        let (adc, reference) = adc.release();
+        let config = ADCConfig::new().resolution(Resolution::Bits8);
+        let adc = adc
+            .setup(reference.set::<V0_55>(), config, &CLOCKS)
+            .unwrap();
+        assert_eq!(host::peek(ADC0.ctrlc()), 0x06);
+        assert_eq!(host::peek(ADC0.ctrla()), 0x05);
+        assert_eq!(adc.millivolts(128), 275);
+
+        host::reset();
+        let config = ADCConfig::new().prescaler(Prescaler::D4);
+        assert_eq!(
+            ADC::new().setup(Vdd::<3300>, config, &CLOCKS).err(),
+            Some(ADCError::ClockTooFast)
+        );
+        assert!(host::writes().is_empty());
+    }
+
+    #[test]
+    fn one_shot_on_pin() {
+        host::reset();
+        let pins = Pins::new();
+        let mut adc = ADC::new()
+            .setup(Vdd::<3300>, ADCConfig::new(), &CLOCKS)
+            .unwrap();
+        let mut pin = pins.pe1.into_disabled();
+        assert_eq!(adc.read(&mut pin), Err(nb::Error::WouldBlock));
+        assert_eq!(host::peek(ADC0.muxpos()), 9);
+        assert_eq!(host::writes_to(ADC0.command()), [0x01]);
+        assert_eq!(adc.read(&mut pin), Err(nb::Error::WouldBlock));
+
+        ADC0.res().write(512);
+        host::poke(ADC0.intflags(), adc::intflags::RESRDY.mask);
+        assert_eq!(adc.read(&mut pin), Ok(512));
+        assert_eq!(adc.millivolts(512), 1650);
+
+        // a different channel starts over
+        assert_eq!(adc.read(&mut Gnd), Err(nb::Error::WouldBlock));
+        assert_eq!(host::peek(ADC0.muxpos()), 0x1F);
+        assert_eq!(host::writes_to(ADC0.command()), [0x01, 0x01]);
+    }
+
+    static LAST: AtomicU16 = AtomicU16::new(0);
+
+    fn store(v: u16) {
+        LAST.store(v, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn free_running_with_interrupt() {
+        let _handlers = HANDLERS.lock();
+        host::reset();
+        let mut adc = ADC::new()
+            .setup(External::<1100>, ADCConfig::new(), &CLOCKS)
+            .unwrap();
+        adc.start_free_running(&TempSense);
+        assert_eq!(host::peek(ADC0.muxpos()), 0x1E);
+        assert_eq!(host::peek(ADC0.ctrla()), 0x03);
+        assert_eq!(host::peek(ADC0.ctrlc()) & 0x30, 0x20);
+        assert_eq!(adc.result(), None);
+
+        adc.listen(store);
+        assert_eq!(host::peek(ADC0.intctrl()), 0x01);
+        ADC0.res().write(300);
+        result_ready();
+        assert_eq!(LAST.load(Ordering::Relaxed), 300);
+        adc.unlisten();
+
+        adc.stop_free_running();
+        assert_eq!(host::peek(ADC0.ctrla()), 0x01);
+    }
+
+    static HIT: AtomicU16 = AtomicU16::new(0);
+    static LEFT: AtomicBool = AtomicBool::new(false);
+
+    fn hit(v: u16, crossing: Crossing) {
+        HIT.store(v, Ordering::Relaxed);
+        LEFT.store(crossing == Crossing::Left, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn window_in_millivolts() {
+        let _handlers = HANDLERS.lock();
+        host::reset();
+        let (reference, _) = VREF::new().split();
+        let config = ADCConfig::new().accumulate(Accumulate::X4);
+        let mut adc = ADC::new()
+            .setup(reference.set::<V2_5>(), config, &CLOCKS)
+            .unwrap();
+        adc.set_window(Window::Outside(1000, 2000));
+        // 4 samples of 1024 over 2.5 V
+        assert_eq!(ADC0.winlt().read(), 1638);
+        assert_eq!(ADC0.winht().read(), 3276);
+        assert_eq!(host::peek(ADC0.ctrle()), 0x04);
+        adc.set_window(Window::Above(3000));
+        assert_eq!(ADC0.winlt().read(), 4092);
+        assert_eq!(ADC0.winht().read(), 4092);
+        assert_eq!(host::peek(ADC0.ctrle()), 0x02);
+
+        assert!(!adc.window_hit());
+        host::poke(ADC0.intflags(), adc::intflags::WCMP.mask);
+        assert!(adc.window_hit());
+        assert_eq!(host::writes_to(ADC0.intflags()), [0x02]);
+
+        adc.listen_window(hit);
+        assert_eq!(host::peek(ADC0.intctrl()), 0x02);
+        ADC0.res().write(4000);
+        window_compare();
+        assert_eq!(HIT.load(Ordering::Relaxed), 4000);
+        assert!(!LEFT.load(Ordering::Relaxed));
+        // waits for the way back
+        assert_eq!(host::peek(ADC0.ctrle()), 0x01);
+        ADC0.res().write(3000);
+        window_compare();
+        assert_eq!(HIT.load(Ordering::Relaxed), 3000);
+        assert!(LEFT.load(Ordering::Relaxed));
+        assert_eq!(host::peek(ADC0.ctrle()), 0x02);
+
+        adc.set_window(Window::Inside(1000, 2000));
+        ADC0.res().write(2000);
+        window_compare();
+        assert_eq!(host::peek(ADC0.ctrle()), 0x04);
+        assert!(!LEFT.load(Ordering::Relaxed));
+        adc.unlisten_window();
+        assert_eq!(host::peek(ADC0.ctrle()), 0x03);
+        adc.clear_window();
+        assert_eq!(host::peek(ADC0.ctrle()), 0x00);
+
+        // the next setup starts without the window
+        adc.set_window(Window::Below(1000));
+        adc.listen_window(hit);
+        let _ = adc.release();
+        assert_eq!(host::peek(ADC0.intctrl()), 0x00);
+        assert_eq!(host::peek(ADC0.ctrle()), 0x00);
+        assert!(WINDOW_HANDLER.get().is_none());
+        assert!(unsafe { WINDOW_MODE } == adc::WINCM::NONE);
+    }
+
+    #[test]
+    fn temperature_from_sigrow() {
+        host::reset();
+        let cal = TempCalibration {
+            gain: 0xA0,
+            offset: 0x10,
+        };
+        // (458 - 16) * 160 / 256 = 276.25 K
+        assert_eq!(cal.kelvin(458, 1), 276);
+        assert_eq!(cal.celsius(458, 1), 3);
+        assert_eq!(cal.celsius(8 * 458, 8), 3);
+
+        let (reference, _) = VREF::new().split();
+        let config = ADCConfig::temperature(&CLOCKS);
+        let mut adc = ADC::new()
+            .setup(reference.set::<V1_1>(), config, &CLOCKS)
+            .unwrap();
+        // 20 MHz / 32, 33 clocks of sampling
+        assert_eq!(host::peek(ADC0.ctrlc()), 0x44);
+        assert_eq!(host::peek(ADC0.sampctrl()), 31);
+        assert_eq!(host::peek(VREF_REGS.ctrla()), 0x10);
+
+        host::poke(SIGROW.tempsense0(), 0xA0);
+        host::poke(SIGROW.tempsense1(), 0xF0);
+        ADC0.res().write(300);
+        host::poke(ADC0.intflags(), adc::intflags::RESRDY.mask);
+        // (300 + 16) * 160 / 256 = 197.5 K
+        assert_eq!(adc.temperature(), Ok(198 - 273));
+        assert_eq!(host::peek(ADC0.muxpos()), 0x1E);
+
+        // the result would go elsewhere, a listener as far as the
+        // registers go
+        host::poke(ADC0.intctrl(), adc::intctrl::RESRDY.mask);
+        assert_eq!(adc.temperature(), Err(ADCError::Busy));
+        host::poke(ADC0.intctrl(), 0);
+        adc.start_free_running(&TempSense);
+        assert_eq!(adc.temperature(), Err(ADCError::Busy));
+        adc.stop_free_running();
+        // nothing converts on the host
+        host::poke(ADC0.intflags(), 0);
+        assert_eq!(adc.temperature(), Err(ADCError::Timeout));
+
+        let (adc, reference) = adc.release();
+        let config = ADCConfig::new().resolution(Resolution::Bits8);
+        let mut adc = adc.setup(reference, config, &CLOCKS).unwrap();
+        assert_eq!(adc.temperature(), Err(ADCError::NotForTemperature));
+    }
+}
@@ -0,0 +1,189 @@
+//! Configurable Custom Logic: four lookup tables of three inputs each.
+//!
+//! A LUT's output is bit `IN2 << 2 | IN1 << 1 | IN0` of its truth table, so
+//! `0x08` is `IN0 & IN1` with IN2 masked. The tables can only be changed
+//! while the CCL is off, [`CCL`] stops it for the write and starts it again.
+
+use crate::gpio::{Output, Pin, PORTA, PORTC, PORTD, PORTF};
+use crate::regs::{self, ccl, portmux, Reg8, CCL as REGS};
+
+/// The CCL, handed out once by [`crate::Peripherals`].
+pub struct CCL {
+    _private: (),
+}
+
+/// What a LUT input follows. The peripheral ones depend on the input:
+/// IN*n* takes USART*n* TXD, TCA0 WO*n* and TCB*n* WO.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    /// Always low
+    Masked = 0x00,
+    /// The LUT's own output, through the sequencer
+    Feedback = 0x01,
+    /// The output of the next LUT
+    Link = 0x02,
+    EventA = 0x03,
+    EventB = 0x04,
+    /// The LUT*n*-IN pin
+    Io = 0x05,
+    Ac0 = 0x06,
+    Usart = 0x08,
+    Spi = 0x09,
+    Tca0 = 0x0A,
+    Tcb = 0x0C,
+}
+
+/// The output pins of LUT `N`, at the default or the alternate
+/// (`ALT = true`) location.
+pub trait OutPin<const N: u8, const ALT: bool> {}
+
+macro_rules! ccl_pins {
+    ($($lut:literal $alt:literal: $port:ident $pin:literal,)*) => {
+        $(
+            impl OutPin<$lut, $alt> for Pin<$port, $pin, Output> {}
+        )*
+    };
+}
+
+ccl_pins! {
+    0 false: PORTA 3,
+    0 true: PORTA 6,
+    1 false: PORTC 3,
+    1 true: PORTC 6,
+    2 false: PORTD 3,
+    2 true: PORTD 6,
+    3 false: PORTF 3,
+    3 true: PORTF 6,
+}
+
+/// CTRLA, CTRLB, CTRLC and TRUTH of LUT `n`.
+fn lut_regs(n: u8) -> [Reg8; 4] {
+    match n {
+        0 => [
+            REGS.lut0ctrla(),
+            REGS.lut0ctrlb(),
+            REGS.lut0ctrlc(),
+            REGS.truth0(),
+        ],
+        1 => [
+            REGS.lut1ctrla(),
+            REGS.lut1ctrlb(),
+            REGS.lut1ctrlc(),
+            REGS.truth1(),
+        ],
+        2 => [
+            REGS.lut2ctrla(),
+            REGS.lut2ctrlb(),
+            REGS.lut2ctrlc(),
+            REGS.truth2(),
+        ],
+        3 => [
+            REGS.lut3ctrla(),
+            REGS.lut3ctrlb(),
+            REGS.lut3ctrlc(),
+            REGS.truth3(),
+        ],
+        _ => unreachable!(),
+    }
+}
+
+impl CCL {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on the CCL.
+    ///
+    /// # Safety
+    ///
+    /// Changing a table stops the whole CCL for the write, the owner's LUT
+    /// outputs drop out meanwhile. Nothing stops it from rewriting a LUT the
+    /// owner uses, either.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Turns LUT `N` into `truth` of `inputs`, for events and other LUTs
+    /// only.
+    pub fn set_lut<const N: u8>(&mut self, inputs: [Input; 3], truth: u8) {
+        self.write_lut(N, inputs, truth, 0);
+    }
+
+    /// Like [`set_lut`](Self::set_lut), and drives `pin` with the output.
+    pub fn set_lut_output<const N: u8, const A: bool>(
+        &mut self,
+        inputs: [Input; 3],
+        truth: u8,
+        _pin: impl OutPin<N, A>,
+    ) {
+        regs::PORTMUX.cclroutea().modify(|v| {
+            let route = portmux::cclroutea::LUT0.mask << N;
+            if A {
+                v | route
+            } else {
+                v & !route
+            }
+        });
+        self.write_lut(N, inputs, truth, ccl::lut0ctrla::OUTEN.mask);
+    }
+
+    /// Stops LUT `N`, its output goes low.
+    pub fn disable_lut<const N: u8>(&mut self) {
+        self.stopped(|| lut_regs(N)[0].write(0));
+    }
+
+    fn write_lut(&mut self, n: u8, inputs: [Input; 3], truth: u8, ctrla: u8) {
+        let [ctrla_reg, ctrlb, ctrlc, truth_reg] = lut_regs(n);
+        self.stopped(|| {
+            ctrla_reg.write(0);
+            ctrlb.write(
+                ccl::lut0ctrlb::INSEL0.bits(inputs[0] as u8)
+                    | ccl::lut0ctrlb::INSEL1.bits(inputs[1] as u8),
+            );
+            ctrlc.write(ccl::lut0ctrlc::INSEL2.bits(inputs[2] as u8));
+            truth_reg.write(truth);
+            ctrla_reg.write(ctrla | ccl::lut0ctrla::ENABLE.mask);
+        });
+    }
+
+    /// Runs `f` with the CCL off, it comes back on if any LUT is in use.
+    fn stopped(&mut self, f: impl FnOnce()) {
+        REGS.ctrla().clear_bits(ccl::ctrla::ENABLE);
+        f();
+        let used = (0..4).any(|n| lut_regs(n)[0].is_set(ccl::lut0ctrla::ENABLE));
+        if used {
+            REGS.ctrla().set_bits(ccl::ctrla::ENABLE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+
+    #[test]
+    fn lut_with_output() {
+        host::reset();
+        let pins = crate::gpio::Pins::new();
+        let mut ccl = CCL::new();
+        ccl.set_lut_output::<2, true>(
+            [Input::Tca0, Input::Usart, Input::Masked],
+            0x08,
+            pins.pd6.into_output(),
+        );
+        assert_eq!(host::peek(regs::PORTMUX.cclroutea()), 0x04);
+        assert_eq!(host::peek(REGS.lut2ctrlb()), 0x8A);
+        assert_eq!(host::peek(REGS.lut2ctrlc()), 0x00);
+        assert_eq!(host::peek(REGS.truth2()), 0x08);
+        assert_eq!(host::peek(REGS.lut2ctrla()), 0x41);
+        // off while the table changed
+        assert_eq!(host::writes_to(REGS.ctrla()), [0x00, 0x01]);
+
+        ccl.set_lut::<0>([Input::EventA, Input::Masked, Input::Masked], 0x02);
+        assert_eq!(host::peek(REGS.lut0ctrla()), 0x01);
+        ccl.disable_lut::<2>();
+        ccl.disable_lut::<0>();
+        assert_eq!(host::peek(REGS.ctrla()), 0x00);
+    }
+}
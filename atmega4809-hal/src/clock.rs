@@ -1,3 +1,5 @@
+use crate::regs::{clkctrl, fuse, slpctrl, CLKCTRL, FUSE, SIGROW, SLPCTRL};
+
 #[repr(u8)]
 #[derive(Clone, Copy)]
 pub enum ClockSelect {
@@ -11,22 +13,49 @@ pub enum ClockSelect {
     EXTCLK = 0x3,
 }
 
-const CLK_CTRL: *mut u8 = 0x0060 as *mut u8;
-
 impl ClockSelect {
-    pub fn set_clock(&self) {
-        let val = *self as u8;
-        //TODO: force all timers to be awake, so we can always switch
-        unsafe { CLK_CTRL.offset(0x18).write_volatile(0x2) };
-        unsafe { CLK_CTRL.write_volatile(val) };
+    /// Nominal frequency in Hz, `None` for an external clock. OSC20M runs at
+    /// 16 or 20 MHz depending on `FUSE.OSCCFG`.
+    pub fn frequency(&self) -> Option<u32> {
+        match self {
+            ClockSelect::OSC20M if osc20m_is_16mhz() => Some(16_000_000),
+            ClockSelect::OSC20M => Some(20_000_000),
+            ClockSelect::OSCULP32K | ClockSelect::XOSC32K => Some(32_768),
+            ClockSelect::EXTCLK => None,
+        }
+    }
+
+    /// Factory measured error of the oscillator at 5V, in 1/1024 of its
+    /// nominal frequency (positive when it runs fast). Only known for OSC20M.
+    pub fn frequency_error(&self) -> i8 {
+        let err = match self {
+            ClockSelect::OSC20M if osc20m_is_16mhz() => SIGROW.osc16err5v(),
+            ClockSelect::OSC20M => SIGROW.osc20err5v(),
+            _ => return 0,
+        };
+        err.read() as i8
     }
-    pub fn get_clock() -> Self {
-        let clock = unsafe { CLK_CTRL.read_volatile() };
-        assert!(clock <= 0x03);
-        unsafe { core::mem::transmute(clock) }
+
+    /// The source the main clock runs from, `None` for a reserved CLKSEL.
+    pub fn get_clock() -> Option<Self> {
+        let clock = CLKCTRL.mclkctrla().read_field(clkctrl::mclkctrla::CLKSEL);
+        Some(match clkctrl::CLKSEL::from_bits(clock)? {
+            clkctrl::CLKSEL::OSC20M => ClockSelect::OSC20M,
+            clkctrl::CLKSEL::OSCULP32K => ClockSelect::OSCULP32K,
+            clkctrl::CLKSEL::XOSC32K => ClockSelect::XOSC32K,
+            clkctrl::CLKSEL::EXTCLK => ClockSelect::EXTCLK,
+        })
     }
 }
 
+fn osc20m_is_16mhz() -> bool {
+    let freqsel = FUSE.osccfg().read_field(fuse::osccfg::FREQSEL);
+    matches!(
+        fuse::FREQSEL::from_bits(freqsel),
+        Some(fuse::FREQSEL::_16MHZ)
+    )
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy)]
 pub enum ClockPrescaler {
@@ -45,23 +74,161 @@ pub enum ClockPrescaler {
 }
 
 impl ClockPrescaler {
-    pub fn set_clock_prescaler(&self) {
-        let pdiv = *self as u8;
-
-        let pen = match self {
+    fn mclkctrlb(&self) -> u8 {
+        match self {
             ClockPrescaler::None => 0,
-            _ => 1,
+            _ => clkctrl::mclkctrlb::PDIV.bits(*self as u8) | clkctrl::mclkctrlb::PEN.mask,
+        }
+    }
+
+    pub fn divisor(&self) -> u32 {
+        match self {
+            ClockPrescaler::None => 1,
+            ClockPrescaler::D2 => 2,
+            ClockPrescaler::D4 => 4,
+            ClockPrescaler::D8 => 8,
+            ClockPrescaler::D16 => 16,
+            ClockPrescaler::D32 => 32,
+            ClockPrescaler::D64 => 64,
+            ClockPrescaler::D6 => 6,
+            ClockPrescaler::D10 => 10,
+            ClockPrescaler::D12 => 12,
+            ClockPrescaler::D24 => 24,
+            ClockPrescaler::D48 => 48,
+        }
+    }
+
+    /// The current prescaler, `None` for a reserved PDIV.
+    pub fn get_clock_prescaler() -> Option<Self> {
+        let v = CLKCTRL.mclkctrlb().read();
+        if v & clkctrl::mclkctrlb::PEN.mask == 0 {
+            return Some(ClockPrescaler::None);
+        }
+
+        let pdiv = clkctrl::PDIV::from_bits(clkctrl::mclkctrlb::PDIV.get(v))?;
+        Some(match pdiv {
+            clkctrl::PDIV::_2X => ClockPrescaler::D2,
+            clkctrl::PDIV::_4X => ClockPrescaler::D4,
+            clkctrl::PDIV::_8X => ClockPrescaler::D8,
+            clkctrl::PDIV::_16X => ClockPrescaler::D16,
+            clkctrl::PDIV::_32X => ClockPrescaler::D32,
+            clkctrl::PDIV::_64X => ClockPrescaler::D64,
+            clkctrl::PDIV::_6X => ClockPrescaler::D6,
+            clkctrl::PDIV::_10X => ClockPrescaler::D10,
+            clkctrl::PDIV::_12X => ClockPrescaler::D12,
+            clkctrl::PDIV::_24X => ClockPrescaler::D24,
+            clkctrl::PDIV::_48X => ClockPrescaler::D48,
+        })
+    }
+}
+
+/// How the main clock is set up, applied by [`ClockConfig::freeze`].
+///
+/// ```ignore
+/// let clocks = ClockConfig::new()
+///     .source(ClockSelect::OSC20M)
+///     .prescaler(ClockPrescaler::None)
+///     .freeze();
+/// ```
+#[derive(Clone, Copy)]
+pub struct ClockConfig {
+    source: ClockSelect,
+    prescaler: ClockPrescaler,
+    /// Frequency of EXTCLK, nothing on the chip knows it
+    external: u32,
+}
+
+impl ClockConfig {
+    /// What the chip starts with after reset: OSC20M divided by 6.
+    pub const fn new() -> Self {
+        Self {
+            source: ClockSelect::OSC20M,
+            prescaler: ClockPrescaler::D6,
+            external: 0,
+        }
+    }
+
+    /// Runs from one of the internal oscillators or the 32.7kHz crystal, use
+    /// [`ClockConfig::external`] for EXTCLK.
+    pub const fn source(mut self, source: ClockSelect) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Runs from a clock of `hz` on EXTCLK.
+    pub const fn external(mut self, hz: u32) -> Self {
+        self.source = ClockSelect::EXTCLK;
+        self.external = hz;
+        self
+    }
+
+    pub const fn prescaler(mut self, prescaler: ClockPrescaler) -> Self {
+        self.prescaler = prescaler;
+        self
+    }
+
+    /// Switches the main clock over and waits until the chip runs from the
+    /// new source. Everything that depends on the clock should be set up
+    /// after this, with the returned [`Clocks`].
+    pub fn freeze(self) -> Clocks {
+        if let ClockSelect::XOSC32K = self.source {
+            // the crystal has to run before it can be selected
+            CLKCTRL
+                .xosc32kctrla()
+                .protected_write(clkctrl::xosc32kctrla::ENABLE.mask);
+        }
+        CLKCTRL
+            .mclkctrla()
+            .protected_write(clkctrl::mclkctrla::CLKSEL.bits(self.source as u8));
+        // SOSC stays set until the new source is stable and in use
+        while CLKCTRL.mclkstatus().is_set(clkctrl::mclkstatus::SOSC) {}
+        CLKCTRL
+            .mclkctrlb()
+            .protected_write(self.prescaler.mclkctrlb());
+
+        let f = match self.source.frequency() {
+            Some(f) => f as i64 * (1024 + self.source.frequency_error() as i64) / 1024,
+            None => self.external as i64,
         };
+        Clocks::from_hz(f as u32 / self.prescaler.divisor())
+    }
+}
 
-        unsafe { CLK_CTRL.offset(1).write_volatile(pdiv << 1 | pen) };
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self::new()
     }
-    pub fn get_clock_prescaler() -> Self {
-        let v = unsafe { CLK_CTRL.offset(1).read_volatile() };
-        if v & 1 == 0 {
-            return ClockPrescaler::None;
+}
+
+/// The frequencies the chip runs at, handed out by [`ClockConfig::freeze`].
+///
+/// Internal oscillators are corrected by the error measured at the factory,
+/// drivers that derive a rate from the clock (USART baud, TWI and SPI
+/// prescalers, [`crate::Delay`]) take one of these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+    f_cpu: u32,
+    f_per: u32,
+}
+
+impl Clocks {
+    /// Clocks for a chip already running at `hz`, e.g. set up by a
+    /// bootloader. CLK_CPU and CLK_PER are the same on this chip.
+    pub const fn from_hz(hz: u32) -> Self {
+        Self {
+            f_cpu: hz,
+            f_per: hz,
         }
+    }
+
+    /// CLK_CPU in Hz.
+    pub const fn f_cpu(&self) -> u32 {
+        self.f_cpu
+    }
 
-        unsafe { core::mem::transmute(v >> 1) }
+    /// CLK_PER in Hz.
+    pub const fn f_per(&self) -> u32 {
+        self.f_per
     }
 }
 
@@ -74,7 +241,89 @@ pub enum Sleep {
 }
 
 impl Sleep {
+    /// Picks the mode the next `sleep` instruction goes into, it doesn't
+    /// sleep itself. See [`crate::sleep`] for that.
     pub fn set_sleep(self) {
-        unsafe { (0x0050 as *mut u8).write_volatile(1 | ((self as u8) << 1)) };
+        SLPCTRL
+            .ctrla()
+            .write(slpctrl::ctrla::SEN.mask | slpctrl::ctrla::SMODE.bits(self as u8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use crate::regs::{cpu, CPU};
+
+    #[test]
+    fn freeze_unlocks_each_write() {
+        host::reset();
+        let clocks = ClockConfig::new()
+            .source(ClockSelect::OSCULP32K)
+            .prescaler(ClockPrescaler::None)
+            .freeze();
+
+        let ccp = (CPU.ccp().addr(), cpu::CCP::IOREG as u8);
+        assert_eq!(
+            host::writes(),
+            [
+                ccp,
+                (CLKCTRL.mclkctrla().addr(), 0x01),
+                ccp,
+                (CLKCTRL.mclkctrlb().addr(), 0)
+            ]
+        );
+        assert_eq!(clocks, Clocks::from_hz(32_768));
+    }
+
+    #[test]
+    fn freeze_waits_for_the_switch() {
+        host::reset();
+        host::script(CLKCTRL.mclkstatus(), &[0x01, 0x01, 0x00]);
+        ClockConfig::new().freeze();
+        // every scripted SOSC was read
+        assert_eq!(CLKCTRL.mclkstatus().read(), 0);
+        // the prescaler is only changed once the new source runs
+        assert_eq!(
+            host::writes().last(),
+            Some(&(CLKCTRL.mclkctrlb().addr(), 0x11))
+        );
+    }
+
+    #[test]
+    fn frequency_is_corrected() {
+        host::reset();
+        host::poke(FUSE.osccfg(), fuse::FREQSEL::_16MHZ as u8);
+        // running 10/1024 fast
+        host::poke(SIGROW.osc16err5v(), 10);
+        let clocks = ClockConfig::new().prescaler(ClockPrescaler::D2).freeze();
+        // 16 MHz * 1034 / 1024 / 2
+        assert_eq!(clocks.f_cpu(), 8_078_125);
+
+        host::poke(SIGROW.osc16err5v(), -4i8 as u8);
+        let clocks = ClockConfig::new().prescaler(ClockPrescaler::None).freeze();
+        // 16 MHz * 1020 / 1024
+        assert_eq!(clocks.f_per(), 15_937_500);
+
+        let clocks = ClockConfig::new().external(8_000_000).freeze();
+        assert_eq!(clocks.f_per(), 8_000_000 / 6);
+    }
+
+    #[test]
+    fn reserved_prescaler_is_none() {
+        host::reset();
+        host::poke(CLKCTRL.mclkctrlb(), 0x11);
+        assert!(matches!(
+            ClockPrescaler::get_clock_prescaler(),
+            Some(ClockPrescaler::D6)
+        ));
+        // PDIV 0x7 is reserved
+        host::poke(CLKCTRL.mclkctrlb(), 0x0F);
+        assert!(ClockPrescaler::get_clock_prescaler().is_none());
+        assert!(matches!(
+            ClockSelect::get_clock(),
+            Some(ClockSelect::OSC20M)
+        ));
     }
 }
@@ -0,0 +1,140 @@
+//! Busy waits counted in CPU cycles.
+//!
+//! The wait itself is an `sbiw`/`brne` loop of exactly four cycles a round
+//! (three for the last one). Microseconds are turned into cycles with a
+//! 16.16 fixed point rate worked out once in [`Delay::new`], so the only
+//! math per call is a 64-bit multiply and shifts.
+//!
+//! The call and that math come on top, a few dozen cycles, which only
+//! matters for single digit microsecond waits.
+//!
+//! On the host the loop doesn't run, [`crate::host::cycles`] counts the
+//! cycles it would have taken instead.
+
+use crate::clock::Clocks;
+use embedded_hal::delay::blocking::DelayUs;
+
+/// Busy waits, see the [module docs](self).
+#[derive(Clone, Copy)]
+pub struct Delay {
+    /// CPU cycles per microsecond, 16.16 fixed point
+    cycles_per_us: u32,
+}
+
+impl Delay {
+    // div_ceil isn't stable on the pinned nightly
+    #[allow(clippy::manual_div_ceil)]
+    pub fn new(clocks: &Clocks) -> Self {
+        Self {
+            cycles_per_us: ((((clocks.f_cpu() as u64) << 16) + 999_999) / 1_000_000) as u32,
+        }
+    }
+
+    /// Waits for `cycles` CPU cycles, give or take the last three.
+    pub fn delay_cycles(&mut self, mut cycles: u64) {
+        // zero wraps to 0xFFFF on the first sbiw, i.e. 0x10000 rounds
+        while cycles >= loop_cycles(0) {
+            busy_loop(0);
+            cycles -= loop_cycles(0);
+        }
+        let rest = ((cycles + 1) / 4) as u16;
+        if rest > 0 {
+            busy_loop(rest);
+        }
+    }
+
+    fn cycles(&self, us: u64) -> u64 {
+        (us * self.cycles_per_us as u64) >> 16
+    }
+}
+
+/// Cycles [`busy_loop`] takes for `rounds`.
+const fn loop_cycles(rounds: u16) -> u64 {
+    let rounds = if rounds == 0 { 0x10000 } else { rounds as u64 };
+    4 * rounds - 1
+}
+
+#[inline(always)]
+fn busy_loop(rounds: u16) {
+    #[cfg(all(target_arch = "avr", not(any(test, feature = "host"))))]
+    unsafe {
+        core::arch::asm!(
+            "1:",
+            "sbiw {n}, 1",
+            "brne 1b",
+            n = inout(reg_iw) rounds => _,
+            options(nomem, nostack),
+        );
+    }
+    #[cfg(any(test, feature = "host"))]
+    crate::host::spend_cycles(loop_cycles(rounds));
+    #[cfg(not(any(target_arch = "avr", test, feature = "host")))]
+    let _ = loop_cycles(rounds);
+}
+
+impl DelayUs for Delay {
+    type Error = !;
+
+    fn delay_us(&mut self, us: u32) -> Result<(), Self::Error> {
+        self.delay_cycles(self.cycles(us as u64));
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, ms: u32) -> Result<(), Self::Error> {
+        self.delay_cycles(self.cycles(ms as u64 * 1000));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+
+    fn spent(f_cpu: u32, f: impl FnOnce(&mut Delay)) -> u64 {
+        host::reset();
+        f(&mut Delay::new(&Clocks::from_hz(f_cpu)));
+        host::cycles()
+    }
+
+    #[test]
+    fn loop_rounds() {
+        assert_eq!(loop_cycles(1), 3);
+        assert_eq!(loop_cycles(0xFFFF), 0x3FFFB);
+        assert_eq!(loop_cycles(0), 0x3FFFF);
+    }
+
+    #[test]
+    fn microseconds_at_20mhz() {
+        // 20 cycles a microsecond, the loop takes 4 a round less one
+        assert_eq!(spent(20_000_000, |d| d.delay_us(1).unwrap()), 19);
+        assert_eq!(spent(20_000_000, |d| d.delay_us(100).unwrap()), 1999);
+        assert_eq!(spent(20_000_000, |d| d.delay_ms(1).unwrap()), 19_999);
+    }
+
+    #[test]
+    fn prescaled_clock() {
+        // 3.333.. cycles a microsecond
+        let us = spent(20_000_000 / 6, |d| d.delay_us(3000).unwrap());
+        assert!(us.abs_diff(10_000) < 4, "{}", us);
+        // a single round
+        assert_eq!(spent(20_000_000 / 6, |d| d.delay_us(1).unwrap()), 3);
+        assert_eq!(spent(20_000_000 / 6, |d| d.delay_cycles(2)), 0);
+    }
+
+    #[test]
+    fn long_waits_dont_overflow() {
+        // a bit over 71 minutes
+        let cycles = spent(20_000_000, |d| d.delay_us(u32::MAX).unwrap());
+        assert!(cycles.abs_diff(u32::MAX as u64 * 20) < 4, "{}", cycles);
+
+        let cycles = spent(16_000_000, |d| d.delay_ms(60_000).unwrap());
+        assert!(cycles.abs_diff(60 * 16_000_000) < 4, "{}", cycles);
+    }
+
+    #[test]
+    fn slow_clock() {
+        let ms = spent(32_768, |d| d.delay_ms(1000).unwrap());
+        assert!(ms.abs_diff(32_768) < 8, "{}", ms);
+    }
+}
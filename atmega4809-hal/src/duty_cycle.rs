@@ -0,0 +1,47 @@
+//! Duty cycle control, the way embedded-hal 1.0 has it.
+//!
+//! The embedded-hal alpha this crate is pinned to has no PWM traits, these
+//! follow 1.0's `embedded_hal::pwm` so drivers written against them only need
+//! their imports changed once the dependency moves on. The module isn't
+//! called `pwm` like 1.0's, that name was the old TCA0 driver, now
+//! [`crate::tca`].
+//!
+//! Implemented by the TCA0 compare channels ([`crate::tca::PwmChannel`],
+//! [`crate::tca::SplitPwmChannel`]) and the TCB 8-bit PWM
+//! ([`crate::tcb::Pwm8`]).
+
+pub trait ErrorType {
+    type Error: core::fmt::Debug;
+}
+
+/// A single PWM channel.
+pub trait SetDutyCycle: ErrorType {
+    /// The duty cycle that keeps the output high all the time.
+    fn max_duty_cycle(&self) -> u16;
+
+    /// Sets the time the output is high, in 1/[`max_duty_cycle`] of the
+    /// period.
+    ///
+    /// [`max_duty_cycle`]: SetDutyCycle::max_duty_cycle
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error>;
+
+    fn set_duty_cycle_fully_off(&mut self) -> Result<(), Self::Error> {
+        self.set_duty_cycle(0)
+    }
+
+    fn set_duty_cycle_fully_on(&mut self) -> Result<(), Self::Error> {
+        self.set_duty_cycle(self.max_duty_cycle())
+    }
+
+    /// Sets the duty cycle to `num / denom`.
+    fn set_duty_cycle_fraction(&mut self, num: u16, denom: u16) -> Result<(), Self::Error> {
+        debug_assert!(denom != 0);
+        debug_assert!(num <= denom);
+        let duty = num as u32 * self.max_duty_cycle() as u32 / denom as u32;
+        self.set_duty_cycle(duty as u16)
+    }
+
+    fn set_duty_cycle_percent(&mut self, percent: u8) -> Result<(), Self::Error> {
+        self.set_duty_cycle_fraction(percent as u16, 100)
+    }
+}
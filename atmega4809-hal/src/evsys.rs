@@ -0,0 +1,35 @@
+//! EVSYS, the event system.
+//!
+//! Generators (pins, AC0, timers, ...) are put on one of eight [`Channel`]s,
+//! users listen to a channel.
+
+use crate::regs::{Reg8, EVSYS};
+
+/// One of the eight EVSYS channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Ch0,
+    Ch1,
+    Ch2,
+    Ch3,
+    Ch4,
+    Ch5,
+    Ch6,
+    Ch7,
+}
+
+impl Channel {
+    /// CHANNELn, the generator of the channel.
+    pub(crate) fn generator(self) -> Reg8 {
+        match self {
+            Channel::Ch0 => EVSYS.channel0(),
+            Channel::Ch1 => EVSYS.channel1(),
+            Channel::Ch2 => EVSYS.channel2(),
+            Channel::Ch3 => EVSYS.channel3(),
+            Channel::Ch4 => EVSYS.channel4(),
+            Channel::Ch5 => EVSYS.channel5(),
+            Channel::Ch6 => EVSYS.channel6(),
+            Channel::Ch7 => EVSYS.channel7(),
+        }
+    }
+}
@@ -1,6 +1,22 @@
-use embedded_hal::digital::{blocking::OutputPin, ErrorType};
+use crate::interrupt::{Handler, NO_HANDLER};
+use crate::regs::{self, port, Reg8};
+use core::marker::PhantomData;
+use embedded_hal::digital::{
+    blocking::{InputPin, OutputPin, StatefulOutputPin, ToggleableOutputPin},
+    ErrorType,
+};
 
-pub enum GPIO {
+pub const PORTA: u16 = 0x0400;
+pub const PORTB: u16 = 0x0420;
+pub const PORTC: u16 = 0x0440;
+pub const PORTD: u16 = 0x0460;
+pub const PORTE: u16 = 0x0480;
+pub const PORTF: u16 = 0x04A0;
+
+/// Any pin, picked at runtime. Only the drivers get to make these, everyone
+/// else goes through an owned [`Pin`].
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum GPIO {
     PORTA(u8),
     PORTB(u8),
     PORTC(u8),
@@ -28,26 +44,26 @@ pub enum ISC {
 
 impl ISC {
     fn val(self) -> u8 {
-        match self {
-            ISC::IntDisable => 0,
-            ISC::BothEdges => 1,
-            ISC::Rising => 2,
-            ISC::Falling => 3,
-            ISC::InputDisable => 4,
-            ISC::Level => 5,
-        }
+        (match self {
+            ISC::IntDisable => port::ISC::INTDISABLE,
+            ISC::BothEdges => port::ISC::BOTHEDGES,
+            ISC::Rising => port::ISC::RISING,
+            ISC::Falling => port::ISC::FALLING,
+            ISC::InputDisable => port::ISC::INPUT_DISABLE,
+            ISC::Level => port::ISC::LEVEL,
+        }) as u8
     }
 }
 impl GPIO {
-    fn base_ptr(&self) -> *mut u8 {
-        (match self {
-            GPIO::PORTA(_) => 0x0400,
-            GPIO::PORTB(_) => 0x0420,
-            GPIO::PORTC(_) => 0x0440,
-            GPIO::PORTD(_) => 0x0460,
-            GPIO::PORTE(_) => 0x0480,
-            GPIO::PORTF(_) => 0x04A0,
-        }) as *mut u8
+    fn port(&self) -> port::RegisterBlock {
+        match self {
+            GPIO::PORTA(_) => regs::PORTA,
+            GPIO::PORTB(_) => regs::PORTB,
+            GPIO::PORTC(_) => regs::PORTC,
+            GPIO::PORTD(_) => regs::PORTD,
+            GPIO::PORTE(_) => regs::PORTE,
+            GPIO::PORTF(_) => regs::PORTF,
+        }
     }
 
     pub fn pin(&self) -> u8 {
@@ -62,79 +78,536 @@ impl GPIO {
     }
 
     pub fn output_enable(&self) {
-        unsafe { self.base_ptr().offset(0x01).write_volatile(1 << self.pin()) }
+        self.port().dirset().write(1 << self.pin())
     }
 
     pub fn output_disable(&self) {
-        unsafe { self.base_ptr().offset(0x02).write_volatile(1 << self.pin()) }
+        self.port().dirclr().write(1 << self.pin())
     }
 
     pub fn output_high(&self) {
-        unsafe { self.base_ptr().offset(0x05).write_volatile(1 << self.pin()) }
+        self.port().outset().write(1 << self.pin())
     }
 
     pub fn output_low(&self) {
-        unsafe { self.base_ptr().offset(0x06).write_volatile(1 << self.pin()) }
+        self.port().outclr().write(1 << self.pin())
     }
 
     pub fn output_toggle(&self) {
-        unsafe { self.base_ptr().offset(0x07).write_volatile(1 << self.pin()) }
+        self.port().outtgl().write(1 << self.pin())
     }
 
     pub fn input_read(&self) -> bool {
-        unsafe { self.base_ptr().offset(0x08).read_volatile() & (1 << self.pin()) > 0 }
+        self.port().r#in().read() & (1 << self.pin()) > 0
+    }
+
+    pub fn output_read(&self) -> bool {
+        self.port().out().read() & (1 << self.pin()) > 0
     }
 
     pub fn int_flag_read(&self) -> bool {
-        unsafe { self.base_ptr().offset(0x09).read_volatile() & (1 << self.pin()) > 0 }
+        self.port().intflags().read() & (1 << self.pin()) > 0
     }
 
     pub fn int_flag_clear(&self) {
-        unsafe { self.base_ptr().offset(0x09).write_volatile(1 << self.pin()) }
+        self.port().intflags().write(1 << self.pin())
     }
 
-    fn pin_ctrl(&self) -> *mut u8 {
-        unsafe { self.base_ptr().offset(self.pin().into()).offset(0x10) }
+    fn pin_ctrl(&self) -> Reg8 {
+        self.port().pin0ctrl().offset(self.pin())
     }
 
     pub fn pin_ctrl_invert(&self, b: bool) {
-        unsafe {
-            let v = if b { 1 } else { 0 };
-            let old = self.pin_ctrl().read_volatile() & 0b0111_1111;
-            self.pin_ctrl().write_volatile(old | (v << 7))
+        self.pin_ctrl().write_field(port::pin0ctrl::INVEN, b as u8)
+    }
+
+    pub fn pin_ctrl_isc(&self, isc: &ISC) {
+        self.pin_ctrl().write_field(port::pin0ctrl::ISC, isc.val())
+    }
+}
+
+/// Pin mode: input with the digital input buffer on.
+pub struct Input<PULL> {
+    _pull: PhantomData<PULL>,
+}
+
+/// No pull resistor.
+pub struct Floating;
+
+/// Internal pull-up enabled.
+pub struct PullUp;
+
+/// Pin mode: push-pull output.
+pub struct Output;
+
+/// Pin mode: input buffer off, for analog signals and to save power on
+/// unconnected pins. Reads as low.
+pub struct Disabled;
+
+/// `MODE` with INVEN set, the port inverts both what is read from and what is
+/// driven on the pin.
+pub struct Inverted<MODE> {
+    _mode: PhantomData<MODE>,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Modes that can read the pin.
+pub trait InputMode: sealed::Sealed {}
+
+/// Modes that drive the pin.
+pub trait OutputMode: sealed::Sealed {}
+
+impl<PULL> sealed::Sealed for Input<PULL> {}
+impl sealed::Sealed for Output {}
+impl<MODE> sealed::Sealed for Inverted<MODE> {}
+impl<PULL> InputMode for Input<PULL> {}
+impl<PULL> InputMode for Inverted<Input<PULL>> {}
+impl OutputMode for Output {}
+impl OutputMode for Inverted<Output> {}
+
+/// One pin of `PORT`, handed out exactly once by [`crate::Peripherals`].
+///
+/// Pins come out of reset as floating inputs, the `into_*` methods configure
+/// DIR and PINnCTRL and hand back the pin in its new `MODE`.
+pub struct Pin<const PORT: u16, const N: u8, MODE = Input<Floating>> {
+    _mode: PhantomData<MODE>,
+}
+
+impl<const PORT: u16, const N: u8, MODE> Pin<PORT, N, MODE> {
+    /// A second handle on the pin, for panic handlers and interrupts.
+    ///
+    /// # Safety
+    ///
+    /// The pin has to be in `MODE` already, nothing sets DIR and PINnCTRL
+    /// for it. A mode change through it changes the owner's pin too, e.g.
+    /// an output the owner drives turns into an input.
+    pub unsafe fn steal() -> Self {
+        Self { _mode: PhantomData }
+    }
+
+    fn gpio(&self) -> GPIO {
+        match PORT {
+            PORTA => GPIO::PORTA(N),
+            PORTB => GPIO::PORTB(N),
+            PORTC => GPIO::PORTC(N),
+            PORTD => GPIO::PORTD(N),
+            PORTE => GPIO::PORTE(N),
+            PORTF => GPIO::PORTF(N),
+            _ => unreachable!(),
         }
     }
 
-    pub fn pin_ctrl_pullup(&self, b: bool) {
-        unsafe {
-            let v = if b { 1 } else { 0 };
-            let old = self.pin_ctrl().read_volatile() & 0b1111_0111;
-            self.pin_ctrl().write_volatile(old | (v << 3))
+    fn regs() -> port::RegisterBlock {
+        match PORT {
+            PORTA => regs::PORTA,
+            PORTB => regs::PORTB,
+            PORTC => regs::PORTC,
+            PORTD => regs::PORTD,
+            PORTE => regs::PORTE,
+            PORTF => regs::PORTF,
+            _ => unreachable!(),
         }
     }
 
+    fn into_mode<NEW>(self) -> Pin<PORT, N, NEW> {
+        Pin { _mode: PhantomData }
+    }
+
+    pub fn pin(&self) -> u8 {
+        N
+    }
+
+    pub fn into_floating_input(self) -> Pin<PORT, N, Input<Floating>> {
+        let gpio = self.gpio();
+        gpio.output_disable();
+        gpio.pin_ctrl().write(0);
+        self.into_mode()
+    }
+
+    pub fn into_pull_up_input(self) -> Pin<PORT, N, Input<PullUp>> {
+        let gpio = self.gpio();
+        gpio.output_disable();
+        gpio.pin_ctrl().write(port::pin0ctrl::PULLUPEN.mask);
+        self.into_mode()
+    }
+
+    /// Output driving whatever is in OUT, low unless it was set before.
+    pub fn into_output(self) -> Pin<PORT, N, Output> {
+        let gpio = self.gpio();
+        gpio.pin_ctrl().write(0);
+        gpio.output_enable();
+        self.into_mode()
+    }
+
+    /// Output, high from the moment it is turned on.
+    pub fn into_output_high(self) -> Pin<PORT, N, Output> {
+        self.gpio().output_high();
+        self.into_output()
+    }
+
+    pub fn into_disabled(self) -> Pin<PORT, N, Disabled> {
+        let gpio = self.gpio();
+        gpio.output_disable();
+        gpio.pin_ctrl()
+            .write(port::pin0ctrl::ISC.bits(ISC::InputDisable.val()));
+        self.into_mode()
+    }
+}
+
+impl<const PORT: u16, const N: u8, PULL> Pin<PORT, N, Input<PULL>> {
+    pub fn into_inverted(self) -> Pin<PORT, N, Inverted<Input<PULL>>> {
+        self.gpio().pin_ctrl_invert(true);
+        self.into_mode()
+    }
+}
+
+impl<const PORT: u16, const N: u8> Pin<PORT, N, Output> {
+    pub fn into_inverted(self) -> Pin<PORT, N, Inverted<Output>> {
+        self.gpio().pin_ctrl_invert(true);
+        self.into_mode()
+    }
+}
+
+impl<const PORT: u16, const N: u8, MODE: InputMode> Pin<PORT, N, MODE> {
+    pub fn is_high(&self) -> bool {
+        self.gpio().input_read()
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    pub fn int_flag_read(&self) -> bool {
+        self.gpio().int_flag_read()
+    }
+
+    pub fn int_flag_clear(&self) {
+        self.gpio().int_flag_clear()
+    }
+
     pub fn pin_ctrl_isc(&self, isc: &ISC) {
-        unsafe {
-            let old = self.pin_ctrl().read_volatile() & 0b1111_1000;
-            self.pin_ctrl().write_volatile(old | isc.val())
+        self.gpio().pin_ctrl_isc(isc)
+    }
+}
+
+/// What makes [`Pin::listen`] call its handler.
+#[derive(Copy, Clone)]
+pub enum Sense {
+    BothEdges,
+    Rising,
+    Falling,
+    /// Keeps firing as long as the pin is low
+    Level,
+}
+
+impl Sense {
+    fn isc(self) -> ISC {
+        match self {
+            Sense::BothEdges => ISC::BothEdges,
+            Sense::Rising => ISC::Rising,
+            Sense::Falling => ISC::Falling,
+            Sense::Level => ISC::Level,
+        }
+    }
+}
+
+impl<const PORT: u16, const N: u8, MODE: InputMode> Pin<PORT, N, MODE> {
+    /// Calls `handler` from the PORT interrupt each time `sense` happens on
+    /// this pin, replacing any handler set before. The flag is already cleared
+    /// when `handler` runs. Nothing happens until interrupts are enabled with
+    /// [`crate::interrupt::enable`], and the app has the port's vector bound
+    /// with [`crate::vectors!`].
+    pub fn listen(&mut self, sense: Sense, handler: fn()) {
+        HANDLERS[port_index(PORT)][N as usize].set(Some(handler));
+        self.int_flag_clear();
+        self.pin_ctrl_isc(&sense.isc());
+    }
+
+    /// Stops sensing, the input buffer stays on.
+    pub fn unlisten(&mut self) {
+        self.pin_ctrl_isc(&ISC::IntDisable);
+        HANDLERS[port_index(PORT)][N as usize].set(None);
+    }
+}
+
+impl<const PORT: u16, const N: u8, MODE: OutputMode> Pin<PORT, N, MODE> {
+    pub fn set_high(&mut self) {
+        self.gpio().output_high()
+    }
+
+    pub fn set_low(&mut self) {
+        self.gpio().output_low()
+    }
+
+    pub fn toggle(&mut self) {
+        self.gpio().output_toggle()
+    }
+
+    /// What the pin is set to drive, read back from OUT.
+    pub fn is_set_high(&self) -> bool {
+        self.gpio().output_read()
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+}
+
+macro_rules! pins {
+    ($($name:ident $field:ident: $port:ident $n:literal,)*) => {
+        $(pub type $name<MODE = Input<Floating>> = Pin<$port, $n, MODE>;)*
+
+        /// Every pin of the 48 pin package.
+        pub struct Pins {
+            $(pub $field: $name,)*
+        }
+
+        impl Pins {
+            pub(crate) const fn new() -> Self {
+                Self {
+                    $($field: Pin { _mode: PhantomData },)*
+                }
+            }
         }
+    };
+}
+
+pins! {
+    PA0 pa0: PORTA 0, PA1 pa1: PORTA 1, PA2 pa2: PORTA 2, PA3 pa3: PORTA 3,
+    PA4 pa4: PORTA 4, PA5 pa5: PORTA 5, PA6 pa6: PORTA 6, PA7 pa7: PORTA 7,
+    PB0 pb0: PORTB 0, PB1 pb1: PORTB 1, PB2 pb2: PORTB 2, PB3 pb3: PORTB 3,
+    PB4 pb4: PORTB 4, PB5 pb5: PORTB 5,
+    PC0 pc0: PORTC 0, PC1 pc1: PORTC 1, PC2 pc2: PORTC 2, PC3 pc3: PORTC 3,
+    PC4 pc4: PORTC 4, PC5 pc5: PORTC 5, PC6 pc6: PORTC 6, PC7 pc7: PORTC 7,
+    PD0 pd0: PORTD 0, PD1 pd1: PORTD 1, PD2 pd2: PORTD 2, PD3 pd3: PORTD 3,
+    PD4 pd4: PORTD 4, PD5 pd5: PORTD 5, PD6 pd6: PORTD 6, PD7 pd7: PORTD 7,
+    PE0 pe0: PORTE 0, PE1 pe1: PORTE 1, PE2 pe2: PORTE 2, PE3 pe3: PORTE 3,
+    PF0 pf0: PORTF 0, PF1 pf1: PORTF 1, PF2 pf2: PORTF 2, PF3 pf3: PORTF 3,
+    PF4 pf4: PORTF 4, PF5 pf5: PORTF 5, PF6 pf6: PORTF 6,
+}
+
+/// Handlers of one port set with [`Pin::listen`], by pin.
+type PortHandlers = [Handler<fn()>; 8];
+
+static HANDLERS: [PortHandlers; 6] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_HANDLERS: PortHandlers = [NO_HANDLER; 8];
+    [NO_HANDLERS; 6]
+};
+
+const fn port_index(port: u16) -> usize {
+    ((port - PORTA) / 0x20) as usize
+}
+
+/// Body of the PORT interrupt: clears the flags that are set and calls the
+/// handler of each of those pins, lowest pin first. Bound by
+/// [`crate::vectors!`].
+#[doc(hidden)]
+pub fn dispatch<const PORT: u16>() {
+    let port = Pin::<PORT, 0>::regs();
+    let flags = port.intflags().read();
+    // clear first, an edge during a handler raises the flag again
+    port.intflags().write(flags);
+    for (n, handler) in HANDLERS[port_index(PORT)].iter().enumerate() {
+        if flags & (1 << n) > 0 {
+            handler.call();
+        }
+    }
+}
+
+impl<const PORT: u16, const N: u8, MODE> ErrorType for Pin<PORT, N, MODE> {
+    type Error = !;
+}
+
+impl<const PORT: u16, const N: u8, MODE: InputMode> InputPin for Pin<PORT, N, MODE> {
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_high())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_low())
     }
 }
 
-impl OutputPin for GPIO {
-    ///Make sure to enable the output mode for this pin
+impl<const PORT: u16, const N: u8, MODE: OutputMode> OutputPin for Pin<PORT, N, MODE> {
     fn set_low(&mut self) -> Result<(), Self::Error> {
-        self.output_low();
+        self.set_low();
         Ok(())
     }
 
-    ///Make sure to enable the output mode for this pin
     fn set_high(&mut self) -> Result<(), Self::Error> {
-        self.output_high();
+        self.set_high();
         Ok(())
     }
 }
 
-impl ErrorType for GPIO {
-    type Error = !;
+impl<const PORT: u16, const N: u8, MODE: OutputMode> StatefulOutputPin for Pin<PORT, N, MODE> {
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_set_high())
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_set_low())
+    }
+}
+
+impl<const PORT: u16, const N: u8, MODE: OutputMode> ToggleableOutputPin for Pin<PORT, N, MODE> {
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        self.toggle();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use crate::regs::{
+        PORTA as PORTA_REGS, PORTD as PORTD_REGS, PORTE as PORTE_REGS, PORTF as PORTF_REGS,
+    };
+
+    #[test]
+    fn output_control_uses_strobe_registers() {
+        host::reset();
+        let led = GPIO::PORTA(2);
+        led.output_enable();
+        led.output_high();
+        led.output_low();
+        led.output_toggle();
+        led.output_disable();
+
+        assert_eq!(host::writes_to(PORTA_REGS.dirset()), [0b100]);
+        assert_eq!(host::writes_to(PORTA_REGS.outset()), [0b100]);
+        assert_eq!(host::writes_to(PORTA_REGS.outclr()), [0b100]);
+        assert_eq!(host::writes_to(PORTA_REGS.outtgl()), [0b100]);
+        assert_eq!(host::writes_to(PORTA_REGS.dirclr()), [0b100]);
+        assert_eq!(host::writes().len(), 5);
+    }
+
+    #[test]
+    fn pin_ctrl_keeps_other_fields() {
+        host::reset();
+        let drdy = GPIO::PORTD(3);
+        drdy.pin_ctrl_isc(&ISC::Rising);
+        drdy.pin_ctrl_invert(true);
+        assert_eq!(host::peek(PORTD_REGS.pin3ctrl()), 0b1000_0010);
+
+        drdy.pin_ctrl_isc(&ISC::InputDisable);
+        assert_eq!(host::peek(PORTD_REGS.pin3ctrl()), 0b1000_0100);
+        assert_eq!(host::peek(PORTD_REGS.pin0ctrl()), 0);
+    }
+
+    #[test]
+    fn input_and_interrupt_flags() {
+        host::reset();
+        let drdy = GPIO::PORTD(0);
+        host::script(PORTD_REGS.r#in(), &[0b0000_0001, 0b1111_1110]);
+        assert!(drdy.input_read());
+        assert!(!drdy.input_read());
+
+        host::poke(PORTD_REGS.intflags(), 0b0000_0001);
+        assert!(drdy.int_flag_read());
+        assert!(!GPIO::PORTD(1).int_flag_read());
+        drdy.int_flag_clear();
+        assert_eq!(host::writes_to(PORTD_REGS.intflags()), [0b0000_0001]);
+    }
+
+    #[test]
+    fn output_pin_traits() {
+        host::reset();
+        let mut pin = Pins::new().pa7.into_output();
+        OutputPin::set_high(&mut pin).unwrap();
+        OutputPin::set_low(&mut pin).unwrap();
+        ToggleableOutputPin::toggle(&mut pin).unwrap();
+        assert_eq!(host::writes_to(PORTA_REGS.outset()), [0x80]);
+        assert_eq!(host::writes_to(PORTA_REGS.outclr()), [0x80]);
+        assert_eq!(host::writes_to(PORTA_REGS.outtgl()), [0x80]);
+
+        host::poke(PORTA_REGS.out(), 0x80);
+        assert!(StatefulOutputPin::is_set_high(&pin).unwrap());
+        host::poke(PORTA_REGS.out(), 0x7F);
+        assert!(StatefulOutputPin::is_set_low(&pin).unwrap());
+    }
+
+    #[test]
+    fn output_high_is_set_before_driving() {
+        host::reset();
+        host::poke(PORTE_REGS.pin2ctrl(), 0b1000_1000);
+        let led: PE2<Output> = Pins::new().pe2.into_output_high();
+        assert_eq!(led.pin(), 2);
+
+        let port = PORTE_REGS;
+        assert_eq!(
+            host::writes(),
+            [
+                (port.outset().addr(), 0b100),
+                (port.pin2ctrl().addr(), 0),
+                (port.dirset().addr(), 0b100),
+            ]
+        );
+    }
+
+    #[test]
+    fn input_modes_configure_pin_ctrl() {
+        host::reset();
+        let pins = Pins::new();
+
+        let button = pins.pf6.into_pull_up_input();
+        assert_eq!(host::writes_to(PORTF_REGS.dirclr()), [0x40]);
+        assert_eq!(host::peek(PORTF_REGS.pin6ctrl()), 0b0000_1000);
+        let button = button.into_inverted();
+        assert_eq!(host::peek(PORTF_REGS.pin6ctrl()), 0b1000_1000);
+
+        // the port does the inverting, IN is read as is
+        host::script(PORTF_REGS.r#in(), &[0x40, 0x00]);
+        assert!(InputPin::is_high(&button).unwrap());
+        assert!(InputPin::is_low(&button).unwrap());
+
+        button.into_floating_input();
+        assert_eq!(host::peek(PORTF_REGS.pin6ctrl()), 0);
+
+        pins.pd0.into_disabled();
+        assert_eq!(host::peek(PORTD_REGS.pin0ctrl()), 0b0000_0100);
+        assert_eq!(host::writes_to(PORTD_REGS.dirclr()), [0x01]);
+    }
+
+    #[test]
+    fn listen_dispatches_port_interrupts() {
+        use core::sync::atomic::{AtomicU8, Ordering::Relaxed};
+        static RISING: AtomicU8 = AtomicU8::new(0);
+        static FALLING: AtomicU8 = AtomicU8::new(0);
+
+        host::reset();
+        let pins = Pins::new();
+        let mut drdy = pins.pd0;
+        let mut button = pins.pd3.into_pull_up_input();
+        drdy.listen(Sense::Rising, || {
+            RISING.fetch_add(1, Relaxed);
+        });
+        button.listen(Sense::Falling, || {
+            FALLING.fetch_add(1, Relaxed);
+        });
+        assert_eq!(host::peek(PORTD_REGS.pin0ctrl()), 0b0000_0010);
+        assert_eq!(host::peek(PORTD_REGS.pin3ctrl()), 0b0000_1011);
+
+        // PD2 has no handler, its flag is cleared all the same
+        host::reset();
+        host::poke(PORTD_REGS.intflags(), 0b0000_1101);
+        dispatch::<PORTD>();
+        assert_eq!(host::writes_to(PORTD_REGS.intflags()), [0b0000_1101]);
+        assert_eq!((RISING.load(Relaxed), FALLING.load(Relaxed)), (1, 1));
+
+        host::poke(PORTD_REGS.intflags(), 0b0000_0001);
+        dispatch::<PORTD>();
+        assert_eq!((RISING.load(Relaxed), FALLING.load(Relaxed)), (2, 1));
+
+        drdy.unlisten();
+        assert_eq!(host::peek(PORTD_REGS.pin0ctrl()), 0);
+        host::poke(PORTD_REGS.intflags(), 0b0000_0001);
+        dispatch::<PORTD>();
+        assert_eq!(RISING.load(Relaxed), 2);
+    }
 }
@@ -0,0 +1,146 @@
+//! In-memory register backend for running the HAL on a dev box.
+//!
+//! Every register is a plain byte in a per-thread copy of the data space.
+//! Writes are recorded so a test can check what a driver did, and reads can be
+//! scripted per register to play back status flags (e.g. `TWI0.MSTATUS`).
+//! Once a register's script runs out, reads return whatever was last written
+//! or [`poke`]d.
+//!
+//! Registers can also be handed to a [`Model`] (see [`crate::sim`]) which
+//! reacts to accesses the way the peripheral would.
+//!
+//! ```ignore
+//! host::reset();
+//! host::poke(TWI0.mstatus(), 0x61); // IDLE | CLKHOLD | WIF
+//! I2C.write(0x2A, &[1, 2]).unwrap();
+//! assert_eq!(host::writes_to(TWI0.mdata()), [1, 2]);
+//! ```
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::vec;
+use std::vec::Vec;
+
+use crate::regs::{Backend, Reg8};
+
+pub struct Host;
+
+/// Behaviour behind a set of registers.
+///
+/// `mem` is the whole data space, models keep their visible register values
+/// in it so [`peek`] shows what the driver would read.
+pub trait Model {
+    fn claims(&self, addr: u16) -> bool;
+    fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8;
+    fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]);
+}
+
+struct State {
+    mem: Vec<u8>,
+    scripts: HashMap<u16, VecDeque<u8>>,
+    writes: Vec<(u16, u8)>,
+    models: Vec<Box<dyn Model>>,
+    cycles: u64,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            mem: vec![0; 0x1_0000],
+            scripts: HashMap::new(),
+            writes: Vec::new(),
+            models: Vec::new(),
+            cycles: 0,
+        }
+    }
+}
+
+std::thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::new());
+}
+
+impl Backend for Host {
+    fn read(addr: u16) -> u8 {
+        STATE.with(|s| {
+            let s = &mut *s.borrow_mut();
+            if let Some(v) = s.scripts.get_mut(&addr).and_then(|q| q.pop_front()) {
+                return v;
+            }
+            match s.models.iter_mut().find(|m| m.claims(addr)) {
+                Some(m) => m.read(addr, &mut s.mem),
+                None => s.mem[addr as usize],
+            }
+        })
+    }
+
+    fn write(addr: u16, v: u8) {
+        STATE.with(|s| {
+            let s = &mut *s.borrow_mut();
+            s.writes.push((addr, v));
+            match s.models.iter_mut().find(|m| m.claims(addr)) {
+                Some(m) => m.write(addr, v, &mut s.mem),
+                None => s.mem[addr as usize] = v,
+            }
+        })
+    }
+}
+
+/// Clears memory, scripts, the write log and the cycle count of the current
+/// thread.
+pub fn reset() {
+    STATE.with(|s| *s.borrow_mut() = State::new());
+}
+
+/// Hands the registers claimed by `model` over to it until the next [`reset`].
+pub fn attach(model: impl Model + 'static) {
+    STATE.with(|s| s.borrow_mut().models.push(Box::new(model)));
+}
+
+/// Sets a register without recording a write.
+pub fn poke(reg: Reg8, v: u8) {
+    STATE.with(|s| s.borrow_mut().mem[reg.addr() as usize] = v);
+}
+
+/// Current value of a register, without consuming scripted reads.
+pub fn peek(reg: Reg8) -> u8 {
+    STATE.with(|s| s.borrow().mem[reg.addr() as usize])
+}
+
+/// Queues values returned by the next reads of `reg`, in order.
+pub fn script(reg: Reg8, values: &[u8]) {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .scripts
+            .entry(reg.addr())
+            .or_default()
+            .extend(values)
+    });
+}
+
+/// Every `(address, value)` written since the last [`reset`], in order.
+pub fn writes() -> Vec<(u16, u8)> {
+    STATE.with(|s| s.borrow().writes.clone())
+}
+
+/// Values written to `reg` since the last [`reset`], in order.
+pub fn writes_to(reg: Reg8) -> Vec<u8> {
+    STATE.with(|s| {
+        s.borrow()
+            .writes
+            .iter()
+            .filter(|(a, _)| *a == reg.addr())
+            .map(|(_, v)| *v)
+            .collect()
+    })
+}
+
+/// CPU cycles spent in busy waits (see [`crate::delay`]) since the last
+/// [`reset`].
+pub fn cycles() -> u64 {
+    STATE.with(|s| s.borrow().cycles)
+}
+
+pub(crate) fn spend_cycles(n: u64) {
+    STATE.with(|s| s.borrow_mut().cycles += n);
+}
@@ -1,6 +1,13 @@
+use crate::clock::Clocks;
+use crate::gpio::{PA2, PA3};
+use crate::regs::{twi, TWI0};
 use embedded_hal::i2c::ErrorKind;
 
-pub struct I2C;
+/// The TWI0 master on its default pins, handed out once by
+/// [`crate::Peripherals`].
+pub struct I2C {
+    _private: (),
+}
 /*
  *
 Before enabling the master or the slave unit, ensure that the correct settings for SDASETUP, SDAHOLD, and, if
@@ -28,22 +35,6 @@ will be considered as part of TLOW. Likewise, TRISE will be in a state between T
 been detected
 
 
-Offset Name Bit Pos.
-0x00 CTRLA 7:0 SDASETUP SDAHOLD[1:0] FMPEN
-0x01 DUALCTRL 7:0 SDAHOLD[1:0] FMPEN ENABLE
-0x02 DBGCTRL 7:0 DBGRUN
-0x03 MCTRLA 7:0 RIEN WIEN QCEN TIMEOUT[1:0] SMEN ENABLE
-0x04 MCTRLB 7:0 FLUSH ACKACT MCMD[1:0]
-0x05 MSTATUS 7:0 RIF WIF CLKHOLD RXACK ARBLOST BUSERR BUSSTATE[1:0]
-0x06 MBAUD 7:0 BAUD[7:0]
-0x07 MADDR 7:0 ADDR[7:0]
-0x08 MDATA 7:0 DATA[7:0]
-0x09 SCTRLA 7:0 DIEN APIEN PIEN PMEN SMEN ENABLE
-0x0A SCTRLB 7:0 ACKACT SCMD[1:0]
-0x0B SSTATUS 7:0 DIF APIF CLKHOLD RXACK COLL BUSERR DIR AP
-0x0C SADDR 7:0 ADDR[7:0]
-0x0D SDATA 7:0 DATA[7:0]
-0x0E SADDRMASK 7:0 ADDRMASK[6:0] ADDREN
 https://2143.me/f/xmwH.png
 
 
@@ -80,29 +71,62 @@ impl embedded_hal::i2c::Error for I2CError {
     }
 }
 
+/// Rise time of SCL allowed for by [`mbaud`], the fast mode maximum.
+const T_RISE_NS: u64 = 300;
+
+/// MBAUD for an SCL of at most `scl_hz`, from
+/// f_SCL = f_per / (10 + 2 * MBAUD + f_per * t_rise).
+// div_ceil isn't stable on the pinned nightly
+#[allow(clippy::manual_div_ceil)]
+fn mbaud(f_per: u32, scl_hz: u32) -> u8 {
+    let f = f_per as u64;
+    let scl = scl_hz.max(1) as u64;
+    let cycles = (f + scl - 1) / scl;
+    let rise = (f * T_RISE_NS + 999_999_999) / 1_000_000_000;
+    let baud = (cycles.saturating_sub(10 + rise) + 1) / 2;
+    baud.min(0xff) as u8
+}
+
 impl I2C {
-    const TWI0: *mut u8 = 0x08A0 as *mut _;
-    pub fn setup() {
-        let bus_timeout = 0x01;
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on the TWI0 master.
+    ///
+    /// # Safety
+    ///
+    /// A transaction started in the middle of the owner's sends its START
+    /// and address into the owner's transfer. The bytes go to the wrong
+    /// device and both transfers fail.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Enables the master with SCL at `scl_hz` or a bit below.
+    pub fn setup<SDA, SCL>(
+        self,
+        _sda: PA2<SDA>,
+        _scl: PA3<SCL>,
+        scl_hz: u32,
+        clocks: &Clocks,
+    ) -> Self {
+        let bus_timeout = twi::mctrla::TIMEOUT.bits(twi::TIMEOUT::_50US as u8);
         //sdahold 500ns, fmpen no
-        unsafe { I2C::TWI0.offset(0x00).write_volatile(0b1100) };
+        TWI0.ctrla().write(twi::ctrla::SDAHOLD.bits(twi::SDAHOLD::_500NS as u8));
         //set bus timeout to 50us, turn off master
-        unsafe { I2C::TWI0.offset(0x03).write_volatile(bus_timeout << 2) };
-        //baud 0x0a = Same that arduino uses when at max clock
-        unsafe { I2C::TWI0.offset(0x06).write_volatile(0x0b) };
-
-        unsafe {
-            //~~interrupts~~ + timeout
-            I2C::TWI0
-                .offset(0x03)
-                .write_volatile(bus_timeout << 2 /* | 0b11000000 */);
-
-            //set bus state to idle
-            I2C::TWI0.offset(0x05).write_volatile(0x01);
-        }
+        TWI0.mctrla().write(bus_timeout);
+        TWI0.mbaud().write(mbaud(clocks.f_per(), scl_hz));
+
+        //~~interrupts~~ + timeout
+        TWI0.mctrla().write(bus_timeout /* | RIEN | WIEN */);
+
+        //set bus state to idle
+        TWI0.mstatus().write(twi::BUSSTATE::IDLE as u8);
+        self
     }
 
-    pub fn wait_for_bus() {
+    fn wait_for_bus() {
         loop {
             match I2C::get_bus_status().get_bus_state() {
                 Ok(BusState::Idle) => break,
@@ -114,7 +138,7 @@ impl I2C {
         }
     }
 
-    pub fn wait_wif() -> BusStatus {
+    fn wait_wif() -> BusStatus {
         loop {
             let status = Self::get_bus_status();
             if status.wif() {
@@ -123,8 +147,8 @@ impl I2C {
         }
     }
 
-    pub fn write_byte(data: u8) -> Result<(), I2CError> {
-        unsafe { I2C::TWI0.offset(0x08).write_volatile(data) };
+    fn write_byte(data: u8) -> Result<(), I2CError> {
+        TWI0.mdata().write(data);
         let status = Self::wait_wif();
 
         if status.arblost() {
@@ -136,8 +160,8 @@ impl I2C {
         Ok(())
     }
 
-    pub fn write_last_byte(data: u8) -> Result<(), I2CError> {
-        unsafe { I2C::TWI0.offset(0x08).write_volatile(data) };
+    fn write_last_byte(data: u8) -> Result<(), I2CError> {
+        TWI0.mdata().write(data);
         Self::stop();
         let status = Self::wait_wif();
         if status.arblost() {
@@ -147,32 +171,31 @@ impl I2C {
         Ok(())
     }
 
-    pub fn stop() {
-        unsafe { I2C::TWI0.offset(0x04).write_volatile(0x03) };
+    fn stop() {
+        TWI0.mctrlb().write(twi::MCMD::STOP as u8);
     }
 
-    pub fn recv_trans() {
-        unsafe { I2C::TWI0.offset(0x04).write_volatile(0x02) };
+    fn recv_trans() {
+        TWI0.mctrlb().write(twi::MCMD::RECVTRANS as u8);
     }
 
-    pub fn rep_start() {
-        unsafe { I2C::TWI0.offset(0x04).write_volatile(0x01) };
+    #[allow(dead_code)]
+    fn rep_start() {
+        TWI0.mctrlb().write(twi::MCMD::REPSTART as u8);
     }
 
-    pub fn respond(c: CK) {
-        unsafe {
-            I2C::TWI0.offset(0x04).write_volatile(match c {
-                CK::NACK => 0x04,
-                CK::ACK => 0x00,
-            })
-        };
+    fn respond(c: CK) {
+        TWI0.mctrlb().write(match c {
+            CK::NACK => twi::mctrlb::ACKACT.bits(twi::ACKACT::NACK as u8),
+            CK::ACK => twi::mctrlb::ACKACT.bits(twi::ACKACT::ACK as u8),
+        });
     }
 
     //for &c in data {
     //Self::write_byte(c);
     //}
 
-    pub fn wait_rif() -> BusStatus {
+    fn wait_rif() -> BusStatus {
         loop {
             let status = Self::get_bus_status();
             if status.rif() {
@@ -181,20 +204,20 @@ impl I2C {
         }
     }
 
-    pub fn read<const N: usize>(address: u8) -> Result<[u8; N], I2CError> {
+    pub fn read<const N: usize>(&mut self, address: u8) -> Result<[u8; N], I2CError> {
         let mut buf = [0u8; N];
-        Self::read_to_buf(address, &mut buf)?;
+        self.read_to_buf(address, &mut buf)?;
         Ok(buf)
     }
 
-    pub fn read_to_buf(address: u8, buf: &mut [u8]) -> Result<(), I2CError> {
-        let pre_enable = unsafe { I2C::TWI0.offset(0x03).read_volatile() };
+    pub fn read_to_buf(&mut self, address: u8, buf: &mut [u8]) -> Result<(), I2CError> {
+        let pre_enable = TWI0.mctrla().read();
         //turn on chip
-        unsafe { I2C::TWI0.offset(0x03).write_volatile(pre_enable | 1) };
+        TWI0.mctrla().write(pre_enable | twi::mctrla::ENABLE.mask);
 
         Self::wait_for_bus();
 
-        unsafe { I2C::TWI0.offset(0x07).write_volatile(address << 1 | 1) };
+        TWI0.maddr().write(address << 1 | 1);
 
         for b in buf {
             *b = Self::read_byte()?;
@@ -203,15 +226,15 @@ impl I2C {
         Self::stop();
 
         for _ in 0..100 {
-            unsafe { I2C::TWI0.offset(0x00).read_volatile() };
+            TWI0.ctrla().read();
         }
         //turn off chip
-        unsafe { I2C::TWI0.offset(0x03).write_volatile(pre_enable | 0) };
+        TWI0.mctrla().write(pre_enable & !twi::mctrla::ENABLE.mask);
 
         Ok(())
     }
 
-    pub fn read_byte() -> Result<u8, I2CError> {
+    fn read_byte() -> Result<u8, I2CError> {
         let mut status = Self::get_bus_status();
         loop {
             if status.arblost() {
@@ -224,7 +247,7 @@ impl I2C {
             status = Self::wait_rif();
         }
 
-        let v = unsafe { I2C::TWI0.offset(0x08).read_volatile() };
+        let v = TWI0.mdata().read();
         Self::respond(CK::ACK);
         Self::recv_trans();
         //Self::stop();
@@ -233,8 +256,7 @@ impl I2C {
     }
 
     pub fn get_bus_status() -> BusStatus {
-        let bs = unsafe { I2C::TWI0.offset(0x05).read_volatile() };
-        BusStatus(bs)
+        BusStatus(TWI0.mstatus().read())
     }
 }
 
@@ -246,40 +268,40 @@ pub enum CK {
 
 impl BusStatus {
     pub fn get_bus_state(&self) -> Result<BusState, ()> {
-        if self.0 & 0b100 > 0 {
+        if self.0 & twi::mstatus::BUSERR.mask > 0 {
             return Err(());
         } else {
-            match self.0 & 0b011 {
-                0 => Ok(BusState::Unknown),
-                1 => Ok(BusState::Idle),
-                2 => Ok(BusState::Owner),
-                3 => Ok(BusState::Busy),
-                _ => unreachable!(),
+            match twi::BUSSTATE::from_bits(twi::mstatus::BUSSTATE.get(self.0)) {
+                Some(twi::BUSSTATE::UNKNOWN) => Ok(BusState::Unknown),
+                Some(twi::BUSSTATE::IDLE) => Ok(BusState::Idle),
+                Some(twi::BUSSTATE::OWNER) => Ok(BusState::Owner),
+                Some(twi::BUSSTATE::BUSY) => Ok(BusState::Busy),
+                None => unreachable!(),
             }
         }
     }
 
     pub fn rif(&self) -> bool {
-        self.0 & 0b1000_0000 > 0
+        self.0 & twi::mstatus::RIF.mask > 0
     }
 
     pub fn wif(&self) -> bool {
-        self.0 & 0b0100_0000 > 0
+        self.0 & twi::mstatus::WIF.mask > 0
     }
 
     pub fn rxack(&self) -> CK {
-        match self.0 & 0b0000_1000 > 0 {
+        match self.0 & twi::mstatus::RXACK.mask > 0 {
             true => CK::NACK,
             false => CK::ACK,
         }
     }
 
     pub fn clkhld(&self) -> bool {
-        self.0 & 0b0010_0000 > 0
+        self.0 & twi::mstatus::CLKHOLD.mask > 0
     }
 
     pub fn arblost(&self) -> bool {
-        self.0 & 0b0000_1000 > 0
+        self.0 & twi::mstatus::ARBLOST.mask > 0
     }
 }
 
@@ -318,21 +340,24 @@ impl BusStatus {
 //}
 impl embedded_hal::i2c::blocking::I2c for I2C {
     fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        Self::read_to_buf(address, buffer)
+        self.read_to_buf(address, buffer)
     }
 
     fn write(&mut self, address: u8, data: &[u8]) -> Result<(), Self::Error> {
-        let pre_enable = unsafe { I2C::TWI0.offset(0x03).read_volatile() };
+        let pre_enable = TWI0.mctrla().read();
         //turn on chip
-        unsafe { I2C::TWI0.offset(0x03).write_volatile(pre_enable | 1) };
+        TWI0.mctrla().write(pre_enable | twi::mctrla::ENABLE.mask);
 
         Self::wait_for_bus();
-        unsafe { I2C::TWI0.offset(0x07).write_volatile(address << 1 | 0) };
+        TWI0.maddr().write(address << 1);
 
         let status = Self::wait_wif();
 
         if status.rxack() == CK::NACK {
             //Case M3: Address Packet " ", Not ACK by client
+            //release the bus, otherwise the next transfer waits for IDLE forever
+            Self::stop();
+            TWI0.mctrla().write(pre_enable & !twi::mctrla::ENABLE.mask);
             return Err(I2CError::NACK);
         } else if status.arblost() {
             //Case M4: Error
@@ -360,7 +385,7 @@ impl embedded_hal::i2c::blocking::I2c for I2C {
         }
 
         //turn off chip
-        unsafe { I2C::TWI0.offset(0x03).write_volatile(pre_enable | 0) };
+        TWI0.mctrla().write(pre_enable & !twi::mctrla::ENABLE.mask);
 
         Ok(())
     }
@@ -378,7 +403,7 @@ impl embedded_hal::i2c::blocking::I2c for I2C {
             buffer: &mut [u8],
         ) -> Result<(), Self::Error> {
         self.write(address, bytes)?;
-        self.read(address, buffer)?;
+        self.read_to_buf(address, buffer)?;
         Ok(())
     }
 
@@ -411,3 +436,109 @@ impl embedded_hal::i2c::blocking::I2c for I2C {
 impl embedded_hal::i2c::ErrorType for I2C {
     type Error = I2CError;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use embedded_hal::i2c::blocking::I2c;
+
+    // BUSSTATE = IDLE with the given flags
+    const IDLE: u8 = 0x01;
+    const RIF: u8 = 0x80;
+    const WIF: u8 = 0x40;
+    const CLKHOLD: u8 = 0x20;
+    const RXACK: u8 = 0x10;
+    const ARBLOST: u8 = 0x08;
+
+    #[test]
+    fn setup_configures_master() {
+        host::reset();
+        let pins = Pins::new();
+        I2C::new().setup(pins.pa2, pins.pa3, 400_000, &Clocks::from_hz(16_000_000));
+        assert_eq!(host::writes_to(TWI0.ctrla()), [0b1100]);
+        assert_eq!(host::writes_to(TWI0.mctrla()), [0b0100, 0b0100]);
+        // (40 - 10 - 4.8) / 2, rounded to the slower side
+        assert_eq!(host::writes_to(TWI0.mbaud()), [13]);
+        assert_eq!(host::writes_to(TWI0.mstatus()), [IDLE]);
+    }
+
+    #[test]
+    fn write_sends_address_then_data_then_stop() {
+        host::reset();
+        host::poke(TWI0.mctrla(), 0b0100);
+        host::poke(TWI0.mstatus(), IDLE | WIF | CLKHOLD);
+
+        I2C::new().write(0x2A, &[0x10, 0x20, 0x30]).unwrap();
+
+        assert_eq!(host::writes_to(TWI0.maddr()), [0x2A << 1]);
+        assert_eq!(host::writes_to(TWI0.mdata()), [0x10, 0x20, 0x30]);
+        assert_eq!(host::writes_to(TWI0.mctrlb()), [twi::MCMD::STOP as u8]);
+        // enabled for the transfer, then restored
+        assert_eq!(host::writes_to(TWI0.mctrla()), [0b0101, 0b0100]);
+    }
+
+    #[test]
+    fn write_address_nack() {
+        host::reset();
+        host::poke(TWI0.mstatus(), IDLE | WIF | RXACK);
+
+        assert!(matches!(I2C::new().write(0x2A, &[1]), Err(I2CError::NACK)));
+        assert!(host::writes_to(TWI0.mdata()).is_empty());
+    }
+
+    #[test]
+    fn write_data_nack_reports_position() {
+        host::reset();
+        host::script(TWI0.mstatus(), &[IDLE, IDLE | WIF | CLKHOLD, IDLE | WIF]);
+        host::poke(TWI0.mstatus(), IDLE | WIF | RXACK);
+
+        assert!(matches!(
+            I2C::new().write(0x2A, &[1, 2, 3]),
+            Err(I2CError::PartialTransmit(1))
+        ));
+    }
+
+    #[test]
+    fn write_arbitration_lost() {
+        host::reset();
+        host::poke(TWI0.mstatus(), IDLE | WIF | ARBLOST);
+        assert!(matches!(I2C::new().write(0x2A, &[1]), Err(I2CError::ArbLost)));
+    }
+
+    #[test]
+    fn write_waits_for_idle_bus() {
+        host::reset();
+        // BUSY, OWNER, then IDLE
+        host::script(TWI0.mstatus(), &[0x03, 0x02]);
+        host::poke(TWI0.mstatus(), IDLE | WIF | CLKHOLD);
+
+        I2C::new().write(0x2A, &[]).unwrap();
+        assert_eq!(host::writes_to(TWI0.maddr()), [0x2A << 1]);
+    }
+
+    #[test]
+    fn read_to_buf_acks_every_byte() {
+        host::reset();
+        host::poke(TWI0.mstatus(), IDLE | RIF);
+        host::script(TWI0.mdata(), &[0xDE, 0xAD, 0xBE]);
+
+        let mut buf = [0u8; 3];
+        I2C::new().read_to_buf(0x2A, &mut buf).unwrap();
+
+        assert_eq!(buf, [0xDE, 0xAD, 0xBE]);
+        assert_eq!(host::writes_to(TWI0.maddr()), [0x2A << 1 | 1]);
+        assert_eq!(
+            host::writes_to(TWI0.mctrlb()),
+            [0x00, 0x02, 0x00, 0x02, 0x00, 0x02, 0x03]
+        );
+    }
+
+    #[test]
+    fn read_arbitration_lost() {
+        host::reset();
+        host::poke(TWI0.mstatus(), IDLE | ARBLOST);
+        assert!(matches!(I2C::new().read::<2>(0x2A), Err(I2CError::ArbLost)));
+    }
+}
@@ -0,0 +1,264 @@
+//! Global interrupt enable, critical sections and the interrupt vectors.
+//!
+//! On anything but the chip (host tests) `cli`/`sei` do nothing, SREG is
+//! still read through the register backend.
+//!
+//! The HAL doesn't define any interrupt vector on its own. An app binds the
+//! ones its drivers need with [`vectors!`](crate::vectors), and is free to
+//! define the others itself.
+
+use crate::regs::{cpu, CPU};
+use core::cell::UnsafeCell;
+
+/// Clears the global interrupt flag.
+#[inline(always)]
+pub fn disable() {
+    #[cfg(target_arch = "avr")]
+    unsafe {
+        core::arch::asm!("cli")
+    };
+}
+
+/// Sets the global interrupt flag.
+///
+/// # Safety
+///
+/// Interrupt handlers may run from here on, anything they share with the main
+/// code has to be ready for that.
+#[inline(always)]
+pub unsafe fn enable() {
+    #[cfg(target_arch = "avr")]
+    core::arch::asm!("sei");
+}
+
+/// Runs `f` with interrupts disabled. They are only turned back on if they
+/// were on before, so this nests.
+#[inline(always)]
+pub fn free<R>(f: impl FnOnce() -> R) -> R {
+    let sreg = CPU.sreg().read();
+    disable();
+    let r = f();
+    if sreg & cpu::sreg::I.mask > 0 {
+        unsafe { enable() };
+    }
+    r
+}
+
+/// What a driver calls from an interrupt, set from the main code with
+/// `listen` and cleared with `unlisten`.
+pub(crate) struct Handler<F>(UnsafeCell<Option<F>>);
+
+// set with interrupts off, and interrupts don't nest
+unsafe impl<F: Send> Sync for Handler<F> {}
+
+impl<F: Copy> Handler<F> {
+    pub(crate) const fn new() -> Self {
+        Self(UnsafeCell::new(None))
+    }
+
+    /// Replaces the handler, with interrupts off so the interrupt never sees
+    /// it half written.
+    pub(crate) fn set(&self, f: Option<F>) {
+        free(|| unsafe { *self.0.get() = f });
+    }
+
+    /// For the interrupt body, which `set` can't run in the middle of.
+    pub(crate) fn get(&self) -> Option<F> {
+        unsafe { *self.0.get() }
+    }
+}
+
+/// For the handler tables, each use in an array is its own `Handler`.
+#[allow(clippy::declare_interior_mutable_const)]
+pub(crate) const NO_HANDLER: Handler<fn()> = Handler::new();
+
+impl Handler<fn()> {
+    /// Calls the handler if there is one.
+    pub(crate) fn call(&self) {
+        if let Some(f) = self.get() {
+            f();
+        }
+    }
+}
+
+/// Binds interrupt vectors to the drivers that serve them, by their name in
+/// the datasheet. Invoked once, at the root of the app:
+///
+/// ```ignore
+/// #![feature(abi_avr_interrupt)]
+///
+/// atmega4809_hal::vectors!(PORTD);
+/// ```
+///
+/// A vector that isn't listed stays free for the app. Whatever a driver was
+/// told to call from it is never called then, and an interrupt enabled for
+/// it jumps to the default handler.
+///
+/// | Name | Served by |
+/// |---|---|
+/// | `PORTA`, `PORTB`, `PORTC`, `PORTD`, `PORTE`, `PORTF` | [`Pin::listen`](crate::gpio::Pin::listen) |
+/// | `USARTn_RXC`, `USARTn_DRE`, `USARTn_TXC`, n = 0..3 | [`BufferedUsart`](crate::usart::BufferedUsart) |
+/// | `TCA0_OVF`, `TCA0_HUNF`, `TCA0_CMP0`, `TCA0_CMP1`, `TCA0_CMP2` | `listen` on [`TCA`](crate::tca::TCA), LUNF and LCMPn in split mode |
+/// | `TCB0`, `TCB1`, `TCB2`, `TCB3` | [`TCB::listen`](crate::tcb::TCB::listen), [`Monotonic`](crate::time::Monotonic) |
+/// | `ADC0_RESRDY` | [`ADC::listen`](crate::adc::ADC::listen) |
+/// | `ADC0_WCOMP` | [`ADC::listen_window`](crate::adc::ADC::listen_window) |
+/// | `AC0_AC` | [`AC::listen`](crate::ac::AC::listen) |
+/// | `RTC_CNT` | [`Counter::listen`](crate::rtc::Counter::listen) |
+/// | `RTC_PIT` | [`Pit`](crate::rtc::Pit), which counts its interrupts |
+#[macro_export]
+macro_rules! vectors {
+    ($($name:ident),* $(,)?) => {
+        $($crate::__vector!($name);)*
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector {
+    (PORTA) => {
+        $crate::__bind!(__vector_6, gpio::dispatch, $crate::gpio::PORTA);
+    };
+    (PORTD) => {
+        $crate::__bind!(__vector_20, gpio::dispatch, $crate::gpio::PORTD);
+    };
+    (PORTC) => {
+        $crate::__bind!(__vector_24, gpio::dispatch, $crate::gpio::PORTC);
+    };
+    (PORTF) => {
+        $crate::__bind!(__vector_29, gpio::dispatch, $crate::gpio::PORTF);
+    };
+    (PORTB) => {
+        $crate::__bind!(__vector_34, gpio::dispatch, $crate::gpio::PORTB);
+    };
+    (PORTE) => {
+        $crate::__bind!(__vector_35, gpio::dispatch, $crate::gpio::PORTE);
+    };
+    (USART0_RXC) => {
+        $crate::__bind!(__vector_17, usart::on_rxc, $crate::usart::USART0);
+    };
+    (USART0_DRE) => {
+        $crate::__bind!(__vector_18, usart::on_dre, $crate::usart::USART0);
+    };
+    (USART0_TXC) => {
+        $crate::__bind!(__vector_19, usart::on_txc, $crate::usart::USART0);
+    };
+    (USART1_RXC) => {
+        $crate::__bind!(__vector_26, usart::on_rxc, $crate::usart::USART1);
+    };
+    (USART1_DRE) => {
+        $crate::__bind!(__vector_27, usart::on_dre, $crate::usart::USART1);
+    };
+    (USART1_TXC) => {
+        $crate::__bind!(__vector_28, usart::on_txc, $crate::usart::USART1);
+    };
+    (USART2_RXC) => {
+        $crate::__bind!(__vector_31, usart::on_rxc, $crate::usart::USART2);
+    };
+    (USART2_DRE) => {
+        $crate::__bind!(__vector_32, usart::on_dre, $crate::usart::USART2);
+    };
+    (USART2_TXC) => {
+        $crate::__bind!(__vector_33, usart::on_txc, $crate::usart::USART2);
+    };
+    (USART3_RXC) => {
+        $crate::__bind!(__vector_37, usart::on_rxc, $crate::usart::USART3);
+    };
+    (USART3_DRE) => {
+        $crate::__bind!(__vector_38, usart::on_dre, $crate::usart::USART3);
+    };
+    (USART3_TXC) => {
+        $crate::__bind!(__vector_39, usart::on_txc, $crate::usart::USART3);
+    };
+    (TCA0_OVF) => {
+        $crate::__bind!(__vector_7, tca::dispatch, 0);
+    };
+    (TCA0_HUNF) => {
+        $crate::__bind!(__vector_8, tca::dispatch, 1);
+    };
+    (TCA0_CMP0) => {
+        $crate::__bind!(__vector_9, tca::dispatch, 2);
+    };
+    (TCA0_CMP1) => {
+        $crate::__bind!(__vector_10, tca::dispatch, 3);
+    };
+    (TCA0_CMP2) => {
+        $crate::__bind!(__vector_11, tca::dispatch, 4);
+    };
+    (TCB0) => {
+        $crate::__bind!(__vector_12, tcb::dispatch, $crate::tcb::TCB0);
+    };
+    (TCB1) => {
+        $crate::__bind!(__vector_13, tcb::dispatch, $crate::tcb::TCB1);
+    };
+    (TCB2) => {
+        $crate::__bind!(__vector_25, tcb::dispatch, $crate::tcb::TCB2);
+    };
+    (TCB3) => {
+        $crate::__bind!(__vector_36, tcb::dispatch, $crate::tcb::TCB3);
+    };
+    (ADC0_RESRDY) => {
+        $crate::__bind!(__vector_22, adc::result_ready);
+    };
+    (ADC0_WCOMP) => {
+        $crate::__bind!(__vector_23, adc::window_compare);
+    };
+    (AC0_AC) => {
+        $crate::__bind!(__vector_21, ac::compare);
+    };
+    (RTC_CNT) => {
+        $crate::__bind!(__vector_3, rtc::count_event);
+    };
+    (RTC_PIT) => {
+        $crate::__bind!(__vector_4, rtc::periodic);
+    };
+}
+
+/// Defines `$vector` to call the `fn()` at `$crate::$body`, with the const
+/// generic `$arg` if there is one.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __bind {
+    ($vector:ident, $($body:ident)::+ $(, $arg:expr)?) => {
+        #[cfg(target_arch = "avr")]
+        #[no_mangle]
+        pub unsafe extern "avr-interrupt" fn $vector() {
+            $crate::$($body)::+$(::<{ $arg }>)?();
+        }
+
+        // type checked off the chip all the same
+        #[cfg(not(target_arch = "avr"))]
+        const _: fn() = $crate::$($body)::+$(::<{ $arg }>)?;
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Handler;
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    // every name is known and its driver body type checks
+    crate::vectors!(PORTA, PORTB, PORTC, PORTD, PORTE, PORTF);
+    crate::vectors!(USART0_RXC, USART0_DRE, USART0_TXC, USART1_RXC, USART1_DRE, USART1_TXC);
+    crate::vectors!(USART2_RXC, USART2_DRE, USART2_TXC, USART3_RXC, USART3_DRE, USART3_TXC);
+    crate::vectors!(TCA0_OVF, TCA0_HUNF, TCA0_CMP0, TCA0_CMP1, TCA0_CMP2);
+    crate::vectors!(TCB0, TCB1, TCB2, TCB3);
+    crate::vectors!(ADC0_RESRDY, ADC0_WCOMP, AC0_AC, RTC_CNT, RTC_PIT);
+
+    static CALLS: AtomicU8 = AtomicU8::new(0);
+
+    fn count() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn handler_set_and_cleared() {
+        static HANDLER: Handler<fn()> = Handler::new();
+        HANDLER.call();
+        HANDLER.set(Some(count));
+        HANDLER.call();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        HANDLER.set(None);
+        HANDLER.call();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,378 @@
+//! Infrared remote transmitter.
+//!
+//! TCA0 in frequency mode makes the carrier on WO0 ([`Carrier`]) and a CCL
+//! LUT lets it through while the gate is high ([`Transmitter`]). The gate is
+//! either
+//!
+//! - TCB1 in single-shot mode, which times each mark of a [`Frame`] (see
+//!   [`Frame::nec`] and [`Frame::rc5`]), or
+//! - the TXD of USART1 set up in IRCOM mode, every 0 bit of a byte sent
+//!   becomes a burst.
+//!
+//! The carrier and the gate meet inside the CCL and only the LUT output goes
+//! to the IR LED, but both still take a pin of their own. [`Carrier`] sets
+//! CMP0EN, which takes WO0 on the port TCA0 is routed to (PA0 unless moved
+//! with [`TCA::set_port`]) away from the port: the pin toggles at the carrier
+//! whenever it is an output. The TCB gate drives the WO pin handed to
+//! [`into_single_shot`](crate::tcb::TCB::into_single_shot), the USART gate
+//! its TXD. None of them can be used for anything else meanwhile.
+//!
+//! ```ignore
+//! // PA0 carries the carrier, PF5 the gate
+//! let carrier = Carrier::new(dp.TCA0, 38_000, &clocks)?;
+//! let shot = dp.TCB1.into_single_shot(pins.pf5.into_output(), EventInput::new(7, Edge::Rising), ClockSource::Div2, 0, &clocks);
+//! let mut ir = Transmitter::new(carrier, dp.CCL, shot, pins.pc3.into_output());
+//! ir.send(&Frame::nec(0x00, 0x45), &mut delay);
+//! ```
+
+use crate::ccl::{Input, OutPin, CCL};
+use crate::clock::Clocks;
+use crate::regs;
+use crate::tca::{Channel, TCAError, WaveformGenerationMode, TCA};
+use crate::tcb::{mode::SingleShot, Timer, TCB1};
+use crate::usart::{USART, USART1};
+use core::ops::RangeInclusive;
+use embedded_hal::delay::blocking::DelayUs;
+
+/// Carriers consumer IR receivers are built for.
+pub const CARRIER_HZ: RangeInclusive<u32> = 36_000..=56_000;
+
+/// Truth table letting the carrier (IN0) through while the gate (IN1) is high.
+const CARRIER_AND_GATE: u8 = 0x08;
+
+#[derive(Debug, PartialEq)]
+pub enum IRError {
+    /// Outside of [`CARRIER_HZ`]
+    CarrierOutOfRange,
+    Timer(TCAError),
+}
+
+/// The IR carrier on TCA0 WO0, which is an output while this runs.
+pub struct Carrier {
+    tca: TCA,
+    hz: u32,
+}
+
+impl Carrier {
+    /// Starts a carrier of `hz`, as close as the clock allows.
+    pub fn new(mut tca: TCA, hz: u32, clocks: &Clocks) -> Result<Self, IRError> {
+        if !CARRIER_HZ.contains(&hz) {
+            return Err(IRError::CarrierOutOfRange);
+        }
+        tca.set_waveform(WaveformGenerationMode::FRQ);
+        let hz = tca.set_frequency(hz, clocks).map_err(IRError::Timer)?;
+        tca.enable_output(Channel::Cmp0);
+        tca.enable(tca.divider());
+        Ok(Self { tca, hz })
+    }
+
+    /// The carrier that came out.
+    pub fn frequency(&self) -> u32 {
+        self.hz
+    }
+
+    pub fn release(mut self) -> TCA {
+        self.tca.disable();
+        self.tca.disable_output(Channel::Cmp0);
+        self.tca
+    }
+}
+
+/// Something that gates the carrier, through LUT input 1.
+pub trait Gate {
+    const INPUT: Input;
+}
+
+impl Gate for Timer<TCB1, SingleShot> {
+    const INPUT: Input = Input::Tcb;
+}
+
+impl<const A: bool> Gate for USART<USART1, A> {
+    const INPUT: Input = Input::Usart;
+}
+
+/// The carrier gated by `G` through LUT `N`, see the [module docs](self).
+pub struct Transmitter<const N: u8, G> {
+    carrier: Carrier,
+    ccl: CCL,
+    gate: G,
+}
+
+impl<const N: u8, G: Gate> Transmitter<N, G> {
+    /// Takes LUT `N` of the CCL and drives `pin` with the IR signal.
+    pub fn new<const A: bool>(
+        carrier: Carrier,
+        mut ccl: CCL,
+        gate: G,
+        pin: impl OutPin<N, A>,
+    ) -> Self {
+        ccl.set_lut_output::<N, A>(
+            [Input::Tca0, G::INPUT, Input::Masked],
+            CARRIER_AND_GATE,
+            pin,
+        );
+        Self { carrier, ccl, gate }
+    }
+
+    pub fn carrier(&self) -> &Carrier {
+        &self.carrier
+    }
+
+    pub fn release(mut self) -> (Carrier, CCL, G) {
+        self.ccl.disable_lut::<N>();
+        (self.carrier, self.ccl, self.gate)
+    }
+}
+
+impl<const N: u8> Transmitter<N, Timer<TCB1, SingleShot>> {
+    /// Sends `frame`, `delay` times the spaces.
+    ///
+    /// A mark longer than the timer counts (the 9 ms NEC leader is 90 000
+    /// clocks at 10 MHz) goes out as back to back shots. `delay` waits for
+    /// each to end before the next is triggered, which leaves a gap of a few
+    /// CPU clocks, well below a carrier period.
+    pub fn send<D: DelayUs>(&mut self, frame: &Frame, delay: &mut D) {
+        for (mark, space) in frame.pulses() {
+            let mut left = self.gate.ticks(mark as u32);
+            while left > 0 {
+                let shot = left.min(0xFFFF) as u16;
+                self.gate.set_length(shot);
+                self.gate.trigger();
+                // rounded up, a trigger while the shot runs is lost
+                let _ = delay.delay_us(self.gate.span(shot).as_micros() + 1);
+                left -= shot as u32;
+            }
+            let _ = delay.delay_us(space as u32);
+        }
+    }
+}
+
+impl<const N: u8, const A: bool> Transmitter<N, USART<USART1, A>> {
+    /// Makes every 0 bit a burst of `cycles` peripheral clocks, 0 is the
+    /// IrDA 3/16 of a bit.
+    pub fn set_pulse_length(&mut self, cycles: u8) {
+        regs::USART1.txplctrl().write(cycles);
+    }
+
+    /// The USART to send with.
+    pub fn usart(&mut self) -> &mut USART<USART1, A> {
+        &mut self.gate
+    }
+}
+
+/// Longest frame, NEC's.
+const FRAME_LEN: usize = 67;
+
+/// A remote control message as the µs of carrier on (marks) and off
+/// (spaces) in turn, starting with a mark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    times: [u16; FRAME_LEN],
+    len: usize,
+}
+
+const NEC_UNIT: u16 = 562;
+const RC5_HALF: u16 = 889;
+
+impl Frame {
+    pub const fn new() -> Self {
+        Self {
+            times: [0; FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Adds `micros` of carrier on or off, after what is there. A space
+    /// before the first mark is dropped.
+    ///
+    /// # Panics
+    ///
+    /// When more than 67 marks and spaces are needed.
+    pub fn push(&mut self, mark: bool, micros: u16) {
+        if self.len == 0 && !mark {
+            return;
+        }
+        if mark == self.ends_with_mark() {
+            self.times[self.len - 1] += micros;
+        } else {
+            self.times[self.len] = micros;
+            self.len += 1;
+        }
+    }
+
+    fn ends_with_mark(&self) -> bool {
+        self.len % 2 == 1
+    }
+
+    /// The marks and spaces so far.
+    pub fn as_slice(&self) -> &[u16] {
+        &self.times[..self.len]
+    }
+
+    /// Each mark with the space after it, 0 after the last one.
+    pub fn pulses(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.as_slice()
+            .chunks(2)
+            .map(|p| (p[0], p.get(1).copied().unwrap_or(0)))
+    }
+
+    /// An NEC message: leader, then address, inverted address, command and
+    /// inverted command LSB first.
+    pub fn nec(address: u8, command: u8) -> Self {
+        Self::nec_extended(u16::from_le_bytes([address, !address]), command)
+    }
+
+    /// An NEC message with a 16-bit address in place of the inverted one.
+    pub fn nec_extended(address: u16, command: u8) -> Self {
+        let mut frame = Self::new();
+        frame.push(true, 16 * NEC_UNIT);
+        frame.push(false, 8 * NEC_UNIT);
+        let bits = address as u32 | (command as u32) << 16 | (!command as u32) << 24;
+        for i in 0..32 {
+            frame.push(true, NEC_UNIT);
+            let space = if bits & 1 << i != 0 { 3 } else { 1 };
+            frame.push(false, space * NEC_UNIT);
+        }
+        frame.push(true, NEC_UNIT);
+        frame
+    }
+
+    /// What an NEC remote sends every 108 ms while the button is held.
+    pub fn nec_repeat() -> Self {
+        let mut frame = Self::new();
+        frame.push(true, 16 * NEC_UNIT);
+        frame.push(false, 4 * NEC_UNIT);
+        frame.push(true, NEC_UNIT);
+        frame
+    }
+
+    /// An RC5 message, Manchester coded MSB first. Commands 64..128 are RC5X,
+    /// the second start bit carries the inverted bit 6. `toggle` has to
+    /// change with every key press, not with repeats.
+    pub fn rc5(address: u8, command: u8, toggle: bool) -> Self {
+        let bits = 1 << 13
+            | ((command & 0x40 == 0) as u16) << 12
+            | (toggle as u16) << 11
+            | ((address & 0x1F) as u16) << 6
+            | (command & 0x3F) as u16;
+        let mut frame = Self::new();
+        for i in (0..14).rev() {
+            let one = bits & 1 << i != 0;
+            // a 1 is off then on
+            frame.push(!one, RC5_HALF);
+            frame.push(one, RC5_HALF);
+        }
+        // the trailing space is idle anyway
+        if !frame.ends_with_mark() {
+            frame.len -= 1;
+        }
+        frame
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::regs::{EVSYS, TCA0};
+    use crate::tcb::{ClockSource, Edge, EventInput, TCB};
+    use crate::Delay;
+
+    const CLOCKS: Clocks = Clocks::from_hz(20_000_000);
+
+    #[test]
+    fn carrier() {
+        host::reset();
+        assert_eq!(
+            Carrier::new(TCA::new(), 30_000, &CLOCKS).err(),
+            Some(IRError::CarrierOutOfRange)
+        );
+        let carrier = Carrier::new(TCA::new(), 38_000, &CLOCKS).unwrap();
+        // 20 MHz / 2 / 263
+        assert_eq!(TCA0.single().cmp0().read(), 262);
+        assert_eq!(carrier.frequency(), 38_023);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x11);
+        assert_eq!(host::peek(TCA0.single().ctrla()), 0x01);
+    }
+
+    #[test]
+    fn nec() {
+        let frame = Frame::nec(0x00, 0x45);
+        let t = frame.as_slice();
+        assert_eq!(t.len(), 67);
+        assert_eq!(&t[..2], [8992, 4496]);
+        // address 0x00: eight short spaces, then eight long ones
+        assert!(t[3..18].iter().all(|&t| t == 562));
+        assert_eq!(t[19], 1686);
+        // command 0x45 = 1010_0010 LSB first
+        let command: [u16; 8] = core::array::from_fn(|i| t[35 + 2 * i]);
+        assert_eq!(command, [1686, 562, 1686, 562, 562, 562, 1686, 562]);
+        assert_eq!(t[66], 562);
+
+        assert_eq!(Frame::nec_repeat().as_slice(), [8992, 2248, 562]);
+    }
+
+    #[test]
+    fn rc5() {
+        // start 1 1, toggle 0, address 00101, command 000011
+        let frame = Frame::rc5(5, 3, false);
+        let (h, l) = (RC5_HALF, 2 * RC5_HALF);
+        assert_eq!(
+            frame.as_slice(),
+            [h, h, l, h, h, h, h, l, l, l, l, h, h, h, h, h, h, l, h, h, h]
+        );
+        // RC5X: the second start bit goes to 0
+        let frame = Frame::rc5(0, 0x40, true);
+        assert_eq!(&frame.as_slice()[..4], [l, l, l, h]);
+    }
+
+    #[test]
+    fn send_through_timer_gate() {
+        host::reset();
+        let pins = Pins::new();
+        let carrier = Carrier::new(TCA::new(), 38_000, &CLOCKS).unwrap();
+        let shot = TCB::<TCB1>::new().into_single_shot(
+            pins.pf5.into_output(),
+            EventInput::new(7, Edge::Rising),
+            ClockSource::Div2,
+            0,
+            &CLOCKS,
+        );
+        let mut ir = Transmitter::new(carrier, CCL::new(), shot, pins.pc3.into_output());
+        assert_eq!(host::peek(regs::CCL.lut1ctrlb()), 0xCA);
+        assert_eq!(host::peek(regs::CCL.truth1()), CARRIER_AND_GATE);
+        assert_eq!(host::peek(regs::CCL.lut1ctrla()), 0x41);
+
+        // the 9 ms leader is 89_920 ticks at 10 MHz, two shots
+        let mut delay = Delay::new(&CLOCKS);
+        ir.send(&Frame::nec_repeat(), &mut delay);
+        assert_eq!(host::writes_to(EVSYS.strobe()), [1 << 7; 3]);
+        // 0xFFFF, 89_920 - 0xFFFF = 0x5F41, 5620 = 0x15F4 after the 0 of setup
+        assert_eq!(
+            host::writes_to(regs::TCB1.ccmp().high()),
+            [0x00, 0xFF, 0x5F, 0x15]
+        );
+        let micros = host::cycles() / 20;
+        assert!(micros.abs_diff(8992 + 2248 + 562) < 100, "{}", micros);
+
+        let start = host::cycles();
+        let frame = Frame::rc5(5, 3, false);
+        ir.send(&frame, &mut delay);
+        assert_eq!(host::writes_to(EVSYS.strobe()), [1 << 7; 3 + 11]);
+        // the last mark, 889 µs at 10 MHz
+        assert_eq!(regs::TCB1.ccmp().read(), 8890);
+        let micros = (host::cycles() - start) / 20;
+        assert!(micros.abs_diff(27 * RC5_HALF as u64) < 100, "{}", micros);
+
+        let (carrier, _ccl, _shot) = ir.release();
+        assert_eq!(host::peek(regs::CCL.ctrla()), 0);
+        carrier.release();
+    }
+}
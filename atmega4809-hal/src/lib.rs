@@ -1,4 +1,5 @@
 #![feature(asm_experimental_arch)]
+#![feature(abi_avr_interrupt)]
 #![feature(never_type)]
 #![no_std]
 /*
@@ -48,43 +49,38 @@
 0x1300 USERROW User Row
 */
 
-use embedded_hal::delay::blocking::DelayUs;
+#[cfg(any(test, feature = "host"))]
+extern crate std;
 
+pub mod ac;
+pub mod adc;
+pub mod ccl;
 pub mod clock;
+pub mod delay;
+pub mod duty_cycle;
+pub mod evsys;
 pub mod gpio;
+#[cfg(any(test, feature = "host"))]
+pub mod host;
 pub mod i2c;
-pub mod pwm;
+pub mod interrupt;
+pub mod ir;
+mod peripherals;
+pub mod regs;
+pub mod rstctrl;
+pub mod rtc;
+pub mod servo;
+#[cfg(any(test, feature = "host"))]
+pub mod sim;
+pub mod sleep;
 pub mod spi;
+pub mod tca;
+pub mod tcb;
+pub mod time;
 pub mod usart;
+pub mod vref;
+pub mod wdt;
 
-pub struct Delay;
+pub use delay::Delay;
+pub use peripherals::Peripherals;
 
-pub fn set16(p: *mut u8, v: u16) {
-    // little endian?
-    unsafe {
-        p.offset(0).write_volatile((v & 0xFF) as u8);
-        p.offset(1).write_volatile((v >> 8) as u8);
-    }
-}
-
-impl DelayUs for Delay {
-    type Error = !;
-
-
-    fn delay_us(&mut self, us: u32) -> Result<(), Self::Error> {
-        // TODO this is broke as hell but close enough
-        let c = clock::ClockSelect::get_clock();
-        let us_per_nop = match c {
-            clock::ClockSelect::OSC20M => 5,
-            clock::ClockSelect::OSCULP32K => 32,
-            clock::ClockSelect::XOSC32K => 32,
-            clock::ClockSelect::EXTCLK => todo!(),
-        };
-
-        for _ in 0..(us / us_per_nop) {
-            unsafe { core::arch::asm!("nop") };
-        }
-
-        Ok(())
-    }
-}
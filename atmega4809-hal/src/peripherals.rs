@@ -0,0 +1,100 @@
+use crate::ac::AC;
+use crate::adc::ADC;
+use crate::ccl::CCL;
+use crate::gpio::Pins;
+use crate::i2c::I2C;
+use crate::interrupt;
+use crate::rstctrl::RSTCTRL;
+use crate::rtc::RTC;
+use crate::spi::SPI;
+use crate::tca::TCA;
+use crate::tcb::{TCB, TCB0, TCB1, TCB2, TCB3};
+use crate::usart::{USART, USART0, USART1, USART2, USART3};
+use crate::vref::VREF;
+use crate::wdt::WDT;
+
+/// Every driver and pin on the chip, once.
+///
+/// Drivers start out unconfigured, `setup` consumes them together with the
+/// pins they route to. A second `take` returns `None`, so two parts of the
+/// program can't both think they own a peripheral.
+#[allow(non_snake_case)]
+pub struct Peripherals {
+    pub USART0: USART<USART0, false>,
+    pub USART1: USART<USART1, false>,
+    pub USART2: USART<USART2, false>,
+    pub USART3: USART<USART3, false>,
+    pub TWI0: I2C,
+    pub SPI0: SPI,
+    pub TCA0: TCA,
+    pub TCB0: TCB<TCB0>,
+    pub TCB1: TCB<TCB1>,
+    pub TCB2: TCB<TCB2>,
+    pub TCB3: TCB<TCB3>,
+    pub CCL: CCL,
+    pub ADC0: ADC,
+    pub AC0: AC,
+    pub VREF: VREF,
+    pub RTC: RTC,
+    pub WDT: WDT,
+    pub RSTCTRL: RSTCTRL,
+    pub pins: Pins,
+}
+
+static mut TAKEN: bool = false;
+
+impl Peripherals {
+    pub fn take() -> Option<Self> {
+        interrupt::free(|| {
+            // SAFETY: interrupts are off, nothing else touches TAKEN
+            if unsafe { TAKEN } {
+                return None;
+            }
+            unsafe { TAKEN = true };
+            Some(unsafe { Self::steal() })
+        })
+    }
+
+    /// Hands out everything again, whether it was taken or not.
+    ///
+    /// # Safety
+    ///
+    /// Only for code that runs when nobody else will (panic handlers), the
+    /// drivers handed out here share registers with the taken ones.
+    pub unsafe fn steal() -> Self {
+        Self {
+            USART0: USART::new(),
+            USART1: USART::new(),
+            USART2: USART::new(),
+            USART3: USART::new(),
+            TWI0: I2C::new(),
+            SPI0: SPI::new(),
+            TCA0: TCA::new(),
+            TCB0: TCB::new(),
+            TCB1: TCB::new(),
+            TCB2: TCB::new(),
+            TCB3: TCB::new(),
+            CCL: CCL::new(),
+            ADC0: ADC::new(),
+            AC0: AC::new(),
+            VREF: VREF::new(),
+            RTC: RTC::new(),
+            WDT: WDT::new(),
+            RSTCTRL: RSTCTRL::new(),
+            pins: Pins::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_once() {
+        crate::host::reset();
+        // tests share the flag, only this one takes
+        assert!(Peripherals::take().is_some());
+        assert!(Peripherals::take().is_none());
+    }
+}
@@ -0,0 +1,193 @@
+//! Typed register access for every peripheral in the memory map.
+//!
+//! Everything below the hand written types is generated by `build.rs` from
+//! `atpack/atdf/ATmega4809.atdf`. Each ATDF module becomes a Rust module
+//! (`regs::twi`) with a `RegisterBlock` that has one method per register, a
+//! submodule per register holding its bitfields (`regs::twi::mstatus::RIF`) and
+//! one enum per value group (`regs::twi::MCMD`). Instances are constants named
+//! like the datasheet (`regs::TWI0`).
+//!
+//! ```ignore
+//! use atmega4809_hal::regs::{twi, TWI0};
+//! let status = TWI0.mstatus();
+//! if status.is_set(twi::mstatus::WIF) { ... }
+//! TWI0.mctrlb().write_field(twi::mctrlb::MCMD, twi::MCMD::STOP as u8);
+//! ```
+
+/// How register reads and writes reach the hardware.
+///
+/// On the chip this is [`Mmio`]. With the `host` feature (and in this crate's
+/// own tests) [`crate::host::Host`] is used instead, which keeps registers in
+/// memory so drivers can run under `cargo test` on a dev box.
+pub trait Backend {
+    fn read(addr: u16) -> u8;
+    fn write(addr: u16, v: u8);
+}
+
+/// Volatile accesses to the data space, i.e. the real peripherals.
+pub struct Mmio;
+
+impl Backend for Mmio {
+    #[inline(always)]
+    fn read(addr: u16) -> u8 {
+        unsafe { (addr as *mut u8).read_volatile() }
+    }
+
+    #[inline(always)]
+    fn write(addr: u16, v: u8) {
+        unsafe { (addr as *mut u8).write_volatile(v) }
+    }
+}
+
+#[cfg(not(any(test, feature = "host")))]
+pub type Active = Mmio;
+#[cfg(any(test, feature = "host"))]
+pub type Active = crate::host::Host;
+
+/// A bitfield inside an 8-bit register, described by its mask.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub mask: u8,
+    pub shift: u8,
+}
+
+impl Field {
+    pub const fn new(mask: u8) -> Self {
+        Self {
+            mask,
+            shift: mask.trailing_zeros() as u8,
+        }
+    }
+
+    /// Shifts `v` into position, dropping bits that do not fit the field.
+    pub const fn bits(&self, v: u8) -> u8 {
+        (v << self.shift) & self.mask
+    }
+
+    /// Extracts this field from a whole register value.
+    pub const fn get(&self, reg: u8) -> u8 {
+        (reg & self.mask) >> self.shift
+    }
+}
+
+/// An 8-bit memory mapped register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reg8 {
+    addr: u16,
+}
+
+impl Reg8 {
+    pub const fn at(addr: u16) -> Self {
+        Self { addr }
+    }
+
+    pub const fn addr(&self) -> u16 {
+        self.addr
+    }
+
+    /// The register `n` bytes after this one, for register arrays like `PINnCTRL`.
+    pub const fn offset(&self, n: u8) -> Self {
+        Self::at(self.addr + n as u16)
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> u8 {
+        Active::read(self.addr)
+    }
+
+    #[inline(always)]
+    pub fn write(&self, v: u8) {
+        Active::write(self.addr, v)
+    }
+
+    #[inline(always)]
+    pub fn modify(&self, f: impl FnOnce(u8) -> u8) {
+        self.write(f(self.read()))
+    }
+
+    pub fn read_field(&self, f: Field) -> u8 {
+        f.get(self.read())
+    }
+
+    /// Read-modify-write of a single field, the other bits are kept.
+    pub fn write_field(&self, f: Field, v: u8) {
+        self.modify(|old| (old & !f.mask) | f.bits(v))
+    }
+
+    pub fn is_set(&self, f: Field) -> bool {
+        self.read() & f.mask > 0
+    }
+
+    pub fn set_bits(&self, f: Field) {
+        self.modify(|old| old | f.mask)
+    }
+
+    pub fn clear_bits(&self, f: Field) {
+        self.modify(|old| old & !f.mask)
+    }
+
+    /// Writes a register under Configuration Change Protection.
+    ///
+    /// The IOREG signature goes to `CPU.CCP` and the write has to follow
+    /// within four instructions, so on the chip both are one `asm!` block.
+    /// Interrupts are ignored by the CPU in between.
+    #[inline(always)]
+    pub fn protected_write(&self, v: u8) {
+        #[cfg(all(target_arch = "avr", not(any(test, feature = "host"))))]
+        unsafe {
+            // 0x34 is CPU.CCP, in the I/O space `out` reaches
+            core::arch::asm!(
+                "out 0x34, {sig}",
+                "st Z, {v}",
+                sig = in(reg) cpu::CCP::IOREG as u8,
+                v = in(reg) v,
+                in("Z") self.addr,
+            );
+        }
+        #[cfg(not(all(target_arch = "avr", not(any(test, feature = "host")))))]
+        {
+            CPU.ccp().write(cpu::CCP::IOREG as u8);
+            self.write(v);
+        }
+    }
+}
+
+/// A 16-bit register made of a low and a high byte.
+///
+/// 16-bit peripheral registers go through the shared TEMP register, so the
+/// low byte is always accessed first for both reads and writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reg16 {
+    addr: u16,
+}
+
+impl Reg16 {
+    pub const fn at(addr: u16) -> Self {
+        Self { addr }
+    }
+
+    pub const fn addr(&self) -> u16 {
+        self.addr
+    }
+
+    pub const fn low(&self) -> Reg8 {
+        Reg8::at(self.addr)
+    }
+
+    pub const fn high(&self) -> Reg8 {
+        Reg8::at(self.addr + 1)
+    }
+
+    pub fn read(&self) -> u16 {
+        let l = self.low().read();
+        let h = self.high().read();
+        (h as u16) << 8 | l as u16
+    }
+
+    pub fn write(&self, v: u16) {
+        self.low().write((v & 0xFF) as u8);
+        self.high().write((v >> 8) as u8);
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/regs.rs"));
@@ -0,0 +1,126 @@
+//! The reset controller: why the chip last reset, and resetting it from
+//! software.
+//!
+//! RSTFR keeps a flag for every reset since it was last cleared, so it is
+//! read and cleared once at start-up with [`RSTCTRL::take_cause`].
+//!
+//! ```ignore
+//! let cause = dp.RSTCTRL.take_cause();
+//! uwrite!(ble, "boot: {}\r\n", cause.map_or("unknown", ResetCause::as_str))?;
+//! ```
+
+use crate::interrupt;
+use crate::regs::{rstctrl, Field, RSTCTRL as REGS};
+
+/// RSTCTRL, handed out once by [`crate::Peripherals`].
+pub struct RSTCTRL {
+    _private: (),
+}
+
+/// What reset the chip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    /// The supply dropped below the BOD level
+    BrownOut,
+    /// The RESET pin
+    External,
+    Watchdog,
+    /// [`software_reset`]
+    Software,
+    /// The programmer, through UPDI
+    Updi,
+}
+
+/// Most telling first: a power-on reset sets other flags along with its
+/// own.
+const CAUSES: [(ResetCause, Field); 6] = [
+    (ResetCause::PowerOn, rstctrl::rstfr::PORF),
+    (ResetCause::BrownOut, rstctrl::rstfr::BORF),
+    (ResetCause::Watchdog, rstctrl::rstfr::WDRF),
+    (ResetCause::Software, rstctrl::rstfr::SWRF),
+    (ResetCause::Updi, rstctrl::rstfr::UPDIRF),
+    (ResetCause::External, rstctrl::rstfr::EXTRF),
+];
+
+impl ResetCause {
+    /// The cause behind RSTFR `flags`, `None` without any.
+    pub fn from_flags(flags: u8) -> Option<Self> {
+        CAUSES
+            .iter()
+            .find(|(_, f)| flags & f.mask != 0)
+            .map(|(cause, _)| *cause)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::BrownOut => "brown-out",
+            ResetCause::External => "external",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Software => "software",
+            ResetCause::Updi => "updi",
+        }
+    }
+}
+
+impl RSTCTRL {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on RSTCTRL.
+    ///
+    /// # Safety
+    ///
+    /// Taking the cause through it clears RSTFR, the owner then finds no
+    /// cause at all.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// The raw RSTFR flags, left as they are.
+    pub fn flags(&self) -> u8 {
+        REGS.rstfr().read()
+    }
+
+    /// Reads and clears RSTFR. `None` if it was cleared already and
+    /// nothing reset the chip since, e.g. after a jump to 0.
+    pub fn take_cause(&mut self) -> Option<ResetCause> {
+        let flags = self.flags();
+        REGS.rstfr().write(flags);
+        ResetCause::from_flags(flags)
+    }
+}
+
+/// Resets the chip right away, the next start sees
+/// [`ResetCause::Software`].
+pub fn software_reset() -> ! {
+    interrupt::disable();
+    REGS.swrr().protected_write(rstctrl::swrr::SWRE.mask);
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+
+    #[test]
+    fn take_cause_clears_flags() {
+        host::reset();
+        let mut rstctrl = RSTCTRL::new();
+        assert_eq!(rstctrl.take_cause(), None);
+
+        // power-on sets the brown-out flag as well
+        host::poke(REGS.rstfr(), 0x03);
+        assert_eq!(rstctrl.take_cause(), Some(ResetCause::PowerOn));
+        assert_eq!(host::writes_to(REGS.rstfr()), [0x00, 0x03]);
+
+        host::poke(REGS.rstfr(), 0x0C);
+        assert_eq!(rstctrl.take_cause(), Some(ResetCause::Watchdog));
+        assert_eq!(ResetCause::from_flags(0x24), Some(ResetCause::Updi));
+        assert_eq!(ResetCause::Software.as_str(), "software");
+    }
+}
@@ -0,0 +1,614 @@
+//! The real-time counter and its periodic interrupt timer.
+//!
+//! Both count the clock picked in [`RTCConfig`], which keeps running in
+//! standby and, for the PIT, in power-down. [`RTC::setup`] starts the 16-bit
+//! [`Counter`] with its prescaler and period and hands out the [`Pit`]
+//! next to it.
+//!
+//! The PIT counts its own interrupts, so started at a period of a second it
+//! is a clock of whole [`seconds`](Pit::seconds) since it was started, e.g.
+//! since arming, with [`TimeOfDay`] to show them. Its interrupt wakes the
+//! chip from every sleep mode.
+//!
+//! ```ignore
+//! let (_, mut pit) = dp.RTC.setup(RTCConfig::new().source(ClockSource::Osculp32k));
+//! pit.start(PitPeriod::Cyc32768);
+//! // ...
+//! uwrite!(stdout, "{}\r\n", pit.time_of_day());
+//! ```
+
+use crate::interrupt::{self, Handler, NO_HANDLER};
+use crate::regs::{clkctrl, rtc, Field, CLKCTRL, RTC as REGS};
+use core::num::NonZeroU32;
+use ufmt::{uDisplay, uWrite, uwrite, Formatter};
+
+/// RTC, handed out once by [`crate::Peripherals`].
+pub struct RTC {
+    _private: (),
+}
+
+/// What the RTC and the PIT count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+    /// The internal 32.768 kHz oscillator
+    Osculp32k,
+    /// The same, divided by 32
+    Int1k,
+    /// A 32.768 kHz crystal on TOSC1/TOSC2
+    Xosc32k,
+    /// A clock of the given Hz on TOSC1
+    External(NonZeroU32),
+}
+
+impl ClockSource {
+    pub fn frequency(&self) -> u32 {
+        match self {
+            ClockSource::Osculp32k | ClockSource::Xosc32k => 32_768,
+            ClockSource::Int1k => 1024,
+            ClockSource::External(hz) => hz.get(),
+        }
+    }
+
+    fn clksel(&self) -> rtc::CLKSEL {
+        match self {
+            ClockSource::Osculp32k => rtc::CLKSEL::INT32K,
+            ClockSource::Int1k => rtc::CLKSEL::INT1K,
+            ClockSource::Xosc32k | ClockSource::External(_) => rtc::CLKSEL::TOSC32K,
+        }
+    }
+}
+
+/// Divider of the RTC clock for the counter, the PIT doesn't use it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prescaler {
+    D1,
+    D2,
+    D4,
+    D8,
+    D16,
+    D32,
+    D64,
+    D128,
+    D256,
+    D512,
+    D1024,
+    D2048,
+    D4096,
+    D8192,
+    D16384,
+    D32768,
+}
+
+impl Prescaler {
+    pub fn divisor(&self) -> u32 {
+        1 << *self as u32
+    }
+}
+
+/// RTC clocks between two PIT interrupts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PitPeriod {
+    Cyc4,
+    Cyc8,
+    Cyc16,
+    Cyc32,
+    Cyc64,
+    Cyc128,
+    Cyc256,
+    Cyc512,
+    Cyc1024,
+    Cyc2048,
+    Cyc4096,
+    Cyc8192,
+    Cyc16384,
+    Cyc32768,
+}
+
+impl PitPeriod {
+    pub fn cycles(&self) -> u32 {
+        4 << *self as u32
+    }
+
+    fn period(&self) -> rtc::PERIOD {
+        // OFF comes first
+        rtc::PERIOD::from_bits(*self as u8 + 1).unwrap()
+    }
+}
+
+/// The interrupts of the [`Counter`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The count went past the period and back to 0
+    Overflow,
+    /// The count reached the compare value
+    Compare,
+}
+
+impl Event {
+    fn field(&self) -> Field {
+        match self {
+            Event::Overflow => rtc::intctrl::OVF,
+            Event::Compare => rtc::intctrl::CMP,
+        }
+    }
+}
+
+/// How the RTC is set up, applied by [`RTC::setup`].
+#[derive(Clone, Copy)]
+pub struct RTCConfig {
+    source: ClockSource,
+    prescaler: Prescaler,
+    period: u16,
+    run_in_standby: bool,
+}
+
+impl RTCConfig {
+    /// The 1 kHz clock, undivided, overflowing after 64 s.
+    pub const fn new() -> Self {
+        Self {
+            source: ClockSource::Int1k,
+            prescaler: Prescaler::D1,
+            period: 0xFFFF,
+            run_in_standby: false,
+        }
+    }
+
+    pub const fn source(mut self, source: ClockSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub const fn prescaler(mut self, prescaler: Prescaler) -> Self {
+        self.prescaler = prescaler;
+        self
+    }
+
+    /// Top of the count, it overflows to 0 after this.
+    pub const fn period(mut self, period: u16) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Keeps the counter going in standby sleep. The PIT always runs.
+    pub const fn run_in_standby(mut self, run: bool) -> Self {
+        self.run_in_standby = run;
+        self
+    }
+}
+
+impl Default for RTCConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits until the RTC clock domain took the last write to the register
+/// behind `busy`.
+fn sync(busy: Field) {
+    while REGS.status().is_set(busy) {}
+}
+
+/// [`sync`] for PITCTRLA, which has a status register of its own.
+fn pit_sync() {
+    while REGS.pitstatus().is_set(rtc::pitstatus::CTRLBUSY) {}
+}
+
+impl RTC {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on the RTC.
+    ///
+    /// # Safety
+    ///
+    /// Setting it up again restarts the counter and the PIT with a new
+    /// clock and prescaler: the owners' [`Counter`] and [`Pit`] keep
+    /// converting with the old ones, and the PIT's seconds start over.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Selects the clock, starts the counter from 0 and hands out the
+    /// still stopped PIT.
+    pub fn setup(self, config: RTCConfig) -> (Counter, Pit) {
+        Pit::disable();
+        sync(rtc::status::CTRLABUSY);
+        REGS.ctrla().write(0);
+        match config.source {
+            ClockSource::Xosc32k | ClockSource::External(_) => {
+                let sel = match config.source {
+                    ClockSource::External(_) => clkctrl::xosc32kctrla::SEL.mask,
+                    _ => 0,
+                };
+                // the RTC asks for it, RUNSTDBY has it running before that
+                CLKCTRL.xosc32kctrla().protected_write(
+                    sel | clkctrl::xosc32kctrla::RUNSTDBY.mask | clkctrl::xosc32kctrla::ENABLE.mask,
+                );
+            }
+            _ => {}
+        }
+        REGS.clksel().write(config.source.clksel().bits());
+
+        REGS.intctrl().write(0);
+        sync(rtc::status::CNTBUSY);
+        REGS.cnt().write(0);
+        sync(rtc::status::PERBUSY);
+        REGS.per().write(config.period);
+        let mut ctrla = rtc::ctrla::PRESCALER.bits(config.prescaler as u8) | rtc::ctrla::RTCEN.mask;
+        if config.run_in_standby {
+            ctrla |= rtc::ctrla::RUNSTDBY.mask;
+        }
+        sync(rtc::status::CTRLABUSY);
+        REGS.ctrla().write(ctrla);
+
+        let f_rtc = config.source.frequency();
+        (
+            Counter {
+                f_counter: f_rtc / config.prescaler.divisor(),
+            },
+            Pit {
+                f_rtc,
+                period: None,
+            },
+        )
+    }
+}
+
+/// The 16-bit RTC counter.
+pub struct Counter {
+    /// Counts per second
+    f_counter: u32,
+}
+
+impl Counter {
+    /// Counts per second.
+    pub fn frequency(&self) -> u32 {
+        self.f_counter
+    }
+
+    pub fn count(&self) -> u16 {
+        REGS.cnt().read()
+    }
+
+    pub fn set_count(&mut self, count: u16) {
+        sync(rtc::status::CNTBUSY);
+        REGS.cnt().write(count);
+    }
+
+    pub fn set_period(&mut self, period: u16) {
+        sync(rtc::status::PERBUSY);
+        REGS.per().write(period);
+    }
+
+    pub fn set_compare(&mut self, compare: u16) {
+        sync(rtc::status::CMPBUSY);
+        REGS.cmp().write(compare);
+    }
+
+    /// The counts in `millis` milliseconds, for periods and compare
+    /// values. Clamped to 16 bits.
+    pub fn ticks(&self, millis: u32) -> u16 {
+        (millis as u64 * self.f_counter as u64 / 1000).min(0xFFFF) as u16
+    }
+
+    /// Whether `event` happened since the last call.
+    pub fn is_pending(&mut self, event: Event) -> bool {
+        let flag = event.field();
+        let pending = REGS.intflags().is_set(flag);
+        if pending {
+            REGS.intflags().write(flag.mask);
+        }
+        pending
+    }
+
+    /// Calls `handler` on every `event`, from the RTC counter interrupt.
+    /// `RTC_CNT` has to be bound with [`crate::vectors!`].
+    pub fn listen(&mut self, event: Event, handler: fn()) {
+        HANDLERS[event as usize].set(Some(handler));
+        REGS.intflags().write(event.field().mask);
+        REGS.intctrl().set_bits(event.field());
+    }
+
+    pub fn unlisten(&mut self, event: Event) {
+        REGS.intctrl().clear_bits(event.field());
+        HANDLERS[event as usize].set(None);
+    }
+
+    /// Stops the counter, the PIT keeps going.
+    pub fn stop(self) {
+        REGS.intctrl().write(0);
+        sync(rtc::status::CTRLABUSY);
+        REGS.ctrla().clear_bits(rtc::ctrla::RTCEN);
+    }
+}
+
+/// The periodic interrupt timer, see the [module docs](self).
+pub struct Pit {
+    /// RTC clock
+    f_rtc: u32,
+    period: Option<PitPeriod>,
+}
+
+impl Pit {
+    /// Starts interrupting every `period`, counting from 0 again.
+    /// `RTC_PIT` has to be bound with [`crate::vectors!`].
+    pub fn start(&mut self, period: PitPeriod) {
+        Self::disable();
+        interrupt::free(|| unsafe { PIT_TICKS = 0 });
+        REGS.pitintflags().write(rtc::pitintflags::PI.mask);
+        REGS.pitintctrl().write(rtc::pitintctrl::PI.mask);
+        // the 0 disable() wrote has to be through first
+        pit_sync();
+        REGS.pitctrla()
+            .write(rtc::pitctrla::PERIOD.bits(period.period().bits()) | rtc::pitctrla::PITEN.mask);
+        self.period = Some(period);
+    }
+
+    pub fn stop(&mut self) {
+        Self::disable();
+        self.period = None;
+    }
+
+    fn disable() {
+        REGS.pitintctrl().write(0);
+        pit_sync();
+        REGS.pitctrla().write(0);
+    }
+
+    /// Calls `handler` on every interrupt, besides counting it.
+    pub fn listen(&mut self, handler: fn()) {
+        PIT_HANDLER.set(Some(handler));
+    }
+
+    pub fn unlisten(&mut self) {
+        PIT_HANDLER.set(None);
+    }
+
+    /// Interrupts since [`start`](Self::start).
+    pub fn ticks(&self) -> u32 {
+        interrupt::free(|| unsafe { PIT_TICKS })
+    }
+
+    /// Whole seconds since [`start`](Self::start), 0 while stopped.
+    pub fn seconds(&self) -> u32 {
+        match self.period {
+            Some(period) => {
+                (self.ticks() as u64 * period.cycles() as u64 / self.f_rtc as u64) as u32
+            }
+            None => 0,
+        }
+    }
+
+    pub fn time_of_day(&self) -> TimeOfDay {
+        TimeOfDay::from_secs(self.seconds())
+    }
+}
+
+/// Seconds split into days, hours, minutes and seconds, shown as
+/// `1d 02:03:04`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeOfDay {
+    pub days: u32,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl TimeOfDay {
+    pub fn from_secs(secs: u32) -> Self {
+        Self {
+            days: secs / 86_400,
+            hours: (secs / 3600 % 24) as u8,
+            minutes: (secs / 60 % 60) as u8,
+            seconds: (secs % 60) as u8,
+        }
+    }
+
+    pub fn as_secs(&self) -> u32 {
+        self.days * 86_400
+            + self.hours as u32 * 3600
+            + self.minutes as u32 * 60
+            + self.seconds as u32
+    }
+}
+
+impl uDisplay for TimeOfDay {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        uwrite!(f, "{}d ", self.days)?;
+        for (i, v) in [self.hours, self.minutes, self.seconds].iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            if *v < 10 {
+                f.write_str("0")?;
+            }
+            uwrite!(f, "{}", *v)?;
+        }
+        Ok(())
+    }
+}
+
+static HANDLERS: [Handler<fn()>; 2] = [NO_HANDLER; 2];
+static PIT_HANDLER: Handler<fn()> = Handler::new();
+static mut PIT_TICKS: u32 = 0;
+
+/// Body of the RTC counter interrupt, overflow and compare share it.
+/// Bound by [`crate::vectors!`].
+#[doc(hidden)]
+pub fn count_event() {
+    let flags = REGS.intflags().read() & REGS.intctrl().read();
+    REGS.intflags().write(flags);
+    for event in [Event::Overflow, Event::Compare] {
+        if flags & event.field().mask != 0 {
+            HANDLERS[event as usize].call();
+        }
+    }
+}
+
+/// Body of the PIT interrupt, bound by [`crate::vectors!`].
+#[doc(hidden)]
+pub fn periodic() {
+    REGS.pitintflags().write(rtc::pitintflags::PI.mask);
+    unsafe { PIT_TICKS = PIT_TICKS.wrapping_add(1) };
+    PIT_HANDLER.call();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use core::sync::atomic::{AtomicU8, Ordering};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    static CALLS: AtomicU8 = AtomicU8::new(0);
+
+    fn call() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn counter_setup_and_events() {
+        host::reset();
+        let config = RTCConfig::new()
+            .source(ClockSource::Xosc32k)
+            .prescaler(Prescaler::D32)
+            .period(999)
+            .run_in_standby(true);
+        let (mut counter, _) = RTC::new().setup(config);
+        assert_eq!(host::peek(CLKCTRL.xosc32kctrla()), 0x03);
+        assert_eq!(host::peek(REGS.clksel()), 0x02);
+        assert_eq!(REGS.per().read(), 999);
+        assert_eq!(host::peek(REGS.ctrla()), 0xA9);
+        assert_eq!(counter.frequency(), 1024);
+        assert_eq!(counter.ticks(500), 512);
+
+        counter.set_compare(counter.ticks(250));
+        assert_eq!(REGS.cmp().read(), 256);
+        assert!(!counter.is_pending(Event::Compare));
+        host::poke(REGS.intflags(), rtc::intflags::CMP.mask);
+        assert!(counter.is_pending(Event::Compare));
+
+        counter.listen(Event::Compare, call);
+        assert_eq!(host::peek(REGS.intctrl()), 0x02);
+        host::poke(REGS.intflags(), 0x03);
+        count_event();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(host::writes_to(REGS.intflags()).last(), Some(&0x02));
+        counter.unlisten(Event::Compare);
+        counter.stop();
+        assert_eq!(host::peek(REGS.ctrla()), 0xA8);
+    }
+
+    /// PITSTATUS.CTRLBUSY for two reads after each PITCTRLA write, logs
+    /// both.
+    #[derive(Clone, Default)]
+    struct PitSync(Rc<RefCell<PitLog>>);
+
+    #[derive(Default)]
+    struct PitLog {
+        busy: u8,
+        /// (written, value) in order
+        log: Vec<(bool, u8)>,
+    }
+
+    impl host::Model for PitSync {
+        fn claims(&self, addr: u16) -> bool {
+            addr == REGS.pitstatus().addr() || addr == REGS.pitctrla().addr()
+        }
+
+        fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8 {
+            if addr != REGS.pitstatus().addr() {
+                return mem[addr as usize];
+            }
+            let mut p = self.0.borrow_mut();
+            let v = (p.busy > 0) as u8;
+            p.busy = p.busy.saturating_sub(1);
+            p.log.push((false, v));
+            v
+        }
+
+        fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]) {
+            mem[addr as usize] = v;
+            if addr == REGS.pitctrla().addr() {
+                let mut p = self.0.borrow_mut();
+                p.busy = 2;
+                p.log.push((true, v));
+            }
+        }
+    }
+
+    #[test]
+    fn pit_start_waits_for_sync() {
+        host::reset();
+        let (_, mut pit) = RTC::new().setup(RTCConfig::new());
+        let sync = PitSync::default();
+        host::attach(sync.clone());
+        pit.start(PitPeriod::Cyc256);
+        // the enabling write only once the 0 is through
+        assert_eq!(
+            sync.0.borrow().log,
+            [
+                (false, 0),
+                (true, 0),
+                (false, 1),
+                (false, 1),
+                (false, 0),
+                (true, 0x39)
+            ]
+        );
+    }
+
+    #[test]
+    fn external_clock_on_tosc1() {
+        host::reset();
+        let hz = NonZeroU32::new(32_000).unwrap();
+        let config = RTCConfig::new().source(ClockSource::External(hz));
+        let (counter, _) = RTC::new().setup(config);
+        assert_eq!(host::peek(CLKCTRL.xosc32kctrla()), 0x07);
+        assert_eq!(host::peek(REGS.clksel()), 0x02);
+        assert_eq!(counter.frequency(), 32_000);
+    }
+
+    #[test]
+    fn pit_counts_seconds() {
+        host::reset();
+        let (_, mut pit) = RTC::new().setup(RTCConfig::new());
+        assert_eq!(host::peek(REGS.clksel()), 0x01);
+        pit.start(PitPeriod::Cyc256);
+        assert_eq!(host::peek(REGS.pitctrla()), 0x39);
+        assert_eq!(host::peek(REGS.pitintctrl()), 0x01);
+        // a quarter second each
+        for _ in 0..(4 * 3725 + 3) {
+            periodic();
+        }
+        assert_eq!(pit.ticks(), 4 * 3725 + 3);
+        assert_eq!(pit.seconds(), 3725);
+        assert_eq!(
+            pit.time_of_day(),
+            TimeOfDay {
+                days: 0,
+                hours: 1,
+                minutes: 2,
+                seconds: 5
+            }
+        );
+        pit.stop();
+        assert_eq!(host::peek(REGS.pitctrla()), 0x00);
+        assert_eq!(pit.seconds(), 0);
+
+        let t = TimeOfDay::from_secs(93_784);
+        assert_eq!(t.as_secs(), 93_784);
+        assert_eq!(
+            t,
+            TimeOfDay {
+                days: 1,
+                hours: 2,
+                minutes: 3,
+                seconds: 4
+            }
+        );
+    }
+}
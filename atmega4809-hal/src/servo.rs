@@ -0,0 +1,382 @@
+//! Hobby servos and ESCs on TCA0.
+//!
+//! [`Servos`] runs TCA0 single-slope at a 50 Hz frame and turns pulse widths
+//! into compare values with the real timer clock, so the same angle comes
+//! out whatever the CPU runs at. Normal mode has three outputs (WO0..WO2) at
+//! full resolution. Split mode has six, but getting eight bits down to 50 Hz
+//! takes the /1024 divider and a CLK_PER at or below about 13 MHz. A step is
+//! then 1024 CPU clocks: about 300 µs at 3.3 MHz, three steps over the usual
+//! 1000..2000 µs, and still 80 µs at 13 MHz. That's too coarse to position a
+//! servo, split mode is for on/off style actuators.
+//!
+//! The pins still have to be outputs on the port picked with
+//! [`TCA::set_port`].
+//!
+//! ```ignore
+//! tca.set_port(PWMPort::PORTB);
+//! let _pin = pins.pb1.into_output();
+//! let mut servos = Servos::new(tca, &clocks).unwrap();
+//! servos.enable(Channel::Cmp1);
+//! servos.set_angle(Channel::Cmp1, 90);
+//! ```
+
+use crate::clock::Clocks;
+use crate::tca::{Channel, Half, Single, Split, TCAError, WaveformGenerationMode, TCA};
+use embedded_hal::delay::blocking::DelayUs;
+
+/// Frame rate every servo understands.
+pub const FRAME_HZ: u32 = 50;
+
+/// How long an ESC wants to see zero throttle before it arms.
+pub const ESC_ARM_MS: u32 = 3000;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeError {
+    /// `max_us` is below `min_us`.
+    Reversed,
+    /// A `max_angle` of 0 has no angles to map.
+    NoAngle,
+}
+
+/// Pulse widths of one output, defaults to the common 1000..2000 µs over
+/// 180°.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PulseRange {
+    /// Pulse at 0° or zero throttle, in µs
+    min_us: u16,
+    /// Pulse at `max_angle` or full throttle, in µs
+    max_us: u16,
+    max_angle: u16,
+}
+
+impl PulseRange {
+    pub const fn new(min_us: u16, max_us: u16, max_angle: u16) -> Result<Self, RangeError> {
+        if max_us < min_us {
+            return Err(RangeError::Reversed);
+        }
+        if max_angle == 0 {
+            return Err(RangeError::NoAngle);
+        }
+        Ok(Self {
+            min_us,
+            max_us,
+            max_angle,
+        })
+    }
+
+    pub const fn min_us(&self) -> u16 {
+        self.min_us
+    }
+
+    pub const fn max_us(&self) -> u16 {
+        self.max_us
+    }
+
+    pub const fn max_angle(&self) -> u16 {
+        self.max_angle
+    }
+
+    /// Pulse for `angle`, clamped to the range.
+    pub fn angle_us(&self, angle: u16) -> u16 {
+        let angle = angle.min(self.max_angle) as u32;
+        let span = (self.max_us - self.min_us) as u32;
+        self.min_us + (span * angle / self.max_angle as u32) as u16
+    }
+
+    /// Pulse for `per_mille` of full throttle, clamped to the range.
+    pub fn throttle_us(&self, per_mille: u16) -> u16 {
+        let per_mille = per_mille.min(1000) as u32;
+        let span = (self.max_us - self.min_us) as u32;
+        self.min_us + (span * per_mille / 1000) as u16
+    }
+}
+
+impl Default for PulseRange {
+    fn default() -> Self {
+        Self {
+            min_us: 1000,
+            max_us: 2000,
+            max_angle: 180,
+        }
+    }
+}
+
+/// What [`Servos`] needs from TCA0 in either mode.
+pub trait ServoTimer {
+    /// Picks one output: a [`Channel`] in normal mode, a [`Half`] and
+    /// [`Channel`] in split mode.
+    type Output: Copy;
+
+    /// Largest compare value.
+    const MAX: u16;
+
+    /// Sets up a 50 Hz frame and starts the timer, returns the timer clock.
+    fn start(&mut self, clocks: &Clocks) -> Result<u32, TCAError>;
+    fn stop(&mut self);
+    fn set_ticks(&mut self, out: Self::Output, ticks: u16);
+    /// Like `set_ticks`, but already in effect for the frame in progress.
+    fn set_ticks_now(&mut self, out: Self::Output, ticks: u16);
+    fn enable_output(&mut self, out: Self::Output);
+    fn disable_output(&mut self, out: Self::Output);
+}
+
+impl ServoTimer for TCA<Single> {
+    type Output = Channel;
+    const MAX: u16 = 0xFFFF;
+
+    fn start(&mut self, clocks: &Clocks) -> Result<u32, TCAError> {
+        self.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+        self.set_frequency(FRAME_HZ, clocks)?;
+        Ok(start(self, clocks))
+    }
+
+    fn stop(&mut self) {
+        self.disable();
+    }
+
+    /// Buffered, a pulse never gets cut short.
+    fn set_ticks(&mut self, ch: Channel, ticks: u16) {
+        self.set_compare_buffered(ch, ticks);
+    }
+
+    /// CMPn for this frame, CMPnBUF so the next update doesn't take it back.
+    fn set_ticks_now(&mut self, ch: Channel, ticks: u16) {
+        self.set_compare(ch, ticks);
+        self.set_compare_buffered(ch, ticks);
+    }
+
+    fn enable_output(&mut self, ch: Channel) {
+        TCA::<Single>::enable_output(self, ch);
+    }
+
+    fn disable_output(&mut self, ch: Channel) {
+        TCA::<Single>::disable_output(self, ch);
+    }
+}
+
+impl ServoTimer for TCA<Split> {
+    type Output = (Half, Channel);
+    const MAX: u16 = 0xFF;
+
+    fn start(&mut self, clocks: &Clocks) -> Result<u32, TCAError> {
+        self.set_frequency(FRAME_HZ, clocks)?;
+        Ok(start(self, clocks))
+    }
+
+    fn stop(&mut self) {
+        self.disable();
+    }
+
+    fn set_ticks(&mut self, (half, ch): (Half, Channel), ticks: u16) {
+        self.set_compare(half, ch, ticks as u8);
+    }
+
+    fn set_ticks_now(&mut self, out: (Half, Channel), ticks: u16) {
+        self.set_ticks(out, ticks);
+    }
+
+    fn enable_output(&mut self, (half, ch): (Half, Channel)) {
+        TCA::<Split>::enable_output(self, half, ch);
+    }
+
+    fn disable_output(&mut self, (half, ch): (Half, Channel)) {
+        TCA::<Split>::disable_output(self, half, ch);
+    }
+}
+
+fn start<MODE>(tca: &mut TCA<MODE>, clocks: &Clocks) -> u32 {
+    let divider = tca.divider();
+    tca.enable(divider);
+    clocks.f_per() / divider.divisor() as u32
+}
+
+/// Up to three (six in split mode) servo or ESC outputs, see the
+/// [module docs](self).
+pub struct Servos<T: ServoTimer> {
+    tca: T,
+    /// Timer clocks per second
+    f_tick: u32,
+    range: PulseRange,
+}
+
+impl<T: ServoTimer> Servos<T> {
+    /// Sets up a 50 Hz frame and starts the timer, all outputs stay off
+    /// until enabled.
+    pub fn new(mut tca: T, clocks: &Clocks) -> Result<Self, TCAError> {
+        let f_tick = tca.start(clocks)?;
+        Ok(Self {
+            tca,
+            f_tick,
+            range: PulseRange::default(),
+        })
+    }
+
+    /// Pulse widths [`set_angle`](Self::set_angle) and
+    /// [`set_throttle`](Self::set_throttle) map to.
+    pub fn set_range(&mut self, range: PulseRange) {
+        self.range = range;
+    }
+
+    pub fn range(&self) -> PulseRange {
+        self.range
+    }
+
+    /// Starts sending pulses on `out`. Set a position first, or the first
+    /// frames carry whatever compare value was left.
+    pub fn enable(&mut self, out: T::Output) {
+        self.tca.enable_output(out);
+    }
+
+    /// Stops the pulses on `out`, most servos go limp.
+    pub fn disable(&mut self, out: T::Output) {
+        self.tca.disable_output(out);
+    }
+
+    /// Sends pulses of `us` microseconds, as close as the timer clock gets.
+    pub fn set_pulse_us(&mut self, out: T::Output, us: u16) {
+        let ticks = self.ticks(us);
+        self.tca.set_ticks(out, ticks);
+    }
+
+    /// Moves to `angle` degrees in the [`PulseRange`].
+    pub fn set_angle(&mut self, out: T::Output, angle: u16) {
+        let us = self.range.angle_us(angle);
+        self.set_pulse_us(out, us);
+    }
+
+    /// Sets an ESC to `per_mille` of full throttle in the [`PulseRange`].
+    pub fn set_throttle(&mut self, out: T::Output, per_mille: u16) {
+        let us = self.range.throttle_us(per_mille);
+        self.set_pulse_us(out, us);
+    }
+
+    /// Arms an ESC: zero throttle goes out from the first pulse on and is
+    /// held for [`ESC_ARM_MS`]. Afterwards the ESC follows
+    /// [`set_throttle`](Self::set_throttle).
+    pub fn arm<D: DelayUs>(&mut self, out: T::Output, delay: &mut D) {
+        // not buffered, the frame in progress already has it
+        let ticks = self.ticks(self.range.throttle_us(0));
+        self.tca.set_ticks_now(out, ticks);
+        self.tca.enable_output(out);
+        let _ = delay.delay_ms(ESC_ARM_MS);
+    }
+
+    /// Timer clocks for `us`, rounded and clamped to the compare value.
+    fn ticks(&self, us: u16) -> u16 {
+        let ticks = (us as u64 * self.f_tick as u64 + 500_000) / 1_000_000;
+        ticks.min(T::MAX as u64) as u16
+    }
+
+    /// Stops the timer and hands it back.
+    pub fn release(mut self) -> T {
+        self.tca.stop();
+        self.tca
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use crate::regs::TCA0;
+    use crate::tca::ClockDivider;
+    use crate::Delay;
+
+    const CLOCKS: Clocks = Clocks::from_hz(20_000_000);
+
+    #[test]
+    fn fifty_hz_frame() {
+        host::reset();
+        let mut servos = Servos::new(TCA::<Single>::new(), &CLOCKS).unwrap();
+        // 2.5 MHz timer clock, 50_000 a frame
+        assert_eq!(TCA0.single().per().read(), 49_999);
+        assert_eq!(host::peek(TCA0.single().ctrla()), 0x07);
+
+        servos.set_pulse_us(Channel::Cmp0, 1500);
+        assert_eq!(TCA0.single().cmp0buf().read(), 3750);
+        servos.set_angle(Channel::Cmp1, 0);
+        assert_eq!(TCA0.single().cmp1buf().read(), 2500);
+        servos.set_angle(Channel::Cmp1, 45);
+        assert_eq!(TCA0.single().cmp1buf().read(), 3125);
+        servos.set_angle(Channel::Cmp1, 500);
+        assert_eq!(TCA0.single().cmp1buf().read(), 5000);
+
+        servos.enable(Channel::Cmp1);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x23);
+        servos.disable(Channel::Cmp1);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x03);
+
+        let tca = servos.release();
+        assert!(!tca.is_enabled());
+    }
+
+    #[test]
+    fn prescaled_clock_same_pulse() {
+        host::reset();
+        let clocks = Clocks::from_hz(20_000_000 / 6);
+        let mut servos = Servos::new(TCA::<Single>::new(), &clocks).unwrap();
+        servos.set_range(PulseRange::new(500, 2500, 270).unwrap());
+        servos.set_angle(Channel::Cmp2, 135);
+        // 3.33 MHz, /2 fits the frame
+        assert_eq!(TCA::<Single>::new().divider(), ClockDivider::D2);
+        assert_eq!(TCA0.single().cmp2buf().read(), 2500);
+    }
+
+    #[test]
+    fn pulse_range_checks() {
+        assert_eq!(PulseRange::new(2000, 1000, 180), Err(RangeError::Reversed));
+        assert_eq!(PulseRange::new(1000, 2000, 0), Err(RangeError::NoAngle));
+        assert_eq!(PulseRange::new(1000, 2000, 180), Ok(PulseRange::default()));
+        // a fixed pulse is fine
+        let range = PulseRange::new(1500, 1500, 90).unwrap();
+        assert_eq!(range.angle_us(45), 1500);
+    }
+
+    #[test]
+    fn six_outputs_in_split_mode() {
+        host::reset();
+        let split = TCA::<Single>::new().into_split();
+        assert_eq!(
+            Servos::new(split, &CLOCKS).err(),
+            Some(TCAError::FrequencyTooLow)
+        );
+
+        host::reset();
+        let split = TCA::<Single>::new().into_split();
+        let mut servos = Servos::new(split, &Clocks::from_hz(20_000_000 / 6)).unwrap();
+        servos.set_pulse_us((Half::High, Channel::Cmp2), 2000);
+        // 1024 divider, 3255 Hz
+        assert_eq!(host::peek(TCA0.split().hcmp2()), 7);
+        servos.enable((Half::High, Channel::Cmp2));
+        assert_eq!(host::peek(TCA0.split().ctrlb()), 0x40);
+    }
+
+    #[test]
+    fn esc_arms_at_zero_throttle() {
+        host::reset();
+        let mut servos = Servos::new(TCA::<Single>::new(), &CLOCKS).unwrap();
+        // something else left from before
+        let cmp0 = TCA0.single().cmp0();
+        cmp0.write(4000);
+        let before = host::writes().len();
+        servos.arm(Channel::Cmp0, &mut Delay::new(&CLOCKS));
+        // the frame in progress goes out with zero throttle already
+        let w = &host::writes()[before..];
+        let enabled = w
+            .iter()
+            .position(|&(a, _)| a == TCA0.single().ctrlb().addr())
+            .unwrap();
+        assert!(w[..enabled].contains(&(cmp0.low().addr(), 0xC4)));
+        assert!(w[..enabled].contains(&(cmp0.high().addr(), 0x09)));
+        assert_eq!(cmp0.read(), 2500);
+        assert_eq!(TCA0.single().cmp0buf().read(), 2500);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x13);
+        let waited = host::cycles() / 20_000;
+        assert!(waited.abs_diff(ESC_ARM_MS as u64) <= 1, "{}", waited);
+
+        servos.set_throttle(Channel::Cmp0, 500);
+        assert_eq!(TCA0.single().cmp0buf().read(), 3750);
+        servos.set_throttle(Channel::Cmp0, 1200);
+        assert_eq!(TCA0.single().cmp0buf().read(), 5000);
+    }
+}
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::I2cDevice;
+
+const CALIB00: u8 = 0x88;
+const CALIB26: u8 = 0xE1;
+const ID: u8 = 0xD0;
+const RESET: u8 = 0xE0;
+const CTRL_HUM: u8 = 0xF2;
+const CTRL_MEAS: u8 = 0xF4;
+const CONFIG: u8 = 0xF5;
+const PRESS_MSB: u8 = 0xF7;
+
+/// Calibration of the compensation example in the datasheet, the humidity
+/// part taken from a real sensor.
+const CALIB_TP: [u8; 26] = [
+    0x70, 0x6B, // T1 27504
+    0x43, 0x67, // T2 26435
+    0x18, 0xFC, // T3 -1000
+    0x7D, 0x8E, // P1 36477
+    0x43, 0xD6, // P2 -10685
+    0xD0, 0x0B, // P3 3024
+    0x27, 0x0B, // P4 2855
+    0x8C, 0x00, // P5 140
+    0xF9, 0xFF, // P6 -7
+    0x8C, 0x3C, // P7 15500
+    0xF8, 0xC6, // P8 -14600
+    0x70, 0x17, // P9 6000
+    0x00, // reserved
+    75,   // H1
+];
+const CALIB_H: [u8; 7] = [
+    0x72, 0x01, // H2 370
+    0,    // H3
+    0x13, // H4 313 = 0x139, bits 11:4
+    0x29, // H4 bits 3:0, H5 bits 3:0
+    0x03, // H5 50 = 0x032, bits 11:4
+    30,   // H6
+];
+
+/// A fake BME280 environment sensor.
+///
+/// Chip id 0x60, soft reset, the register pointer auto increments on reads and
+/// writes are register/value pairs like on the real part. Writing a forced
+/// (or normal) mode to `ctrl_meas` finishes a measurement at once, channels
+/// with oversampling off read back as the datasheet's skipped values
+/// (0x80000, 0x8000). The default raw values come out as 25.08 °C, 1006.53 hPa
+/// and 56.2 %RH with the built in calibration.
+#[derive(Clone)]
+pub struct Bme280(Rc<RefCell<Chip>>);
+
+struct Chip {
+    address: u8,
+    regs: [u8; 0x100],
+    ptr: u8,
+    /// Next written byte is a register address rather than data
+    addressing: bool,
+    raw: (u32, u32, u16),
+}
+
+impl Bme280 {
+    pub const PRIMARY: u8 = 0x76;
+    pub const SECONDARY: u8 = 0x77;
+
+    pub fn new(address: u8) -> Self {
+        let mut chip = Chip {
+            address,
+            regs: [0; 0x100],
+            ptr: 0,
+            addressing: false,
+            raw: (519888, 415148, 30000),
+        };
+        chip.reset();
+        Self(Rc::new(RefCell::new(chip)))
+    }
+
+    /// Uncompensated temperature, pressure (20 bits) and humidity (16 bits)
+    /// returned by the next measurements.
+    pub fn set_raw(&self, temperature: u32, pressure: u32, humidity: u16) {
+        self.0.borrow_mut().raw = (temperature, pressure, humidity);
+    }
+
+    pub fn reg(&self, addr: u8) -> u8 {
+        self.0.borrow().regs[addr as usize]
+    }
+}
+
+impl Chip {
+    fn reset(&mut self) {
+        self.regs = [0; 0x100];
+        self.regs[ID as usize] = 0x60;
+        let tp = CALIB00 as usize;
+        self.regs[tp..tp + CALIB_TP.len()].copy_from_slice(&CALIB_TP);
+        let h = CALIB26 as usize;
+        self.regs[h..h + CALIB_H.len()].copy_from_slice(&CALIB_H);
+        self.latch(0x8_0000, 0x8_0000, 0x8000);
+    }
+
+    fn latch(&mut self, t: u32, p: u32, h: u16) {
+        let p20 = [(p >> 12) as u8, (p >> 4) as u8, (p << 4) as u8];
+        let t20 = [(t >> 12) as u8, (t >> 4) as u8, (t << 4) as u8];
+        let d = PRESS_MSB as usize;
+        self.regs[d..d + 3].copy_from_slice(&p20);
+        self.regs[d + 3..d + 6].copy_from_slice(&t20);
+        self.regs[d + 6..d + 8].copy_from_slice(&h.to_be_bytes());
+    }
+
+    fn measure(&mut self) {
+        let meas = self.regs[CTRL_MEAS as usize];
+        let (t, p, h) = self.raw;
+        let t = if meas >> 5 == 0 { 0x8_0000 } else { t };
+        let p = if (meas >> 2) & 0x7 == 0 { 0x8_0000 } else { p };
+        let h = if self.regs[CTRL_HUM as usize] & 0x7 == 0 {
+            0x8000
+        } else {
+            h
+        };
+        self.latch(t, p, h);
+        // forced mode goes back to sleep when done
+        if meas & 0x3 != 0x3 {
+            self.regs[CTRL_MEAS as usize] &= !0x3;
+        }
+    }
+
+    fn store(&mut self, addr: u8, v: u8) {
+        match addr {
+            RESET if v == 0xB6 => self.reset(),
+            CTRL_HUM | CONFIG => self.regs[addr as usize] = v,
+            CTRL_MEAS => {
+                self.regs[addr as usize] = v;
+                if v & 0x3 != 0 {
+                    self.measure();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl I2cDevice for Bme280 {
+    fn address(&self) -> u8 {
+        self.0.borrow().address
+    }
+
+    fn start(&mut self, read: bool) -> bool {
+        let mut c = self.0.borrow_mut();
+        c.addressing = !read;
+        // normal mode keeps converting, hand out a fresh one per burst read
+        if read && c.ptr == PRESS_MSB && c.regs[CTRL_MEAS as usize] & 0x3 == 0x3 {
+            c.measure();
+        }
+        true
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        let mut c = self.0.borrow_mut();
+        if c.addressing {
+            c.ptr = byte;
+        } else {
+            let ptr = c.ptr;
+            c.store(ptr, byte);
+        }
+        c.addressing = !c.addressing;
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let mut c = self.0.borrow_mut();
+        let v = c.regs[c.ptr as usize];
+        c.ptr = c.ptr.wrapping_add(1);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clocks;
+    use crate::delay::Delay;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::i2c::I2C;
+    use crate::regs::TWI0;
+    use crate::sim::Twi;
+    use bme280::i2c::BME280;
+    use embedded_hal::i2c::blocking::I2c;
+
+    const ADDR: u8 = Bme280::SECONDARY;
+
+    fn setup() -> Bme280 {
+        host::reset();
+        let bus = Twi::attach(TWI0);
+        let bme = Bme280::new(ADDR);
+        bus.add(bme.clone());
+        let pins = Pins::new();
+        I2C::new().setup(pins.pa2, pins.pa3, 100_000, &Clocks::from_hz(20_000_000));
+        bme
+    }
+
+    /// The `testing::test_bme` flow through the `bme280` crate: `init`
+    /// then one forced `measure`.
+    #[test]
+    fn init_and_measure() {
+        let bme = setup();
+        let mut delay = Delay::new(&Clocks::from_hz(20_000_000));
+
+        let mut sensor = BME280::new_secondary(I2C::new());
+        sensor.init(&mut delay).unwrap();
+        let m = sensor.measure(&mut delay).unwrap();
+
+        assert!((m.temperature - 25.08).abs() < 0.01);
+        assert!((m.pressure - 100653.25).abs() < 1.0);
+        assert!((m.humidity - 56.2).abs() < 0.1);
+        assert_eq!(
+            bme.reg(CTRL_MEAS) & 0x3,
+            0,
+            "back to sleep after a forced measurement"
+        );
+    }
+
+    #[test]
+    fn skipped_channels_read_invalid() {
+        let bme = setup();
+        bme.set_raw(1, 2, 3);
+
+        // temperature only
+        I2C::new().write(ADDR, &[CTRL_MEAS, 0b0010_0001]).unwrap();
+        let mut data = [0u8; 8];
+        I2C::new()
+            .write_read(ADDR, &[PRESS_MSB], &mut data)
+            .unwrap();
+        assert_eq!(data, [0x80, 0, 0, 0, 0, 0x10, 0x80, 0]);
+
+        I2C::new().write(ADDR, &[RESET, 0xB6]).unwrap();
+        assert_eq!(bme.reg(CTRL_MEAS), 0);
+        assert_eq!(bme.reg(PRESS_MSB + 3), 0x80);
+    }
+
+    #[test]
+    fn primary_address_does_not_answer_secondary() {
+        let _bme = setup();
+        assert!(I2C::new().write(Bme280::PRIMARY, &[ID]).is_err());
+    }
+}
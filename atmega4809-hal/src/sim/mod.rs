@@ -0,0 +1,48 @@
+//! Behavioural models of the serial peripherals, for running drivers on a dev
+//! box.
+//!
+//! The plain [`host`](crate::host) backend only stores bytes, so a driver that
+//! polls a flag spins forever unless the test scripts every read. The models
+//! here react to register accesses like the silicon does: a [`Usart`] raises
+//! DREIF/TXCIF/RXCIF as bytes move, [`Twi`] walks the master through its bus
+//! states and talks to [`I2cDevice`]s, [`Spi`] shifts bytes through a
+//! [`SpiDevice`].
+//!
+//! There is no clock. Time only passes when the driver polls a status register
+//! (or a device's data ready pin), and every poll finishes whatever byte is on
+//! the wire.
+//!
+//! ```ignore
+//! host::reset();
+//! let bus = sim::Twi::attach(regs::TWI0);
+//! let nau = sim::Nau7802::new();
+//! bus.add(nau.clone());
+//! host::attach(nau.drdy_pin(regs::PORTD, 0));
+//! ```
+//!
+//! Models and devices are cheap handles (`Rc` inside), keep a clone around to
+//! look at them after the driver ran. They run while the host backend is
+//! borrowed, so callbacks must not touch registers themselves.
+
+mod bme280;
+mod nau7802;
+mod spi;
+mod twi;
+mod usart;
+
+pub use self::bme280::Bme280;
+pub use self::nau7802::{DrdyPin, Nau7802};
+pub use self::spi::{Loopback, Spi, SpiDevice};
+pub use self::twi::{I2cDevice, Twi};
+pub use self::usart::Usart;
+
+use crate::regs::Reg8;
+
+/// The testbed's NAU7802 bring-up, run against [`Nau7802`].
+#[cfg(test)]
+#[path = "../../../testbed/src/nau.rs"]
+mod testbed_nau;
+
+fn at(reg: Reg8) -> usize {
+    reg.addr() as usize
+}
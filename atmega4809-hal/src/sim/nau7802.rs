@@ -0,0 +1,328 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::I2cDevice;
+use crate::host::Model;
+use crate::regs::port;
+
+const PU_CTRL: u8 = 0x00;
+const CTRL2: u8 = 0x02;
+const ADCO_B2: u8 = 0x12;
+const ADCO_B0: u8 = 0x14;
+const REVISION_ID: u8 = 0x1F;
+
+// PU_CTRL
+const RR: u8 = 1 << 0;
+const PUD: u8 = 1 << 1;
+const PUA: u8 = 1 << 2;
+const PUR: u8 = 1 << 3;
+const CR: u8 = 1 << 5;
+
+// CTRL2
+const CALS: u8 = 1 << 2;
+const CAL_ERR: u8 = 1 << 3;
+
+/// Polls of CTRL2 before an AFE calibration finishes.
+const CAL_POLLS: u8 = 3;
+
+/// A fake NAU7802 load cell ADC at address 0x2A.
+///
+/// Register pointer plus auto increment like the real part. Power up is
+/// instant once PUD is set, conversions run while PUD and PUA are set and a new
+/// one is ready each time PU_CTRL (or the DRDY pin) is polled. Reading ADCO_B0
+/// clears CR. Samples come from [`Nau7802::push_samples`], the last one repeats.
+#[derive(Clone)]
+pub struct Nau7802(Rc<RefCell<Chip>>);
+
+struct Chip {
+    regs: [u8; 0x20],
+    ptr: u8,
+    /// Next written byte sets the register pointer
+    addressing: bool,
+    samples: VecDeque<i32>,
+    last: i32,
+    cal_polls: u8,
+    cal_fails: bool,
+}
+
+impl Nau7802 {
+    pub const ADDRESS: u8 = 0x2A;
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mut chip = Chip {
+            regs: [0; 0x20],
+            ptr: 0,
+            addressing: false,
+            samples: VecDeque::new(),
+            last: 0,
+            cal_polls: 0,
+            cal_fails: false,
+        };
+        chip.reset();
+        Self(Rc::new(RefCell::new(chip)))
+    }
+
+    /// Queues conversion results, in order.
+    pub fn push_samples(&self, samples: &[i32]) {
+        self.0.borrow_mut().samples.extend(samples);
+    }
+
+    /// Makes the next AFE calibrations end with CAL_ERR.
+    pub fn fail_calibration(&self) {
+        self.0.borrow_mut().cal_fails = true;
+    }
+
+    pub fn reg(&self, addr: u8) -> u8 {
+        self.0.borrow().regs[addr as usize]
+    }
+
+    /// A model of the DRDY output wired to `pin` of `port`, high while a
+    /// conversion is ready. Hand it to [`crate::host::attach`].
+    pub fn drdy_pin(&self, port: port::RegisterBlock, pin: u8) -> DrdyPin {
+        DrdyPin {
+            chip: self.clone(),
+            port,
+            mask: 1 << pin,
+        }
+    }
+}
+
+impl Chip {
+    fn reset(&mut self) {
+        self.regs = [0; 0x20];
+        self.regs[REVISION_ID as usize] = 0x0F;
+    }
+
+    fn convert(&mut self) {
+        let pu = self.regs[PU_CTRL as usize];
+        if pu & (PUD | PUA) != PUD | PUA || pu & CR > 0 {
+            return;
+        }
+        if let Some(s) = self.samples.pop_front() {
+            self.last = s;
+        }
+        let [_, b2, b1, b0] = self.last.to_be_bytes();
+        self.regs[ADCO_B2 as usize..=ADCO_B0 as usize].copy_from_slice(&[b2, b1, b0]);
+        self.regs[PU_CTRL as usize] |= CR;
+    }
+
+    fn store(&mut self, addr: u8, v: u8) {
+        match addr {
+            PU_CTRL => {
+                if v & RR > 0 {
+                    self.reset();
+                }
+                let status = self.regs[PU_CTRL as usize] & CR;
+                let ready = if v & PUD > 0 { PUR } else { 0 };
+                self.regs[PU_CTRL as usize] = (v & !(PUR | CR)) | ready | status;
+            }
+            CTRL2 => {
+                if v & CALS > 0 {
+                    self.cal_polls = CAL_POLLS;
+                }
+                self.regs[CTRL2 as usize] = v & !CAL_ERR;
+            }
+            ADCO_B2..=ADCO_B0 | REVISION_ID => {}
+            _ => self.regs[addr as usize] = v,
+        }
+    }
+
+    fn load(&mut self, addr: u8) -> u8 {
+        match addr {
+            PU_CTRL => self.convert(),
+            CTRL2 if self.cal_polls > 0 => {
+                self.cal_polls -= 1;
+                if self.cal_polls == 0 {
+                    let err = if self.cal_fails { CAL_ERR } else { 0 };
+                    self.regs[CTRL2 as usize] = (self.regs[CTRL2 as usize] & !CALS) | err;
+                }
+            }
+            _ => {}
+        }
+        let v = self.regs[addr as usize];
+        if addr == ADCO_B0 {
+            self.regs[PU_CTRL as usize] &= !CR;
+        }
+        v
+    }
+}
+
+impl I2cDevice for Nau7802 {
+    fn address(&self) -> u8 {
+        Self::ADDRESS
+    }
+
+    fn start(&mut self, read: bool) -> bool {
+        self.0.borrow_mut().addressing = !read;
+        true
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        let mut c = self.0.borrow_mut();
+        if c.addressing {
+            c.addressing = false;
+            c.ptr = byte & 0x1F;
+        } else {
+            let ptr = c.ptr;
+            c.store(ptr, byte);
+            c.ptr = (ptr + 1) & 0x1F;
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let mut c = self.0.borrow_mut();
+        let ptr = c.ptr;
+        c.ptr = (ptr + 1) & 0x1F;
+        c.load(ptr)
+    }
+}
+
+/// The NAU7802 DRDY line as seen through a port's IN register.
+pub struct DrdyPin {
+    chip: Nau7802,
+    port: port::RegisterBlock,
+    mask: u8,
+}
+
+impl Model for DrdyPin {
+    fn claims(&self, addr: u16) -> bool {
+        addr == self.port.r#in().addr()
+    }
+
+    fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8 {
+        let mut c = self.chip.0.borrow_mut();
+        c.convert();
+        let pin = &mut mem[addr as usize];
+        if c.regs[PU_CTRL as usize] & CR > 0 {
+            *pin |= self.mask;
+        } else {
+            *pin &= !self.mask;
+        }
+        *pin
+    }
+
+    fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]) {
+        mem[addr as usize] = v;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clocks;
+    use crate::delay::Delay;
+    use crate::gpio::{Pin, Pins, PD0};
+    use crate::host;
+    use crate::i2c::{I2CError, I2C};
+    use crate::regs::{PORTD, TWI0, USART3 as USART3_REGS};
+    use crate::sim::testbed_nau::{nau_begin, FatalStartupError};
+    use crate::sim::{Twi, Usart};
+    use crate::usart::{CharacterSize, CommunicationMode, ParityMode, StopBitMode, USART, USART3};
+    use embedded_hal::i2c::blocking::I2c;
+
+    /// The testbed's `Stdout`
+    type Stdout = USART<USART3, true>;
+
+    fn analog_drdy() -> PD0 {
+        unsafe { Pin::steal() }
+    }
+
+    fn get(reg: u8) -> u8 {
+        let mut v = [0];
+        I2C::new()
+            .write_read(Nau7802::ADDRESS, &[reg], &mut v)
+            .unwrap();
+        v[0]
+    }
+
+    fn clocks() -> Clocks {
+        Clocks::from_hz(20_000_000)
+    }
+
+    /// The I2C bus with nothing on it, and the testbed's stdout.
+    fn board() -> (Twi, Usart, Stdout) {
+        host::reset();
+        let bus = Twi::attach(TWI0);
+        let line = Usart::attach(USART3_REGS);
+        let pins = Pins::new();
+        I2C::new().setup(pins.pa2, pins.pa3, 100_000, &clocks());
+        let stdout = USART::<USART3, false>::new()
+            .setup(
+                pins.pb4,
+                pins.pb5,
+                57600,
+                CommunicationMode::Asynchronous,
+                ParityMode::Disabled,
+                StopBitMode::One,
+                CharacterSize::B8,
+                &clocks(),
+            )
+            .unwrap();
+        (bus, line, stdout)
+    }
+
+    fn setup() -> (Nau7802, Usart, Stdout) {
+        let (bus, line, stdout) = board();
+        let nau = Nau7802::new();
+        bus.add(nau.clone());
+        host::attach(nau.drdy_pin(PORTD, 0));
+        (nau, line, stdout)
+    }
+
+    /// `testbed::process::nau_setup` through the `nau7802` crate, then the
+    /// samples it reads once ANALOG_DRDY is up.
+    #[test]
+    fn nau_setup() {
+        let (nau, line, mut stdout) = setup();
+        nau.push_samples(&[100, -200, 0x7F_FFFF, -0x80_0000]);
+
+        let mut adc = nau_begin(I2C::new(), &mut stdout, &mut Delay::new(&clocks())).unwrap();
+        let log = line.tx();
+        assert!(log.starts_with(b"Calibrating."));
+        assert!(log.ends_with(b"success\r\n"));
+        // LDO 3.9V, gain 128, 80SPS
+        assert_eq!(nau.reg(0x01), 0b0001_0111);
+        assert_eq!(nau.reg(CTRL2) & 0b0111_0000, 0b0011_0000);
+        assert_eq!(nau.reg(CTRL2) & (CALS | CAL_ERR), 0);
+
+        for sample in [100, -200, 0x7F_FFFF, -0x80_0000, -0x80_0000] {
+            assert!(analog_drdy().is_high());
+            assert_eq!(nb::block!(adc.read()).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn nau_setup_calibration_failure() {
+        let (nau, line, mut stdout) = setup();
+        nau.fail_calibration();
+        assert!(matches!(
+            nau_begin(I2C::new(), &mut stdout, &mut Delay::new(&clocks())),
+            Err(FatalStartupError::CalibrationFailure("nau"))
+        ));
+        assert!(line.tx().ends_with(b"rip\r\n"));
+    }
+
+    #[test]
+    fn nau_setup_without_sensor() {
+        let (_bus, _line, mut stdout) = board();
+        assert!(matches!(
+            I2C::new().write(Nau7802::ADDRESS, &[PU_CTRL, RR]),
+            Err(I2CError::NACK)
+        ));
+        assert!(matches!(
+            nau_begin(I2C::new(), &mut stdout, &mut Delay::new(&clocks())),
+            Err(FatalStartupError::NoSensor)
+        ));
+    }
+
+    #[test]
+    fn no_sample_while_powered_down() {
+        let _ = setup();
+        assert_eq!(get(REVISION_ID), 0x0F);
+        assert!(analog_drdy().is_low());
+        assert_eq!(get(PU_CTRL) & (PUR | CR), 0);
+    }
+}
@@ -0,0 +1,200 @@
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use super::at;
+use crate::host::{self, Model};
+use crate::regs::spi::{self, RegisterBlock};
+
+/// Whatever sits on the other end of MOSI/MISO.
+pub trait SpiDevice {
+    /// Clocks one byte in and returns the byte shifted out at the same time.
+    fn transfer(&mut self, mosi: u8) -> u8;
+}
+
+/// MISO wired to MOSI.
+pub struct Loopback;
+
+impl SpiDevice for Loopback {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        mosi
+    }
+}
+
+/// A simulated SPI master in buffer mode (`CTRLB.BUFEN`), which is the only
+/// mode [`crate::spi::SPI`] uses.
+///
+/// One byte of transmit buffer in front of the shift register and two bytes of
+/// receive buffer. A byte received while the receive buffer is full is lost
+/// and sets BUFOVF.
+#[derive(Clone)]
+pub struct Spi(Rc<RefCell<Master>>);
+
+struct Master {
+    regs: RegisterBlock,
+    device: Box<dyn SpiDevice>,
+    txdata: Option<u8>,
+    shifting: Option<u8>,
+    rx: VecDeque<u8>,
+    sent: Vec<u8>,
+}
+
+impl Spi {
+    /// Takes over the data and flag registers of `regs`.
+    pub fn attach(regs: RegisterBlock, device: impl SpiDevice + 'static) -> Self {
+        let s = Self(Rc::new(RefCell::new(Master {
+            regs,
+            device: Box::new(device),
+            txdata: None,
+            shifting: None,
+            rx: VecDeque::new(),
+            sent: Vec::new(),
+        })));
+        host::attach(s.clone());
+        s
+    }
+
+    /// Every byte shifted out on MOSI so far.
+    pub fn sent(&self) -> Vec<u8> {
+        self.0.borrow().sent.clone()
+    }
+}
+
+impl Master {
+    fn tick(&mut self, mem: &mut [u8]) {
+        if let Some(b) = self.shifting.take() {
+            self.sent.push(b);
+            let miso = self.device.transfer(b);
+            if self.rx.len() < 2 {
+                self.rx.push_back(miso);
+            } else {
+                mem[at(self.regs.intflags())] |= spi::intflags::BUFOVF.mask;
+            }
+            self.shifting = self.txdata.take();
+            if self.shifting.is_none() {
+                mem[at(self.regs.intflags())] |= spi::intflags::TXCIF.mask;
+            }
+        }
+    }
+
+    fn sync(&self, mem: &mut [u8]) {
+        let f = &mut mem[at(self.regs.intflags())];
+        *f &= !(spi::intflags::RXCIF.mask | spi::intflags::DREIF.mask);
+        if !self.rx.is_empty() {
+            *f |= spi::intflags::RXCIF.mask;
+        }
+        if self.txdata.is_none() {
+            *f |= spi::intflags::DREIF.mask;
+        }
+    }
+}
+
+impl Model for Spi {
+    fn claims(&self, addr: u16) -> bool {
+        let r = self.0.borrow().regs;
+        addr == r.data().addr() || addr == r.intflags().addr()
+    }
+
+    fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8 {
+        let mut m = self.0.borrow_mut();
+        let r = m.regs;
+        if addr == r.intflags().addr() {
+            m.tick(mem);
+        } else if let Some(b) = m.rx.pop_front() {
+            mem[at(r.data())] = b;
+        }
+        m.sync(mem);
+        mem[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]) {
+        let mut m = self.0.borrow_mut();
+        let r = m.regs;
+        if addr == r.intflags().addr() {
+            let w1c =
+                spi::intflags::TXCIF.mask | spi::intflags::SSIF.mask | spi::intflags::BUFOVF.mask;
+            mem[addr as usize] &= !(v & w1c);
+        } else if mem[at(r.ctrla())] & spi::ctrla::ENABLE.mask > 0 && m.txdata.is_none() {
+            if m.shifting.is_none() {
+                m.shifting = Some(v);
+            } else {
+                m.txdata = Some(v);
+            }
+        }
+        m.sync(mem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clocks;
+    use crate::gpio::Pins;
+    use crate::regs::SPI0;
+    use crate::spi::{Polarity, SPI};
+    use embedded_hal::spi::blocking::Transfer;
+
+    /// Answers with the complement of the previous byte.
+    struct Echo(u8);
+
+    impl SpiDevice for Echo {
+        fn transfer(&mut self, mosi: u8) -> u8 {
+            core::mem::replace(&mut self.0, !mosi)
+        }
+    }
+
+    fn setup(sck_hz: u32, wait_for_receive: bool, mode: Polarity) -> SPI {
+        let pins = Pins::new();
+        SPI::new().setup(
+            pins.pa4,
+            pins.pa5,
+            pins.pa6,
+            sck_hz,
+            wait_for_receive,
+            mode,
+            &Clocks::from_hz(20_000_000),
+        )
+    }
+
+    #[test]
+    fn loopback_transfer() {
+        host::reset();
+        let bus = Spi::attach(SPI0, Loopback);
+        let mut spi = setup(5_000_000, true, Polarity::P0);
+
+        let mut read = [0u8; 4];
+        spi.transfer(&mut read, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(read, [1, 2, 3, 4]);
+        assert_eq!(bus.sent(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn transfer_reads_device_replies() {
+        host::reset();
+        let _bus = Spi::attach(SPI0, Echo(0xFF));
+        let mut spi = setup(100_000, true, Polarity::P1);
+
+        let mut read = [0u8; 3];
+        spi.transfer(&mut read, &[0x0F, 0xF0, 0x00]).unwrap();
+        assert_eq!(read, [0xFF, 0xF0, 0x0F]);
+    }
+
+    #[test]
+    fn receive_buffer_overflow() {
+        host::reset();
+        let _bus = Spi::attach(SPI0, Loopback);
+        let _spi = setup(5_000_000, true, Polarity::P0);
+
+        for b in 0..3 {
+            SPI0.data().write(b);
+            SPI0.intflags().read();
+        }
+        assert!(SPI0.intflags().is_set(spi::intflags::BUFOVF));
+        assert_eq!(SPI0.data().read(), 0);
+        assert_eq!(SPI0.data().read(), 1);
+        assert!(!SPI0.intflags().is_set(spi::intflags::RXCIF));
+    }
+}
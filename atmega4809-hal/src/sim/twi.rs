@@ -0,0 +1,301 @@
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use super::at;
+use crate::host::{self, Model};
+use crate::regs::twi::{self, RegisterBlock};
+
+/// A slave on the simulated I2C bus.
+pub trait I2cDevice {
+    /// 7-bit bus address.
+    fn address(&self) -> u8;
+
+    /// Addressed after a (repeated) START. Return `false` to NACK.
+    fn start(&mut self, read: bool) -> bool {
+        let _ = read;
+        true
+    }
+
+    /// A byte from the master. Return `false` to NACK.
+    fn write(&mut self, byte: u8) -> bool;
+
+    /// The next byte clocked out to the master.
+    fn read(&mut self) -> u8;
+
+    fn stop(&mut self) {}
+}
+
+/// The TWI master and whatever is hanging off its bus.
+///
+/// Follows the master cases of the datasheet (25.3.4.2): an address packet
+/// ends in WIF (write, or NACK) or RIF with the first byte already in MDATA
+/// (read), CLKHOLD is set while the master waits on software. A STOP leaves
+/// RIF/WIF alone since the byte in flight completes first.
+///
+/// With a TIMEOUT configured the bus goes IDLE as soon as the master is
+/// enabled, without one it stays UNKNOWN until IDLE is written to BUSSTATE.
+#[derive(Clone)]
+pub struct Twi(Rc<RefCell<Bus>>);
+
+struct Bus {
+    regs: RegisterBlock,
+    devices: Vec<Box<dyn I2cDevice>>,
+    /// Index of the addressed device, `None` after a NACKed address
+    target: Option<usize>,
+    reading: bool,
+}
+
+impl Twi {
+    /// Takes over the master registers of `regs`.
+    pub fn attach(regs: RegisterBlock) -> Self {
+        let t = Self(Rc::new(RefCell::new(Bus {
+            regs,
+            devices: Vec::new(),
+            target: None,
+            reading: false,
+        })));
+        host::attach(t.clone());
+        t
+    }
+
+    pub fn add(&self, device: impl I2cDevice + 'static) {
+        self.0.borrow_mut().devices.push(Box::new(device));
+    }
+}
+
+const FLAGS: u8 = twi::mstatus::RIF.mask
+    | twi::mstatus::WIF.mask
+    | twi::mstatus::CLKHOLD.mask
+    | twi::mstatus::RXACK.mask
+    | twi::mstatus::ARBLOST.mask
+    | twi::mstatus::BUSERR.mask;
+
+impl Bus {
+    fn set_state(&self, mem: &mut [u8], state: twi::BUSSTATE) {
+        let s = &mut mem[at(self.regs.mstatus())];
+        *s = (*s & !twi::mstatus::BUSSTATE.mask) | twi::mstatus::BUSSTATE.bits(state as u8);
+    }
+
+    fn state(&self, mem: &[u8]) -> Option<twi::BUSSTATE> {
+        twi::BUSSTATE::from_bits(twi::mstatus::BUSSTATE.get(mem[at(self.regs.mstatus())]))
+    }
+
+    /// Replaces RIF/WIF/CLKHOLD/RXACK/... with `flags`.
+    fn set_flags(&self, mem: &mut [u8], flags: u8) {
+        let s = &mut mem[at(self.regs.mstatus())];
+        *s = (*s & !FLAGS) | flags;
+    }
+
+    fn address(&mut self, v: u8, mem: &mut [u8]) {
+        self.set_state(mem, twi::BUSSTATE::OWNER);
+        self.reading = v & 1 == 1;
+        let reading = self.reading;
+        self.target = self
+            .devices
+            .iter()
+            .position(|d| d.address() == v >> 1)
+            .filter(|&i| self.devices[i].start(reading));
+
+        match self.target {
+            None => self.set_flags(
+                mem,
+                twi::mstatus::WIF.mask | twi::mstatus::CLKHOLD.mask | twi::mstatus::RXACK.mask,
+            ),
+            Some(_) if reading => self.receive(mem),
+            Some(_) => self.set_flags(mem, twi::mstatus::WIF.mask | twi::mstatus::CLKHOLD.mask),
+        }
+    }
+
+    fn receive(&mut self, mem: &mut [u8]) {
+        if let Some(i) = self.target {
+            mem[at(self.regs.mdata())] = self.devices[i].read();
+            self.set_flags(mem, twi::mstatus::RIF.mask | twi::mstatus::CLKHOLD.mask);
+        }
+    }
+
+    fn transmit(&mut self, v: u8, mem: &mut [u8]) {
+        let ack = match self.target {
+            Some(i) if !self.reading => self.devices[i].write(v),
+            _ => false,
+        };
+        let nack = if ack { 0 } else { twi::mstatus::RXACK.mask };
+        self.set_flags(
+            mem,
+            twi::mstatus::WIF.mask | twi::mstatus::CLKHOLD.mask | nack,
+        );
+    }
+
+    fn stop(&mut self, mem: &mut [u8]) {
+        if let Some(i) = self.target.take() {
+            self.devices[i].stop();
+        }
+        let s = &mut mem[at(self.regs.mstatus())];
+        *s &= !twi::mstatus::CLKHOLD.mask;
+        self.set_state(mem, twi::BUSSTATE::IDLE);
+    }
+
+    fn command(&mut self, v: u8, mem: &mut [u8]) {
+        let ack = v & twi::mctrlb::ACKACT.mask == 0;
+        match twi::MCMD::from_bits(twi::mctrlb::MCMD.get(v)) {
+            Some(twi::MCMD::REPSTART) => self.address(mem[at(self.regs.maddr())], mem),
+            Some(twi::MCMD::RECVTRANS) if self.reading && ack => self.receive(mem),
+            Some(twi::MCMD::STOP) => self.stop(mem),
+            _ => {}
+        }
+    }
+
+    fn control(&mut self, v: u8, mem: &mut [u8]) {
+        let was = mem[at(self.regs.mctrla())] & twi::mctrla::ENABLE.mask > 0;
+        let now = v & twi::mctrla::ENABLE.mask > 0;
+        mem[at(self.regs.mctrla())] = v;
+
+        if was && !now {
+            self.target = None;
+            self.set_flags(mem, 0);
+            self.set_state(mem, twi::BUSSTATE::UNKNOWN);
+        } else if !was && now && twi::mctrla::TIMEOUT.get(v) != twi::TIMEOUT::DISABLED as u8 {
+            self.set_state(mem, twi::BUSSTATE::IDLE);
+        }
+    }
+}
+
+impl Model for Twi {
+    fn claims(&self, addr: u16) -> bool {
+        let r = self.0.borrow().regs;
+        [r.mctrla(), r.mctrlb(), r.mstatus(), r.maddr(), r.mdata()]
+            .iter()
+            .any(|reg| reg.addr() == addr)
+    }
+
+    fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8 {
+        mem[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]) {
+        let mut b = self.0.borrow_mut();
+        let r = b.regs;
+        if addr == r.mctrla().addr() {
+            b.control(v, mem);
+        } else if addr == r.mctrlb().addr() {
+            mem[addr as usize] = v & !twi::mctrlb::MCMD.mask;
+            b.command(v, mem);
+        } else if addr == r.mstatus().addr() {
+            let w1c = twi::mstatus::RIF.mask
+                | twi::mstatus::WIF.mask
+                | twi::mstatus::ARBLOST.mask
+                | twi::mstatus::BUSERR.mask;
+            let s = &mut mem[addr as usize];
+            *s &= !(v & w1c);
+            if v & (twi::mstatus::RIF.mask | twi::mstatus::WIF.mask) > 0 {
+                *s &= !twi::mstatus::CLKHOLD.mask;
+            }
+            if twi::mstatus::BUSSTATE.get(v) == twi::BUSSTATE::IDLE as u8 {
+                b.set_state(mem, twi::BUSSTATE::IDLE);
+            }
+        } else if addr == r.maddr().addr() {
+            mem[addr as usize] = v;
+            if mem[at(r.mctrla())] & twi::mctrla::ENABLE.mask > 0 {
+                match b.state(mem) {
+                    Some(twi::BUSSTATE::IDLE) | Some(twi::BUSSTATE::OWNER) => b.address(v, mem),
+                    _ => b.set_flags(mem, twi::mstatus::BUSERR.mask),
+                }
+            }
+        } else if addr == r.mdata().addr() {
+            mem[addr as usize] = v;
+            if b.state(mem) == Some(twi::BUSSTATE::OWNER) {
+                b.transmit(v, mem);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clocks;
+    use crate::gpio::Pins;
+    use crate::i2c::{I2CError, I2C};
+    use crate::regs::TWI0;
+    use embedded_hal::i2c::blocking::I2c;
+    use std::vec;
+
+    /// Remembers what was written, reads back a counter.
+    #[derive(Clone, Default)]
+    struct Probe(Rc<RefCell<(Vec<u8>, u8, usize)>>);
+
+    impl I2cDevice for Probe {
+        fn address(&self) -> u8 {
+            0x42
+        }
+
+        fn write(&mut self, byte: u8) -> bool {
+            self.0.borrow_mut().0.push(byte);
+            true
+        }
+
+        fn read(&mut self) -> u8 {
+            let mut p = self.0.borrow_mut();
+            p.1 += 1;
+            p.1
+        }
+
+        fn stop(&mut self) {
+            self.0.borrow_mut().2 += 1;
+        }
+    }
+
+    fn setup() -> (Twi, Probe) {
+        host::reset();
+        let bus = Twi::attach(TWI0);
+        let probe = Probe::default();
+        bus.add(probe.clone());
+        let pins = Pins::new();
+        I2C::new().setup(pins.pa2, pins.pa3, 100_000, &Clocks::from_hz(20_000_000));
+        (bus, probe)
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let (_bus, probe) = setup();
+
+        let mut buf = [0u8; 3];
+        I2C::new().write_read(0x42, &[0xF7], &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 2, 3]);
+        let p = probe.0.borrow();
+        assert_eq!(p.0, vec![0xF7]);
+        // one STOP after the write, one after the read
+        assert_eq!(p.2, 2);
+        assert_eq!(
+            twi::mstatus::BUSSTATE.get(host::peek(TWI0.mstatus())),
+            twi::BUSSTATE::UNKNOWN as u8
+        );
+    }
+
+    #[test]
+    fn missing_device_nacks() {
+        let (_bus, probe) = setup();
+        assert!(matches!(I2C::new().write(0x10, &[1]), Err(I2CError::NACK)));
+        // the bus is usable again afterwards
+        I2C::new().write(0x42, &[2]).unwrap();
+        assert_eq!(probe.0.borrow().0, vec![2]);
+    }
+
+    #[test]
+    fn master_waits_for_idle_without_timeout() {
+        host::reset();
+        let _bus = Twi::attach(TWI0);
+        TWI0.mctrla().write(twi::mctrla::ENABLE.mask);
+        assert_eq!(
+            twi::mstatus::BUSSTATE.get(TWI0.mstatus().read()),
+            twi::BUSSTATE::UNKNOWN as u8
+        );
+        TWI0.mstatus().write(twi::BUSSTATE::IDLE as u8);
+        assert_eq!(
+            twi::mstatus::BUSSTATE.get(TWI0.mstatus().read()),
+            twi::BUSSTATE::IDLE as u8
+        );
+    }
+}
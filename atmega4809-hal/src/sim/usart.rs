@@ -0,0 +1,273 @@
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use super::at;
+use crate::host::{self, Model};
+use crate::regs::usart::{self, RegisterBlock};
+
+/// A simulated USART, and the far end of its TXD/RXD lines.
+///
+/// Writes to TXDATAL go through the data register and the shift register like
+/// on the chip, so DREIF only drops when both are full. Every STATUS poll
+/// finishes the byte being shifted out; TXCIF is set once nothing is left and
+/// stays set until a 1 is written to it.
+#[derive(Clone)]
+pub struct Usart(Rc<RefCell<Line>>);
+
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8>>;
+
+struct Line {
+    regs: RegisterBlock,
+    /// Received bytes with their RXDATAH error flags
+    rx: VecDeque<(u8, u8)>,
+    txdata: Option<u8>,
+    shifting: Option<u8>,
+    tx: Vec<u8>,
+    line: Vec<u8>,
+    responder: Option<Responder>,
+}
+
+impl Usart {
+    /// Takes over the data and status registers of `regs`.
+    pub fn attach(regs: RegisterBlock) -> Self {
+        let u = Self(Rc::new(RefCell::new(Line {
+            regs,
+            rx: VecDeque::new(),
+            txdata: None,
+            shifting: None,
+            tx: Vec::new(),
+            line: Vec::new(),
+            responder: None,
+        })));
+        host::attach(u.clone());
+        u
+    }
+
+    /// Bytes arriving on RXD.
+    pub fn push_rx(&self, bytes: &[u8]) {
+        self.0.borrow_mut().rx.extend(bytes.iter().map(|&b| (b, 0)));
+    }
+
+    /// A byte arriving with RXDATAH error flags set (`usart::rxdatah::FERR`,
+    /// `PERR`, `BUFOVF`).
+    pub fn push_rx_error(&self, byte: u8, flags: u8) {
+        self.0.borrow_mut().rx.push_back((byte, flags));
+    }
+
+    /// Everything sent on TXD so far. Bytes still in the data or shift
+    /// register are included, the hardware finishes them on its own.
+    pub fn tx(&self) -> Vec<u8> {
+        let l = self.0.borrow();
+        let mut tx = l.tx.clone();
+        tx.extend(l.shifting.iter().chain(l.txdata.iter()));
+        tx
+    }
+
+    /// Answers every line sent on TXD (up to and including `\n`) with the
+    /// bytes returned by `f`, like a modem answering AT commands.
+    pub fn respond(&self, f: impl FnMut(&[u8]) -> Vec<u8> + 'static) {
+        self.0.borrow_mut().responder = Some(Box::new(f));
+    }
+}
+
+impl Line {
+    fn enabled(&self, mem: &[u8], f: crate::regs::Field) -> bool {
+        mem[at(self.regs.ctrlb())] & f.mask > 0
+    }
+
+    /// One character time passes.
+    fn tick(&mut self, mem: &mut [u8]) {
+        if let Some(b) = self.shifting.take() {
+            self.sent(b, mem);
+            self.shifting = self.txdata.take();
+            if self.shifting.is_none() {
+                mem[at(self.regs.status())] |= usart::status::TXCIF.mask;
+            }
+        }
+    }
+
+    fn sent(&mut self, b: u8, mem: &[u8]) {
+        self.tx.push(b);
+        if mem[at(self.regs.ctrla())] & usart::ctrla::LBME.mask > 0 {
+            self.rx.push_back((b, 0));
+        }
+        self.line.push(b);
+        if b == b'\n' {
+            if let Some(f) = self.responder.as_mut() {
+                let reply = f(&self.line);
+                self.rx.extend(reply.into_iter().map(|b| (b, 0)));
+            }
+            self.line.clear();
+        }
+    }
+
+    /// Mirrors the state into STATUS and RXDATAH.
+    fn sync(&self, mem: &mut [u8]) {
+        let rxcif = usart::status::RXCIF.mask;
+        let dreif = usart::status::DREIF.mask;
+        let ready = self.enabled(mem, usart::ctrlb::RXEN) && !self.rx.is_empty();
+
+        let status = &mut mem[at(self.regs.status())];
+        *status &= !(rxcif | dreif);
+        if ready {
+            *status |= rxcif;
+        }
+        if self.txdata.is_none() {
+            *status |= dreif;
+        }
+
+        mem[at(self.regs.rxdatah())] = match (ready, self.rx.front()) {
+            (true, Some(&(_, flags))) => usart::rxdatah::RXCIF.mask | flags,
+            _ => 0,
+        };
+    }
+}
+
+impl Model for Usart {
+    fn claims(&self, addr: u16) -> bool {
+        let r = self.0.borrow().regs;
+        [r.rxdatal(), r.rxdatah(), r.txdatal(), r.status()]
+            .iter()
+            .any(|reg| reg.addr() == addr)
+    }
+
+    fn read(&mut self, addr: u16, mem: &mut [u8]) -> u8 {
+        let mut l = self.0.borrow_mut();
+        let r = l.regs;
+        if addr == r.status().addr() {
+            l.tick(mem);
+        } else if addr == r.rxdatal().addr() && l.enabled(mem, usart::ctrlb::RXEN) {
+            if let Some((b, _)) = l.rx.pop_front() {
+                mem[at(r.rxdatal())] = b;
+            }
+        }
+        l.sync(mem);
+        mem[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, v: u8, mem: &mut [u8]) {
+        let mut l = self.0.borrow_mut();
+        let r = l.regs;
+        if addr == r.status().addr() {
+            // flags are cleared by writing a one, DREIF and RXCIF are read only
+            let w1c = usart::status::TXCIF.mask
+                | usart::status::RXSIF.mask
+                | usart::status::ISFIF.mask
+                | usart::status::BDF.mask;
+            mem[addr as usize] &= !(v & w1c);
+        } else if addr == r.txdatal().addr() {
+            mem[addr as usize] = v;
+            // data written while DREIF is zero is ignored
+            if l.enabled(mem, usart::ctrlb::TXEN) && l.txdata.is_none() {
+                if l.shifting.is_none() {
+                    l.shifting = Some(v);
+                } else {
+                    l.txdata = Some(v);
+                }
+            }
+        }
+        l.sync(mem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clocks;
+    use crate::gpio::Pins;
+    use crate::regs::USART1 as USART1_REGS;
+    use crate::usart::{CharacterSize, CommunicationMode, ParityMode, StopBitMode, USART, USART1};
+
+    type Ble = USART<USART1, true>;
+
+    fn setup() -> (Usart, Ble) {
+        host::reset();
+        let line = Usart::attach(USART1_REGS);
+        let pins = Pins::new();
+        let ble = USART::<USART1, false>::new()
+            .setup(
+                pins.pc4,
+                pins.pc5,
+                9600,
+                CommunicationMode::Asynchronous,
+                ParityMode::Disabled,
+                StopBitMode::One,
+                CharacterSize::B8,
+                &Clocks::from_hz(20_000_000),
+            )
+            .unwrap();
+        (line, ble)
+    }
+
+    #[test]
+    fn transact_gets_reply_line() {
+        let (line, mut ble) = setup();
+        line.respond(|cmd| match cmd {
+            b"AT\r\n" => b"OK\r\n".to_vec(),
+            _ => b"ERROR\r\n".to_vec(),
+        });
+
+        let mut buf = [0u8; 16];
+        let reply = ble.transact(b"AT\r\n", &mut buf).unwrap();
+
+        assert_eq!(reply, b"OK\r\n");
+        assert_eq!(line.tx(), b"AT\r\n");
+    }
+
+    #[test]
+    fn transact_write_only() {
+        let (line, mut ble) = setup();
+        ble.transact(b"ping\r\n", &mut []).unwrap();
+        assert_eq!(line.tx(), b"ping\r\n");
+    }
+
+    #[test]
+    fn transact_gives_up_without_newline() {
+        let (line, mut ble) = setup();
+        line.push_rx(b"abc");
+
+        let mut buf = [0u8; 8];
+        assert_eq!(ble.transact(b"x", &mut buf).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn flags_follow_the_bytes() {
+        let _ = setup();
+        let status = USART1_REGS.status();
+
+        assert_eq!(status.read(), usart::status::DREIF.mask);
+        // first byte goes straight to the shift register, the second fills
+        // the data register
+        USART1_REGS.txdatal().write(1);
+        assert_eq!(host::peek(status), usart::status::DREIF.mask);
+        USART1_REGS.txdatal().write(2);
+        assert_eq!(host::peek(status), 0);
+
+        assert_eq!(status.read(), usart::status::DREIF.mask);
+        assert!(status.is_set(usart::status::TXCIF));
+        status.write(usart::status::TXCIF.mask);
+        assert_eq!(host::peek(status), usart::status::DREIF.mask);
+    }
+
+    #[test]
+    fn receive_with_errors_and_loopback() {
+        let (line, _ble) = setup();
+        line.push_rx_error(0x55, usart::rxdatah::FERR.mask);
+
+        assert!(USART1_REGS.status().is_set(usart::status::RXCIF));
+        assert_eq!(
+            USART1_REGS.rxdatah().read(),
+            usart::rxdatah::RXCIF.mask | usart::rxdatah::FERR.mask
+        );
+        assert_eq!(USART1_REGS.rxdatal().read(), 0x55);
+        assert!(!USART1_REGS.status().is_set(usart::status::RXCIF));
+
+        USART1_REGS.ctrla().set_bits(usart::ctrla::LBME);
+        USART1_REGS.txdatal().write(0xA5);
+        assert!(USART1_REGS.status().is_set(usart::status::RXCIF));
+        assert_eq!(USART1_REGS.rxdatal().read(), 0xA5);
+    }
+}
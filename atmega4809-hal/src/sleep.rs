@@ -0,0 +1,384 @@
+//! Sleeping, and what wakes the chip up from it.
+//!
+//! Any enabled interrupt wakes the chip from [`Sleep::Idle`]. The deeper
+//! modes stop the peripheral clock, only some interrupts get through:
+//!
+//! | Source                            | Idle | Standby    | Power-down |
+//! |-----------------------------------|------|------------|------------|
+//! | Pin, both edges or level          | yes  | yes        | yes        |
+//! | Pin, rising/falling (not Px2/Px6) | yes  | no         | no         |
+//! | PIT                               | yes  | yes        | yes        |
+//! | USART start of frame              | yes  | yes        | yes        |
+//! | TWI address match                 | yes  | yes        | yes        |
+//! | RTC, AC0, ADC0, TCBn              | yes  | RUNSTDBY   | no         |
+//! | Other USART/TWI interrupts, TCA0  | yes  | no         | no         |
+//!
+//! [`wake_sources`] reads which of these are armed, [`sleep`] refuses to go
+//! to sleep when none are. RUNSTDBY of each peripheral is set with
+//! [`run_in_standby`].
+//!
+//! Sleeping is racy: an interrupt that comes in after the code checked
+//! whether to sleep, but before it sleeps, is slept through. [`sleep_until`]
+//! does the check with interrupts off and only enables them together with
+//! the `sleep` instruction.
+//!
+//! ```ignore
+//! // between launches, wake up on the PIT or the arm switch
+//! sleep::sleep_until(Sleep::PowerOff, || ARMED.load(Ordering::Relaxed))?;
+//! ```
+
+pub use crate::clock::Sleep;
+use crate::interrupt;
+use crate::regs::{
+    self, ac, adc, clkctrl, port, rtc, slpctrl, tca, tcb, twi, usart, Field, Reg8, AC0, ADC0, CCL,
+    CLKCTRL, RTC, SLPCTRL, TCA0, TWI0,
+};
+
+/// Something that can end a sleep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WakeSource {
+    Pin,
+    Rtc,
+    Pit,
+    Usart,
+    Twi,
+    Ac0,
+    Adc0,
+    Tca0,
+    Tcb,
+}
+
+/// A set of [`WakeSource`]s.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WakeSources(u16);
+
+impl WakeSources {
+    pub fn contains(&self, source: WakeSource) -> bool {
+        self.0 & 1 << source as u16 != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn insert(&mut self, source: WakeSource, armed: bool) {
+        if armed {
+            self.0 |= 1 << source as u16;
+        }
+    }
+}
+
+/// Peripherals with a RUNSTDBY bit, for [`run_in_standby`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Standby {
+    Rtc,
+    Ac0,
+    Adc0,
+    Ccl,
+    Tcb(Tcb),
+    /// The 16/20 MHz oscillator, for peripherals running from it
+    Osc20m,
+    Osc32k,
+    Xosc32k,
+}
+
+impl Standby {
+    /// The register and the RUNSTDBY bit in it.
+    fn bit(&self) -> (Reg8, Field) {
+        match self {
+            Standby::Rtc => (RTC.ctrla(), rtc::ctrla::RUNSTDBY),
+            Standby::Ac0 => (AC0.ctrla(), ac::ctrla::RUNSTDBY),
+            Standby::Adc0 => (ADC0.ctrla(), adc::ctrla::RUNSTBY),
+            Standby::Ccl => (CCL.ctrla(), regs::ccl::ctrla::RUNSTDBY),
+            Standby::Tcb(n) => (n.regs().ctrla(), tcb::ctrla::RUNSTDBY),
+            Standby::Osc20m => (CLKCTRL.osc20mctrla(), clkctrl::osc20mctrla::RUNSTDBY),
+            Standby::Osc32k => (CLKCTRL.osc32kctrla(), clkctrl::osc32kctrla::RUNSTDBY),
+            Standby::Xosc32k => (CLKCTRL.xosc32kctrla(), clkctrl::xosc32kctrla::RUNSTDBY),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SleepError {
+    /// Nothing armed could wake the chip from the mode.
+    NoWakeSource,
+}
+
+/// One of TCB0..TCB3, for [`Standby::Tcb`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tcb {
+    Tcb0,
+    Tcb1,
+    Tcb2,
+    Tcb3,
+}
+
+impl Tcb {
+    const ALL: [Tcb; 4] = [Tcb::Tcb0, Tcb::Tcb1, Tcb::Tcb2, Tcb::Tcb3];
+
+    fn regs(&self) -> tcb::RegisterBlock {
+        match self {
+            Tcb::Tcb0 => regs::TCB0,
+            Tcb::Tcb1 => regs::TCB1,
+            Tcb::Tcb2 => regs::TCB2,
+            Tcb::Tcb3 => regs::TCB3,
+        }
+    }
+}
+
+const PORTS: [port::RegisterBlock; 6] = [
+    regs::PORTA,
+    regs::PORTB,
+    regs::PORTC,
+    regs::PORTD,
+    regs::PORTE,
+    regs::PORTF,
+];
+
+const USARTS: [usart::RegisterBlock; 4] = [regs::USART0, regs::USART1, regs::USART2, regs::USART3];
+
+/// Keeps `peripheral` running in standby, or stops it there.
+pub fn run_in_standby(peripheral: Standby, on: bool) {
+    let (reg, bit) = peripheral.bit();
+    let v = if on {
+        reg.read() | bit.mask
+    } else {
+        reg.read() & !bit.mask
+    };
+    match peripheral {
+        Standby::Osc20m | Standby::Osc32k | Standby::Xosc32k => reg.protected_write(v),
+        Standby::Rtc => {
+            while RTC.status().is_set(rtc::status::CTRLABUSY) {}
+            reg.write(v);
+        }
+        _ => reg.write(v),
+    }
+}
+
+pub fn runs_in_standby(peripheral: Standby) -> bool {
+    let (reg, bit) = peripheral.bit();
+    reg.is_set(bit)
+}
+
+/// Whether a peripheral that needs its clock gets one in `mode`.
+fn clocked(mode: Sleep, peripheral: Standby) -> bool {
+    match mode {
+        Sleep::Idle => true,
+        Sleep::Standby => runs_in_standby(peripheral),
+        Sleep::PowerOff => false,
+    }
+}
+
+/// Whether a pin with `isc` wakes the chip from `mode`. Only pins 2 and 6
+/// of each port sense edges without a clock.
+fn pin_wakes(mode: Sleep, pin: u8, isc: u8) -> bool {
+    let sense = port::ISC::from_bits(isc);
+    match sense {
+        Some(port::ISC::BOTHEDGES | port::ISC::LEVEL) => true,
+        Some(port::ISC::RISING | port::ISC::FALLING) => {
+            matches!(mode, Sleep::Idle) || pin == 2 || pin == 6
+        }
+        _ => false,
+    }
+}
+
+/// The interrupts enabled now that would wake the chip from `mode`.
+pub fn wake_sources(mode: Sleep) -> WakeSources {
+    let mut sources = WakeSources::default();
+    let idle = matches!(mode, Sleep::Idle);
+
+    let pins = PORTS.iter().any(|p| {
+        (0..8).any(|n| {
+            let isc = p.pin0ctrl().offset(n).read_field(port::pin0ctrl::ISC);
+            pin_wakes(mode, n, isc)
+        })
+    });
+    sources.insert(WakeSource::Pin, pins);
+
+    let rtc = RTC.ctrla().is_set(rtc::ctrla::RTCEN) && RTC.intctrl().read() != 0;
+    sources.insert(WakeSource::Rtc, rtc && clocked(mode, Standby::Rtc));
+    let pit =
+        RTC.pitctrla().is_set(rtc::pitctrla::PITEN) && RTC.pitintctrl().is_set(rtc::pitintctrl::PI);
+    sources.insert(WakeSource::Pit, pit);
+
+    let usart = USARTS.iter().any(|u| {
+        let (ctrla, ctrlb) = (u.ctrla().read(), u.ctrlb().read());
+        if ctrlb & (usart::ctrlb::RXEN.mask | usart::ctrlb::TXEN.mask) == 0 {
+            return false;
+        }
+        let start_of_frame = ctrla & usart::ctrla::RXSIE.mask != 0
+            && ctrlb & (usart::ctrlb::SFDEN.mask | usart::ctrlb::RXEN.mask)
+                == usart::ctrlb::SFDEN.mask | usart::ctrlb::RXEN.mask;
+        let any = ctrla
+            & (usart::ctrla::RXCIE.mask
+                | usart::ctrla::TXCIE.mask
+                | usart::ctrla::DREIE.mask
+                | usart::ctrla::RXSIE.mask
+                | usart::ctrla::ABEIE.mask)
+            != 0;
+        start_of_frame || idle && any
+    });
+    sources.insert(WakeSource::Usart, usart);
+
+    let sctrla = TWI0.sctrla().read();
+    let client = sctrla & twi::sctrla::ENABLE.mask != 0;
+    let address_match = client && sctrla & twi::sctrla::APIEN.mask != 0;
+    let host = TWI0.mctrla().read();
+    let any = client
+        && sctrla & (twi::sctrla::APIEN.mask | twi::sctrla::PIEN.mask | twi::sctrla::DIEN.mask)
+            != 0
+        || host & twi::mctrla::ENABLE.mask != 0
+            && host & (twi::mctrla::RIEN.mask | twi::mctrla::WIEN.mask) != 0;
+    sources.insert(WakeSource::Twi, address_match || idle && any);
+
+    let ac = AC0.ctrla().is_set(ac::ctrla::ENABLE) && AC0.intctrl().is_set(ac::intctrl::CMP);
+    sources.insert(WakeSource::Ac0, ac && clocked(mode, Standby::Ac0));
+    let adc = ADC0.ctrla().is_set(adc::ctrla::ENABLE) && ADC0.intctrl().read() != 0;
+    sources.insert(WakeSource::Adc0, adc && clocked(mode, Standby::Adc0));
+    let tca = TCA0.single().ctrla().is_set(tca::single::ctrla::ENABLE)
+        && TCA0.single().intctrl().read() != 0;
+    sources.insert(WakeSource::Tca0, tca && idle);
+    let tcb = Tcb::ALL.into_iter().any(|n| {
+        let r = n.regs();
+        r.ctrla().is_set(tcb::ctrla::ENABLE)
+            && r.intctrl().read() != 0
+            && clocked(mode, Standby::Tcb(n))
+    });
+    sources.insert(WakeSource::Tcb, tcb);
+
+    sources
+}
+
+/// Sleeps in `mode` until an interrupt has run. Call it with interrupts
+/// disabled, they are enabled by the `sleep` instruction itself, so one
+/// pending from before wakes it right away. Returns with them enabled.
+pub fn sleep_now(mode: Sleep) {
+    mode.set_sleep();
+    // sei only takes effect after the next instruction, nothing can run
+    // between the two
+    #[cfg(all(target_arch = "avr", not(any(test, feature = "host"))))]
+    unsafe {
+        core::arch::asm!("sei", "sleep")
+    };
+    #[cfg(not(all(target_arch = "avr", not(any(test, feature = "host")))))]
+    unsafe {
+        interrupt::enable()
+    };
+    SLPCTRL.ctrla().clear_bits(slpctrl::ctrla::SEN);
+}
+
+/// Sleeps in `mode` until the next interrupt, if one is armed that wakes
+/// from it. Returns with interrupts enabled.
+pub fn sleep(mode: Sleep) -> Result<(), SleepError> {
+    interrupt::disable();
+    if wake_sources(mode).is_empty() {
+        unsafe { interrupt::enable() };
+        return Err(SleepError::NoWakeSource);
+    }
+    sleep_now(mode);
+    Ok(())
+}
+
+/// Sleeps in `mode` until `done` returns true, checking it after every
+/// interrupt. `done` runs with interrupts disabled, so what an interrupt
+/// sets can't be missed. Returns with interrupts enabled.
+pub fn sleep_until(mode: Sleep, mut done: impl FnMut() -> bool) -> Result<(), SleepError> {
+    loop {
+        interrupt::disable();
+        if done() {
+            break;
+        }
+        if wake_sources(mode).is_empty() {
+            unsafe { interrupt::enable() };
+            return Err(SleepError::NoWakeSource);
+        }
+        sleep_now(mode);
+    }
+    unsafe { interrupt::enable() };
+    Ok(())
+}
+
+/// Stops the CPU for good, in power-down with interrupts off. Only a reset
+/// gets it going again. For panic handlers.
+pub fn halt() -> ! {
+    interrupt::disable();
+    Sleep::PowerOff.set_sleep();
+    loop {
+        sleep_instruction();
+    }
+}
+
+#[inline(always)]
+fn sleep_instruction() {
+    #[cfg(all(target_arch = "avr", not(any(test, feature = "host"))))]
+    unsafe {
+        core::arch::asm!("sleep")
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+
+    #[test]
+    fn wake_sources_per_mode() {
+        host::reset();
+        assert!(wake_sources(Sleep::Idle).is_empty());
+        assert_eq!(sleep(Sleep::Idle), Err(SleepError::NoWakeSource));
+        assert_eq!(host::writes_to(SLPCTRL.ctrla()), []);
+
+        // PD3 on a falling edge and TCB1 with RUNSTDBY
+        regs::PORTD.pin3ctrl().write(port::ISC::FALLING.bits());
+        regs::TCB1.ctrla().write(tcb::ctrla::ENABLE.mask);
+        regs::TCB1.intctrl().write(tcb::intctrl::CAPT.mask);
+        let idle = wake_sources(Sleep::Idle);
+        assert!(idle.contains(WakeSource::Pin) && idle.contains(WakeSource::Tcb));
+        assert!(wake_sources(Sleep::Standby).is_empty());
+        run_in_standby(Standby::Tcb(Tcb::Tcb1), true);
+        assert_eq!(host::peek(regs::TCB1.ctrla()), 0x41);
+        assert!(runs_in_standby(Standby::Tcb(Tcb::Tcb1)));
+        let standby = wake_sources(Sleep::Standby);
+        assert!(!standby.contains(WakeSource::Pin) && standby.contains(WakeSource::Tcb));
+
+        // PD2 is fully asynchronous, the PIT runs in every mode
+        regs::PORTD.pin2ctrl().write(port::ISC::RISING.bits());
+        RTC.pitctrla().write(rtc::pitctrla::PITEN.mask);
+        RTC.pitintctrl().write(rtc::pitintctrl::PI.mask);
+        let power_down = wake_sources(Sleep::PowerOff);
+        assert!(power_down.contains(WakeSource::Pin) && power_down.contains(WakeSource::Pit));
+        assert!(!power_down.contains(WakeSource::Tcb));
+
+        assert_eq!(sleep(Sleep::PowerOff), Ok(()));
+        assert_eq!(host::writes_to(SLPCTRL.ctrla()), [0x05, 0x04]);
+    }
+
+    #[test]
+    fn standby_of_clocks_is_protected() {
+        host::reset();
+        run_in_standby(Standby::Osc32k, true);
+        assert_eq!(host::peek(CLKCTRL.osc32kctrla()), 0x02);
+        assert_eq!(
+            host::writes()[0],
+            (regs::CPU.ccp().addr(), regs::cpu::CCP::IOREG as u8)
+        );
+    }
+
+    #[test]
+    fn sleep_until_checks_after_each_wake() {
+        host::reset();
+        regs::USART1.ctrla().write(usart::ctrla::RXSIE.mask);
+        regs::USART1
+            .ctrlb()
+            .write(usart::ctrlb::RXEN.mask | usart::ctrlb::SFDEN.mask);
+        assert!(wake_sources(Sleep::PowerOff).contains(WakeSource::Usart));
+        let mut checks = 0;
+        let r = sleep_until(Sleep::PowerOff, || {
+            checks += 1;
+            checks == 3
+        });
+        assert_eq!(r, Ok(()));
+        assert_eq!(checks, 3);
+        assert_eq!(host::writes_to(SLPCTRL.ctrla()), [0x05, 0x04, 0x05, 0x04]);
+    }
+}
@@ -1,28 +1,21 @@
+use crate::clock::Clocks;
+use crate::gpio::{PA4, PA5, PA6};
+use crate::regs::{spi, SPI0};
 use embedded_hal::spi::{blocking::Transfer, ErrorKind, ErrorType};
 use ufmt::derive::uDebug;
 
-pub struct SPI;
-
-/*
- *
-24.4 Register Summary - SPIn
-Offset Name Bit Pos.
-0x00 CTRLA 7:0 DORD MASTER CLK2X PRESC[1:0] ENABLE
-0x01 CTRLB 7:0 BUFEN BUFWR SSD MODE[1:0]
-0x02 INTCTRL 7:0 RXCIE TXCIE DREIE SSIE IE
-0x03 INTFLAGS 7:0 RXCIF TXCIF DREIF SSIF BUFOVF
-0x04 DATA 7:0 DATA[7:0]
+/// SPI0 as a master on its default pins, handed out once by
+/// [`crate::Peripherals`].
+pub struct SPI {
+    _private: (),
+}
 
-*/
 #[derive(Debug, uDebug)]
 pub enum SPIError {
     ReadOverflow,
     Other,
 }
 
-pub const PORTMUX: *mut u8 = 0x05E0 as *mut _;
-pub const SPI0: *mut u8 = 0x08C0 as *mut _;
-
 pub enum Polarity {
     ///Leading edge: Rising, sample
     ///Trailing edge: Falling, setup
@@ -39,7 +32,32 @@ pub enum Polarity {
 }
 
 impl SPI {
-    pub fn setup(high_speed: bool, wait_for_receive: bool, mode: Polarity) {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on SPI0, e.g. for an interrupt.
+    ///
+    /// # Safety
+    ///
+    /// There is one DATA register: a transfer started in the middle of the
+    /// owner's (from an interrupt, say) overwrites it and both read the
+    /// other's bytes.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn setup<MOSI, MISO, SCK>(
+        self,
+        mosi: PA4<MOSI>,
+        _miso: PA5<MISO>,
+        sck: PA6<SCK>,
+        sck_hz: u32,
+        wait_for_receive: bool,
+        mode: Polarity,
+        clocks: &Clocks,
+    ) -> Self {
         // Initialization
         // Initialize the SPI to a basic functional state by following these steps:
         // 1. Configure the SS pin in the port peripheral.
@@ -55,33 +73,59 @@ impl SPI {
         // 7. Optional: To disable the multi-master support in Master mode, write ‘1’ to the Slave Select Disable bit (SSD) in
         // SPIn.CTRLB.
         let ctrl_b = {
-            0b1000_0100 | //Buffer enable, disable SS
-            if wait_for_receive { 0b0100_0000 } else { 0 } |
-            (mode as u8)
+            spi::ctrlb::BUFEN.mask | spi::ctrlb::SSD.mask | //Buffer enable, disable SS
+            if wait_for_receive { spi::ctrlb::BUFWR.mask } else { 0 } |
+            spi::ctrlb::MODE.bits(mode as u8)
         };
-        unsafe { SPI0.offset(0x01).write_volatile(ctrl_b) };
+        SPI0.ctrlb().write(ctrl_b);
         // 8. Enable the SPI by writing a ‘1’ to the ENABLE bit in SPIn.CTRLA
         let ctrl_a = {
-            0b0110_0000 | // LSB first, master mode,
-            if !high_speed { 0b0000_0110 } else { 0 } | // low speed, write 0x3 to PRESC
-            1 //enable
+            spi::ctrla::DORD.mask | spi::ctrla::MASTER.mask | // LSB first, master mode,
+            prescaler(clocks.f_per(), sck_hz) |
+            spi::ctrla::ENABLE.mask
         };
-        unsafe { SPI0.offset(0x00).write_volatile(ctrl_a) };
+        SPI0.ctrla().write(ctrl_a);
+
+        // the master does not override MOSI/SCK direction, MISO stays an input
+        mosi.into_output();
+        sck.into_output();
+        self
     }
 
     fn raw_read_byte() -> u8 {
-        unsafe { SPI0.offset(0x04).read_volatile() }
+        SPI0.data().read()
     }
 
     fn raw_write_byte(byte: u8) {
-        unsafe { SPI0.offset(0x04).write_volatile(byte) }
+        SPI0.data().write(byte)
     }
 
     pub fn get_bus_status() -> BusStatus {
-        BusStatus(unsafe { SPI0.offset(0x03).read_volatile() })
+        BusStatus(SPI0.intflags().read())
     }
 }
 
+/// PRESC and CLK2X for the fastest SCK that isn't above `sck_hz`, or
+/// CLK_PER / 128 when none is slow enough.
+fn prescaler(f_per: u32, sck_hz: u32) -> u8 {
+    // (divider, PRESC, CLK2X), fastest first
+    const DIVIDERS: [(u32, spi::PRESC, bool); 7] = [
+        (2, spi::PRESC::DIV4, true),
+        (4, spi::PRESC::DIV4, false),
+        (8, spi::PRESC::DIV16, true),
+        (16, spi::PRESC::DIV16, false),
+        (32, spi::PRESC::DIV64, true),
+        (64, spi::PRESC::DIV64, false),
+        (128, spi::PRESC::DIV128, false),
+    ];
+    let (_, presc, clk2x) = DIVIDERS
+        .iter()
+        .find(|(div, _, _)| f_per / div <= sck_hz)
+        .unwrap_or(&DIVIDERS[6]);
+    let clk2x = if *clk2x { spi::ctrla::CLK2X.mask } else { 0 };
+    spi::ctrla::PRESC.bits(*presc as u8) | clk2x
+}
+
 impl Transfer<u8> for SPI {
     fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
         let mut wptr = 0;
@@ -89,10 +133,6 @@ impl Transfer<u8> for SPI {
 
         loop {
             let status = Self::get_bus_status();
-            if wptr < write.len() && status.dreif() {
-                Self::raw_write_byte(write[wptr]);
-                wptr += 1;
-            }
 
             if status.rxcif() {
                 if rptr < read.len() {
@@ -120,6 +160,14 @@ impl Transfer<u8> for SPI {
 
                 break;
             }
+
+            // TXCIF sticks until cleared, so clear it with every byte and
+            // only trust it on the next poll, after the last byte went out
+            if wptr < write.len() && status.dreif() {
+                SPI0.intflags().write(spi::intflags::TXCIF.mask);
+                Self::raw_write_byte(write[wptr]);
+                wptr += 1;
+            }
         }
 
         Ok(())
@@ -134,22 +182,113 @@ pub struct BusStatus(u8);
 
 impl BusStatus {
     pub fn rxcif(&self) -> bool {
-        self.0 & 0b1000_0000 > 0
+        self.0 & spi::intflags::RXCIF.mask > 0
     }
 
     pub fn txcif(&self) -> bool {
-        self.0 & 0b0100_0000 > 0
+        self.0 & spi::intflags::TXCIF.mask > 0
     }
 
     pub fn dreif(&self) -> bool {
-        self.0 & 0b0010_0000 > 0
+        self.0 & spi::intflags::DREIF.mask > 0
     }
 
     pub fn ssif(&self) -> bool {
-        self.0 & 0b0001_0000 > 0
+        self.0 & spi::intflags::SSIF.mask > 0
     }
 
     pub fn bufovf(&self) -> bool {
-        self.0 & 0b1000_0001 > 0
+        self.0 & spi::intflags::BUFOVF.mask > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::regs::PORTA;
+
+    fn setup(sck_hz: u32, wait_for_receive: bool, mode: Polarity) -> SPI {
+        let pins = Pins::new();
+        SPI::new().setup(
+            pins.pa4,
+            pins.pa5,
+            pins.pa6,
+            sck_hz,
+            wait_for_receive,
+            mode,
+            &Clocks::from_hz(20_000_000),
+        )
+    }
+
+    #[test]
+    fn setup_master_lsb_first() {
+        host::reset();
+        // nothing slower than 20 MHz / 128
+        setup(100_000, true, Polarity::P2);
+        assert_eq!(host::writes_to(SPI0.ctrlb()), [0b1100_0110]);
+        assert_eq!(host::writes_to(SPI0.ctrla()), [0b0110_0111]);
+        // MOSI (PA4) and SCK (PA6) driven
+        assert_eq!(host::writes_to(PORTA.dirset()), [0x10, 0x40]);
+
+        host::reset();
+        setup(5_000_000, false, Polarity::P0);
+        assert_eq!(host::writes_to(SPI0.ctrlb()), [0b1000_0100]);
+        assert_eq!(host::writes_to(SPI0.ctrla()), [0b0110_0001]);
+    }
+
+    #[test]
+    fn prescaler_picks_fastest_allowed() {
+        let clk2x = spi::ctrla::CLK2X.mask;
+        let presc = |p: spi::PRESC| spi::ctrla::PRESC.bits(p as u8);
+        assert_eq!(
+            prescaler(20_000_000, 10_000_000),
+            presc(spi::PRESC::DIV4) | clk2x
+        );
+        assert_eq!(prescaler(20_000_000, 9_000_000), presc(spi::PRESC::DIV4));
+        assert_eq!(
+            prescaler(20_000_000, 1_000_000),
+            presc(spi::PRESC::DIV64) | clk2x
+        );
+        assert_eq!(prescaler(20_000_000, 1), presc(spi::PRESC::DIV128));
+    }
+
+    #[test]
+    fn transfer_writes_and_reads_each_byte() {
+        host::reset();
+        // DREIF | TXCIF | RXCIF for each byte, then TXCIF once the last
+        // one is out. Scripted rather than poked, the TXCIF clear before
+        // each byte overwrites a poked value.
+        host::script(SPI0.intflags(), &[0xE0, 0xE0, 0xE0, 0x40]);
+        host::script(SPI0.data(), &[0xA1, 0xA2, 0xA3]);
+
+        let mut read = [0u8; 3];
+        SPI::new().transfer(&mut read, &[1, 2, 3]).unwrap();
+
+        assert_eq!(host::writes_to(SPI0.data()), [1, 2, 3]);
+        assert_eq!(read, [0xA1, 0xA2, 0xA3]);
+    }
+
+    #[test]
+    fn transfer_waits_for_data_register_empty() {
+        host::reset();
+        host::script(SPI0.intflags(), &[0, 0b0010_0000, 0, 0b0010_0000]);
+        host::poke(SPI0.intflags(), 0b0100_0000);
+
+        SPI::new().transfer(&mut [], &[7, 8]).unwrap();
+        assert_eq!(host::writes_to(SPI0.data()), [7, 8]);
+    }
+
+    #[test]
+    fn transfer_overrun_when_read_buffer_is_short() {
+        host::reset();
+        host::script(SPI0.intflags(), &[0xE0, 0xE0]);
+
+        let mut read = [0u8; 1];
+        assert!(matches!(
+            SPI::new().transfer(&mut read, &[1, 2]),
+            Err(ErrorKind::Overrun)
+        ));
     }
 }
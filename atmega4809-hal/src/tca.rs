@@ -0,0 +1,921 @@
+//! TCA0, the 16-bit timer with three compare channels.
+//!
+//! [`TCA`] starts out in normal (single) mode: one 16-bit counter, PER and
+//! three compare channels that each drive a waveform output. [`TCA::into_split`]
+//! turns it into two 8-bit down counters with three channels each, six
+//! independent PWM outputs sharing the clock.
+//!
+//! PER and CMPn can be written directly or through their buffers, a buffered
+//! value only takes effect at the next UPDATE condition (the end of the
+//! period) so a waveform never gets a cut short cycle.
+
+use crate::clock::Clocks;
+use crate::duty_cycle::{ErrorType, SetDutyCycle};
+use crate::gpio::GPIO;
+use crate::interrupt::{Handler, NO_HANDLER};
+use crate::regs::{self, evsys, tca, EVSYS, TCA0};
+use core::marker::PhantomData;
+
+/// Normal mode, one 16-bit counter.
+pub struct Single;
+/// Split mode, two 8-bit counters.
+pub struct Split;
+
+/// TCA0, handed out once by [`crate::Peripherals`].
+pub struct TCA<MODE = Single> {
+    _mode: PhantomData<MODE>,
+}
+
+/// Pins the waveform outputs are routed to (WO0..WO5 on pins 0..5).
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum PWMPort {
+    PORTA,
+    PORTB,
+    PORTC,
+    PORTD,
+    PORTE,
+    PORTF,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum WaveformGenerationMode {
+    ///Normal PER TOP(1) TOP(1)
+    NORMAL = 0x0,
+    ///Frequency CMP0 TOP(1) TOP(1)
+    FRQ = 0x1,
+    ///Single-slope PWM PER BOTTOM BOTTOM
+    SINGLESLOPE = 0x3,
+    ///Dual-slope PWM PER BOTTOM TOP
+    DSTOP = 0x5,
+    ///Dual-slope PWM PER BOTTOM TOP and BOTTOM
+    DSBOTH = 0x6,
+    ///Dual-slope PWM PER BOTTOM BOTTOM
+    DSBOTTOM = 0x7,
+}
+
+/// Division of the peripheral clock the counter runs on.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockDivider {
+    D1 = 0x0,
+    D2 = 0x1,
+    D4 = 0x2,
+    D8 = 0x3,
+    D16 = 0x4,
+    D64 = 0x5,
+    D256 = 0x6,
+    D1024 = 0x7,
+}
+
+impl ClockDivider {
+    /// What TCA0 is set to, TCBs can count it.
+    pub(crate) fn current() -> Self {
+        let bits = TCA0.single().ctrla().read_field(tca::single::ctrla::CLKSEL);
+        match bits {
+            0x0 => ClockDivider::D1,
+            0x1 => ClockDivider::D2,
+            0x2 => ClockDivider::D4,
+            0x3 => ClockDivider::D8,
+            0x4 => ClockDivider::D16,
+            0x5 => ClockDivider::D64,
+            0x6 => ClockDivider::D256,
+            _ => ClockDivider::D1024,
+        }
+    }
+
+    pub fn divisor(&self) -> u16 {
+        match self {
+            ClockDivider::D1 => 1,
+            ClockDivider::D2 => 2,
+            ClockDivider::D4 => 4,
+            ClockDivider::D8 => 8,
+            ClockDivider::D16 => 16,
+            ClockDivider::D64 => 64,
+            ClockDivider::D256 => 256,
+            ClockDivider::D1024 => 1024,
+        }
+    }
+}
+
+impl WaveformGenerationMode {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x0 => Some(WaveformGenerationMode::NORMAL),
+            0x1 => Some(WaveformGenerationMode::FRQ),
+            0x3 => Some(WaveformGenerationMode::SINGLESLOPE),
+            0x5 => Some(WaveformGenerationMode::DSTOP),
+            0x6 => Some(WaveformGenerationMode::DSBOTH),
+            0x7 => Some(WaveformGenerationMode::DSBOTTOM),
+            _ => None,
+        }
+    }
+
+    /// Timer clocks per period as (times TOP, plus). Dual-slope counts up
+    /// and down, FRQ toggles the output once per count to TOP.
+    fn period_shape(&self) -> (u32, u32) {
+        match self {
+            WaveformGenerationMode::NORMAL | WaveformGenerationMode::SINGLESLOPE => (1, 1),
+            WaveformGenerationMode::FRQ => (2, 1),
+            _ => (2, 0),
+        }
+    }
+}
+
+/// Division of the peripheral clock the counter runs on, smallest first.
+const DIVIDERS: [ClockDivider; 8] = [
+    ClockDivider::D1,
+    ClockDivider::D2,
+    ClockDivider::D4,
+    ClockDivider::D8,
+    ClockDivider::D16,
+    ClockDivider::D64,
+    ClockDivider::D256,
+    ClockDivider::D1024,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum TCAError {
+    /// Even with the largest divider TOP doesn't fit the counter.
+    FrequencyTooLow,
+    /// Less than one timer clock per period.
+    FrequencyTooHigh,
+}
+
+/// The smallest divider (finest duty steps) and TOP for `hz`, with the
+/// period shape of [`WaveformGenerationMode::period_shape`].
+fn divider_and_top(
+    f_per: u32,
+    hz: u32,
+    (times, plus): (u32, u32),
+    max_top: u32,
+) -> Result<(ClockDivider, u32), TCAError> {
+    if hz == 0 {
+        return Err(TCAError::FrequencyTooLow);
+    }
+    for divider in DIVIDERS {
+        let per_period = hz as u64 * divider.divisor() as u64 * times as u64;
+        let clocks = (f_per as u64 + per_period / 2) / per_period;
+        if clocks <= plus as u64 {
+            return Err(TCAError::FrequencyTooHigh);
+        }
+        let top = clocks - plus as u64;
+        if top <= max_top as u64 {
+            return Ok((divider, top as u32));
+        }
+    }
+    Err(TCAError::FrequencyTooLow)
+}
+
+fn frequency_of(f_per: u32, divider: ClockDivider, (times, plus): (u32, u32), top: u32) -> u32 {
+    let clocks = divider.divisor() as u32 * times * (top + plus);
+    (f_per + clocks / 2) / clocks
+}
+
+/// A compare channel, in split mode the one of the low or high half.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Cmp0 = 0,
+    Cmp1 = 1,
+    Cmp2 = 2,
+}
+
+/// The two counters of split mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Half {
+    /// WO0..WO2
+    Low,
+    /// WO3..WO5
+    High,
+}
+
+/// What an event on the TCA0 event input does.
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum EventAction {
+    /// Count rising edges instead of clock ticks
+    PositiveEdge = 0x0,
+    /// Count both edges instead of clock ticks
+    AnyEdge = 0x1,
+    /// Count clock ticks while the event is high
+    HighLevel = 0x2,
+    /// Count clock ticks, up while the event is low and down while high
+    UpDown = 0x3,
+}
+
+/// Interrupts in normal mode.
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    /// The counter reached TOP (or BOTTOM counting down)
+    Overflow,
+    Compare(Channel),
+}
+
+/// Interrupts in split mode, the high half has no compare interrupts.
+#[derive(Clone, Copy)]
+pub enum SplitInterrupt {
+    LowUnderflow,
+    HighUnderflow,
+    LowCompare(Channel),
+}
+
+/// INTCTRL/INTFLAGS bit of each vector, in vector order. The bits are the same
+/// in both modes.
+const VECTOR_FLAGS: [u8; 5] = [0x01, 0x02, 0x10, 0x20, 0x40];
+
+impl Interrupt {
+    fn vector(&self) -> usize {
+        match self {
+            Interrupt::Overflow => 0,
+            Interrupt::Compare(ch) => 2 + *ch as usize,
+        }
+    }
+}
+
+impl SplitInterrupt {
+    fn vector(&self) -> usize {
+        match self {
+            SplitInterrupt::LowUnderflow => 0,
+            SplitInterrupt::HighUnderflow => 1,
+            SplitInterrupt::LowCompare(ch) => 2 + *ch as usize,
+        }
+    }
+}
+
+impl<MODE> TCA<MODE> {
+    pub(crate) const fn new() -> Self {
+        Self { _mode: PhantomData }
+    }
+
+    /// A second handle on TCA0.
+    ///
+    /// # Safety
+    ///
+    /// Frequency and compare values written through it move the owner's
+    /// waveforms. `MODE` has to match CTRLD.SPLITM: the normal and split
+    /// mode registers share addresses, with the wrong one the writes land
+    /// in other registers.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Routes the waveform outputs to pins 0..5 of `port`.
+    pub fn set_port(&mut self, p: PWMPort) {
+        regs::PORTMUX
+            .tcaroutea()
+            .write_field(regs::portmux::tcaroutea::TCA0, p as u8);
+    }
+
+    /// Starts counting on the peripheral clock divided by `divider`.
+    pub fn enable(&mut self, divider: ClockDivider) {
+        TCA0.single().ctrla().write(
+            tca::single::ctrla::CLKSEL.bits(divider as u8) | tca::single::ctrla::ENABLE.mask,
+        );
+    }
+
+    pub fn disable(&mut self) {
+        TCA0.single().ctrla().write(0x0);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        TCA0.single().ctrla().is_set(tca::single::ctrla::ENABLE)
+    }
+
+    fn set_divider(&mut self, divider: ClockDivider) {
+        TCA0.single()
+            .ctrla()
+            .write_field(tca::single::ctrla::CLKSEL, divider as u8);
+    }
+
+    pub fn divider(&self) -> ClockDivider {
+        ClockDivider::current()
+    }
+
+    /// Stops the timer and puts every register back to its reset value,
+    /// the counter mode included.
+    fn reset(&mut self) {
+        self.disable();
+        TCA0.single()
+            .ctrleset()
+            .write(tca::single::ctrleset::CMD.bits(tca::SINGLE_CMD::RESET.bits()));
+    }
+
+    fn set_handler(vector: usize, handler: Option<fn()>) {
+        HANDLERS[vector].set(handler);
+    }
+
+    fn listen_vector(vector: usize, handler: fn()) {
+        Self::set_handler(vector, Some(handler));
+        TCA0.single().intflags().write(VECTOR_FLAGS[vector]);
+        TCA0.single().intctrl().modify(|v| v | VECTOR_FLAGS[vector]);
+    }
+
+    fn unlisten_vector(vector: usize) {
+        TCA0.single()
+            .intctrl()
+            .modify(|v| v & !VECTOR_FLAGS[vector]);
+        Self::set_handler(vector, None);
+    }
+}
+
+impl TCA<Single> {
+    /// Sets how the counter runs and where the outputs switch, see the
+    /// variants of [`WaveformGenerationMode`].
+    pub fn set_waveform(&mut self, w: WaveformGenerationMode) {
+        TCA0.single()
+            .ctrlb()
+            .write_field(tca::single::ctrlb::WGMODE, w as u8);
+    }
+
+    pub fn waveform(&self) -> Option<WaveformGenerationMode> {
+        WaveformGenerationMode::from_bits(
+            TCA0.single().ctrlb().read_field(tca::single::ctrlb::WGMODE),
+        )
+    }
+
+    /// Picks the divider and PER (CMP0 in FRQ mode) for a period of `hz` in
+    /// the current waveform mode and returns the frequency that came out.
+    /// The smallest divider that fits wins, it leaves the most duty cycle
+    /// steps.
+    ///
+    /// Compare values stay what they were, duty cycles have to be set again.
+    pub fn set_frequency(&mut self, hz: u32, clocks: &Clocks) -> Result<u32, TCAError> {
+        let mode = self.waveform().unwrap_or(WaveformGenerationMode::NORMAL);
+        let shape = mode.period_shape();
+        let (divider, top) = divider_and_top(clocks.f_per(), hz, shape, 0xFFFF)?;
+        match mode {
+            WaveformGenerationMode::FRQ => self.set_compare(Channel::Cmp0, top as u16),
+            _ => self.set_period(top as u16),
+        }
+        self.set_divider(divider);
+        Ok(frequency_of(clocks.f_per(), divider, shape, top))
+    }
+
+    /// Compare channel `ch` as a [`SetDutyCycle`] output. The duty cycle goes
+    /// through the buffer so it changes at the end of a period.
+    pub fn pwm_channel(&self, ch: Channel) -> PwmChannel<'_> {
+        PwmChannel {
+            ch,
+            _tca: PhantomData,
+        }
+    }
+
+    /// Hands the pin of `ch` (WOn) to the timer. The pin still has to be an
+    /// output.
+    pub fn enable_output(&mut self, ch: Channel) {
+        TCA0.single().ctrlb().modify(|v| v | cmp_en(ch));
+    }
+
+    pub fn disable_output(&mut self, ch: Channel) {
+        TCA0.single().ctrlb().modify(|v| v & !cmp_en(ch));
+    }
+
+    pub fn counter(&self) -> u16 {
+        TCA0.single().cnt().read()
+    }
+
+    pub fn set_counter(&mut self, v: u16) {
+        TCA0.single().cnt().write(v);
+    }
+
+    pub fn period(&self) -> u16 {
+        TCA0.single().per().read()
+    }
+
+    /// Sets TOP right away.
+    pub fn set_period(&mut self, v: u16) {
+        TCA0.single().per().write(v);
+    }
+
+    /// Sets TOP at the end of the current period.
+    pub fn set_period_buffered(&mut self, v: u16) {
+        TCA0.single().perbuf().write(v);
+    }
+
+    pub fn compare(&self, ch: Channel) -> u16 {
+        cmp(ch).read()
+    }
+
+    /// Sets the compare value of `ch` right away.
+    pub fn set_compare(&mut self, ch: Channel, v: u16) {
+        cmp(ch).write(v);
+    }
+
+    /// Sets the compare value of `ch` at the end of the current period.
+    pub fn set_compare_buffered(&mut self, ch: Channel, v: u16) {
+        cmp_buf(ch).write(v);
+    }
+
+    /// Starts the period over, keeping the direction.
+    pub fn restart(&mut self) {
+        TCA0.single()
+            .ctrleset()
+            .write(tca::single::ctrleset::CMD.bits(tca::SINGLE_CMD::RESTART.bits()));
+    }
+
+    /// Copies the buffered values over now instead of at the end of the
+    /// period.
+    pub fn force_update(&mut self) {
+        TCA0.single()
+            .ctrleset()
+            .write(tca::single::ctrleset::CMD.bits(tca::SINGLE_CMD::UPDATE.bits()));
+    }
+
+    /// Calls `handler` from the interrupt of `int`, replacing any handler set
+    /// before. The flag is already cleared when `handler` runs. Its vector
+    /// has to be bound with [`crate::vectors!`].
+    pub fn listen(&mut self, int: Interrupt, handler: fn()) {
+        Self::listen_vector(int.vector(), handler);
+    }
+
+    pub fn unlisten(&mut self, int: Interrupt) {
+        Self::unlisten_vector(int.vector());
+    }
+
+    /// Counts on the event channel `channel` (0..=7) as set by `action`.
+    /// The generator of the channel is picked in EVSYS.
+    pub fn count_events(&mut self, channel: u8, action: EventAction) {
+        assert!(channel < 8);
+        // 0 is off, channel n is n + 1
+        EVSYS
+            .usertca0()
+            .write_field(evsys::usertca0::CHANNEL, channel + 1);
+        TCA0.single()
+            .evctrl()
+            .write(tca::single::evctrl::EVACT.bits(action as u8) | tca::single::evctrl::CNTEI.mask);
+    }
+
+    /// Back to counting clock ticks.
+    pub fn stop_counting_events(&mut self) {
+        TCA0.single().evctrl().write(0);
+        EVSYS.usertca0().write(0);
+    }
+
+    /// Stops the timer and switches to split mode. Everything set in normal
+    /// mode is reset, interrupts are off until listened to again.
+    pub fn into_split(mut self) -> TCA<Split> {
+        self.reset();
+        TCA0.single().ctrld().write(tca::single::ctrld::SPLITM.mask);
+        TCA::new()
+    }
+}
+
+impl TCA<Split> {
+    /// Hands the pin of `ch` in `half` (WO0..WO2 low, WO3..WO5 high) to the
+    /// timer. The pin still has to be an output.
+    pub fn enable_output(&mut self, half: Half, ch: Channel) {
+        TCA0.split().ctrlb().modify(|v| v | split_cmp_en(half, ch));
+    }
+
+    pub fn disable_output(&mut self, half: Half, ch: Channel) {
+        TCA0.split().ctrlb().modify(|v| v & !split_cmp_en(half, ch));
+    }
+
+    pub fn counter(&self, half: Half) -> u8 {
+        match half {
+            Half::Low => TCA0.split().lcnt().read(),
+            Half::High => TCA0.split().hcnt().read(),
+        }
+    }
+
+    /// Sets TOP of `half`, the counters count down from it.
+    pub fn set_period(&mut self, half: Half, v: u8) {
+        match half {
+            Half::Low => TCA0.split().lper().write(v),
+            Half::High => TCA0.split().hper().write(v),
+        }
+    }
+
+    pub fn period(&self, half: Half) -> u8 {
+        match half {
+            Half::Low => TCA0.split().lper().read(),
+            Half::High => TCA0.split().hper().read(),
+        }
+    }
+
+    /// Sets the compare value of `ch` in `half`. Split mode has no buffers,
+    /// this takes effect right away.
+    pub fn set_compare(&mut self, half: Half, ch: Channel, v: u8) {
+        split_cmp(half, ch).write(v);
+    }
+
+    pub fn compare(&self, half: Half, ch: Channel) -> u8 {
+        split_cmp(half, ch).read()
+    }
+
+    /// Picks the divider and the PER of both halves for a period of `hz` and
+    /// returns the frequency that came out, like [`TCA::set_frequency`] in
+    /// normal mode.
+    pub fn set_frequency(&mut self, hz: u32, clocks: &Clocks) -> Result<u32, TCAError> {
+        let shape = WaveformGenerationMode::SINGLESLOPE.period_shape();
+        let (divider, top) = divider_and_top(clocks.f_per(), hz, shape, 0xFF)?;
+        self.set_period(Half::Low, top as u8);
+        self.set_period(Half::High, top as u8);
+        self.set_divider(divider);
+        Ok(frequency_of(clocks.f_per(), divider, shape, top))
+    }
+
+    /// Channel `ch` of `half` as a [`SetDutyCycle`] output.
+    pub fn pwm_channel(&self, half: Half, ch: Channel) -> SplitPwmChannel<'_> {
+        SplitPwmChannel {
+            half,
+            ch,
+            _tca: PhantomData,
+        }
+    }
+
+    /// Calls `handler` from the interrupt of `int`, replacing any handler set
+    /// before. The flag is already cleared when `handler` runs. Its vector
+    /// has to be bound with [`crate::vectors!`].
+    pub fn listen(&mut self, int: SplitInterrupt, handler: fn()) {
+        Self::listen_vector(int.vector(), handler);
+    }
+
+    pub fn unlisten(&mut self, int: SplitInterrupt) {
+        Self::unlisten_vector(int.vector());
+    }
+
+    /// Stops the timer and switches back to normal mode. Everything set in
+    /// split mode is reset, interrupts are off until listened to again.
+    pub fn into_single(mut self) -> TCA<Single> {
+        self.reset();
+        TCA0.split().ctrld().write(0);
+        TCA::new()
+    }
+}
+
+/// A compare channel of TCA0 in normal mode, see [`TCA::pwm_channel`].
+pub struct PwmChannel<'a> {
+    ch: Channel,
+    _tca: PhantomData<&'a TCA<Single>>,
+}
+
+impl ErrorType for PwmChannel<'_> {
+    type Error = !;
+}
+
+impl SetDutyCycle for PwmChannel<'_> {
+    /// PER + 1 in single-slope mode (a compare value past TOP never clears
+    /// the output), PER in the dual-slope modes.
+    fn max_duty_cycle(&self) -> u16 {
+        let per = TCA0.single().per().read();
+        match WaveformGenerationMode::from_bits(
+            TCA0.single().ctrlb().read_field(tca::single::ctrlb::WGMODE),
+        ) {
+            Some(WaveformGenerationMode::SINGLESLOPE) => per.saturating_add(1),
+            _ => per,
+        }
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        cmp_buf(self.ch).write(duty);
+        Ok(())
+    }
+}
+
+/// A compare channel of TCA0 in split mode, see [`TCA::pwm_channel`].
+pub struct SplitPwmChannel<'a> {
+    half: Half,
+    ch: Channel,
+    _tca: PhantomData<&'a TCA<Split>>,
+}
+
+impl ErrorType for SplitPwmChannel<'_> {
+    type Error = !;
+}
+
+impl SetDutyCycle for SplitPwmChannel<'_> {
+    /// PER + 1 of the channel's half.
+    fn max_duty_cycle(&self) -> u16 {
+        let per = match self.half {
+            Half::Low => TCA0.split().lper().read(),
+            Half::High => TCA0.split().hper().read(),
+        };
+        per as u16 + 1
+    }
+
+    /// Takes effect right away, split mode has no buffers.
+    ///
+    /// A compare value only goes to 0xFF, one short of fully on with a PER of
+    /// 0xFF. If the output is enabled that one is done by the port: OUT of
+    /// the WO pin goes high before the output is disabled, and low again once
+    /// a lower duty cycle gave the timer the pin back. The pin's OUT has to
+    /// be low otherwise.
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        let duty = duty.min(self.max_duty_cycle());
+        let en = split_cmp_en(self.half, self.ch);
+        let pin = split_wo_pin(self.half, self.ch);
+        let ctrlb = TCA0.split().ctrlb();
+        if duty > 0xFF {
+            if ctrlb.read() & en > 0 {
+                pin.output_high();
+                ctrlb.modify(|v| v & !en);
+            }
+        } else if pin.output_read() && ctrlb.read() & en == 0 {
+            ctrlb.modify(|v| v | en);
+            pin.output_low();
+        }
+        split_cmp(self.half, self.ch).write(duty.min(0xFF) as u8);
+        Ok(())
+    }
+}
+
+fn cmp_en(ch: Channel) -> u8 {
+    tca::single::ctrlb::CMP0EN.mask << ch as u8
+}
+
+fn split_cmp_en(half: Half, ch: Channel) -> u8 {
+    match half {
+        Half::Low => tca::split::ctrlb::LCMP0EN.mask << ch as u8,
+        Half::High => tca::split::ctrlb::HCMP0EN.mask << ch as u8,
+    }
+}
+
+/// WOn of `ch` in `half` on the routed port, WO0..WO2 low, WO3..WO5 high.
+fn split_wo_pin(half: Half, ch: Channel) -> GPIO {
+    let n = match half {
+        Half::Low => ch as u8,
+        Half::High => ch as u8 + 3,
+    };
+    match regs::PORTMUX
+        .tcaroutea()
+        .read_field(regs::portmux::tcaroutea::TCA0)
+    {
+        0 => GPIO::PORTA(n),
+        1 => GPIO::PORTB(n),
+        2 => GPIO::PORTC(n),
+        3 => GPIO::PORTD(n),
+        4 => GPIO::PORTE(n),
+        _ => GPIO::PORTF(n),
+    }
+}
+
+fn cmp(ch: Channel) -> regs::Reg16 {
+    match ch {
+        Channel::Cmp0 => TCA0.single().cmp0(),
+        Channel::Cmp1 => TCA0.single().cmp1(),
+        Channel::Cmp2 => TCA0.single().cmp2(),
+    }
+}
+
+fn cmp_buf(ch: Channel) -> regs::Reg16 {
+    match ch {
+        Channel::Cmp0 => TCA0.single().cmp0buf(),
+        Channel::Cmp1 => TCA0.single().cmp1buf(),
+        Channel::Cmp2 => TCA0.single().cmp2buf(),
+    }
+}
+
+fn split_cmp(half: Half, ch: Channel) -> regs::Reg8 {
+    let split = TCA0.split();
+    match (half, ch) {
+        (Half::Low, Channel::Cmp0) => split.lcmp0(),
+        (Half::Low, Channel::Cmp1) => split.lcmp1(),
+        (Half::Low, Channel::Cmp2) => split.lcmp2(),
+        (Half::High, Channel::Cmp0) => split.hcmp0(),
+        (Half::High, Channel::Cmp1) => split.hcmp1(),
+        (Half::High, Channel::Cmp2) => split.hcmp2(),
+    }
+}
+
+/// Handlers set with `listen`, by vector.
+static HANDLERS: [Handler<fn()>; 5] = [NO_HANDLER; 5];
+
+/// Body of TCA0 vector `N` (OVF/LUNF, HUNF, CMP0..2), bound by
+/// [`crate::vectors!`].
+#[doc(hidden)]
+pub fn dispatch<const N: usize>() {
+    TCA0.single().intflags().write(VECTOR_FLAGS[N]);
+    HANDLERS[N].call();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    #[test]
+    fn single_slope_pwm() {
+        host::reset();
+        let mut tca = TCA::<Single>::new();
+        tca.set_port(PWMPort::PORTB);
+        tca.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+        tca.enable_output(Channel::Cmp1);
+        tca.set_period(0xAF00);
+        tca.set_compare(Channel::Cmp1, 0x5780);
+        tca.enable(ClockDivider::D64);
+
+        assert_eq!(host::peek(regs::PORTMUX.tcaroutea()), 1);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x23);
+        assert_eq!(tca.period(), 0xAF00);
+        assert_eq!(tca.compare(Channel::Cmp1), 0x5780);
+        assert_eq!(host::peek(TCA0.single().ctrla()), 0x0B);
+        assert!(tca.is_enabled());
+        assert_eq!(tca.divider(), ClockDivider::D64);
+
+        tca.disable_output(Channel::Cmp1);
+        assert_eq!(host::peek(TCA0.single().ctrlb()), 0x03);
+        tca.disable();
+        assert!(!tca.is_enabled());
+    }
+
+    #[test]
+    fn buffered_updates() {
+        host::reset();
+        let mut tca = TCA::<Single>::new();
+        tca.set_period_buffered(1000);
+        tca.set_compare_buffered(Channel::Cmp2, 250);
+        assert_eq!(TCA0.single().perbuf().read(), 1000);
+        assert_eq!(TCA0.single().cmp2buf().read(), 250);
+        // PER and CMP2 are left alone until the update
+        assert_eq!(tca.period(), 0);
+        assert_eq!(tca.compare(Channel::Cmp2), 0);
+
+        tca.force_update();
+        assert_eq!(host::peek(TCA0.single().ctrleset()), 0x04);
+    }
+
+    #[test]
+    fn split_mode_channels() {
+        host::reset();
+        let mut tca = TCA::<Single>::new().into_split();
+        assert_eq!(
+            host::writes(),
+            [
+                (TCA0.single().ctrla().addr(), 0),
+                (TCA0.single().ctrleset().addr(), 0x0C),
+                (TCA0.single().ctrld().addr(), 0x01),
+            ]
+        );
+
+        tca.set_period(Half::Low, 0xFF);
+        tca.set_period(Half::High, 0x7F);
+        tca.set_compare(Half::Low, Channel::Cmp2, 10);
+        tca.set_compare(Half::High, Channel::Cmp0, 20);
+        tca.enable_output(Half::Low, Channel::Cmp2);
+        tca.enable_output(Half::High, Channel::Cmp0);
+        assert_eq!(host::peek(TCA0.split().lper()), 0xFF);
+        assert_eq!(host::peek(TCA0.split().hper()), 0x7F);
+        assert_eq!(host::peek(TCA0.split().lcmp2()), 10);
+        assert_eq!(host::peek(TCA0.split().hcmp0()), 20);
+        assert_eq!(host::peek(TCA0.split().ctrlb()), 0x14);
+        assert_eq!(tca.compare(Half::High, Channel::Cmp0), 20);
+
+        let _tca = tca.into_single();
+        assert_eq!(host::peek(TCA0.single().ctrld()), 0);
+    }
+
+    #[test]
+    fn split_fully_on_at_full_period() {
+        host::reset();
+        let mut tca = TCA::<Single>::new().into_split();
+        tca.set_port(PWMPort::PORTD);
+        tca.set_period(Half::High, 0xFF);
+        tca.enable_output(Half::High, Channel::Cmp1);
+        let mut ch = tca.pwm_channel(Half::High, Channel::Cmp1);
+        assert_eq!(ch.max_duty_cycle(), 0x100);
+
+        // WO4 is held high by the port instead
+        ch.set_duty_cycle_fully_on().unwrap();
+        assert_eq!(host::writes_to(regs::PORTD.outset()), [1 << 4]);
+        assert_eq!(host::peek(TCA0.split().ctrlb()), 0);
+        assert_eq!(host::peek(TCA0.split().hcmp1()), 0xFF);
+
+        // the host doesn't model OUTSET
+        host::poke(regs::PORTD.out(), 1 << 4);
+        ch.set_duty_cycle(0x80).unwrap();
+        assert_eq!(host::peek(TCA0.split().ctrlb()), 0x20);
+        assert_eq!(host::writes_to(regs::PORTD.outclr()), [1 << 4]);
+        assert_eq!(host::peek(TCA0.split().hcmp1()), 0x80);
+    }
+
+    #[test]
+    fn event_counting() {
+        host::reset();
+        let mut tca = TCA::<Single>::new();
+        tca.count_events(3, EventAction::AnyEdge);
+        assert_eq!(host::peek(EVSYS.usertca0()), 4);
+        assert_eq!(host::peek(TCA0.single().evctrl()), 0x03);
+
+        tca.stop_counting_events();
+        assert_eq!(host::peek(EVSYS.usertca0()), 0);
+        assert_eq!(host::peek(TCA0.single().evctrl()), 0);
+    }
+
+    #[test]
+    fn frequency_in_hz() {
+        host::reset();
+        let clocks = Clocks::from_hz(20_000_000);
+        let mut tca = TCA::<Single>::new();
+        tca.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+        tca.enable(ClockDivider::D1);
+        // 333_333 clocks, /8 is the first to fit
+        assert_eq!(tca.set_frequency(60, &clocks), Ok(60));
+        assert_eq!(tca.divider(), ClockDivider::D8);
+        assert_eq!(tca.period(), 41_666);
+        assert!(tca.is_enabled());
+
+        assert_eq!(tca.set_frequency(20_000, &clocks), Ok(20_000));
+        assert_eq!(tca.divider(), ClockDivider::D1);
+        assert_eq!(tca.period(), 999);
+
+        // counting up and down takes twice as long
+        tca.set_waveform(WaveformGenerationMode::DSBOTTOM);
+        assert_eq!(tca.set_frequency(20_000, &clocks), Ok(20_000));
+        assert_eq!(tca.period(), 500);
+
+        // the carrier of an IR remote, on CMP0
+        tca.set_waveform(WaveformGenerationMode::FRQ);
+        assert_eq!(tca.set_frequency(38_000, &clocks), Ok(38_023));
+        assert_eq!(tca.compare(Channel::Cmp0), 262);
+    }
+
+    #[test]
+    fn frequency_out_of_range() {
+        host::reset();
+        let clocks = Clocks::from_hz(20_000_000);
+        let mut tca = TCA::<Single>::new();
+        tca.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+        assert_eq!(tca.set_frequency(0x10, &clocks), Ok(0x10));
+        assert_eq!(tca.divider(), ClockDivider::D64);
+        assert_eq!(
+            tca.set_frequency(0, &clocks),
+            Err(TCAError::FrequencyTooLow)
+        );
+        assert_eq!(
+            tca.set_frequency(20_000_000, &clocks),
+            Err(TCAError::FrequencyTooHigh)
+        );
+
+        // eight bits don't get 50 Hz out of 20 MHz, but out of 3.33
+        let mut tca = tca.into_split();
+        assert_eq!(
+            tca.set_frequency(50, &clocks),
+            Err(TCAError::FrequencyTooLow)
+        );
+        assert_eq!(
+            tca.set_frequency(50, &Clocks::from_hz(20_000_000 / 6)),
+            Ok(50)
+        );
+        assert_eq!(tca.divider(), ClockDivider::D1024);
+        assert_eq!(tca.period(Half::High), 64);
+    }
+
+    #[test]
+    fn duty_cycle() {
+        host::reset();
+        let mut tca = TCA::<Single>::new();
+        tca.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+        tca.set_period(999);
+        let mut ch = tca.pwm_channel(Channel::Cmp2);
+        assert_eq!(ch.max_duty_cycle(), 1000);
+        ch.set_duty_cycle_percent(25).unwrap();
+        assert_eq!(TCA0.single().cmp2buf().read(), 250);
+        ch.set_duty_cycle_fully_on().unwrap();
+        assert_eq!(TCA0.single().cmp2buf().read(), 1000);
+        ch.set_duty_cycle_fraction(1, 3).unwrap();
+        assert_eq!(TCA0.single().cmp2buf().read(), 333);
+
+        tca.set_waveform(WaveformGenerationMode::DSBOTH);
+        assert_eq!(tca.pwm_channel(Channel::Cmp0).max_duty_cycle(), 999);
+
+        let mut tca = tca.into_split();
+        tca.set_period(Half::Low, 99);
+        let mut ch = tca.pwm_channel(Half::Low, Channel::Cmp1);
+        assert_eq!(ch.max_duty_cycle(), 100);
+        ch.set_duty_cycle_percent(40).unwrap();
+        assert_eq!(host::peek(TCA0.split().lcmp1()), 40);
+    }
+
+    static CALLS: AtomicU8 = AtomicU8::new(0);
+
+    fn count() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn compare_interrupt_calls_handler() {
+        host::reset();
+        let mut tca = TCA::<Single>::new();
+        tca.listen(Interrupt::Compare(Channel::Cmp1), count);
+        tca.listen(Interrupt::Overflow, count);
+        assert_eq!(host::peek(TCA0.single().intctrl()), 0x21);
+
+        dispatch::<3>();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            host::writes_to(TCA0.single().intflags()),
+            [0x20, 0x01, 0x20]
+        );
+
+        tca.unlisten(Interrupt::Compare(Channel::Cmp1));
+        assert_eq!(host::peek(TCA0.single().intctrl()), 0x01);
+        dispatch::<3>();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        dispatch::<0>();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+        tca.unlisten(Interrupt::Overflow);
+    }
+}
@@ -0,0 +1,833 @@
+//! The four 16-bit TCB timers.
+//!
+//! A [`TCB`] is turned into a [`Timer`] in one of its counter modes, an
+//! 8-bit PWM ([`TCB::into_pwm`]), or the timebase of [`crate::time`].
+//!
+//! The measuring modes listen to an [`EventInput`], an EVSYS channel. The
+//! generator of the channel (a pin, AC0, ...) is picked in EVSYS, e.g.
+//! `EVSYS.channel2().write(0x40 + 3)` for PC3 on channel 2. Results come as
+//! [`Span`]s of timer clocks. The counter is 16 bits: pick a [`ClockSource`]
+//! slow enough that the longest period expected fits, a longer one wraps.
+//!
+//! ```ignore
+//! // tachometer pulses on event channel 2
+//! let mut tach = dp.TCB1.into_frequency(EventInput::new(2, Edge::Rising), ClockSource::TCA, &clocks);
+//! if let Some(period) = tach.read() {
+//!     let rpm = period.hz() * 60;
+//! }
+//! ```
+
+use crate::clock::Clocks;
+use crate::duty_cycle::{ErrorType, SetDutyCycle};
+use crate::gpio::{Output, Pin, GPIO, PORTA, PORTB, PORTC, PORTF};
+use crate::interrupt::{Handler, NO_HANDLER};
+use crate::regs::{self, evsys, portmux, tcb, EVSYS};
+use crate::tca::ClockDivider;
+use crate::time::Duration;
+use core::marker::PhantomData;
+use core::num::NonZeroU16;
+
+/// One of TCB0..TCB3, handed out once by [`crate::Peripherals`].
+pub struct TCB<const ADDR: u16> {
+    _private: (),
+}
+
+pub const TCB0: u16 = 0x0A80;
+pub const TCB1: u16 = 0x0A90;
+pub const TCB2: u16 = 0x0AA0;
+pub const TCB3: u16 = 0x0AB0;
+
+/// Clock a TCB counts.
+#[derive(Clone, Copy)]
+pub enum ClockSource {
+    /// CLK_PER
+    Div1,
+    /// CLK_PER / 2
+    Div2,
+    /// Whatever TCA0 counts, its divider included
+    TCA,
+}
+
+impl ClockSource {
+    /// Timer clocks per second.
+    pub fn frequency(&self, clocks: &Clocks) -> u32 {
+        match self {
+            ClockSource::Div1 => clocks.f_per(),
+            ClockSource::Div2 => clocks.f_per() / 2,
+            ClockSource::TCA => clocks.f_per() / ClockDivider::current().divisor() as u32,
+        }
+    }
+
+    fn clksel(&self) -> tcb::CLKSEL {
+        match self {
+            ClockSource::Div1 => tcb::CLKSEL::CLKDIV1,
+            ClockSource::Div2 => tcb::CLKSEL::CLKDIV2,
+            ClockSource::TCA => tcb::CLKSEL::CLKTCA,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// The event a measuring mode listens to.
+#[derive(Clone, Copy)]
+pub struct EventInput {
+    /// EVSYS channel, 0..=7
+    channel: u8,
+    /// Edge that starts (or captures), the other one ends a pulse
+    edge: Edge,
+    /// Only take the event after it was stable for four samples
+    filter: bool,
+}
+
+impl EventInput {
+    pub const fn new(channel: u8, edge: Edge) -> Self {
+        assert!(channel < 8);
+        Self {
+            channel,
+            edge,
+            filter: false,
+        }
+    }
+
+    /// Turns on the noise canceler, which delays the event by four timer
+    /// clocks.
+    pub const fn filtered(self) -> Self {
+        Self {
+            filter: true,
+            ..self
+        }
+    }
+
+    fn evctrl(&self) -> u8 {
+        let mut v = tcb::evctrl::CAPTEI.mask;
+        if self.edge == Edge::Falling {
+            v |= tcb::evctrl::EDGE.mask;
+        }
+        if self.filter {
+            v |= tcb::evctrl::FILTER.mask;
+        }
+        v
+    }
+}
+
+/// A number of timer clocks, with the clock to make sense of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub ticks: u16,
+    /// Timer clocks per second
+    f_tcb: u32,
+}
+
+impl Span {
+    pub fn as_micros(&self) -> u32 {
+        (self.ticks as u64 * 1_000_000 / self.f_tcb as u64) as u32
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_micros(self.as_micros() as u64)
+    }
+
+    /// The frequency of something with this period, 0 for an empty span.
+    pub fn hz(&self) -> u32 {
+        match self.ticks {
+            0 => 0,
+            t => (self.f_tcb + t as u32 / 2) / t as u32,
+        }
+    }
+}
+
+/// What [`Timer<_, FrequencyPulseWidth>::read`] measures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeriodAndPulse {
+    pub period: Span,
+    pub pulse: Span,
+}
+
+impl PeriodAndPulse {
+    /// Pulse over period, in percent.
+    pub fn duty_percent(&self) -> u8 {
+        match self.period.ticks {
+            0 => 0,
+            t => (self.pulse.ticks as u32 * 100 / t as u32) as u8,
+        }
+    }
+}
+
+/// Counter modes of a [`Timer`].
+pub mod mode {
+    /// CAPT every period.
+    pub struct Periodic;
+    /// The counter starts on the event edge and stops on the other one, CAPT
+    /// if it reaches the timeout first.
+    pub struct Timeout;
+    /// The counter runs free, the event copies it to CCMP.
+    pub struct Capture;
+    /// The event copies the counter to CCMP and restarts it: the period.
+    pub struct Frequency;
+    /// The event edge restarts the counter, the other edge copies it: the
+    /// pulse width.
+    pub struct PulseWidth;
+    /// Both of the above from one pulse: the width is in CCMP, the period in
+    /// CNT.
+    pub struct FrequencyPulseWidth;
+    /// The event starts a pulse of fixed length on the WO pin.
+    pub struct SingleShot;
+}
+
+use mode::*;
+
+/// A TCB in one of its counter modes.
+pub struct Timer<const ADDR: u16, MODE> {
+    tcb: TCB<ADDR>,
+    /// Timer clocks per second
+    f_tcb: u32,
+    /// EVSYS channel, for single shots
+    channel: u8,
+    _mode: PhantomData<MODE>,
+}
+
+/// A pin TCBn can drive its WO output on, with the given PORTMUX route.
+pub trait WoPin<const ADDR: u16, const ALT: bool> {}
+
+macro_rules! tcb_pins {
+    ($($tcb:ident $alt:literal: $port:ident $pin:literal,)*) => {
+        $(
+            impl WoPin<$tcb, $alt> for Pin<$port, $pin, Output> {}
+        )*
+    };
+}
+
+tcb_pins! {
+    TCB0 false: PORTA 2,
+    TCB0 true: PORTF 4,
+    TCB1 false: PORTA 3,
+    TCB1 true: PORTF 5,
+    TCB2 false: PORTC 0,
+    TCB2 true: PORTB 4,
+    TCB3 false: PORTB 5,
+    TCB3 true: PORTC 1,
+}
+
+impl<const ADDR: u16> TCB<ADDR> {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on TCB`ADDR`.
+    ///
+    /// # Safety
+    ///
+    /// A counter mode or clock set through it is taken for the owner's: a
+    /// [`Timer`] or [`Monotonic`](crate::time::Monotonic) then reads
+    /// garbage, and `listen` takes over the CAPT handler.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    pub(crate) fn regs() -> tcb::RegisterBlock {
+        match ADDR {
+            TCB0 => regs::TCB0,
+            TCB1 => regs::TCB1,
+            TCB2 => regs::TCB2,
+            TCB3 => regs::TCB3,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Calls `handler` from the CAPT interrupt (the period in periodic
+    /// interrupt mode) and enables it. The flag is cleared before `handler`
+    /// runs. The `TCBn` vector has to be bound with [`crate::vectors!`].
+    pub fn listen(&mut self, handler: fn()) {
+        HANDLERS[index(ADDR)].set(Some(handler));
+        let regs = Self::regs();
+        regs.intflags().write(tcb::intflags::CAPT.mask);
+        regs.intctrl().set_bits(tcb::intctrl::CAPT);
+    }
+
+    /// Disables the CAPT interrupt and forgets its handler.
+    pub fn unlisten(&mut self) {
+        Self::regs().intctrl().clear_bits(tcb::intctrl::CAPT);
+        HANDLERS[index(ADDR)].set(None);
+    }
+
+    fn user() -> regs::Reg8 {
+        match ADDR {
+            TCB0 => EVSYS.usertcb0(),
+            TCB1 => EVSYS.usertcb1(),
+            TCB2 => EVSYS.usertcb2(),
+            TCB3 => EVSYS.usertcb3(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn into_timer<MODE>(
+        self,
+        mode: tcb::CNTMODE,
+        input: Option<EventInput>,
+        clock: ClockSource,
+        ccmp: u16,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, MODE> {
+        let regs = Self::regs();
+        regs.ctrla().write(0);
+        let mut ctrlb = tcb::ctrlb::CNTMODE.bits(mode.bits());
+        if let tcb::CNTMODE::SINGLE = mode {
+            ctrlb |= tcb::ctrlb::CCMPEN.mask;
+        }
+        regs.ctrlb().write(ctrlb);
+        match input {
+            Some(input) => {
+                // 0 is off, channel n is n + 1
+                Self::user().write_field(evsys::usertcb0::CHANNEL, input.channel + 1);
+                regs.evctrl().write(input.evctrl());
+            }
+            None => regs.evctrl().write(0),
+        }
+        regs.ccmp().write(ccmp);
+        regs.cnt().write(0);
+        regs.intflags().write(tcb::intflags::CAPT.mask);
+        regs.ctrla()
+            .write(tcb::ctrla::CLKSEL.bits(clock.clksel().bits()) | tcb::ctrla::ENABLE.mask);
+        Timer {
+            tcb: self,
+            f_tcb: clock.frequency(clocks),
+            channel: input.map_or(0, |i| i.channel),
+            _mode: PhantomData,
+        }
+    }
+
+    /// Sets CAPT every `period` timer clocks.
+    pub fn into_periodic(
+        self,
+        clock: ClockSource,
+        period: NonZeroU16,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, Periodic> {
+        self.into_timer(tcb::CNTMODE::INT, None, clock, period.get() - 1, clocks)
+    }
+
+    /// Sets CAPT when the pulse starting at the event edge lasts longer than
+    /// `timeout` timer clocks.
+    pub fn into_timeout(
+        self,
+        input: EventInput,
+        clock: ClockSource,
+        timeout: u16,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, Timeout> {
+        self.into_timer(tcb::CNTMODE::TIMEOUT, Some(input), clock, timeout, clocks)
+    }
+
+    /// Timestamps each event edge.
+    pub fn into_capture(
+        self,
+        input: EventInput,
+        clock: ClockSource,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, Capture> {
+        self.into_timer(tcb::CNTMODE::CAPT, Some(input), clock, 0, clocks)
+    }
+
+    /// Measures the time between event edges.
+    pub fn into_frequency(
+        self,
+        input: EventInput,
+        clock: ClockSource,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, Frequency> {
+        self.into_timer(tcb::CNTMODE::FRQ, Some(input), clock, 0, clocks)
+    }
+
+    /// Measures from the event edge to the other one, the high time with
+    /// [`Edge::Rising`].
+    pub fn into_pulse_width(
+        self,
+        input: EventInput,
+        clock: ClockSource,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, PulseWidth> {
+        self.into_timer(tcb::CNTMODE::PW, Some(input), clock, 0, clocks)
+    }
+
+    /// Measures period and pulse width of one cycle, e.g. a servo signal.
+    pub fn into_frequency_pulse_width(
+        self,
+        input: EventInput,
+        clock: ClockSource,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, FrequencyPulseWidth> {
+        self.into_timer(tcb::CNTMODE::FRQPW, Some(input), clock, 0, clocks)
+    }
+
+    /// Drives `pin` high for `length` timer clocks after each event edge, or
+    /// [`Timer::trigger`].
+    pub fn into_single_shot<const A: bool>(
+        self,
+        _pin: impl WoPin<ADDR, A>,
+        input: EventInput,
+        clock: ClockSource,
+        length: u16,
+        clocks: &Clocks,
+    ) -> Timer<ADDR, SingleShot> {
+        route::<ADDR>(A);
+        self.into_timer(tcb::CNTMODE::SINGLE, Some(input), clock, length, clocks)
+    }
+
+    /// Runs as an 8-bit PWM on `pin`: the counter goes 0..=`period` on
+    /// `clock` and the output is high until it reaches the duty cycle. Starts
+    /// fully off. The pin's OUT has to stay low, it drives the pin when the
+    /// duty cycle can't be reached with a compare value.
+    pub fn into_pwm<const A: bool>(
+        self,
+        _pin: impl WoPin<ADDR, A>,
+        clock: ClockSource,
+        period: u8,
+    ) -> Pwm8<ADDR> {
+        route::<ADDR>(A);
+        let regs = Self::regs();
+        regs.ctrla().write(0);
+        regs.ctrlb()
+            .write(tcb::ctrlb::CNTMODE.bits(tcb::CNTMODE::PWM8.bits()) | tcb::ctrlb::CCMPEN.mask);
+        regs.ccmp().write(period as u16);
+        regs.cnt().write(0);
+        regs.ctrla()
+            .write(tcb::ctrla::CLKSEL.bits(clock.clksel().bits()) | tcb::ctrla::ENABLE.mask);
+        Pwm8 {
+            tcb: self,
+            pin: wo_pin::<ADDR>(A),
+            period,
+            duty: 0,
+        }
+    }
+}
+
+/// The pin [`WoPin`] describes for the route.
+fn wo_pin<const ADDR: u16>(alt: bool) -> GPIO {
+    match (ADDR, alt) {
+        (TCB0, false) => GPIO::PORTA(2),
+        (TCB0, true) => GPIO::PORTF(4),
+        (TCB1, false) => GPIO::PORTA(3),
+        (TCB1, true) => GPIO::PORTF(5),
+        (TCB2, false) => GPIO::PORTC(0),
+        (TCB2, true) => GPIO::PORTB(4),
+        (TCB3, false) => GPIO::PORTB(5),
+        (TCB3, true) => GPIO::PORTC(1),
+        _ => unreachable!(),
+    }
+}
+
+fn route<const ADDR: u16>(alt: bool) {
+    regs::PORTMUX.tcbroutea().modify(|v| {
+        let route = portmux::tcbroutea::TCB0.mask << index(ADDR);
+        if alt {
+            v | route
+        } else {
+            v & !route
+        }
+    });
+}
+
+impl<const ADDR: u16, MODE> Timer<ADDR, MODE> {
+    /// Calls `handler` from the CAPT interrupt, see [`TCB::listen`]. The
+    /// interrupt clears the flag, `read` and friends only see what comes
+    /// after the handler.
+    pub fn listen(&mut self, handler: fn()) {
+        self.tcb.listen(handler);
+    }
+
+    pub fn unlisten(&mut self) {
+        self.tcb.unlisten();
+    }
+
+    /// Whether the counter is counting, it stops in some modes.
+    pub fn is_running(&self) -> bool {
+        TCB::<ADDR>::regs().status().is_set(tcb::status::RUN)
+    }
+
+    pub fn counter(&self) -> u16 {
+        TCB::<ADDR>::regs().cnt().read()
+    }
+
+    /// `ticks` timer clocks of this timer.
+    pub fn span(&self, ticks: u16) -> Span {
+        Span {
+            ticks,
+            f_tcb: self.f_tcb,
+        }
+    }
+
+    /// Timer clocks in `micros`, the other way round from [`Span`].
+    pub fn ticks(&self, micros: u32) -> u32 {
+        ((micros as u64 * self.f_tcb as u64 + 500_000) / 1_000_000) as u32
+    }
+
+    /// Stops the timer and disconnects it from its event.
+    pub fn release(mut self) -> TCB<ADDR> {
+        self.tcb.unlisten();
+        let regs = TCB::<ADDR>::regs();
+        regs.ctrla().write(0);
+        regs.ctrlb().write(0);
+        regs.evctrl().write(0);
+        TCB::<ADDR>::user().write(0);
+        self.tcb
+    }
+
+    /// Takes CCMP if an event came in, reading it clears the flag.
+    fn captured(&mut self) -> Option<u16> {
+        let regs = TCB::<ADDR>::regs();
+        if !regs.intflags().is_set(tcb::intflags::CAPT) {
+            return None;
+        }
+        Some(regs.ccmp().read())
+    }
+
+    fn take_flag(&mut self) -> bool {
+        let intflags = TCB::<ADDR>::regs().intflags();
+        let set = intflags.is_set(tcb::intflags::CAPT);
+        if set {
+            intflags.write(tcb::intflags::CAPT.mask);
+        }
+        set
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, Periodic> {
+    /// Whether a period ended since the last call.
+    pub fn elapsed(&mut self) -> bool {
+        self.take_flag()
+    }
+
+    pub fn set_period(&mut self, period: NonZeroU16) {
+        TCB::<ADDR>::regs().ccmp().write(period.get() - 1);
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, Timeout> {
+    /// Whether a pulse ran past the timeout since the last call.
+    pub fn timed_out(&mut self) -> bool {
+        self.take_flag()
+    }
+
+    pub fn set_timeout(&mut self, timeout: u16) {
+        TCB::<ADDR>::regs().ccmp().write(timeout);
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, Capture> {
+    /// The counter at the last event, if there was one since the last read.
+    pub fn read(&mut self) -> Option<u16> {
+        self.captured()
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, Frequency> {
+    /// The last period, if a new one ended since the last read.
+    pub fn read(&mut self) -> Option<Span> {
+        self.captured().map(|t| self.span(t))
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, PulseWidth> {
+    /// The last pulse, if a new one ended since the last read.
+    pub fn read(&mut self) -> Option<Span> {
+        self.captured().map(|t| self.span(t))
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, FrequencyPulseWidth> {
+    /// The last cycle, if one was measured since the last read. The counter
+    /// stands still until then, reading starts the next measurement.
+    pub fn read(&mut self) -> Option<PeriodAndPulse> {
+        let regs = TCB::<ADDR>::regs();
+        if !regs.intflags().is_set(tcb::intflags::CAPT) {
+            return None;
+        }
+        let period = regs.cnt().read();
+        let pulse = regs.ccmp().read();
+        Some(PeriodAndPulse {
+            period: self.span(period),
+            pulse: self.span(pulse),
+        })
+    }
+}
+
+impl<const ADDR: u16> Timer<ADDR, SingleShot> {
+    /// Starts a pulse from software, through the EVSYS strobe of its
+    /// channel. Other generators on the channel are unaffected.
+    pub fn trigger(&mut self) {
+        EVSYS.strobe().write(1 << self.channel);
+    }
+
+    pub fn set_length(&mut self, length: u16) {
+        TCB::<ADDR>::regs().ccmp().write(length);
+    }
+}
+
+/// A TCB in 8-bit PWM mode, see [`TCB::into_pwm`].
+pub struct Pwm8<const ADDR: u16> {
+    tcb: TCB<ADDR>,
+    pin: GPIO,
+    /// CCMPL
+    period: u8,
+    /// High time in timer clocks, up to `period + 1`
+    duty: u16,
+}
+
+impl<const ADDR: u16> Pwm8<ADDR> {
+    /// Sets TOP, the duty cycle in timer clocks stays.
+    pub fn set_period(&mut self, period: u8) {
+        self.period = period;
+        self.write();
+    }
+
+    pub fn period(&self) -> u8 {
+        self.period
+    }
+
+    /// Stops the timer and gives the pin back to the port.
+    pub fn release(self) -> TCB<ADDR> {
+        let regs = TCB::<ADDR>::regs();
+        regs.ctrla().write(0);
+        regs.ctrlb().write(0);
+        self.pin.output_low();
+        self.tcb
+    }
+
+    /// CCMPH only goes to 0xFF, which is one clock short of fully on with a
+    /// period of 0xFF. That one is done by the port: OUT goes high before
+    /// CCMPEN is cleared, and low again after the timer has the pin back.
+    ///
+    /// CCMP is written through TEMP, the low byte has to go in as well.
+    fn write(&self) {
+        let regs = TCB::<ADDR>::regs();
+        if self.duty > 0xFF {
+            self.pin.output_high();
+            regs.ctrlb().clear_bits(tcb::ctrlb::CCMPEN);
+        } else {
+            regs.ctrlb().set_bits(tcb::ctrlb::CCMPEN);
+            self.pin.output_low();
+        }
+        regs.ccmp()
+            .write(self.duty.min(0xFF) << 8 | self.period as u16);
+    }
+}
+
+impl<const ADDR: u16> ErrorType for Pwm8<ADDR> {
+    type Error = !;
+}
+
+impl<const ADDR: u16> SetDutyCycle for Pwm8<ADDR> {
+    /// The period plus one, a duty cycle past TOP keeps the output high.
+    fn max_duty_cycle(&self) -> u16 {
+        self.period as u16 + 1
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        self.duty = duty.min(self.max_duty_cycle());
+        self.write();
+        Ok(())
+    }
+}
+
+static HANDLERS: [Handler<fn()>; 4] = [NO_HANDLER; 4];
+
+const fn index(addr: u16) -> usize {
+    ((addr - TCB0) / 0x10) as usize
+}
+
+/// Body of the TCB interrupt, bound by [`crate::vectors!`].
+#[doc(hidden)]
+pub fn dispatch<const ADDR: u16>() {
+    TCB::<ADDR>::regs()
+        .intflags()
+        .write(tcb::intflags::CAPT.mask);
+    HANDLERS[index(ADDR)].call();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    static CALLS: AtomicU8 = AtomicU8::new(0);
+
+    fn count() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn pwm8() {
+        host::reset();
+        let pins = crate::gpio::Pins::new();
+        let pin = pins.pb4.into_output();
+        let mut pwm = TCB::<TCB2>::new().into_pwm(pin, ClockSource::TCA, 199);
+        assert_eq!(host::peek(regs::PORTMUX.tcbroutea()), 0x04);
+        assert_eq!(host::peek(regs::TCB2.ctrlb()), 0x17);
+        assert_eq!(host::peek(regs::TCB2.ctrla()), 0x05);
+        assert_eq!(regs::TCB2.ccmp().read(), 199);
+
+        assert_eq!(pwm.max_duty_cycle(), 200);
+        pwm.set_duty_cycle_percent(25).unwrap();
+        assert_eq!(regs::TCB2.ccmp().read(), 50 << 8 | 199);
+        pwm.set_period(99);
+        assert_eq!(regs::TCB2.ccmp().read(), 50 << 8 | 99);
+        pwm.set_duty_cycle_fully_on().unwrap();
+        assert_eq!(regs::TCB2.ccmp().read(), 100 << 8 | 99);
+
+        let _tcb = pwm.release();
+        assert_eq!(host::peek(regs::TCB2.ctrla()), 0);
+    }
+
+    #[test]
+    fn pwm8_fully_on_at_full_period() {
+        use crate::regs::PORTB;
+
+        host::reset();
+        let pins = crate::gpio::Pins::new();
+        let pin = pins.pb4.into_output();
+        let mut pwm = TCB::<TCB2>::new().into_pwm(pin, ClockSource::TCA, 0xFF);
+        assert_eq!(pwm.max_duty_cycle(), 0x100);
+
+        // a compare value can't get past TOP, the port holds the pin high
+        pwm.set_duty_cycle_fully_on().unwrap();
+        assert_eq!(host::writes_to(PORTB.outset()), [1 << 4]);
+        assert_eq!(host::peek(regs::TCB2.ctrlb()), 0x07);
+        // anything past the maximum is the maximum
+        pwm.set_duty_cycle(0x1000).unwrap();
+        assert_eq!(host::peek(regs::TCB2.ctrlb()), 0x07);
+
+        pwm.set_duty_cycle(0xFF).unwrap();
+        assert_eq!(host::peek(regs::TCB2.ctrlb()), 0x17);
+        assert_eq!(host::writes_to(PORTB.outclr()), [1 << 4]);
+        assert_eq!(regs::TCB2.ccmp().read(), 0xFFFF);
+    }
+
+    const CLOCKS: Clocks = Clocks::from_hz(20_000_000);
+
+    #[test]
+    fn frequency_measurement() {
+        host::reset();
+        let input = EventInput::new(2, Edge::Rising).filtered();
+        let mut tach = TCB::<TCB1>::new().into_frequency(input, ClockSource::Div2, &CLOCKS);
+        assert_eq!(host::peek(EVSYS.usertcb1()), 3);
+        assert_eq!(host::peek(regs::TCB1.evctrl()), 0x41);
+        assert_eq!(host::peek(regs::TCB1.ctrlb()), 0x03);
+        assert_eq!(host::peek(regs::TCB1.ctrla()), 0x03);
+
+        host::poke(regs::TCB1.intflags(), 0);
+        assert_eq!(tach.read(), None);
+        // 400 Hz on a 10 MHz timer clock
+        host::poke(regs::TCB1.intflags(), tcb::intflags::CAPT.mask);
+        regs::TCB1.ccmp().write(25_000);
+        let period = tach.read().unwrap();
+        assert_eq!(period.ticks, 25_000);
+        assert_eq!(period.hz(), 400);
+        assert_eq!(period.as_micros(), 2500);
+
+        let _tcb = tach.release();
+        assert_eq!(host::peek(EVSYS.usertcb1()), 0);
+        assert_eq!(host::peek(regs::TCB1.ctrla()), 0);
+    }
+
+    #[test]
+    fn servo_signal() {
+        host::reset();
+        regs::TCA0.single().ctrla().write(0x0B); // DIV64, running
+        let input = EventInput::new(0, Edge::Rising);
+        let mut servo =
+            TCB::<TCB0>::new().into_frequency_pulse_width(input, ClockSource::TCA, &CLOCKS);
+        assert_eq!(host::peek(regs::TCB0.ctrlb()), 0x05);
+        assert_eq!(host::peek(regs::TCB0.ctrla()), 0x05);
+
+        host::poke(regs::TCB0.intflags(), tcb::intflags::CAPT.mask);
+        // 50 Hz, 1.5 ms at 312.5 kHz
+        regs::TCB0.cnt().write(6250);
+        regs::TCB0.ccmp().write(469);
+        let cycle = servo.read().unwrap();
+        assert_eq!(cycle.period.hz(), 50);
+        assert_eq!(cycle.pulse.as_micros(), 1500);
+        assert_eq!(cycle.duty_percent(), 7);
+    }
+
+    #[test]
+    fn pulse_width_on_falling_edge() {
+        host::reset();
+        let input = EventInput::new(7, Edge::Falling);
+        let mut pw = TCB::<TCB2>::new().into_pulse_width(input, ClockSource::Div1, &CLOCKS);
+        assert_eq!(host::peek(EVSYS.usertcb2()), 8);
+        assert_eq!(host::peek(regs::TCB2.evctrl()), 0x11);
+        assert_eq!(host::peek(regs::TCB2.ctrlb()), 0x04);
+
+        host::poke(regs::TCB2.intflags(), tcb::intflags::CAPT.mask);
+        regs::TCB2.ccmp().write(200);
+        assert_eq!(pw.read().unwrap().as_micros(), 10);
+    }
+
+    #[test]
+    fn periodic_and_timeout() {
+        host::reset();
+        let period = NonZeroU16::new(10_000).unwrap();
+        let mut t = TCB::<TCB1>::new().into_periodic(ClockSource::Div2, period, &CLOCKS);
+        assert_eq!(regs::TCB1.ccmp().read(), 9_999);
+        t.set_period(NonZeroU16::new(1).unwrap());
+        assert_eq!(regs::TCB1.ccmp().read(), 0);
+        assert_eq!(host::peek(regs::TCB1.evctrl()), 0);
+        host::poke(regs::TCB1.intflags(), tcb::intflags::CAPT.mask);
+        assert!(t.elapsed());
+        assert_eq!(
+            host::writes_to(regs::TCB1.intflags()).last(),
+            Some(&tcb::intflags::CAPT.mask)
+        );
+
+        let input = EventInput::new(1, Edge::Rising);
+        let mut t = t
+            .release()
+            .into_timeout(input, ClockSource::Div1, 2000, &CLOCKS);
+        assert_eq!(host::peek(regs::TCB1.ctrlb()), 0x01);
+        assert_eq!(regs::TCB1.ccmp().read(), 2000);
+        host::poke(regs::TCB1.intflags(), 0);
+        assert!(!t.timed_out());
+    }
+
+    #[test]
+    fn single_shot() {
+        host::reset();
+        let pins = crate::gpio::Pins::new();
+        let pin = pins.pa3.into_output();
+        let input = EventInput::new(5, Edge::Rising);
+        let mut shot =
+            TCB::<TCB1>::new().into_single_shot(pin, input, ClockSource::Div1, 1000, &CLOCKS);
+        assert_eq!(host::peek(regs::PORTMUX.tcbroutea()), 0);
+        assert_eq!(host::peek(regs::TCB1.ctrlb()), 0x16);
+        assert_eq!(regs::TCB1.ccmp().read(), 1000);
+        shot.trigger();
+        assert_eq!(host::writes_to(EVSYS.strobe()), [1 << 5]);
+    }
+
+    #[test]
+    fn capt_calls_handler() {
+        host::reset();
+        let mut tcb = TCB::<TCB3>::new();
+        tcb.listen(count);
+        assert_eq!(host::peek(regs::TCB3.intctrl()), tcb::intctrl::CAPT.mask);
+
+        host::poke(regs::TCB3.intflags(), tcb::intflags::CAPT.mask);
+        dispatch::<TCB3>();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            host::writes_to(regs::TCB3.intflags()),
+            [tcb::intflags::CAPT.mask; 2]
+        );
+
+        tcb.unlisten();
+        dispatch::<TCB3>();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(host::peek(regs::TCB3.intctrl()), 0);
+    }
+}
@@ -0,0 +1,402 @@
+//! Time since start up, counted by a TCB.
+//!
+//! [`Monotonic`] runs its TCB in periodic interrupt mode with a period of a
+//! millisecond (512 timer clocks at least, so slow clocks aren't drowned in
+//! interrupts). The interrupt counts periods, [`Monotonic::now`] adds what
+//! the counter is at, so readings have the resolution of the peripheral
+//! clock. The count only moves on while interrupts are enabled, or while
+//! [`Monotonic::wait_until`] polls it.
+//!
+//! As a [`DelayUs`] it sleeps in idle between ticks instead of spinning, and
+//! waits stay right with other interrupts going on. Waits shorter than a
+//! period spin on the counter. With interrupts disabled, e.g. in
+//! [`interrupt::free`] or before they are first enabled, waits spin and
+//! never turn them on.
+//!
+//! On the host nothing ticks on its own, a wait skips ahead to the first
+//! tick past its end.
+
+use crate::clock::Clocks;
+use crate::interrupt;
+use crate::regs::{cpu, tcb, CPU};
+use crate::sleep::{self, Sleep};
+use crate::tcb::{TCB, TCB0};
+use core::ops::{Add, Sub};
+use embedded_hal::delay::blocking::DelayUs;
+
+/// Fewest timer clocks between two interrupts.
+const MIN_PERIOD: u32 = 512;
+
+/// A span of time, in microseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    micros: u64,
+}
+
+impl Duration {
+    pub const fn from_micros(us: u64) -> Self {
+        Self { micros: us }
+    }
+
+    pub const fn from_millis(ms: u64) -> Self {
+        Self { micros: ms * 1000 }
+    }
+
+    pub const fn from_secs(s: u64) -> Self {
+        Self {
+            micros: s * 1_000_000,
+        }
+    }
+
+    pub const fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.micros / 1000
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_micros(self.micros + rhs.micros)
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        Duration::from_micros(self.micros - rhs.micros)
+    }
+}
+
+/// A point in time read from a [`Monotonic`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    /// Since the timebase was started
+    micros: u64,
+}
+
+impl Instant {
+    /// Microseconds since the timebase was started.
+    pub const fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    /// Milliseconds since the timebase was started.
+    pub const fn as_millis(&self) -> u64 {
+        self.micros / 1000
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub const fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.micros.saturating_sub(earlier.micros))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            micros: self.micros + rhs.micros,
+        }
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// A TCB counting time, see the [module docs](self).
+pub struct Monotonic<const ADDR: u16> {
+    tcb: TCB<ADDR>,
+    /// Timer clocks per interrupt, CCMP + 1
+    period: u32,
+    /// Timer clock
+    f_tcb: u32,
+}
+
+impl<const ADDR: u16> Monotonic<ADDR> {
+    /// Starts counting from zero. The TCB's vector has to be bound with
+    /// [`crate::vectors!`].
+    pub fn new(mut tcb: TCB<ADDR>, clocks: &Clocks) -> Self {
+        let f_tcb = clocks.f_per();
+        let period = (f_tcb / 1000).clamp(MIN_PERIOD, 0x10000);
+
+        let regs = TCB::<ADDR>::regs();
+        regs.ctrla().write(0);
+        regs.ctrlb()
+            .write(tcb::ctrlb::CNTMODE.bits(tcb::CNTMODE::INT.bits()));
+        regs.ccmp().write((period - 1) as u16);
+        regs.cnt().write(0);
+        with_periods::<ADDR, _>(|p| *p = 0);
+        tcb.listen(tick::<ADDR>);
+        regs.ctrla()
+            .write(tcb::ctrla::CLKSEL.bits(tcb::CLKSEL::CLKDIV1.bits()) | tcb::ctrla::ENABLE.mask);
+
+        Self { tcb, period, f_tcb }
+    }
+
+    pub fn now(&self) -> Instant {
+        let regs = TCB::<ADDR>::regs();
+        let ticks = interrupt::free(|| {
+            let mut periods = with_periods::<ADDR, _>(|p| *p);
+            let mut cnt = regs.cnt().read();
+            if regs.intflags().is_set(tcb::intflags::CAPT) {
+                // wrapped, but the interrupt hasn't run yet. The first read
+                // may be from either side of it, the second one is after
+                cnt = regs.cnt().read();
+                periods += 1;
+            }
+            periods * self.period as u64 + cnt as u64
+        });
+        Instant {
+            micros: self.micros(ticks),
+        }
+    }
+
+    /// Time passed since `earlier`.
+    pub fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().duration_since(earlier)
+    }
+
+    /// Waits until `end`, sleeping in idle between ticks. With interrupts
+    /// disabled it spins instead and counts the periods itself, they are
+    /// left as they were either way.
+    pub fn wait_until(&mut self, end: Instant) {
+        let enabled = CPU.sreg().is_set(cpu::sreg::I);
+        let period = Duration::from_micros(self.micros(self.period as u64) + 1);
+        loop {
+            interrupt::disable();
+            let now = self.now();
+            if now >= end {
+                break;
+            }
+            if !enabled {
+                poll::<ADDR>();
+            } else if end - now > period {
+                // the next tick is still before the end
+                idle::<ADDR>();
+            } else {
+                unsafe { interrupt::enable() };
+                spin::<ADDR>();
+            }
+        }
+        if enabled {
+            unsafe { interrupt::enable() };
+        }
+    }
+
+    /// Stops the timer.
+    pub fn release(mut self) -> TCB<ADDR> {
+        TCB::<ADDR>::regs().ctrla().write(0);
+        self.tcb.unlisten();
+        self.tcb
+    }
+
+    fn micros(&self, ticks: u64) -> u64 {
+        let f = self.f_tcb as u64;
+        ticks / f * 1_000_000 + ticks % f * 1_000_000 / f
+    }
+}
+
+impl<const ADDR: u16> DelayUs for Monotonic<ADDR> {
+    type Error = !;
+
+    fn delay_us(&mut self, us: u32) -> Result<(), Self::Error> {
+        let end = self.now() + Duration::from_micros(us as u64);
+        self.wait_until(end);
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, ms: u32) -> Result<(), Self::Error> {
+        let end = self.now() + Duration::from_millis(ms as u64);
+        self.wait_until(end);
+        Ok(())
+    }
+}
+
+/// Periods counted by the interrupt, per TCB.
+#[cfg(not(any(test, feature = "host")))]
+static mut PERIODS: [u64; 4] = [0; 4];
+
+// per thread on the host, like the registers
+#[cfg(any(test, feature = "host"))]
+std::thread_local! {
+    static PERIODS: core::cell::RefCell<[u64; 4]> = const { core::cell::RefCell::new([0; 4]) };
+}
+
+fn with_periods<const ADDR: u16, R>(f: impl FnOnce(&mut u64) -> R) -> R {
+    let n = ((ADDR - TCB0) / 0x10) as usize;
+    // SAFETY: interrupts are off, neither the vector nor the owner can get
+    // in between
+    #[cfg(not(any(test, feature = "host")))]
+    return interrupt::free(|| f(unsafe { &mut (*core::ptr::addr_of_mut!(PERIODS))[n] }));
+    #[cfg(any(test, feature = "host"))]
+    return interrupt::free(|| PERIODS.with(|p| f(&mut p.borrow_mut()[n])));
+}
+
+/// CAPT handler.
+fn tick<const ADDR: u16>() {
+    with_periods::<ADDR, _>(|p| *p += 1);
+}
+
+/// Sleeps until the next interrupt, expects them disabled.
+fn idle<const ADDR: u16>() {
+    #[cfg(any(test, feature = "host"))]
+    next_tick::<ADDR>();
+    sleep::sleep_now(Sleep::Idle);
+}
+
+fn spin<const ADDR: u16>() {
+    #[cfg(any(test, feature = "host"))]
+    next_tick::<ADDR>();
+}
+
+/// Does the interrupt's work while interrupts are disabled.
+fn poll<const ADDR: u16>() {
+    #[cfg(any(test, feature = "host"))]
+    next_tick::<ADDR>();
+    let regs = TCB::<ADDR>::regs();
+    if regs.intflags().is_set(tcb::intflags::CAPT) {
+        regs.intflags().write(tcb::intflags::CAPT.mask);
+        tick::<ADDR>();
+    }
+}
+
+/// Runs the timer to the end of its period and the interrupt after it.
+#[cfg(any(test, feature = "host"))]
+fn next_tick<const ADDR: u16>() {
+    let regs = TCB::<ADDR>::regs();
+    regs.cnt().write(0);
+    tick::<ADDR>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use crate::regs::{slpctrl, SLPCTRL, TCB0 as TCB0_REGS};
+
+    fn start(f_per: u32) -> Monotonic<TCB0> {
+        host::reset();
+        let m = Monotonic::new(TCB::new(), &Clocks::from_hz(f_per));
+        // the flag is cleared by writing a one, memory just keeps it
+        host::poke(TCB0_REGS.intflags(), 0);
+        m
+    }
+
+    #[test]
+    fn setup_periodic_interrupt() {
+        let m = start(20_000_000);
+        assert_eq!(m.period, 20_000);
+        assert_eq!(TCB0_REGS.ccmp().read(), 19_999);
+        assert_eq!(host::peek(TCB0_REGS.ctrlb()), 0);
+        assert_eq!(host::peek(TCB0_REGS.intctrl()), tcb::intctrl::CAPT.mask);
+        assert_eq!(host::peek(TCB0_REGS.ctrla()), tcb::ctrla::ENABLE.mask);
+        assert_eq!(m.now().as_micros(), 0);
+
+        let _tcb = m.release();
+        assert_eq!(host::peek(TCB0_REGS.ctrla()), 0);
+        assert_eq!(host::peek(TCB0_REGS.intctrl()), 0);
+    }
+
+    #[test]
+    fn counts_periods_and_ticks() {
+        let m = start(20_000_000);
+        for _ in 0..3 {
+            tick::<TCB0>();
+        }
+        TCB0_REGS.cnt().write(10_000);
+        assert_eq!(m.now().as_micros(), 3500);
+        assert_eq!(m.now().as_millis(), 3);
+
+        TCB0_REGS.cnt().write(19);
+        let earlier = m.now();
+        TCB0_REGS.cnt().write(39);
+        assert_eq!(m.elapsed(earlier), Duration::from_micros(1));
+    }
+
+    #[test]
+    fn pending_wrap_counts() {
+        let m = start(20_000_000);
+        tick::<TCB0>();
+        host::poke(TCB0_REGS.intflags(), tcb::intflags::CAPT.mask);
+        // the first read is before the wrap, the second after
+        host::script(TCB0_REGS.cnt().low(), &[0x1F, 0x05]);
+        host::script(TCB0_REGS.cnt().high(), &[0x4E, 0x00]);
+        assert_eq!(m.now().as_micros(), 2000);
+    }
+
+    #[test]
+    fn slow_clock() {
+        let m = start(32_768);
+        assert_eq!(TCB0_REGS.ccmp().read(), MIN_PERIOD as u16 - 1);
+        for _ in 0..64 {
+            tick::<TCB0>();
+        }
+        assert_eq!(m.now().as_micros(), 1_000_000);
+        TCB0_REGS.cnt().write(1);
+        assert_eq!(m.now().as_micros(), 1_000_030);
+    }
+
+    #[test]
+    fn no_overflow_after_days() {
+        let m = start(20_000_000);
+        // 30 days of ticks
+        with_periods::<TCB0, _>(|p| *p = 30 * 24 * 3600 * 1000);
+        assert_eq!(m.now().as_millis(), 30 * 24 * 3600 * 1000);
+    }
+
+    #[test]
+    fn delay_sleeps_until_past_the_end() {
+        let mut m = start(20_000_000);
+        host::poke(CPU.sreg(), cpu::sreg::I.mask);
+        TCB0_REGS.cnt().write(5000);
+        m.delay_us(2500).unwrap();
+        assert_eq!(m.now().as_micros(), 3000);
+        m.delay_ms(5).unwrap();
+        assert_eq!(m.now().as_micros(), 8000);
+        // slept in idle, SEN is off again
+        assert!(host::writes_to(SLPCTRL.ctrla()).contains(&slpctrl::ctrla::SEN.mask));
+        assert_eq!(host::peek(SLPCTRL.ctrla()), 0);
+    }
+
+    #[test]
+    fn delay_with_interrupts_off_spins() {
+        let mut m = start(20_000_000);
+        m.delay_ms(3).unwrap();
+        assert_eq!(m.now().as_millis(), 3);
+        // didn't sleep, nothing would wake it
+        assert!(host::writes_to(SLPCTRL.ctrla()).is_empty());
+        // the pending wrap is counted and cleared by the wait
+        let cleared = host::writes_to(TCB0_REGS.intflags()).len();
+        host::poke(TCB0_REGS.intflags(), tcb::intflags::CAPT.mask);
+        m.delay_us(500).unwrap();
+        assert_eq!(host::writes_to(TCB0_REGS.intflags()).len(), cleared + 1);
+        host::poke(TCB0_REGS.intflags(), 0);
+        assert_eq!(m.now().as_micros(), 5000);
+    }
+
+    #[test]
+    fn durations() {
+        let d = Duration::from_millis(3) + Duration::from_micros(500);
+        assert_eq!(d.as_micros(), 3500);
+        assert_eq!(d.as_millis(), 3);
+        assert_eq!((d - Duration::from_millis(1)).as_micros(), 2500);
+        assert_eq!(Duration::from_secs(2).as_millis(), 2000);
+
+        let a = Instant { micros: 100 };
+        let b = a + Duration::from_micros(50);
+        assert_eq!(b - a, Duration::from_micros(50));
+        assert_eq!(a - b, Duration::default());
+    }
+}
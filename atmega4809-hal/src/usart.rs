@@ -1,34 +1,25 @@
-use crate::{gpio::GPIO, set16};
-
-pub struct USART<const ADDR: u16, const ALT: bool>;
-
-/*
-0x00 RXDATAL 7:0 DATA[7:0]
-0x01 RXDATAH 7:0 RXCIF BUFOVF FERR PERR DATA[8]
-0x02 TXDATAL 7:0 DATA[7:0]
-0x03 TXDATAH 7:0 DATA[8]
-0x04 STATUS 7:0 RXCIF TXCIF DREIF RXSIF ISFIF BDF WFB
-0x05 CTRLA 7:0 RXCIE TXCIE DREIE RXSIE LBME ABEIE RS485[1:0]
-0x06 CTRLB 7:0 RXEN TXEN SFDEN ODME RXMODE[1:0] MPCM
-0x07 CTRLC 7:0 CMODE[1:0] PMODE[1:0] SBMODE CHSIZE[2:0]
-0x07 CTRLC 7:0 CMODE[1:0] UDORD UCPHA
-0x08 BAUDL 7:0 BAUD[7:0]
-0x09 BAUDH 7:0 BAUD[15:8]
-0x0A CTRLD 8:0 ABW[1:0]
-0x0B DBGCTRL 7:0 DBGRUN
-0x0C EVCTRL 7:0 IREI
-0x0D TXPLCTRL 7:0 TXPL[7:0]
-0x0E RXPLCTRL 7:0 RXPL[6:0]
-*/
-
-pub const PORTMUX: *mut u8 = 0x05E0 as *mut _;
+use crate::clock::Clocks;
+use crate::gpio::{Pin, GPIO, PORTA, PORTB, PORTC, PORTF};
+use crate::interrupt;
+use crate::regs::{self, portmux, usart};
+
+/// USARTn with its pins routed to the default (`ALT = false`) or alternate
+/// location. Handed out once by [`crate::Peripherals`], the route is picked by
+/// the pins given to [`USART::setup`].
+pub struct USART<const ADDR: u16, const ALT: bool> {
+    _private: (),
+}
+
 pub const USART0: u16 = 0x0800;
 pub const USART1: u16 = 0x0820;
 pub const USART2: u16 = 0x0840;
 pub const USART3: u16 = 0x0860;
 
-pub const BAUD9600: u16 = (17 << 6) | 0b0001_1000;
+/// Largest difference between the requested and the achieved baud rate, in
+/// 1/1000. The receiver copes with about 2% for 8 bit frames in normal mode.
+pub const BAUD_TOLERANCE: u32 = 20;
 
+#[derive(Clone, Copy)]
 pub enum CommunicationMode {
     ///Asynchronous USART
     Asynchronous = 0x00,
@@ -69,21 +60,123 @@ pub enum CharacterSize {
     B9H = 0x07,
 }
 
+/// A pin USARTn can use for TXD with the given PORTMUX route.
+pub trait TxPin<const UADDR: u16, const ALT: bool> {}
+
+/// A pin USARTn can use for RXD with the given PORTMUX route.
+pub trait RxPin<const UADDR: u16, const ALT: bool> {}
+
+macro_rules! usart_pins {
+    ($($usart:ident $alt:literal: $port:ident $tx:literal $rx:literal,)*) => {
+        $(
+            impl<MODE> TxPin<$usart, $alt> for Pin<$port, $tx, MODE> {}
+            impl<MODE> RxPin<$usart, $alt> for Pin<$port, $rx, MODE> {}
+        )*
+    };
+}
+
+usart_pins! {
+    USART0 false: PORTA 0 1,
+    USART0 true: PORTA 4 5,
+    USART1 false: PORTC 0 1,
+    USART1 true: PORTC 4 5,
+    USART2 false: PORTF 0 1,
+    USART2 true: PORTF 4 5,
+    USART3 false: PORTB 0 1,
+    USART3 true: PORTB 4 5,
+}
+
 #[derive(Debug)]
 pub enum USARTError {
     ReadOverflow,
+    /// The requested baud rate is off by more than [`BAUD_TOLERANCE`] from
+    /// anything CLK_PER can reach.
+    BaudRate,
     Other,
 }
 
-//testing func
-pub fn t() {
-    GPIO::PORTA(1).output_enable();
-    GPIO::PORTA(1).output_high();
+/// BAUD register value and whether it needs the receiver in CLK2X mode.
+#[derive(Debug, PartialEq, Eq)]
+struct Baud {
+    value: u16,
+    clk2x: bool,
+}
+
+impl Baud {
+    fn rxmode(&self) -> usart::RXMODE {
+        if self.clk2x {
+            usart::RXMODE::CLK2X
+        } else {
+            usart::RXMODE::NORMAL
+        }
+    }
+}
+
+fn div_round(n: u64, d: u64) -> u64 {
+    (n + d / 2) / d
+}
+
+/// Finds the BAUD value for `bps` from a CLK_PER of `f_per` Hz.
+///
+/// Asynchronous modes use normal speed (16 samples a bit) when the value fits
+/// and fall back to CLK2X (8 samples) for rates normal speed can't reach.
+fn calculate_baud(f_per: u32, bps: u32, synchronous: bool) -> Result<Baud, USARTError> {
+    if bps == 0 {
+        return Err(USARTError::BaudRate);
+    }
+    let (f, bps) = (f_per as u64, bps as u64);
+
+    let (value, actual, clk2x) = if synchronous {
+        // only BAUD[15:6] counts, f_per / (2 * BAUD[15:6])
+        let int = div_round(f, 2 * bps).max(1);
+        (int << 6, f / (2 * int), false)
+    } else {
+        // 64 * f_per / (S * BAUD), S = 16 or 8 with CLK2X
+        let normal = div_round(4 * f, bps);
+        let (s, value) = if normal >= 64 {
+            (16, normal)
+        } else {
+            (8, div_round(8 * f, bps))
+        };
+        let actual = if value > 0 { 64 * f / (s * value) } else { 0 };
+        (value, actual, s == 8)
+    };
+
+    // BAUD values below 64 aren't allowed
+    if !(64..=0xffff).contains(&value) || actual.abs_diff(bps) * 1000 > BAUD_TOLERANCE as u64 * bps
+    {
+        return Err(USARTError::BaudRate);
+    }
+    Ok(Baud {
+        value: value as u16,
+        clk2x,
+    })
 }
 
 impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
-    fn addr() -> *mut u8 {
-        UADDR as *mut _
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on the USART, for panic handlers and interrupts.
+    ///
+    /// # Safety
+    ///
+    /// Only use it on a USART that is already set up. Bytes written through
+    /// it go into DATA between the owner's, so the output is mixed, and a
+    /// [`BufferedUsart`] owner has its DRE interrupt racing the writes.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    fn regs() -> usart::RegisterBlock {
+        match UADDR {
+            USART0 => regs::USART0,
+            USART1 => regs::USART1,
+            USART2 => regs::USART2,
+            USART3 => regs::USART3,
+            _ => unreachable!(),
+        }
     }
 
     fn get_out_pin() -> GPIO {
@@ -97,68 +190,116 @@ impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
         }
     }
 
-    pub fn change_baud(baud: u16) {
-        set16(unsafe { Self::addr().offset(0x08) }, baud);
+    fn get_in_pin() -> GPIO {
+        let pin = if ALT { 5 } else { 1 };
+        match UADDR {
+            USART0 => GPIO::PORTA(pin),
+            USART1 => GPIO::PORTC(pin),
+            USART2 => GPIO::PORTF(pin),
+            USART3 => GPIO::PORTB(pin),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sets the baud rate in bits per second.
+    pub fn change_baud(&self, bps: u32, clocks: &Clocks) -> Result<(), USARTError> {
+        let cmode = Self::regs().ctrlc().read_field(usart::ctrlc::CMODE);
+        let baud = Self::baud(bps, cmode, clocks)?;
+        Self::write_baud(&baud);
+        Ok(())
+    }
+
+    fn baud(bps: u32, cmode: u8, clocks: &Clocks) -> Result<Baud, USARTError> {
+        let synchronous =
+            cmode == CommunicationMode::Synchronous as u8 || cmode == CommunicationMode::MSPI as u8;
+        calculate_baud(clocks.f_per(), bps, synchronous)
     }
 
-    pub fn setup(
-        baud: u16,
+    fn write_baud(baud: &Baud) {
+        Self::regs().baud().write(baud.value);
+        Self::regs()
+            .ctrlb()
+            .write_field(usart::ctrlb::RXMODE, baud.rxmode() as u8);
+    }
+
+    /// Configures the USART for `bps` bits per second and routes it to the
+    /// location of `tx`/`rx`. Nothing is touched if the rate can't be reached
+    /// from `clocks`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn setup<const A: bool>(
+        self,
+        _tx: impl TxPin<UADDR, A>,
+        _rx: impl RxPin<UADDR, A>,
+        bps: u32,
+        m: CommunicationMode,
+        p: ParityMode,
+        s: StopBitMode,
+        wsize: CharacterSize,
+        clocks: &Clocks,
+    ) -> Result<USART<UADDR, A>, USARTError> {
+        let baud = Self::baud(bps, m as u8, clocks)?;
+        let usart = USART::<UADDR, A>::new();
+        usart.init(&baud, m, p, s, wsize);
+        Ok(usart)
+    }
+
+    fn init(
+        &self,
+        baud: &Baud,
         m: CommunicationMode,
         p: ParityMode,
         s: StopBitMode,
         wsize: CharacterSize,
     ) {
         // 1. Set the baud rate (USARTn.BAUD).
-        Self::change_baud(baud);
-        //let baud = 0b00010001 | 0b00011010 << 8;
-        //set16(unsafe { Self::addr().offset(0x08) }, baud);
+        Self::regs().baud().write(baud.value);
 
         // 2. Set the frame format and mode of operation (USARTn.CTRLC).
         let ctrl_c = {
-            (m as u8) << 6 | // (fmt)
-            (p as u8) << 4 |
-            (s as u8) << 3 |
-            wsize as u8
+            usart::ctrlc::CMODE.bits(m as u8)
+                | usart::ctrlc::PMODE.bits(p as u8)
+                | usart::ctrlc::SBMODE.bits(s as u8)
+                | usart::ctrlc::CHSIZE.bits(wsize as u8)
         };
-        unsafe { Self::addr().offset(0x07).write_volatile(ctrl_c) };
+        Self::regs().ctrlc().write(ctrl_c);
 
         // 3. Configure the TXD pin as an output.
         // see 15.3.3 PORTMUX Control for USART
-        //unsafe { PORTMUX.offset(0x02).write(0b0100_0000) };
-        let usart_pmux = unsafe { PORTMUX.offset(0x02) };
-        let cur = unsafe { usart_pmux.read_volatile() };
-        let bit_target = match UADDR {
-            USART0 => 0b0000_0001,
-            USART1 => 0b0000_0100,
-            USART2 => 0b0001_0000,
-            USART3 => 0b0100_0000,
+        let route = match UADDR {
+            USART0 => portmux::usartroutea::USART0,
+            USART1 => portmux::usartroutea::USART1,
+            USART2 => portmux::usartroutea::USART2,
+            USART3 => portmux::usartroutea::USART3,
             _ => unreachable!(),
         };
-
-        unsafe {
-            if ALT {
-                usart_pmux.write_volatile(cur | bit_target)
-            } else {
-                //turn 0x1 to 0x3 and clear the bits
-                usart_pmux.write_volatile(cur & !(bit_target | (bit_target << 1)))
-            }
+        // every USARTn route uses the same DEFAULT/ALT1 encoding
+        let alt = if ALT {
+            portmux::USART0::ALT1
+        } else {
+            portmux::USART0::DEFAULT
         };
+        regs::PORTMUX.usartroutea().write_field(route, alt as u8);
 
         let out_pin = Self::get_out_pin();
         out_pin.output_enable();
         //out_pin.pin_ctrl_pullup(true);
         out_pin.pin_ctrl_isc(&crate::gpio::ISC::InputDisable);
+        Self::get_in_pin().output_disable();
         //crate::gpio::GPIO::PORTC(4).output_high();
 
-        // (3.5, enable ints)
-        unsafe { Self::addr().offset(0x05).write_volatile(0b1110_0000) };
+        // (3.5, interrupts stay off) nothing services the USART vectors while
+        // polling, and DREIE would fire as soon as interrupts are enabled
+        Self::regs().ctrla().write(0);
 
         for _ in 0..0xff {
             unsafe { core::arch::asm!("nop") };
         }
         // 4. Enable the transmitter and the receiver (USARTn.CTRLB)
-        //unsafe { Self::addr().offset(0x06).write_volatile(0b1100_0000) };
-        unsafe { Self::addr().offset(0x06).write_volatile(0b1100_0000) };
+        Self::regs().ctrlb().write(
+            usart::ctrlb::RXEN.mask
+                | usart::ctrlb::TXEN.mask
+                | usart::ctrlb::RXMODE.bits(baud.rxmode() as u8),
+        );
 
         //9600
         //8 0b00010001
@@ -199,7 +340,11 @@ impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
         // 0x00000000  0xe
     }
 
-    pub fn transact<'k>(mut write: &[u8], read: &'k mut [u8]) -> Result<&'k [u8], USARTError> {
+    pub fn transact<'k>(
+        &mut self,
+        mut write: &[u8],
+        read: &'k mut [u8],
+    ) -> Result<&'k [u8], USARTError> {
         let mut read_is_done = false;
         let mut empty_reads = 0u16;
         let mut char_count = 0;
@@ -208,7 +353,7 @@ impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
             if !write.is_empty() && status.dreif() {
                 match write.split_first() {
                     Some((to_write, rest)) => {
-                        unsafe { Self::addr().offset(0x02).write_volatile(*to_write) };
+                        Self::regs().txdatal().write(*to_write);
                         write = rest;
                     }
                     None => {}
@@ -216,7 +361,7 @@ impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
             }
 
             if status.rxcif() && char_count < read.len() {
-                let new_byte = unsafe { Self::addr().offset(0x00).read_volatile() };
+                let new_byte = Self::regs().rxdatal().read();
                 read[char_count] = new_byte;
                 char_count += 1;
 
@@ -239,16 +384,16 @@ impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
         Ok(&read[0..char_count])
     }
 
-    pub fn stop() {
-        //unsafe { Self::addr().offset(0x06).write_volatile(0b0000_0000) };
+    pub fn stop(&self) {
+        //Self::regs().ctrlb().write(0);
     }
 
-    pub fn off() {
-        unsafe { Self::addr().offset(0x06).write_volatile(0b0000_0000) };
+    pub fn off(&self) {
+        Self::regs().ctrlb().write(0);
     }
 
     pub fn get_bus_status() -> BusStatus {
-        BusStatus(unsafe { Self::addr().offset(0x04).read_volatile() })
+        BusStatus(Self::regs().status().read())
     }
 }
 
@@ -256,31 +401,31 @@ pub struct BusStatus(u8);
 
 impl BusStatus {
     pub fn rxcif(&self) -> bool {
-        self.0 & 0b1000_0000 > 0
+        self.0 & usart::status::RXCIF.mask > 0
     }
 
     pub fn txcif(&self) -> bool {
-        self.0 & 0b0100_0000 > 0
+        self.0 & usart::status::TXCIF.mask > 0
     }
 
     pub fn dreif(&self) -> bool {
-        self.0 & 0b0010_0000 > 0
+        self.0 & usart::status::DREIF.mask > 0
     }
 
     pub fn rxsif(&self) -> bool {
-        self.0 & 0b0001_0000 > 0
+        self.0 & usart::status::RXSIF.mask > 0
     }
 
     pub fn isfif(&self) -> bool {
-        self.0 & 0b0000_1000 > 0
+        self.0 & usart::status::ISFIF.mask > 0
     }
 
     pub fn bdf(&self) -> bool {
-        self.0 & 0b0000_0010 > 0
+        self.0 & usart::status::BDF.mask > 0
     }
 
     pub fn wfb(&self) -> bool {
-        self.0 & 0b1000_0001 > 0
+        self.0 & usart::status::WFB.mask > 0
     }
 }
 
@@ -288,6 +433,532 @@ impl<const UADDR: u16, const ALT: bool> ufmt::uWrite for USART<UADDR, ALT> {
     type Error = USARTError;
 
     fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
-        Self::transact(s.as_bytes(), &mut []).map(drop)
+        self.transact(s.as_bytes(), &mut []).map(drop)
+    }
+}
+
+/// Size of each RX and TX ring. One slot stays empty so a full ring can be
+/// told apart from an empty one.
+pub const BUFFER_SIZE: usize = 64;
+
+struct Ring {
+    buf: [u8; BUFFER_SIZE],
+    /// Next slot to write
+    head: usize,
+    /// Next slot to read
+    tail: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: [0; BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    fn push(&mut self, b: u8) -> bool {
+        let next = (self.head + 1) % BUFFER_SIZE;
+        if next == self.tail {
+            return false;
+        }
+        self.buf[self.head] = b;
+        self.head = next;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let b = self.buf[self.tail];
+        self.tail = (self.tail + 1) % BUFFER_SIZE;
+        Some(b)
+    }
+}
+
+/// Receive errors counted by the RXC interrupt, from the RXDATAH flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    /// BUFOVF, or a byte dropped because the RX ring was full
+    pub overflow: u16,
+    /// FERR, the stop bit was missing
+    pub framing: u16,
+    /// PERR
+    pub parity: u16,
+}
+
+struct Buffers {
+    rx: Ring,
+    tx: Ring,
+    errors: ErrorCounts,
+    /// Set while bytes are queued or still being shifted out
+    sending: bool,
+}
+
+impl Buffers {
+    const EMPTY: Self = Self {
+        rx: Ring::new(),
+        tx: Ring::new(),
+        errors: ErrorCounts {
+            overflow: 0,
+            framing: 0,
+            parity: 0,
+        },
+        sending: false,
+    };
+}
+
+/// Shared between [`BufferedUsart`] and the USART vectors, by USART index.
+/// Only touched through [`with_buffers`].
+#[cfg(not(any(test, feature = "host")))]
+static mut BUFFERS: [Buffers; 4] = [Buffers::EMPTY; 4];
+
+// per thread on the host, like the registers
+#[cfg(any(test, feature = "host"))]
+std::thread_local! {
+    static BUFFERS: core::cell::RefCell<[Buffers; 4]> =
+        const { core::cell::RefCell::new([Buffers::EMPTY; 4]) };
+}
+
+fn with_buffers<const UADDR: u16, R>(f: impl FnOnce(&mut Buffers) -> R) -> R {
+    let n = ((UADDR - USART0) / 0x20) as usize;
+    // SAFETY: interrupts are off, neither the vectors nor the owner can get
+    // in between
+    #[cfg(not(any(test, feature = "host")))]
+    return interrupt::free(|| f(unsafe { &mut (*core::ptr::addr_of_mut!(BUFFERS))[n] }));
+    #[cfg(any(test, feature = "host"))]
+    return interrupt::free(|| BUFFERS.with(|b| f(&mut b.borrow_mut()[n])));
+}
+
+/// A set up USART driven by its interrupts.
+///
+/// Received bytes are moved into an RX ring by the RXC vector and bytes
+/// written are sent from a TX ring by DRE, so nothing is lost while the main
+/// code is busy as long as the rings don't fill up. Global interrupts have to
+/// be enabled for anything to move, and the app has to bind the USART's RXC,
+/// DRE and TXC vectors with [`crate::vectors!`].
+pub struct BufferedUsart<const ADDR: u16, const ALT: bool> {
+    usart: USART<ADDR, ALT>,
+}
+
+impl<const UADDR: u16, const ALT: bool> USART<UADDR, ALT> {
+    /// Hands the USART over to its interrupts. Anything left in the rings from
+    /// an earlier [`BufferedUsart`] is dropped.
+    pub fn into_buffered(self) -> BufferedUsart<UADDR, ALT> {
+        with_buffers::<UADDR, _>(|b| *b = Buffers::EMPTY);
+        Self::regs().ctrla().write(usart::ctrla::RXCIE.mask);
+        BufferedUsart { usart: self }
+    }
+}
+
+impl<const UADDR: u16, const ALT: bool> BufferedUsart<UADDR, ALT> {
+    /// Moves received bytes into `buf` without waiting, returns how many.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        with_buffers::<UADDR, _>(|b| {
+            let mut n = 0;
+            while n < buf.len() {
+                match b.rx.pop() {
+                    Some(v) => buf[n] = v,
+                    None => break,
+                }
+                n += 1;
+            }
+            n
+        })
+    }
+
+    /// Queues as much of `data` as fits in the TX ring without waiting,
+    /// returns how many bytes were taken.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        with_buffers::<UADDR, _>(|b| {
+            let n = data.iter().take_while(|&&v| b.tx.push(v)).count();
+            if n > 0 {
+                b.sending = true;
+                // DRE picks the bytes up, TXC is turned on once it runs dry
+                USART::<UADDR, ALT>::regs()
+                    .ctrla()
+                    .modify(|v| (v | usart::ctrla::DREIE.mask) & !usart::ctrla::TXCIE.mask);
+            }
+            n
+        })
+    }
+
+    /// Waits until every queued byte has left the shift register.
+    pub fn flush(&mut self) {
+        while with_buffers::<UADDR, _>(|b| b.sending) {}
+    }
+
+    /// Receive errors since the last [`BufferedUsart::clear_errors`].
+    pub fn errors(&self) -> ErrorCounts {
+        with_buffers::<UADDR, _>(|b| b.errors)
+    }
+
+    pub fn clear_errors(&mut self) {
+        with_buffers::<UADDR, _>(|b| b.errors = ErrorCounts::default());
+    }
+
+    pub fn change_baud(&self, bps: u32, clocks: &Clocks) -> Result<(), USARTError> {
+        self.usart.change_baud(bps, clocks)
+    }
+
+    /// Turns the interrupts off and goes back to polling. Bytes still queued
+    /// are dropped, call [`BufferedUsart::flush`] first to keep them.
+    pub fn release(self) -> USART<UADDR, ALT> {
+        USART::<UADDR, ALT>::regs().ctrla().write(0);
+        with_buffers::<UADDR, _>(|b| *b = Buffers::EMPTY);
+        self.usart
+    }
+
+    pub fn off(&self) {
+        self.usart.off();
+    }
+}
+
+impl<const UADDR: u16, const ALT: bool> ufmt::uWrite for BufferedUsart<UADDR, ALT> {
+    type Error = USARTError;
+
+    /// Blocks while the TX ring is full.
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        let mut s = s.as_bytes();
+        while !s.is_empty() {
+            let n = self.write(s);
+            s = &s[n..];
+        }
+        Ok(())
+    }
+}
+
+/// Body of the RXC interrupt, bound by [`crate::vectors!`]: moves the
+/// received byte into the RX ring.
+///
+/// Bytes with a framing or parity error are counted and dropped, BUFOVF means
+/// an earlier byte was lost so the one read is still kept.
+#[doc(hidden)]
+pub fn on_rxc<const UADDR: u16>() {
+    let regs = USART::<UADDR, false>::regs();
+    // RXDATAH first, reading RXDATAL moves the receive buffer along
+    let flags = regs.rxdatah().read();
+    let byte = regs.rxdatal().read();
+    with_buffers::<UADDR, _>(|b| {
+        let e = &mut b.errors;
+        let corrupt = usart::rxdatah::FERR.mask | usart::rxdatah::PERR.mask;
+        if flags & usart::rxdatah::BUFOVF.mask > 0 {
+            e.overflow = e.overflow.saturating_add(1);
+        }
+        if flags & usart::rxdatah::FERR.mask > 0 {
+            e.framing = e.framing.saturating_add(1);
+        }
+        if flags & usart::rxdatah::PERR.mask > 0 {
+            e.parity = e.parity.saturating_add(1);
+        }
+        if flags & corrupt == 0 && !b.rx.push(byte) {
+            e.overflow = e.overflow.saturating_add(1);
+        }
+    });
+}
+
+/// Body of the DRE interrupt: sends the next byte of the TX ring, or swaps
+/// DREIE for TXCIE once it is empty.
+#[doc(hidden)]
+pub fn on_dre<const UADDR: u16>() {
+    let regs = USART::<UADDR, false>::regs();
+    with_buffers::<UADDR, _>(|b| match b.tx.pop() {
+        Some(byte) => {
+            // a stale TXCIF would end flush() before this byte is out
+            regs.status().write(usart::status::TXCIF.mask);
+            regs.txdatal().write(byte);
+        }
+        None => regs
+            .ctrla()
+            .modify(|v| (v & !usart::ctrla::DREIE.mask) | usart::ctrla::TXCIE.mask),
+    });
+}
+
+/// Body of the TXC interrupt: the last byte is out.
+#[doc(hidden)]
+pub fn on_txc<const UADDR: u16>() {
+    let regs = USART::<UADDR, false>::regs();
+    regs.status().write(usart::status::TXCIF.mask);
+    regs.ctrla().clear_bits(usart::ctrla::TXCIE);
+    with_buffers::<UADDR, _>(|b| b.sending = false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::host;
+    use crate::regs::{
+        PORTB as PORTB_REGS, PORTC as PORTC_REGS, PORTMUX, USART1 as USART1_REGS,
+        USART3 as USART3_REGS,
+    };
+    use crate::sim::Usart;
+
+    const CLOCKS: Clocks = Clocks::from_hz(20_000_000);
+
+    fn setup_8n1<const UADDR: u16, const ALT: bool>(
+        tx: impl TxPin<UADDR, ALT>,
+        rx: impl RxPin<UADDR, ALT>,
+        bps: u32,
+    ) -> USART<UADDR, ALT> {
+        USART::<UADDR, false>::new()
+            .setup(
+                tx,
+                rx,
+                bps,
+                CommunicationMode::Asynchronous,
+                ParityMode::Disabled,
+                StopBitMode::One,
+                CharacterSize::B8,
+                &CLOCKS,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn setup_writes_baud_frame_and_enables() {
+        host::reset();
+        let pins = Pins::new();
+        // 4 * 20 MHz / 57600 = 1388.9
+        setup_8n1(pins.pb4, pins.pb5, 57600);
+
+        let u = USART3_REGS;
+        assert_eq!(host::writes_to(u.baud().low()), [(1389 & 0xff) as u8]);
+        assert_eq!(host::writes_to(u.baud().high()), [(1389 >> 8) as u8]);
+        assert_eq!(host::writes_to(u.ctrlc()), [0x03]);
+        assert_eq!(host::writes_to(u.ctrla()), [0]);
+        assert_eq!(host::writes_to(u.ctrlb()), [0b1100_0000]);
+
+        // TXD on PB4, routed through PORTMUX
+        assert_eq!(host::peek(PORTMUX.usartroutea()), 0b0100_0000);
+        assert_eq!(host::writes_to(PORTB_REGS.dirset()), [1 << 4]);
+        assert_eq!(host::peek(PORTB_REGS.pin4ctrl()), 0x04);
+        // RXD on PB5 is an input
+        assert_eq!(host::writes_to(PORTB_REGS.dirclr()), [1 << 5]);
+    }
+
+    #[test]
+    fn setup_frame_format() {
+        host::reset();
+        let pins = Pins::new();
+        USART::<USART1, false>::new()
+            .setup(
+                pins.pc0,
+                pins.pc1,
+                9600,
+                CommunicationMode::Asynchronous,
+                ParityMode::Odd,
+                StopBitMode::Two,
+                CharacterSize::B7,
+                &CLOCKS,
+            )
+            .unwrap();
+        assert_eq!(host::writes_to(USART1_REGS.ctrlc()), [0b0011_1010]);
+        assert_eq!(host::writes_to(PORTC_REGS.dirset()), [1 << 0]);
+    }
+
+    #[test]
+    fn setup_clears_alternate_route() {
+        host::reset();
+        host::poke(PORTMUX.usartroutea(), 0b1111_1111);
+        let pins = Pins::new();
+        setup_8n1(pins.pc0, pins.pc1, 9600);
+        assert_eq!(host::peek(PORTMUX.usartroutea()), 0b1111_0011);
+    }
+
+    /// Runs the USART1 vectors the way the interrupt controller would, lowest
+    /// vector first, until none is pending. Every STATUS read lets the line
+    /// model send a character.
+    fn service_usart1() {
+        loop {
+            let status = USART1_REGS.status().read();
+            let ctrla = host::peek(USART1_REGS.ctrla());
+            let pending = |flag: crate::regs::Field, enable: crate::regs::Field| {
+                status & flag.mask > 0 && ctrla & enable.mask > 0
+            };
+            if pending(usart::status::RXCIF, usart::ctrla::RXCIE) {
+                on_rxc::<USART1>();
+            } else if pending(usart::status::DREIF, usart::ctrla::DREIE) {
+                on_dre::<USART1>();
+            } else if pending(usart::status::TXCIF, usart::ctrla::TXCIE) {
+                on_txc::<USART1>();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn buffered_usart1() -> (Usart, BufferedUsart<USART1, true>) {
+        host::reset();
+        let line = Usart::attach(USART1_REGS);
+        let pins = Pins::new();
+        let ble = setup_8n1(pins.pc4, pins.pc5, 9600).into_buffered();
+        (line, ble)
+    }
+
+    #[test]
+    fn buffered_write_is_sent_by_interrupts() {
+        let (line, mut ble) = buffered_usart1();
+        assert_eq!(host::peek(USART1_REGS.ctrla()), usart::ctrla::RXCIE.mask);
+
+        assert_eq!(ble.write(b"AT\r\n"), 4);
+        assert!(USART1_REGS.ctrla().is_set(usart::ctrla::DREIE));
+        assert!(line.tx().is_empty());
+
+        service_usart1();
+        assert_eq!(line.tx(), b"AT\r\n");
+        // TX done, only RXCIE is left
+        assert_eq!(host::peek(USART1_REGS.ctrla()), usart::ctrla::RXCIE.mask);
+        ble.flush();
+    }
+
+    #[test]
+    fn buffered_write_stops_when_full() {
+        let (line, mut ble) = buffered_usart1();
+        let data = [0x5A; BUFFER_SIZE + 10];
+        assert_eq!(ble.write(&data), BUFFER_SIZE - 1);
+        assert_eq!(ble.write(&data), 0);
+
+        service_usart1();
+        assert_eq!(line.tx().len(), BUFFER_SIZE - 1);
+        assert_eq!(ble.write(&data), BUFFER_SIZE - 1);
+    }
+
+    #[test]
+    fn buffered_read_gets_reply() {
+        let (line, mut ble) = buffered_usart1();
+        line.respond(|_| b"OK\r\n".to_vec());
+
+        let mut buf = [0u8; 8];
+        assert_eq!(ble.read(&mut buf), 0);
+
+        ufmt::uwrite!(ble, "AT\r\n").unwrap();
+        service_usart1();
+        assert_eq!(ble.read(&mut buf[..2]), 2);
+        assert_eq!(ble.read(&mut buf[2..]), 2);
+        assert_eq!(&buf[..4], b"OK\r\n");
+    }
+
+    #[test]
+    fn buffered_counts_receive_errors() {
+        let (line, mut ble) = buffered_usart1();
+        line.push_rx(b"a");
+        line.push_rx_error(b'b', usart::rxdatah::FERR.mask);
+        line.push_rx_error(b'c', usart::rxdatah::PERR.mask);
+        line.push_rx_error(b'd', usart::rxdatah::BUFOVF.mask);
+        service_usart1();
+
+        assert_eq!(
+            ble.errors(),
+            ErrorCounts {
+                overflow: 1,
+                framing: 1,
+                parity: 1,
+            }
+        );
+        // corrupt bytes are dropped, the one after an overrun is kept
+        let mut buf = [0u8; 8];
+        assert_eq!(ble.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ad");
+
+        ble.clear_errors();
+        assert_eq!(ble.errors(), ErrorCounts::default());
+    }
+
+    #[test]
+    fn buffered_rx_ring_overflow_is_counted() {
+        let (line, mut ble) = buffered_usart1();
+        line.push_rx(&[1; BUFFER_SIZE]);
+        service_usart1();
+
+        assert_eq!(ble.errors().overflow, 1);
+        let mut buf = [0u8; BUFFER_SIZE];
+        assert_eq!(ble.read(&mut buf), BUFFER_SIZE - 1);
+    }
+
+    #[test]
+    fn release_turns_interrupts_off() {
+        let (_line, mut ble) = buffered_usart1();
+        ble.write(b"x");
+        let _ble = ble.release();
+        assert_eq!(host::peek(USART1_REGS.ctrla()), 0);
+    }
+
+    #[test]
+    fn baud_from_clock() {
+        // 16 MHz / 6, the reset clock
+        assert_eq!(
+            calculate_baud(16_000_000 / 6, 9600, false).unwrap(),
+            Baud {
+                value: 1111,
+                clk2x: false
+            }
+        );
+        // normal speed would need BAUD = 40
+        assert_eq!(
+            calculate_baud(20_000_000, 2_000_000, false).unwrap(),
+            Baud {
+                value: 80,
+                clk2x: true
+            }
+        );
+        // synchronous only has the integer part
+        assert_eq!(
+            calculate_baud(20_000_000, 1_000_000, true).unwrap().value,
+            10 << 6
+        );
+    }
+
+    #[test]
+    fn baud_out_of_range() {
+        // too fast even with CLK2X
+        assert!(calculate_baud(32_768, 9600, false).is_err());
+        // BAUD doesn't fit
+        assert!(calculate_baud(20_000_000, 300, false).is_err());
+        // 20 MHz / 2 / 3 is 11% away from 3 MHz
+        assert!(calculate_baud(20_000_000, 3_000_000, true).is_err());
+        assert!(calculate_baud(20_000_000, 0, false).is_err());
+    }
+
+    #[test]
+    fn change_baud_switches_to_clk2x() {
+        host::reset();
+        let pins = Pins::new();
+        let stdout = setup_8n1(pins.pb4, pins.pb5, 115200);
+        let clocks = Clocks::from_hz(20_000_000 / 6);
+
+        // 4 * 3.33 MHz / 115200 = 115.7
+        stdout.change_baud(115200, &clocks).unwrap();
+        assert_eq!(host::peek(USART3_REGS.baud().low()), 116);
+        assert_eq!(
+            host::peek(USART3_REGS.ctrlb()) & usart::ctrlb::RXMODE.mask,
+            0
+        );
+
+        // 1 Mbps is too far off even with CLK2X
+        assert!(stdout.change_baud(1_000_000, &clocks).is_err());
+        // 8 * 3.33 MHz / 250000 = 106.7
+        stdout.change_baud(250_000, &clocks).unwrap();
+        assert_eq!(host::peek(USART3_REGS.baud().low()), 107);
+        assert_eq!(
+            host::peek(USART3_REGS.ctrlb()) & usart::ctrlb::RXMODE.mask,
+            usart::ctrlb::RXMODE.bits(usart::RXMODE::CLK2X as u8)
+        );
+    }
+
+    #[test]
+    fn bus_status_flags() {
+        let s = BusStatus(0b0000_0001);
+        assert!(s.wfb());
+        assert!(!s.rxcif());
+        let s = BusStatus(0b1000_0000);
+        assert!(s.rxcif());
+        assert!(!s.wfb());
     }
 }
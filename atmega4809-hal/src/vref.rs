@@ -0,0 +1,145 @@
+//! VREF, the internal voltage references of ADC0 and AC0.
+//!
+//! Each of the two has its own [`Reference`], picked independently. The
+//! voltage is part of the type, so the drivers that take one convert to
+//! millivolts without being told again.
+//!
+//! ```ignore
+//! let (adc_ref, ac_ref) = dp.VREF.split();
+//! let adc = dp.ADC0.setup(adc_ref.set::<V2_5>(), ADCConfig::new(), &clocks)?;
+//! ```
+
+use crate::regs::{vref, Field, VREF as REGS};
+use core::marker::PhantomData;
+
+/// VREF, handed out once by [`crate::Peripherals`].
+pub struct VREF {
+    _private: (),
+}
+
+/// Reference of ADC0.
+pub struct Adc;
+
+/// Reference of AC0, the base of its DACREF.
+pub struct Ac;
+
+/// An internal reference voltage.
+pub trait Voltage {
+    const MILLIVOLTS: u16;
+    /// ADC0REFSEL and AC0REFSEL, they are the same
+    const REFSEL: u8;
+}
+
+macro_rules! voltages {
+    ($($name:ident: $mv:literal $sel:ident,)*) => {
+        $(
+            #[doc = concat!(stringify!($mv), " mV")]
+            pub struct $name;
+
+            impl Voltage for $name {
+                const MILLIVOLTS: u16 = $mv;
+                const REFSEL: u8 = vref::ADC0REFSEL::$sel.bits();
+            }
+        )*
+    };
+}
+
+voltages! {
+    V0_55: 550 _0V55,
+    V1_1: 1100 _1V1,
+    V1_5: 1500 _1V5,
+    V2_5: 2500 _2V5,
+    V4_3: 4340 _4V34,
+}
+
+/// The reference of `USER` ([`Adc`] or [`Ac`]) at `V`.
+pub struct Reference<USER, V> {
+    _marker: PhantomData<(USER, V)>,
+}
+
+/// Which VREF bits belong to a user.
+pub trait User {
+    #[doc(hidden)]
+    const REFSEL: Field;
+    #[doc(hidden)]
+    const REFEN: Field;
+}
+
+impl User for Adc {
+    const REFSEL: Field = vref::ctrla::ADC0REFSEL;
+    const REFEN: Field = vref::ctrlb::ADC0REFEN;
+}
+
+impl User for Ac {
+    const REFSEL: Field = vref::ctrla::AC0REFSEL;
+    const REFEN: Field = vref::ctrlb::AC0REFEN;
+}
+
+impl VREF {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on VREF.
+    ///
+    /// # Safety
+    ///
+    /// A voltage picked through it changes what the ADC0 or AC0 owner
+    /// measures against, their millivolts are then off by the ratio.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// The two references, at the 0.55 V they start with.
+    pub fn split(self) -> (Reference<Adc, V0_55>, Reference<Ac, V0_55>) {
+        (Reference::new(), Reference::new())
+    }
+}
+
+impl<USER: User, V: Voltage> Reference<USER, V> {
+    const fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+
+    /// Switches to `W`.
+    pub fn set<W: Voltage>(self) -> Reference<USER, W> {
+        REGS.ctrla().write_field(USER::REFSEL, W::REFSEL);
+        Reference::new()
+    }
+
+    pub fn millivolts(&self) -> u16 {
+        V::MILLIVOLTS
+    }
+
+    /// Keeps the reference running when its user doesn't ask for it, it
+    /// needs no start-up time then.
+    pub fn force_on(&mut self, on: bool) {
+        if on {
+            REGS.ctrlb().set_bits(USER::REFEN);
+        } else {
+            REGS.ctrlb().clear_bits(USER::REFEN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+
+    #[test]
+    fn independent_references() {
+        host::reset();
+        let (adc, ac) = VREF::new().split();
+        let adc = adc.set::<V2_5>();
+        let mut ac = ac.set::<V4_3>();
+        assert_eq!(host::peek(REGS.ctrla()), 0x23);
+        assert_eq!(adc.millivolts(), 2500);
+        ac.force_on(true);
+        assert_eq!(host::peek(REGS.ctrlb()), 0x01);
+        ac.force_on(false);
+        assert_eq!(host::peek(REGS.ctrlb()), 0x00);
+    }
+}
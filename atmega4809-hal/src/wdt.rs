@@ -0,0 +1,252 @@
+//! The watchdog timer.
+//!
+//! Once started, the chip resets unless [`Watchdog::feed`] is called within
+//! the period. With a window, feeding too early resets it as well: the
+//! feed has to come after the window and before the period after that.
+//! Periods are in cycles of the 1.024 kHz ULP oscillator, about a
+//! millisecond each. CTRLA is protected by CCP, and locked for good until
+//! the next reset after [`WDTConfig::lock`].
+//!
+//! The watchdog can also be on from reset through FUSE0, [`WDT::setup`]
+//! changes it then, unless the fuse locked it.
+//!
+//! ```ignore
+//! let mut wdt = dp.WDT.setup(WDTConfig::new(Period::Ms256)).map_err(|(_, e)| e)?;
+//! loop {
+//!     // ...
+//!     wdt.feed();
+//! }
+//! ```
+
+use crate::regs::{wdt, WDT as REGS};
+use crate::sleep;
+
+/// The WDT, handed out once by [`crate::Peripherals`].
+pub struct WDT {
+    _private: (),
+}
+
+/// Cycles of the 1.024 kHz clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Ms8,
+    Ms16,
+    Ms32,
+    Ms64,
+    Ms128,
+    Ms256,
+    Ms512,
+    Ms1024,
+    Ms2048,
+    Ms4096,
+    Ms8192,
+}
+
+impl Period {
+    pub fn cycles(&self) -> u16 {
+        8 << *self as u16
+    }
+
+    /// PERIOD and WINDOW, 0 is off
+    fn bits(&self) -> u8 {
+        *self as u8 + 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WDTError {
+    /// The settings are locked, by [`WDTConfig::lock`] or the fuse.
+    Locked,
+}
+
+/// How the watchdog is set up, applied by [`WDT::setup`].
+#[derive(Clone, Copy)]
+pub struct WDTConfig {
+    period: Period,
+    window: Option<Period>,
+    lock: bool,
+}
+
+impl WDTConfig {
+    /// Resets after `period` without a feed.
+    pub const fn new(period: Period) -> Self {
+        Self {
+            period,
+            window: None,
+            lock: false,
+        }
+    }
+
+    /// Resets on a feed sooner than `window` after the last one.
+    pub const fn window(mut self, window: Period) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Keeps anything from changing or stopping the watchdog until reset.
+    pub const fn lock(mut self) -> Self {
+        self.lock = true;
+        self
+    }
+}
+
+fn is_locked() -> bool {
+    REGS.status().is_set(wdt::status::LOCK)
+}
+
+/// Writes CTRLA once the last write reached the WDT clock domain.
+fn write_ctrla(v: u8) {
+    while REGS.status().is_set(wdt::status::SYNCBUSY) {}
+    REGS.ctrla().protected_write(v);
+}
+
+/// The `wdr` instruction.
+#[inline(always)]
+fn wdr() {
+    #[cfg(all(target_arch = "avr", not(any(test, feature = "host"))))]
+    unsafe {
+        core::arch::asm!("wdr")
+    };
+}
+
+impl WDT {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// A second handle on the watchdog.
+    ///
+    /// # Safety
+    ///
+    /// A new period or window set through it isn't what the owner feeds the
+    /// watchdog for: too short a period or a window it doesn't know about
+    /// resets the chip, stopping it leaves the owner unprotected.
+    pub unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    /// Starts the watchdog, or changes it if it already runs.
+    pub fn setup(self, config: WDTConfig) -> Result<Watchdog, (Self, WDTError)> {
+        if is_locked() {
+            return Err((self, WDTError::Locked));
+        }
+        let running = REGS.ctrla().read();
+        if running & wdt::ctrla::WINDOW.mask != 0 {
+            // a wdr in a closed window resets the chip, any time is open
+            // without one
+            write_ctrla(running & wdt::ctrla::PERIOD.mask);
+            while REGS.status().is_set(wdt::status::SYNCBUSY) {}
+        }
+        let window = config.window.map_or(0, |w| w.bits());
+        // the count starts over
+        wdr();
+        write_ctrla(
+            wdt::ctrla::WINDOW.bits(window) | wdt::ctrla::PERIOD.bits(config.period.bits()),
+        );
+        if config.lock {
+            while REGS.status().is_set(wdt::status::SYNCBUSY) {}
+            REGS.status().protected_write(wdt::status::LOCK.mask);
+        }
+        Ok(Watchdog { wdt: self, config })
+    }
+}
+
+/// A running watchdog.
+pub struct Watchdog {
+    wdt: WDT,
+    config: WDTConfig,
+}
+
+impl Watchdog {
+    /// Starts the period over. With a window, only after the window.
+    #[inline(always)]
+    pub fn feed(&mut self) {
+        wdr();
+    }
+
+    pub fn period(&self) -> Period {
+        self.config.period
+    }
+
+    pub fn is_locked(&self) -> bool {
+        is_locked()
+    }
+
+    /// Stops the watchdog, unless it is locked.
+    pub fn stop(self) -> Result<WDT, (Self, WDTError)> {
+        if is_locked() {
+            return Err((self, WDTError::Locked));
+        }
+        write_ctrla(0);
+        Ok(self.wdt)
+    }
+}
+
+/// Resets the chip through the watchdog: sets the shortest period, unless
+/// locked, and sleeps until it bites. For panic handlers. A locked
+/// watchdog bites after its own period.
+pub fn force_reset() -> ! {
+    crate::interrupt::disable();
+    if !is_locked() {
+        write_ctrla(wdt::ctrla::PERIOD.bits(Period::Ms8.bits()));
+    }
+    // the watchdog keeps running in power-down
+    sleep::halt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host;
+    use crate::regs::{cpu, CPU};
+
+    #[test]
+    fn setup_window_and_lock() {
+        host::reset();
+        let config = WDTConfig::new(Period::Ms256).window(Period::Ms32);
+        let mut wdt = WDT::new().setup(config).ok().unwrap();
+        assert_eq!(Period::Ms256.cycles(), 256);
+        assert_eq!(host::peek(REGS.ctrla()), 0x36);
+        let ccp = (CPU.ccp().addr(), cpu::CCP::IOREG as u8);
+        assert_eq!(host::writes(), [ccp, (REGS.ctrla().addr(), 0x36)]);
+        wdt.feed();
+        let wdt = wdt.stop().ok().unwrap();
+        assert_eq!(host::peek(REGS.ctrla()), 0x00);
+
+        let wdt = wdt
+            .setup(WDTConfig::new(Period::Ms8192).lock())
+            .ok()
+            .unwrap();
+        assert_eq!(host::peek(REGS.ctrla()), 0x0B);
+        assert_eq!(host::peek(REGS.status()), 0x80);
+        assert!(wdt.is_locked());
+        let (wdt, e) = wdt.stop().err().unwrap();
+        assert_eq!(e, WDTError::Locked);
+        assert_eq!(host::peek(REGS.ctrla()), 0x0B);
+        assert_eq!(wdt.period(), Period::Ms8192);
+
+        let (_, e) = WDT::new().setup(WDTConfig::new(Period::Ms8)).err().unwrap();
+        assert_eq!(e, WDTError::Locked);
+        assert_eq!(host::peek(REGS.ctrla()), 0x0B);
+    }
+
+    #[test]
+    fn setup_leaves_the_window_first() {
+        host::reset();
+        host::poke(REGS.ctrla(), 0x36);
+        WDT::new()
+            .setup(WDTConfig::new(Period::Ms1024))
+            .ok()
+            .unwrap();
+        let ccp = (CPU.ccp().addr(), cpu::CCP::IOREG as u8);
+        assert_eq!(
+            host::writes(),
+            [
+                ccp,
+                (REGS.ctrla().addr(), 0x06),
+                ccp,
+                (REGS.ctrla().addr(), 0x08)
+            ]
+        );
+    }
+}
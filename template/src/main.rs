@@ -3,79 +3,75 @@
 #![no_std]
 #![no_main]
 
-use atmega4809_hal::clock::{self, ClockPrescaler, ClockSelect};
-use atmega4809_hal::gpio::{GPIO, ISC};
-use atmega4809_hal::i2c::I2C;
+use atmega4809_hal::clock::{ClockConfig, ClockPrescaler, ClockSelect, Clocks};
+use atmega4809_hal::gpio::{Floating, Input, Output, Pin, PB4, PB5, PD0, PD1, PE2, PF4};
 use atmega4809_hal::usart::{USART, USART3};
+use atmega4809_hal::{sleep, Peripherals};
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
-    loop {
-        ONBOARD_LED.output_high();
-        //poweroff
-        clock::Sleep::Idle.set_sleep();
-        ONBOARD_LED.output_low();
-    }
+    let mut onboard_led: OnboardLed = unsafe { Pin::steal() };
+    onboard_led.set_high();
+    #[cfg(feature = "panic-reset")]
+    atmega4809_hal::wdt::force_reset();
+    #[cfg(not(feature = "panic-reset"))]
+    sleep::halt()
 }
 
 type Stdout = USART<USART3, true>;
-const STDOUT: Stdout = USART;
 
-const ANALOG_DRDY: GPIO = GPIO::PORTD(0);
-const BLE_STATE: GPIO = GPIO::PORTF(4);
-const BLE_KEY: GPIO = GPIO::PORTD(1);
-const ONBOARD_LED: GPIO = GPIO::PORTE(2);
+type AnalogDrdy = PD0<Input<Floating>>;
+type BleState = PF4<Input<Floating>>;
+type BleKey = PD1<Output>;
+type OnboardLed = PE2<Output>;
 
-fn setup_usart() {
-    Stdout::setup(
-        ((17 << 6) | 0b0001_1000) / 6, //9600 / 6 = 57200
+fn setup_usart(usart: USART<USART3, false>, tx: PB4, rx: PB5, clocks: &Clocks) -> Stdout {
+    usart.setup(
+        tx,
+        rx,
+        57600,
         atmega4809_hal::usart::CommunicationMode::Asynchronous,
         atmega4809_hal::usart::ParityMode::Disabled,
         atmega4809_hal::usart::StopBitMode::One,
         atmega4809_hal::usart::CharacterSize::B8,
-    );
+        clocks,
+    )
+    .unwrap()
 }
 
 #[no_mangle]
 pub fn main() -> ! {
-    real_main();
+    let mut stdout = real_main(Peripherals::take().unwrap());
     // Wait for any peripherials to finish.
     for _ in 0..0x100 {
         unsafe { core::arch::asm!("nop") };
     }
-    ufmt::uwrite!(STDOUT, "Idling...\r\n").unwrap();
+    ufmt::uwrite!(stdout, "Idling...\r\n").unwrap();
 
-    //Ble::off();
-    Stdout::off();
-    clock::Sleep::Idle.set_sleep();
-    loop {}
+    //ble.off();
+    stdout.off();
+    sleep::halt()
 }
 
-pub fn real_main() {
-    ClockSelect::OSC20M.set_clock();
-    //ClockSelect::OSCULP32K.set_clock();
-    ClockPrescaler::None.set_clock_prescaler();
-    setup_usart();
-    I2C::setup();
-
-    ANALOG_DRDY.output_disable();
-    ANALOG_DRDY.pin_ctrl_pullup(false);
-    ANALOG_DRDY.pin_ctrl_isc(&ISC::IntDisable);
+pub fn real_main(dp: Peripherals) -> Stdout {
+    let pins = dp.pins;
 
-    BLE_STATE.output_disable();
-    BLE_STATE.pin_ctrl_pullup(false);
-    BLE_STATE.pin_ctrl_isc(&ISC::IntDisable);
+    let clocks = ClockConfig::new()
+        .source(ClockSelect::OSC20M)
+        //.source(ClockSelect::OSCULP32K)
+        .prescaler(ClockPrescaler::None)
+        .freeze();
+    let mut stdout = setup_usart(dp.USART3, pins.pb4, pins.pb5, &clocks);
+    let _i2c = dp.TWI0.setup(pins.pa2, pins.pa3, 400_000, &clocks);
 
-    BLE_KEY.output_enable();
-    BLE_KEY.pin_ctrl_pullup(false);
-    BLE_KEY.pin_ctrl_isc(&ISC::IntDisable);
-    BLE_KEY.output_high();
+    let _analog_drdy: AnalogDrdy = pins.pd0.into_floating_input();
+    let _ble_state: BleState = pins.pf4.into_floating_input();
+    let _ble_key: BleKey = pins.pd1.into_output_high();
 
-    ONBOARD_LED.output_enable();
-    ONBOARD_LED.pin_ctrl_isc(&ISC::IntDisable);
-    ONBOARD_LED.output_low();
+    let mut onboard_led: OnboardLed = pins.pe2.into_output();
+    onboard_led.set_low();
 
-    ufmt::uwrite!(STDOUT, "Startup complete.\r\n").unwrap();
+    ufmt::uwrite!(stdout, "Startup complete.\r\n").unwrap();
 
     loop {}
 }
@@ -1,129 +1,184 @@
 #![feature(asm_experimental_arch)]
+#![feature(abi_avr_interrupt)]
 #![allow(dead_code)]
 #![no_std]
 #![no_main]
 
+mod nau;
 mod process;
 
-use atmega4809_hal::clock::{self, ClockPrescaler, ClockSelect};
-use atmega4809_hal::gpio::{GPIO, ISC};
-use atmega4809_hal::i2c::I2C;
-use atmega4809_hal::usart::{BAUD9600, USART, USART1, USART3};
+use atmega4809_hal::adc::{ADCConfig, ADC};
+use atmega4809_hal::clock::{ClockConfig, ClockPrescaler, ClockSelect, Clocks};
+use atmega4809_hal::gpio::{
+    Floating, Input, Output, Pin, Sense, PB4, PB5, PC4, PC5, PD0, PD1, PD2, PE2, PF4,
+};
+use atmega4809_hal::rstctrl::{self, ResetCause};
+use atmega4809_hal::tcb::TCB0;
+use atmega4809_hal::time::Monotonic;
+use atmega4809_hal::usart::{BufferedUsart, USART, USART1, USART3};
+use atmega4809_hal::vref::{self, V1_1};
+use atmega4809_hal::{interrupt, sleep, Delay, Peripherals};
 use ufmt::uwrite;
 
+// ANALOG_DRDY, the BLE USART and the Clock
+atmega4809_hal::vectors!(PORTD, USART1_RXC, USART1_DRE, USART1_TXC, TCB0);
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
-    //let _ = uwrite!(STDOUT, "\r\nPanic!\r\n");
+    //let mut stdout = unsafe { Stdout::steal() };
+    //let _ = uwrite!(stdout, "\r\nPanic!\r\n");
     //if let Some(loc) = _info.location() {
-    //let _ = uwrite!(STDOUT, "File: {}\r\nLine: {}\r\n", loc.file(), loc.line());
+    //let _ = uwrite!(stdout, "File: {}\r\nLine: {}\r\n", loc.file(), loc.line());
     //}
-    loop {
-        ONBOARD_LED.output_high();
-        //poweroff
-        clock::Sleep::Idle.set_sleep();
-    }
+    let mut led: OnboardLed = unsafe { Pin::steal() };
+    led.set_high();
+    #[cfg(feature = "panic-reset")]
+    atmega4809_hal::wdt::force_reset();
+    #[cfg(not(feature = "panic-reset"))]
+    sleep::halt()
 }
 
-type Stdout = USART<USART3, true>;
-const STDOUT: Stdout = USART;
-
-type Ble = USART<USART1, true>;
-const BLE: Ble = USART;
-
-const ANALOG_DRDY: GPIO = GPIO::PORTD(0);
-const BLE_STATE: GPIO = GPIO::PORTF(4);
-const BLE_KEY: GPIO = GPIO::PORTD(1);
-const ONBOARD_LED: GPIO = GPIO::PORTE(2);
-const BLE_POWER: GPIO = GPIO::PORTD(2);
-
-const BLE_RX: GPIO = GPIO::PORTC(5);
-const BLE_TX: GPIO = GPIO::PORTC(4);
-
-fn setup_usart() {
-    Stdout::setup(
-        BAUD9600 / 6, //9600 / 6 = 57200
+pub type Stdout = USART<USART3, true>;
+pub type Ble = BufferedUsart<USART1, true>;
+pub type Clock = Monotonic<TCB0>;
+pub type TempSensor = ADC<vref::Reference<vref::Adc, V1_1>>;
+
+pub type AnalogDrdy = PD0<Input<Floating>>;
+type BleState = PF4<Input<Floating>>;
+pub type BleKey = PD1<Output>;
+type OnboardLed = PE2<Output>;
+pub type BlePower = PD2<Output>;
+
+type BleRx = PC5;
+type BleTx = PC4;
+
+fn setup_usart(
+    stdout: USART<USART3, false>,
+    stdout_tx: PB4,
+    stdout_rx: PB5,
+    ble: USART<USART1, false>,
+    ble_tx: BleTx,
+    ble_rx: BleRx,
+    clocks: &Clocks,
+) -> (Stdout, Ble) {
+    let stdout = stdout.setup(
+        stdout_tx,
+        stdout_rx,
+        57600,
         atmega4809_hal::usart::CommunicationMode::Asynchronous,
         atmega4809_hal::usart::ParityMode::Disabled,
         atmega4809_hal::usart::StopBitMode::One,
         atmega4809_hal::usart::CharacterSize::B8,
-    );
-
-    Ble::setup(
-        BAUD9600 / 4, //9600 / 4 = 37600
+        clocks,
+    )
+    .unwrap();
+
+    let ble = ble.setup(
+        ble_tx,
+        ble_rx,
+        38400,
         atmega4809_hal::usart::CommunicationMode::Asynchronous,
         atmega4809_hal::usart::ParityMode::Disabled,
         atmega4809_hal::usart::StopBitMode::One,
         atmega4809_hal::usart::CharacterSize::B8,
-    );
+        clocks,
+    )
+    .unwrap();
+
+    // BLE bytes arrive whenever the module feels like it, let the USART
+    // interrupts catch them
+    (stdout, ble.into_buffered())
 }
 
 #[no_mangle]
 pub fn main() -> ! {
-    real_main();
+    let (mut stdout, mut ble) = real_main(Peripherals::take().unwrap());
     // Wait for any peripherials to finish.
     for _ in 0..0x100 {
         unsafe { core::arch::asm!("nop") };
     }
-    let _ = uwrite!(STDOUT, "Idling...\r\n");
+    let _ = uwrite!(stdout, "Idling...\r\n");
 
-    Ble::off();
-    Stdout::off();
-    clock::Sleep::Idle.set_sleep();
-    loop {}
+    ble.flush();
+    ble.off();
+    stdout.off();
+    sleep::halt()
 }
 
-pub fn real_main() {
-    //default settings
-    ClockSelect::OSC20M.set_clock();
-    ClockPrescaler::D6.set_clock_prescaler();
+pub fn real_main(dp: Peripherals) -> (Stdout, Ble) {
+    let pins = dp.pins;
+    // read before anything can reset again, logged once BLE is up
+    let mut rstctrl = dp.RSTCTRL;
+    let boot = rstctrl.take_cause();
 
-    setup_usart();
-    I2C::setup();
-
-    BLE_POWER.output_enable();
-    BLE_POWER.pin_ctrl_pullup(false);
-    BLE_POWER.pin_ctrl_isc(&ISC::IntDisable);
-    BLE_POWER.output_low();
+    //default settings
+    let clocks = ClockConfig::new()
+        .source(ClockSelect::OSC20M)
+        .prescaler(ClockPrescaler::D6)
+        .freeze();
+    let mut delay = Delay::new(&clocks);
+    // timestamps for the samples
+    let clock: Clock = Monotonic::new(dp.TCB0, &clocks);
+    // board temperature, logged with the samples
+    let (adc_ref, _) = dp.VREF.split();
+    let mut adc: TempSensor = dp
+        .ADC0
+        .setup(
+            adc_ref.set::<V1_1>(),
+            ADCConfig::temperature(&clocks),
+            &clocks,
+        )
+        .unwrap();
+
+    let (mut stdout, mut ble) = setup_usart(
+        dp.USART3, pins.pb4, pins.pb5, dp.USART1, pins.pc4, pins.pc5, &clocks,
+    );
+    let i2c = dp.TWI0.setup(pins.pa2, pins.pa3, 100_000, &clocks);
 
-    ANALOG_DRDY.output_disable();
-    ANALOG_DRDY.pin_ctrl_pullup(false);
-    ANALOG_DRDY.pin_ctrl_isc(&ISC::Rising);
+    let mut ble_power: BlePower = pins.pd2.into_output();
+    ble_power.set_low();
 
-    BLE_STATE.output_disable();
-    BLE_STATE.pin_ctrl_pullup(false);
-    BLE_STATE.pin_ctrl_isc(&ISC::IntDisable);
+    // a new sample is ready on every rising edge
+    let mut analog_drdy: AnalogDrdy = pins.pd0.into_floating_input();
+    analog_drdy.listen(Sense::Rising, process::nau_ready);
+    // a sample that was ready before the pin was armed has no edge left
+    if analog_drdy.is_high() {
+        process::nau_ready();
+    }
 
-    BLE_KEY.output_enable();
-    BLE_KEY.pin_ctrl_pullup(false);
-    BLE_KEY.pin_ctrl_isc(&ISC::IntDisable);
+    let _ble_state: BleState = pins.pf4.into_floating_input();
 
-    ONBOARD_LED.output_enable();
-    ONBOARD_LED.pin_ctrl_isc(&ISC::IntDisable);
-    ONBOARD_LED.output_low();
+    let mut ble_key: BleKey = pins.pd1.into_output();
 
-    BLE_RX.output_disable();
-    BLE_RX.pin_ctrl_pullup(false);
-    BLE_RX.pin_ctrl_isc(&ISC::IntDisable);
+    let mut onboard_led: OnboardLed = pins.pe2.into_output();
+    onboard_led.set_low();
 
+    // BLE_RX is made an input by Ble::setup, pullup and ISC are left at
+    // their reset values
     // maybe need pullup when using serial?
-    //GPIO::PORTC(4).output_enable();
-    GPIO::PORTC(5).pin_ctrl_pullup(false);
-
-    let _ = uwrite!(STDOUT, "Startup complete.\r\n");
 
-    process::ble_begin();
-    let mut nau = process::nau_setup().unwrap_or_else(|_| panic!("Nau setup failed"));
-    process::nau_run(&mut nau);
-}
+    unsafe { interrupt::enable() };
 
-#[no_mangle]
-pub fn __vector_20() {
-    //ONBOARD_LED.output_high();
-}
+    let _ = uwrite!(stdout, "Startup complete.\r\n");
 
-#[no_mangle]
-pub fn __vector_34() {
-    ONBOARD_LED.output_high();
+    process::ble_begin(&mut ble, &mut ble_key, &mut ble_power, &clocks);
+    let _ = uwrite!(
+        ble,
+        "boot: {}\r\n",
+        boot.map_or("unknown", ResetCause::as_str)
+    );
+    let mut nau = match process::nau_setup(i2c, &mut stdout, &mut delay, &clock) {
+        Ok(nau) => nau,
+        // a sensor that didn't answer may come up after a reboot
+        Err(process::FatalStartupError::NoSensor) => {
+            let _ = uwrite!(ble, "no sensor, rebooting\r\n");
+            ble.flush();
+            rstctrl::software_reset()
+        }
+        Err(_) => panic!("Nau setup failed"),
+    };
+    process::nau_run(&mut nau, &mut ble, &clock, &mut adc);
+    (stdout, ble)
 }
 
 pub fn run_bt_command() {
@@ -0,0 +1,46 @@
+//! NAU7802 bring-up. Only needs the embedded-hal traits, so the HAL's
+//! simulator tests run the same flow against a fake NAU7802.
+
+use embedded_hal::delay::blocking::DelayUs;
+use embedded_hal::i2c::blocking::I2c;
+use nau7802::{AfeCalibrationStatus, Gain, Ldo, Nau7802, SamplesPerSecond};
+use ufmt::{uWrite, uwrite};
+
+#[derive(Debug)]
+pub enum FatalStartupError {
+    NoSensor,
+    CalibrationFailure(&'static str),
+}
+
+/// Powers the NAU7802 up at 3.9 V, gain 128 and 80 SPS, then runs an AFE
+/// calibration, logging the progress to `log`.
+pub fn nau_begin<I: I2c, D: DelayUs, W: uWrite>(
+    i2c: I,
+    log: &mut W,
+    delay: &mut D,
+) -> Result<Nau7802<I>, FatalStartupError> {
+    let mut v =
+        Nau7802::new_with_settings(i2c, Ldo::L3v9, Gain::G128, SamplesPerSecond::SPS80, delay)
+            .map_err(|_| FatalStartupError::NoSensor)?;
+
+    let _ = uwrite!(log, "Calibrating.");
+    v.begin_afe_calibration()
+        .map_err(|_| FatalStartupError::CalibrationFailure("nau start"))?;
+
+    loop {
+        match v.poll_afe_calibration_status().unwrap() {
+            AfeCalibrationStatus::InProgress => {
+                let _ = uwrite!(log, ".");
+                let _ = delay.delay_ms(1);
+            }
+            AfeCalibrationStatus::Failure => {
+                let _ = uwrite!(log, "rip\r\n");
+                return Err(FatalStartupError::CalibrationFailure("nau"));
+            }
+            AfeCalibrationStatus::Success => {
+                let _ = uwrite!(log, "success\r\n");
+                return Ok(v);
+            }
+        };
+    }
+}
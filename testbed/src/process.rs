@@ -1,102 +1,111 @@
-use crate::{Ble, ANALOG_DRDY, BLE, BLE_KEY, BLE_POWER, STDOUT};
-use atmega4809_hal::{i2c::I2C, usart::BAUD9600, Delay, DelayMs};
+use crate::{nau, Ble, BleKey, BlePower, Clock, Stdout, TempSensor};
+use atmega4809_hal::sleep::{self, Sleep};
+use atmega4809_hal::time::Duration;
+use atmega4809_hal::{clock::Clocks, i2c::I2C, interrupt, Delay};
 use core::str::from_utf8_unchecked;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::delay::blocking::DelayUs;
 use nau7802::Nau7802;
 use ufmt::uwrite;
 
-pub fn read_nau(n: &mut Nau7802<I2C>) -> Result<i32, ()> {
-    for _ in 0..10000 {
-        if !ANALOG_DRDY.input_read() {
-            continue;
+/// Set from the ANALOG_DRDY interrupt, cleared once the sample is read.
+static NAU_READY: AtomicBool = AtomicBool::new(false);
+
+/// ANALOG_DRDY rising edge handler.
+pub fn nau_ready() {
+    NAU_READY.store(true, Ordering::Relaxed);
+}
+
+fn take_nau_ready() -> bool {
+    interrupt::free(|| {
+        let ready = NAU_READY.load(Ordering::Relaxed);
+        NAU_READY.store(false, Ordering::Relaxed);
+        ready
+    })
+}
+
+/// A few conversions at 80 SPS, the first one after calibration takes
+/// longer than the rest.
+const SAMPLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Waits for the next sample, sleeping in idle until ANALOG_DRDY rises. The
+/// clock's tick wakes it every millisecond to check the deadline.
+pub fn read_nau(n: &mut Nau7802<I2C>, clock: &Clock) -> Result<i32, ()> {
+    let deadline = clock.now() + SAMPLE_TIMEOUT;
+    loop {
+        sleep::sleep_until(Sleep::Idle, || {
+            NAU_READY.load(Ordering::Relaxed) || clock.now() >= deadline
+        })
+        .map_err(|_| ())?;
+        if !take_nau_ready() {
+            return Err(());
         }
         match n.read() {
             Ok(v) => return Ok(v),
-            Err(_) => {} //sleep(10),
+            // DRDY stays high until the sample is read, no edge comes for it
+            Err(_) => nau_ready(),
         };
     }
-    Err(())
 }
 
-#[derive(Debug)]
-pub enum FatalStartupError {
-    NoSensor,
-    CalibrationFailure(&'static str),
-}
+pub use crate::nau::FatalStartupError;
 
 pub type Nau = Nau7802<I2C>;
 
-pub fn nau_setup() -> Result<Nau, FatalStartupError> {
-    let mut v = Nau7802::new_with_settings(
-        I2C,
-        nau7802::Ldo::L3v9,
-        nau7802::Gain::G128,
-        nau7802::SamplesPerSecond::SPS80,
-        &mut Delay,
-    )
-    .map_err(|_| FatalStartupError::NoSensor)?;
-
-    let _ = uwrite!(STDOUT, "Calibrating.");
-    v.begin_afe_calibration()
-        .map_err(|_| FatalStartupError::CalibrationFailure("nau start"))?;
-
-    loop {
-        match v.poll_afe_calibration_status().unwrap() {
-            nau7802::AfeCalibrationStatus::InProgress => {
-                let _ = uwrite!(STDOUT, ".");
-                Delay.delay_ms(1);
-            }
-            nau7802::AfeCalibrationStatus::Failure => {
-                let _ = uwrite!(STDOUT, "rip\r\n");
-                return Err(FatalStartupError::CalibrationFailure("nau"));
-            }
-            nau7802::AfeCalibrationStatus::Success => {
-                let _ = uwrite!(STDOUT, "success\r\n");
-                break;
-            }
-        };
-    }
+pub fn nau_setup(
+    i2c: I2C,
+    stdout: &mut Stdout,
+    delay: &mut Delay,
+    clock: &Clock,
+) -> Result<Nau, FatalStartupError> {
+    let mut v = nau::nau_begin(i2c, stdout, delay)?;
 
     for _ in 0..10 {
-        read_nau(&mut v).unwrap();
+        read_nau(&mut v, clock).unwrap();
     }
 
     Ok(v)
 }
 
-pub fn nau_run(n: &mut Nau7802<I2C>) {
-    let first = read_nau(n).unwrap();
+pub fn nau_run(n: &mut Nau7802<I2C>, ble: &mut Ble, clock: &Clock, adc: &mut TempSensor) {
+    let first = read_nau(n, clock).unwrap();
 
     loop {
-        let s = read_nau(n).unwrap();
+        let s = read_nau(n, clock).unwrap();
         if s.abs_diff(first) > 1000 {
             break;
         }
     }
 
-    ufmt::uwriteln!(BLE, "Triggered.\r\n").unwrap();
+    ufmt::uwriteln!(ble, "Triggered.\r\n").unwrap();
+    let start = clock.now();
 
     for _ in 0..10000 {
-        let s = read_nau(n).unwrap();
+        let s = read_nau(n, clock).unwrap();
+        // microseconds since the trigger
+        let t = clock.elapsed(start).as_micros();
         let s = s - first;
+        // board temperature in °C
+        let c = adc.temperature().unwrap();
 
-        //ufmt::uwrite!(STDOUT, "{}\r\n", s).unwrap();
-        ufmt::uwrite!(BLE, "{}\r\n", s).unwrap();
+        //ufmt::uwrite!(stdout, "{}\r\n", s).unwrap();
+        ufmt::uwrite!(ble, "{},{},{}\r\n", t, s, c).unwrap();
         //let mut k = [0u8; 10];
-        //let k = Ble::transact(b"", &mut k).unwrap();
+        //let k = ble.transact(b"", &mut k).unwrap();
     }
 }
 
-pub fn ble_begin() {
+pub fn ble_begin(ble: &mut Ble, key: &mut BleKey, power: &mut BlePower, clocks: &Clocks) {
     //Delay.delay_ms(200);
-    //BLE_KEY.output_high();
-    //BLE_POWER.output_high();
+    //key.set_high();
+    //power.set_high();
 
     //Delay.delay_ms(200);
 
-    //BLE_POWER.output_low();
-    BLE_KEY.output_low();
-    BLE_POWER.output_high();
-    Ble::change_baud(BAUD9600 / 12);
+    //power.set_low();
+    key.set_low();
+    power.set_high();
+    ble.change_baud(115200, clocks).unwrap();
     //Delay.delay_ms(200);
 
     // Assume \r\n:
@@ -107,25 +116,33 @@ pub fn ble_begin() {
     // AT+BIND=ACD6,18,E95B5F
 }
 
-fn send_ble_terminal_heartbeat() {
+fn send_ble_terminal_heartbeat(ble: &mut Ble, stdout: &mut Stdout, delay: &mut Delay) {
     //loop {
     //let mut k = [0; 10];
-    //let res = Stdout::transact(b"Write: ", &mut k).unwrap();
+    //let res = stdout.transact(b"Write: ", &mut k).unwrap();
     //if res.len() > 0 {
     //break;
     //}
     //}
 
+    let _ = uwrite!(ble, "ping\r\n");
+    // the reply is collected by the RX interrupt meanwhile
+    let _ = delay.delay_ms(500);
+
     let mut k = [0u8; 10];
-    match Ble::transact(b"ping\r\n", &mut k) {
-        Ok(d) => {
-            uwrite!(STDOUT, "-{}\r\n", d.len()).unwrap();
-            let d = unsafe { from_utf8_unchecked(d) };
-            uwrite!(STDOUT, "$$$${}\r\n", d).unwrap();
-        }
-        Err(_) => {
-            uwrite!(STDOUT, "+{}\r\n", k[0]).unwrap();
-        }
+    let n = ble.read(&mut k);
+    uwrite!(stdout, "-{}\r\n", n).unwrap();
+    let d = unsafe { from_utf8_unchecked(&k[..n]) };
+    uwrite!(stdout, "$$$${}\r\n", d).unwrap();
+    let errors = ble.errors();
+    if errors != Default::default() {
+        uwrite!(
+            stdout,
+            "+{} {} {}\r\n",
+            errors.overflow,
+            errors.framing,
+            errors.parity
+        )
+        .unwrap();
     }
-    Delay.delay_ms(500);
 }
@@ -47,8 +47,3 @@
 pub fn __vector_15() {
     todo!()
 }
-
-#[no_mangle]
-pub fn __vector_39() {
-    todo!()
-}
@@ -11,12 +11,14 @@ pub mod avr_alloc;
 pub mod ints;
 pub mod testing;
 
-use atmega4809_hal::clock::{self, ClockPrescaler, ClockSelect};
-use atmega4809_hal::gpio::{GPIO, ISC};
+use atmega4809_hal::clock::{ClockConfig, ClockPrescaler, ClockSelect, Clocks};
+use atmega4809_hal::duty_cycle::SetDutyCycle;
+use atmega4809_hal::gpio::{Output, Pin, PA0, PA4, PA5, PA6, PB1, PB4, PB5, PE2};
 use atmega4809_hal::i2c::I2C;
-use atmega4809_hal::pwm::PWM;
-use atmega4809_hal::usart::{USART, USART1, USART3, BAUD9600};
-use atmega4809_hal::Delay;
+use atmega4809_hal::servo::Servos;
+use atmega4809_hal::tca::{Channel, PWMPort, WaveformGenerationMode, TCA};
+use atmega4809_hal::usart::{USART, USART1, USART3};
+use atmega4809_hal::{sleep, Delay, Peripherals};
 use avr_alloc::AVRAlloc;
 use embedded_hal::delay::blocking::DelayUs;
 
@@ -28,32 +30,31 @@ static ALLOCATOR: AVRAlloc = AVRAlloc::new();
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
-    let _ = ufmt::uwrite!(STDOUT, "PANIC!\r\n");
+    let mut stdout = unsafe { Stdout::steal() };
+    let _ = ufmt::uwrite!(stdout, "PANIC!\r\n");
     let l = _info.location().unwrap().line();
     let f = _info.location().unwrap().file();
-    let _ = ufmt::uwrite!(STDOUT, "{} {}\r\n", l, f);
-    loop {
-        ONBOARD_LED.output_high();
-        //poweroff
-        BRIGHT_LED.output_high();
-        clock::Sleep::Idle.set_sleep();
-        ONBOARD_LED.output_low();
-    }
+    let _ = ufmt::uwrite!(stdout, "{} {}\r\n", l, f);
+    let mut onboard_led: OnboardLed = unsafe { Pin::steal() };
+    let mut bright_led: BrightLed = unsafe { Pin::steal() };
+    onboard_led.set_high();
+    bright_led.set_high();
+    #[cfg(feature = "panic-reset")]
+    atmega4809_hal::wdt::force_reset();
+    #[cfg(not(feature = "panic-reset"))]
+    sleep::halt()
 }
 
 type Stdout = USART<USART3, true>;
-const STDOUT: Stdout = USART;
-
 type Ble = USART<USART1, true>;
-const BLE: Ble = USART;
 
-const ONBOARD_LED: GPIO = GPIO::PORTE(2);
-const BRIGHT_LED: GPIO = GPIO::PORTA(0);
-const PWM_PIN: GPIO = GPIO::PORTB(1);
+type OnboardLed = PE2<Output>;
+type BrightLed = PA0<Output>;
+type PwmPin = PB1<Output>;
 
 fn test_nau() {
     //let mut v = nau7802::Nau7802::new_with_settings(
-        //I2C,
+        //i2c,
         //nau7802::Ldo::L3v3,
         //nau7802::Gain::G128,
         //nau7802::SamplesPerSecond::SPS80,
@@ -69,22 +70,22 @@ fn test_nau() {
             //};
         //};
 
-        //ufmt::uwrite!(STDOUT, "{}\r\n", s).unwrap();
-        //// 0 - 200_000 -> 0x1000 - 0x1800
+        //ufmt::uwrite!(stdout, "{}\r\n", s).unwrap();
+        //// 0 - 200_000 -> 0 - 180 degrees
         //let sanatized = match s + 100_000 {
             //i32::MIN..=0 => 0,
             //v @ 1..=200_000 => v,
             //200_001..=i32::MAX => 200_000,
         //};
 
-        //PWM::set_cmp1(((sanatized * 0x800) / 200_000 + 0x1000).try_into().unwrap());
+        //servos.set_angle(Channel::Cmp1, (sanatized * 180 / 200_000) as u16);
         //sleep(0xff00);
     //}
 }
 
-fn test_bme() {
-    //let b = bme280::i2c::BME280::new(I2C, 0x77);
-    let mut bme = bme280::i2c::BME280::new_secondary(I2C);
+fn test_bme(i2c: I2C, stdout: &mut Stdout, delay: &mut Delay) {
+    //let b = bme280::i2c::BME280::new(i2c, 0x77);
+    let mut bme = bme280::i2c::BME280::new_secondary(i2c);
 
     // or, initialize the BME280 using the secondary I2C address 0x77
     // let mut bme280 = BME280::new_secondary(i2c_bus, Delay);
@@ -93,153 +94,170 @@ fn test_bme() {
     // let bme280_i2c_addr = 0x88;
     // let mut bme280 = BME280::new(i2c_bus, bme280_i2c_addr, Delay);
 
-    ufmt::uwrite!(STDOUT, "BME Init Start\r\n").unwrap();
+    ufmt::uwrite!(stdout, "BME Init Start\r\n").unwrap();
     // initialize the sensor
-    bme.init(&mut Delay).unwrap();
-    ufmt::uwrite!(STDOUT, "BME Init Complete, starting base measure\r\n").unwrap();
+    bme.init(delay).unwrap();
+    ufmt::uwrite!(stdout, "BME Init Complete, starting base measure\r\n").unwrap();
 
-    Delay.delay_ms(1).unwrap();
+    delay.delay_ms(1).unwrap();
 
     // measure temperature, pressure, and humidity
-    let measurements = match bme.measure(&mut Delay) {
+    let measurements = match bme.measure(delay) {
         Ok(m) => m,
         Err(bme280::Error::CompensationFailed) => {
-            ufmt::uwrite!(STDOUT, "BME Compensation Failed\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME Compensation Failed\r\n").unwrap();
             return;
         },
         Err(bme280::Error::Bus(_)) => {
-            ufmt::uwrite!(STDOUT, "BME BUS\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME BUS\r\n").unwrap();
             return;
         },
         Err(bme280::Error::InvalidData) => {
-            ufmt::uwrite!(STDOUT, "BME Invalid Data\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME Invalid Data\r\n").unwrap();
             return;
         },
         Err(bme280::Error::NoCalibrationData) => {
-            ufmt::uwrite!(STDOUT, "BME No Calibration Data\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME No Calibration Data\r\n").unwrap();
             return;
         },
         Err(bme280::Error::UnsupportedChip) => {
-            ufmt::uwrite!(STDOUT, "BME Unsupported Chip\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME Unsupported Chip\r\n").unwrap();
             return;
         },
         Err(bme280::Error::Delay) => {
-            ufmt::uwrite!(STDOUT, "BME Delay\r\n").unwrap();
+            ufmt::uwrite!(stdout, "BME Delay\r\n").unwrap();
             return;
         },
     };
 
-    ufmt::uwrite!(STDOUT, "Relative Humidity = {}%", measurements.humidity as u32).unwrap();
-    ufmt::uwrite!(STDOUT, "Temperature = {} deg C", measurements.temperature as u32).unwrap();
-    ufmt::uwrite!(STDOUT, "Pressure = {} pascals", measurements.pressure as u32).unwrap();
+    ufmt::uwrite!(stdout, "Relative Humidity = {}%", measurements.humidity as u32).unwrap();
+    ufmt::uwrite!(stdout, "Temperature = {} deg C", measurements.temperature as u32).unwrap();
+    ufmt::uwrite!(stdout, "Pressure = {} pascals", measurements.pressure as u32).unwrap();
 }
 
-fn setup_pwm() {
-    PWM_PIN.output_enable();
-    PWM_PIN.pin_ctrl_isc(&ISC::IntDisable);
-    PWM::change_port_tca(atmega4809_hal::pwm::PWMPort::PORTB); // pin 28
-    //PWM::set_per(69); //38.13khz (IR transmission = 38khz)
-    PWM::set_per(0xAF00); //60hz
-    PWM::enable(atmega4809_hal::pwm::WaveformGenerationMode::SINGLESLOPE);
-    PWM::set_cmp1(0xAF00 / 2); //60hz
+fn setup_pwm(tca: &mut TCA, pin: PB1, clocks: &Clocks) -> PwmPin {
+    let pin = pin.into_output();
+    tca.set_port(PWMPort::PORTB); // pin 28
+    tca.set_waveform(WaveformGenerationMode::SINGLESLOPE);
+    tca.set_frequency(60, clocks).unwrap();
+    tca.enable_output(Channel::Cmp1);
+    tca.pwm_channel(Channel::Cmp1).set_duty_cycle_percent(50).unwrap();
+    tca.force_update();
+    tca.enable(tca.divider());
+    pin
 }
 
-fn setup_usart() {
-    Stdout::setup(
-        BAUD9600 / 12, //9600 / 6 = 57200
+fn setup_usart(usart: USART<USART3, false>, tx: PB4, rx: PB5, clocks: &Clocks) -> Stdout {
+    usart.setup(
+        tx,
+        rx,
+        115200,
         atmega4809_hal::usart::CommunicationMode::Asynchronous,
         atmega4809_hal::usart::ParityMode::Disabled,
         atmega4809_hal::usart::StopBitMode::One,
         atmega4809_hal::usart::CharacterSize::B8,
-    );
+        clocks,
+    )
+    .unwrap()
 }
 
-fn test_pwm() {
-    setup_pwm();
+fn test_servo(mut tca: TCA, pin: PB1, clocks: &Clocks, delay: &mut Delay) {
+    let _pin: PwmPin = pin.into_output();
+    tca.set_port(PWMPort::PORTB); // pin 28
+    let mut servos = Servos::new(tca, clocks).unwrap();
+    servos.set_angle(Channel::Cmp1, 0);
+    servos.enable(Channel::Cmp1);
     loop {
-        for x in (0x1000..0x1400).step_by(0x10) {
-            PWM::set_cmp1(x);
-            sleep(0xFFFF);
+        for angle in (0..=180).chain((0..180).rev()) {
+            servos.set_angle(Channel::Cmp1, angle);
+            delay.delay_ms(10).unwrap();
         }
     }
 }
 
-//fn test_icm() {
-    //ufmt::uwrite!(STDOUT, "Starting to measure {:x}\r\n", 0x69).unwrap();
-    //let icm: ICMI2C<I2C, atmega4809_hal::i2c::I2CError, 0x69> = ICMI2C::new(&mut I2C).unwrap();
+//fn test_icm(mut i2c: I2C, stdout: &mut Stdout) {
+    //ufmt::uwrite!(stdout, "Starting to measure {:x}\r\n", 0x69).unwrap();
+    //let icm: ICMI2C<I2C, atmega4809_hal::i2c::I2CError, 0x69> = ICMI2C::new(&mut i2c).unwrap();
     //loop {
-        //let v = icm.get_values_accel_gyro(&mut I2C).unwrap();
+        //let v = icm.get_values_accel_gyro(&mut i2c).unwrap();
 
-        //ufmt::uwrite!(STDOUT, "{:?}\r\n", v).unwrap();
+        //ufmt::uwrite!(stdout, "{:?}\r\n", v).unwrap();
     //}
 //}
 
-fn test_ble() {
-    Stdout::transact(b"Starting BLE Tets\r\n", &mut []);
+fn test_ble(stdout: &mut Stdout, ble: &mut Ble) {
+    stdout.transact(b"Starting BLE Tets\r\n", &mut []);
 
     //let d = data_back.into_iter().take_while(|c| c != b'\n').collect();
     let mut data_back = [0; 10];
-    Ble::transact(b"AT\r\n", &mut data_back);
+    ble.transact(b"AT\r\n", &mut data_back);
 
-    ufmt::uwrite!(STDOUT, "Ok\r\n").unwrap();
-    //ufmt::uwrite!(STDOUT, "Got back some data: '{}'\r\n", d).unwrap();
+    ufmt::uwrite!(stdout, "Ok\r\n").unwrap();
+    //ufmt::uwrite!(stdout, "Got back some data: '{}'\r\n", d).unwrap();
 }
 
-fn test_spi() {
+fn test_spi(
+    spi: atmega4809_hal::spi::SPI,
+    pins: (PA4, PA5, PA6),
+    stdout: &mut Stdout,
+    clocks: &Clocks,
+) {
     use atmega4809_hal::spi;
-    use spi::SPI;
-    ufmt::uwrite!(STDOUT, "Starting SPI...\r\n").unwrap();
-    SPI::setup(false, false, spi::Polarity::P0);
-    ufmt::uwrite!(STDOUT, "SPI Setup Complete\r\n").unwrap();
+    ufmt::uwrite!(stdout, "Starting SPI...\r\n").unwrap();
+    let (mosi, miso, sck) = pins;
+    let spi = spi.setup(mosi, miso, sck, 100_000, false, spi::Polarity::P0, clocks);
+    ufmt::uwrite!(stdout, "SPI Setup Complete\r\n").unwrap();
     let mut test = [1, 2, 3, 4u8];
-    //match SPI.transfer(&mut test) {
+    //match spi.transfer(&mut test) {
         //Ok(v) => {
-            //ufmt::uwrite!(STDOUT, "OK\r\n").unwrap();
+            //ufmt::uwrite!(stdout, "OK\r\n").unwrap();
         //}
         //Err(e) => {
-            //ufmt::uwrite!(STDOUT, "Err {:?}\r\n", e).unwrap();
+            //ufmt::uwrite!(stdout, "Err {:?}\r\n", e).unwrap();
         //}
     //}
-    ufmt::uwrite!(STDOUT, "Transfer Complete\r\n").unwrap();
+    ufmt::uwrite!(stdout, "Transfer Complete\r\n").unwrap();
 }
 
-fn test_imu() {
-    ufmt::uwrite!(STDOUT, "Yo...\r\n").unwrap();
+fn test_imu(stdout: &mut Stdout) {
+    ufmt::uwrite!(stdout, "Yo...\r\n").unwrap();
 }
 
 #[no_mangle]
 pub fn main() -> ! {
     sleep(0xff);
-    real_main();
+    let mut stdout = real_main(Peripherals::take().unwrap());
     // Wait for any peripherials to finish.
     for _ in 0..10 {
         sleep(0xffff);
     }
 
-    let _ = ufmt::uwrite!(STDOUT, "Idling...\r\n");
+    let _ = ufmt::uwrite!(stdout, "Idling...\r\n");
 
-    //Ble::off();
-    Stdout::off();
-    clock::Sleep::Idle.set_sleep();
-    loop {}
+    //ble.off();
+    stdout.off();
+    sleep::halt()
 }
 
-pub fn real_main() {
-    ClockSelect::OSC20M.set_clock();
-    //ClockSelect::OSCULP32K.set_clock();
-    ClockPrescaler::None.set_clock_prescaler();
+pub fn real_main(dp: Peripherals) -> Stdout {
+    let pins = dp.pins;
+
+    let clocks = ClockConfig::new()
+        .source(ClockSelect::OSC20M)
+        //.source(ClockSelect::OSCULP32K)
+        .prescaler(ClockPrescaler::None)
+        .freeze();
+    let mut delay = Delay::new(&clocks);
 
-    ONBOARD_LED.output_enable();
-    ONBOARD_LED.pin_ctrl_isc(&ISC::IntDisable);
-    BRIGHT_LED.output_enable();
-    BRIGHT_LED.pin_ctrl_isc(&ISC::IntDisable);
-    ONBOARD_LED.output_low();
-    BRIGHT_LED.output_low();
+    let mut onboard_led: OnboardLed = pins.pe2.into_output();
+    let mut bright_led: BrightLed = pins.pa0.into_output();
+    onboard_led.set_low();
+    bright_led.set_low();
 
-    I2C::setup();
-    //setup_pwm();
-    //test_pwm();
-    setup_usart();
+    let i2c = dp.TWI0.setup(pins.pa2, pins.pa3, 400_000, &clocks);
+    //setup_pwm(&mut dp.TCA0, pins.pb1, &clocks);
+    //test_servo(dp.TCA0, pins.pb1, &clocks, &mut delay);
+    let mut stdout = setup_usart(dp.USART3, pins.pb4, pins.pb5, &clocks);
 
     //let mut x = alloc::vec::Vec::new();
     //x.push(2u8);
@@ -252,21 +270,22 @@ pub fn real_main() {
     // Test Floats
     let s = 3.0;
     for i in 0..10 {
-        ufmt::uwrite!(STDOUT, "Counting to 10: {}\r\n", i).unwrap();
-        Delay.delay_ms(1000).unwrap();
+        ufmt::uwrite!(stdout, "Counting to 10: {}\r\n", i).unwrap();
+        delay.delay_ms(1000).unwrap();
     }
 
-    //ufmt::uwrite!(STDOUT, "Nice\r\n").unwrap();
-    //ufmt::uwrite!(STDOUT, "Test Heaps: 1=0x{:x} 2=0x{:x}\r\n", ptr, ptr2).unwrap();
-    //ufmt::uwrite!(STDOUT, "Test Heaps: 1=0x{:x} 2=0x{:x}\r\n", 1, 2).unwrap();
+    //ufmt::uwrite!(stdout, "Nice\r\n").unwrap();
+    //ufmt::uwrite!(stdout, "Test Heaps: 1=0x{:x} 2=0x{:x}\r\n", ptr, ptr2).unwrap();
+    //ufmt::uwrite!(stdout, "Test Heaps: 1=0x{:x} 2=0x{:x}\r\n", 1, 2).unwrap();
 
-    ufmt::uwrite!(STDOUT, "Startup complete...\r\n").unwrap();
+    ufmt::uwrite!(stdout, "Startup complete...\r\n").unwrap();
 
-    //test_bme();
-    //test_ble();
+    //test_bme(i2c, &mut stdout, &mut delay);
+    //test_ble(&mut stdout, &mut ble);
     //test_nau();
-    //test_icm();
-    //BRIGHT_LED.output_high();
-    //test_spi();
-    //test_imu();
+    //test_icm(i2c, &mut stdout);
+    //bright_led.set_high();
+    //test_spi(dp.SPI0, (pins.pa4, pins.pa5, pins.pa6), &mut stdout, &clocks);
+    //test_imu(&mut stdout);
+    stdout
 }